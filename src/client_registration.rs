@@ -0,0 +1,279 @@
+//! src/client_registration.rs
+//!
+//! This module implements OAuth 2.0 dynamic client registration (RFC 7591), so that adding a
+//! client doesn't need an administrator to click through the admin API. Registration is
+//! protected by initial access tokens: an administrator issues a token that can register a
+//! limited number of clients before it expires, e.g. for a CI pipeline or a partner.
+//!
+//! Dynamically registered clients get the server's token lifetimes. Only administrators can
+//! give a client other lifetimes through the admin API.
+
+use crate::database::{Database, OAuthClient, RegistrationToken};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::oauth::{
+    oauth_error, register_client, validate_redirect_uri, validate_registration, ClientRegistration,
+    ClientType,
+};
+use crate::tokens::generate_opaque_token;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+const REGISTRATION_TOKEN_LIFETIME_ENV: &str = "OAUTH_REGISTRATION_TOKEN_LIFETIME_SECONDS";
+const DEFAULT_REGISTRATION_TOKEN_LIFETIME_SECONDS: i64 = 24 * 60 * 60;
+
+/// The authentication method of public clients at the token endpoint.
+const NONE_AUTH_METHOD: &str = "none";
+/// The authentication method of confidential clients using HTTP Basic, the default.
+const CLIENT_SECRET_BASIC_AUTH_METHOD: &str = "client_secret_basic";
+/// The authentication method of confidential clients sending their secret in the body.
+const CLIENT_SECRET_POST_AUTH_METHOD: &str = "client_secret_post";
+
+/// Represents the metadata of a client to register (RFC 7591, section 2).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientMetadata {
+    /// The redirect URIs the client may use.
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    /// How the client authenticates at the token endpoint. `none` registers a public client.
+    pub token_endpoint_auth_method: Option<String>,
+    /// The grant types the client may use.
+    pub grant_types: Option<Vec<String>>,
+    /// The response types the client may use. Only `code` is supported.
+    pub response_types: Option<Vec<String>>,
+    /// The name of the client, shown to users.
+    pub client_name: Option<String>,
+    /// The URL of the client's logo, shown to users next to its name.
+    pub logo_uri: Option<String>,
+    /// The space-separated scopes the client may be granted.
+    pub scope: Option<String>,
+}
+
+/// Represents the response to a successful registration (RFC 7591, section 3.2.1).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInformationResponse {
+    /// The ID of the new client.
+    pub client_id: String,
+    /// The secret of a confidential client. It is only returned once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    /// The timestamp at which the client was registered.
+    pub client_id_issued_at: i64,
+    /// When the secret expires. `0` because secrets don't expire until they are rotated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret_expires_at: Option<i64>,
+    /// The name of the client.
+    pub client_name: String,
+    /// The redirect URIs of the client.
+    pub redirect_uris: Vec<String>,
+    /// The grant types of the client.
+    pub grant_types: Vec<String>,
+    /// How the client authenticates at the token endpoint.
+    pub token_endpoint_auth_method: String,
+    /// The URL of the client's logo.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_uri: Option<String>,
+    /// The space-separated scopes of the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+/// Represents a newly issued initial access token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedRegistrationToken {
+    /// The token. It is only returned once.
+    pub token: String,
+    /// The number of clients the token can register.
+    pub remaining_uses: i64,
+    /// The expiration timestamp of the token.
+    pub expires_at: i64,
+}
+
+/// Returns the lifetime of initial access tokens in seconds.
+///
+/// The lifetime is read from the `OAUTH_REGISTRATION_TOKEN_LIFETIME_SECONDS` environment
+/// variable and defaults to one day if it is missing or invalid.
+pub fn registration_token_lifetime() -> i64 {
+    env::var(REGISTRATION_TOKEN_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_REGISTRATION_TOKEN_LIFETIME_SECONDS)
+}
+
+/// Issues an initial access token for dynamic client registration.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `created_by` - The ID of the administrator who issues the token.
+/// * `uses` - The number of clients the token can register.
+///
+/// # Returns
+///
+/// A `Result` containing the token.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Storing the token fails.
+pub async fn issue_registration_token(
+    db: &Database,
+    created_by: &str,
+    uses: i64,
+) -> Result<IssuedRegistrationToken, CustomError> {
+    let token = generate_opaque_token();
+    let now = Utc::now().timestamp();
+    let stored = RegistrationToken {
+        token_hash: hash_token(&token),
+        created_by: created_by.to_string(),
+        remaining_uses: uses,
+        expires_at: now + registration_token_lifetime(),
+        created_at: now,
+    };
+    db.store_registration_token(&stored).await?;
+    tracing::info!(
+        "User {} issued an initial access token for {} clients",
+        created_by,
+        uses
+    );
+
+    Ok(IssuedRegistrationToken {
+        token,
+        remaining_uses: stored.remaining_uses,
+        expires_at: stored.expires_at,
+    })
+}
+
+/// Registers a client with the metadata it sent itself.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `initial_access_token` - The bearer token sent with the request, if any.
+/// * `metadata` - The metadata of the client.
+///
+/// # Returns
+///
+/// A `Result` containing the registered client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` with the OAuth error:
+/// - `invalid_token` if the initial access token is missing, unknown, expired or used up.
+/// - `invalid_redirect_uri` if a redirect URI is invalid.
+/// - `invalid_client_metadata` if other metadata is invalid.
+///
+/// Returns other `CustomError`s if storing the client fails.
+pub async fn register_dynamic_client(
+    db: &Database,
+    initial_access_token: Option<&str>,
+    metadata: ClientMetadata,
+) -> Result<ClientInformationResponse, CustomError> {
+    let invalid_token = || oauth_error("invalid_token", "The initial access token is invalid");
+    let initial_access_token = initial_access_token.ok_or_else(invalid_token)?;
+
+    // Invalid metadata is rejected before the token is used, so that it isn't used up
+    let registration = client_registration(metadata)?;
+    validate_registration(registration.clone()).map_err(invalid_client_metadata)?;
+    if !db
+        .use_registration_token(&hash_token(initial_access_token), Utc::now().timestamp())
+        .await?
+    {
+        tracing::warn!("Client registration with an invalid initial access token");
+        return Err(invalid_token());
+    }
+
+    let registered = register_client(db, registration)
+        .await
+        .map_err(invalid_client_metadata)?;
+    Ok(client_information(
+        &registered.client,
+        registered.client_secret,
+    ))
+}
+
+/// Reports an invalid client setting as invalid client metadata.
+fn invalid_client_metadata(error: CustomError) -> CustomError {
+    match error {
+        CustomError::InvalidClient(message) => oauth_error("invalid_client_metadata", &message),
+        error => error,
+    }
+}
+
+/// Turns the metadata of a client into a registration, checking what `register_client`
+/// doesn't know about.
+fn client_registration(metadata: ClientMetadata) -> Result<ClientRegistration, CustomError> {
+    let invalid_metadata = |description: &str| oauth_error("invalid_client_metadata", description);
+
+    let client_type = match metadata.token_endpoint_auth_method.as_deref() {
+        None | Some(CLIENT_SECRET_BASIC_AUTH_METHOD | CLIENT_SECRET_POST_AUTH_METHOD) => {
+            ClientType::Confidential
+        }
+        Some(NONE_AUTH_METHOD) => ClientType::Public,
+        Some(_) => {
+            return Err(invalid_metadata(
+                "The token endpoint authentication method isn't supported",
+            ))
+        }
+    };
+    if metadata
+        .response_types
+        .as_ref()
+        .is_some_and(|types| types.iter().any(|response_type| response_type != "code"))
+    {
+        return Err(invalid_metadata("Only the code response type is supported"));
+    }
+    let name = metadata
+        .client_name
+        .filter(|name| !name.trim().is_empty())
+        .ok_or_else(|| invalid_metadata("client_name is required"))?;
+    for uri in &metadata.redirect_uris {
+        validate_redirect_uri(uri, client_type).map_err(|_| {
+            oauth_error(
+                "invalid_redirect_uri",
+                &format!("Invalid redirect URI: {}", uri),
+            )
+        })?;
+    }
+
+    Ok(ClientRegistration {
+        name,
+        client_type,
+        redirect_uris: metadata.redirect_uris,
+        grant_types: metadata.grant_types,
+        scopes: metadata
+            .scope
+            .map(|scope| scope.split(' ').map(str::to_string).collect())
+            .unwrap_or_default(),
+        logo_uri: metadata.logo_uri,
+        access_token_lifetime: None,
+        refresh_token_lifetime: None,
+        first_party: false,
+    })
+}
+
+/// Describes a registered client in the terms of RFC 7591.
+fn client_information(
+    client: &OAuthClient,
+    client_secret: Option<String>,
+) -> ClientInformationResponse {
+    ClientInformationResponse {
+        client_id: client.client_id.clone(),
+        client_secret_expires_at: client_secret.as_ref().map(|_| 0),
+        client_secret,
+        client_id_issued_at: client.created_at,
+        client_name: client.name.clone(),
+        redirect_uris: client.redirect_uris.clone(),
+        grant_types: client.grant_types.clone(),
+        token_endpoint_auth_method: match client.secret_hash {
+            Some(_) => CLIENT_SECRET_BASIC_AUTH_METHOD,
+            None => NONE_AUTH_METHOD,
+        }
+        .to_string(),
+        logo_uri: client.logo_uri.clone(),
+        scope: (!client.scopes.is_empty()).then(|| client.scopes.join(" ")),
+    }
+}
@@ -0,0 +1,348 @@
+//! src/consent.rs
+//!
+//! This module asks users to consent before third-party clients get access to their account.
+//! After logging in through the authorization endpoint, a user who hasn't yet allowed a client
+//! the requested scopes is sent to a server-rendered consent page. The decision is stored per
+//! user and client, so that the user isn't asked again for scopes they already allowed.
+//!
+//! First-party clients, which belong to the operator of the server, never ask for consent.
+//! Users list and revoke their consents themselves. Revoking a consent also revokes the refresh
+//! tokens the client got for the user.
+
+use crate::database::{Consent, ConsentRequest, Database, OAuthClient};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::oauth::{issue_authorization_code, redirect_url, ValidatedAuthorization};
+use crate::tokens::generate_opaque_token;
+
+use chrono::Utc;
+use std::collections::BTreeSet;
+use std::env;
+
+const CONSENT_LIFETIME_ENV: &str = "OAUTH_CONSENT_LIFETIME_SECONDS";
+const DEFAULT_CONSENT_LIFETIME_SECONDS: i64 = 10 * 60;
+
+/// Returns how long a user has to decide on a consent page in seconds.
+///
+/// The lifetime is read from the `OAUTH_CONSENT_LIFETIME_SECONDS` environment variable and
+/// defaults to ten minutes if it is missing or invalid.
+pub fn consent_lifetime() -> i64 {
+    env::var(CONSENT_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_CONSENT_LIFETIME_SECONDS)
+}
+
+/// Checks whether a user already allowed a client the requested scopes.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client` - The client that asks for authorization.
+/// * `user_id` - The ID of the user.
+/// * `scope` - The normalized scopes requested by the client.
+///
+/// # Returns
+///
+/// A `Result` containing `true` if the client is first-party or a stored consent covers all
+/// requested scopes.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the consent fails.
+pub async fn has_consent(
+    db: &Database,
+    client: &OAuthClient,
+    user_id: &str,
+    scope: Option<&str>,
+) -> Result<bool, CustomError> {
+    if client.first_party {
+        return Ok(true);
+    }
+    let Some(consent) = db.get_consent(user_id, &client.client_id).await? else {
+        return Ok(false);
+    };
+    Ok(scope.is_none_or(|scope| {
+        scope
+            .split(' ')
+            .all(|scope| consent.scopes.iter().any(|granted| granted == scope))
+    }))
+}
+
+/// Stores an authorization request until the user decides on the consent page.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `authorization` - The validated authorization request.
+/// * `user_id` - The ID of the authenticated user.
+/// * `auth_methods` - The methods the user authenticated with.
+///
+/// # Returns
+///
+/// A `Result` containing the ticket that identifies the request on the consent page.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Storing the request fails.
+pub async fn request_consent(
+    db: &Database,
+    authorization: &ValidatedAuthorization,
+    user_id: &str,
+    auth_methods: &[String],
+) -> Result<String, CustomError> {
+    let ticket = generate_opaque_token();
+    db.store_consent_request(&ConsentRequest {
+        consent_hash: hash_token(&ticket),
+        client_id: authorization.client.client_id.clone(),
+        user_id: user_id.to_string(),
+        redirect_uri: authorization.redirect_uri.clone(),
+        explicit_redirect_uri: authorization.explicit_redirect_uri,
+        scope: authorization.scope.clone(),
+        state: authorization.state.clone(),
+        code_challenge: authorization.code_challenge.clone(),
+        nonce: authorization.nonce.clone(),
+        auth_methods: auth_methods.to_vec(),
+        expires_at: Utc::now().timestamp() + consent_lifetime(),
+    })
+    .await?;
+    tracing::info!(
+        "Asking user {} to consent to client {}",
+        user_id,
+        authorization.client.client_id
+    );
+    Ok(ticket)
+}
+
+/// Looks up the pending request and the client a consent ticket belongs to.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `ticket` - The consent ticket.
+///
+/// # Returns
+///
+/// A `Result` containing the request and its client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The ticket is unknown or expired, or the client was disabled or deleted.
+/// - Looking up the request fails.
+pub async fn find_consent_request(
+    db: &Database,
+    ticket: &str,
+) -> Result<(ConsentRequest, OAuthClient), CustomError> {
+    let request = db
+        .get_consent_request(&hash_token(ticket))
+        .await?
+        .ok_or(CustomError::InvalidConsentRequest)?;
+    let client = db
+        .get_oauth_client(&request.client_id)
+        .await?
+        .filter(|client| !client.disabled)
+        .ok_or(CustomError::InvalidConsentRequest)?;
+    Ok((request, client))
+}
+
+/// Applies the user's decision on a consent page.
+///
+/// Approving stores the consent, adding the requested scopes to those allowed before, and
+/// issues an authorization code. Denying sends the `access_denied` error to the client. Either
+/// way the ticket can't be used again.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `ticket` - The consent ticket.
+/// * `approved` - Whether the user approved the request.
+///
+/// # Returns
+///
+/// A `Result` containing the redirect URL that delivers the response to the client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The ticket is unknown, expired or already used, or the client was disabled or deleted.
+/// - Storing the consent or the authorization code fails.
+pub async fn decide_consent(
+    db: &Database,
+    ticket: &str,
+    approved: bool,
+) -> Result<String, CustomError> {
+    let now = Utc::now().timestamp();
+    let request = db
+        .take_consent_request(&hash_token(ticket))
+        .await?
+        .filter(|request| request.expires_at > now)
+        .ok_or(CustomError::InvalidConsentRequest)?;
+    let client = db
+        .get_oauth_client(&request.client_id)
+        .await?
+        .filter(|client| !client.disabled)
+        .ok_or(CustomError::InvalidConsentRequest)?;
+
+    if !approved {
+        tracing::info!(
+            "User {} denied consent to client {}",
+            request.user_id,
+            client.client_id
+        );
+        let mut query = vec![
+            ("error", "access_denied"),
+            ("error_description", "The user denied the request"),
+        ];
+        if let Some(state) = &request.state {
+            query.push(("state", state));
+        }
+        return Ok(redirect_url(&request.redirect_uri, &query));
+    }
+
+    let existing = db.get_consent(&request.user_id, &client.client_id).await?;
+    let mut scopes: BTreeSet<String> = existing
+        .as_ref()
+        .map(|consent| consent.scopes.iter().cloned().collect())
+        .unwrap_or_default();
+    if let Some(scope) = &request.scope {
+        scopes.extend(scope.split(' ').map(str::to_string));
+    }
+    db.save_consent(&Consent {
+        user_id: request.user_id.clone(),
+        client_id: client.client_id.clone(),
+        scopes: scopes.into_iter().collect(),
+        created_at: existing.map_or(now, |consent| consent.created_at),
+        updated_at: now,
+    })
+    .await?;
+    tracing::info!(
+        "User {} consented to client {}",
+        request.user_id,
+        client.client_id
+    );
+
+    let authorization = ValidatedAuthorization {
+        client,
+        redirect_uri: request.redirect_uri,
+        explicit_redirect_uri: request.explicit_redirect_uri,
+        scope: request.scope,
+        state: request.state,
+        code_challenge: request.code_challenge,
+        nonce: request.nonce,
+    };
+    issue_authorization_code(db, &authorization, &request.user_id, &request.auth_methods).await
+}
+
+/// Revokes the consent a user gave a client together with the client's refresh tokens.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `client_id` - The ID of the client.
+///
+/// # Returns
+///
+/// A `Result` containing `true` if the consent existed.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Revoking the consent fails.
+pub async fn revoke_consent(
+    db: &Database,
+    user_id: &str,
+    client_id: &str,
+) -> Result<bool, CustomError> {
+    let revoked = db.delete_consent(user_id, client_id).await?;
+    if revoked {
+        tracing::info!("User {} revoked consent to client {}", user_id, client_id);
+    }
+    Ok(revoked)
+}
+
+/// Renders the consent page.
+///
+/// # Arguments
+///
+/// * `client` - The client that asks for authorization.
+/// * `scope` - The scopes requested by the client.
+/// * `ticket` - The consent ticket, which the form posts back.
+///
+/// # Returns
+///
+/// The HTML of the page.
+pub fn render_consent_page(client: &OAuthClient, scope: Option<&str>, ticket: &str) -> String {
+    let logo = client.logo_uri.as_deref().map_or(String::new(), |uri| {
+        format!(
+            r#"<img src="{}" alt="" width="64" height="64">"#,
+            escape_html(uri)
+        )
+    });
+    let scopes: String = scope
+        .map_or(Vec::new(), |scope| scope.split(' ').collect())
+        .into_iter()
+        .map(|scope| format!("<li>{}</li>", escape_html(scope_description(scope))))
+        .collect();
+    let scopes = if scopes.is_empty() {
+        "<p>It asks for access to your account.</p>".to_string()
+    } else {
+        format!("<p>It asks for access to:</p><ul>{}</ul>", scopes)
+    };
+    let name = escape_html(&client.name);
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Authorize {name}</title>
+<style>body {{ font-family: sans-serif; max-width: 28rem; margin: 3rem auto; padding: 0 1rem; }} button {{ margin-right: 0.5rem; }}</style>
+</head>
+<body>
+{logo}
+<h1>Authorize {name}</h1>
+{scopes}
+<form method="post" action="consent">
+<input type="hidden" name="consent" value="{ticket}">
+<button type="submit" name="decision" value="approve">Allow</button>
+<button type="submit" name="decision" value="deny">Deny</button>
+</form>
+</body>
+</html>
+"#,
+        name = name,
+        logo = logo,
+        scopes = scopes,
+        ticket = escape_html(ticket),
+    )
+}
+
+/// Describes a scope to the user. Unknown scopes are shown as they are.
+fn scope_description(scope: &str) -> &str {
+    match scope {
+        "openid" => "Your identity",
+        "profile" => "Your name and username",
+        "email" => "Your email address",
+        scope => scope,
+    }
+}
+
+/// Escapes text for use in HTML content and attribute values.
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
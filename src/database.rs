@@ -12,7 +12,7 @@ use std::collections::BTreeMap;
 use std::process::exit;
 use surrealdb::{
     engine::local::{Db, RocksDb},
-    sql::Value,
+    sql::{thing, Thing, Value},
     Surreal,
 };
 use uuid::Uuid;
@@ -36,16 +36,543 @@ pub struct User {
     pub email: String,
     /// The user's creation timestamp.
     pub created_at: String,
+    /// The user's token generation. Access tokens issued for an older generation are rejected.
+    #[serde(default)]
+    pub token_generation: i64,
+    /// The user's encrypted TOTP secret, set once enrollment has started.
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    /// Whether TOTP enrollment has been confirmed and a second factor is required on login.
+    #[serde(default)]
+    pub totp_enabled: bool,
+    /// The time step of the last accepted TOTP code, used to reject replayed codes.
+    #[serde(default)]
+    pub totp_last_step: Option<i64>,
+    /// Whether the user has proven that they own their email address.
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+/// Represents a stored refresh token.
+///
+/// Only the hash of the opaque token is stored. Tokens issued from the same login share a
+/// `family_id` so that a replayed token can revoke the whole chain.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RefreshToken {
+    /// The SHA-256 hash of the refresh token.
+    pub token_hash: String,
+    /// The ID of the user the token was issued to.
+    pub user_id: String,
+    /// The ID of the token family.
+    pub family_id: String,
+    /// The expiration timestamp of the token.
+    pub expires_at: i64,
+    /// Whether the token has already been exchanged for a new one.
+    pub used: bool,
+    /// Whether the token has been revoked.
+    pub revoked: bool,
+    /// The methods the user authenticated with when the token family was started.
+    #[serde(default)]
+    pub auth_methods: Vec<String>,
+    /// The ID of the OAuth client the token was issued to, if any.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// The scopes granted to the OAuth client.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// The JWK thumbprint of the DPoP key the token is bound to, if any.
+    #[serde(default)]
+    pub jkt: Option<String>,
+}
+
+/// Represents a pending MFA challenge created by a password or federated login.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MfaChallenge {
+    /// The SHA-256 hash of the challenge token.
+    pub challenge_hash: String,
+    /// The ID of the user who passed the first factor.
+    pub user_id: String,
+    /// The expiration timestamp of the challenge.
+    pub expires_at: i64,
+    /// The number of wrong codes entered for this challenge.
+    #[serde(default)]
+    pub attempts: i64,
+    /// The methods the user passed the first factor with, e.g. `pwd` or `fed`.
+    #[serde(default = "default_first_factor")]
+    pub auth_methods: Vec<String>,
+}
+
+/// Represents a single-use MFA recovery code.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecoveryCode {
+    /// The record ID of the code.
+    pub id: Thing,
+    /// The ID of the user the code belongs to.
+    pub user_id: String,
+    /// The Argon2 hash of the code.
+    pub code_hash: String,
+    /// Whether the code has already been used.
+    pub used: bool,
+}
+
+/// Represents a registered WebAuthn credential (passkey).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebAuthnCredential {
+    /// The base64url-encoded credential ID chosen by the authenticator.
+    pub credential_id: String,
+    /// The ID of the user the credential belongs to.
+    pub user_id: String,
+    /// The base64url-encoded COSE public key.
+    pub public_key: String,
+    /// The COSE algorithm identifier of the public key, e.g. `-7` for ES256.
+    pub algorithm: i64,
+    /// The last signature counter reported by the authenticator.
+    pub sign_count: i64,
+    /// The name given to the credential by the user.
+    pub name: String,
+    /// The creation timestamp of the credential.
+    pub created_at: i64,
+    /// The timestamp of the last successful assertion.
+    #[serde(default)]
+    pub last_used_at: Option<i64>,
+}
+
+/// Represents a pending WebAuthn ceremony.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebAuthnChallenge {
+    /// The SHA-256 hash of the challenge.
+    pub challenge_hash: String,
+    /// The ceremony the challenge was issued for: `registration` or `authentication`.
+    pub ceremony: String,
+    /// The ID of the user the ceremony is bound to, if any.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Whether the authenticator has to verify the user, e.g. with a PIN or biometrics.
+    pub user_verification_required: bool,
+    /// The expiration timestamp of the challenge.
+    pub expires_at: i64,
+}
+
+/// Represents a pending password reset.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordResetToken {
+    /// The SHA-256 hash of the reset token.
+    pub token_hash: String,
+    /// The ID of the user whose password can be reset.
+    pub user_id: String,
+    /// The expiration timestamp of the token.
+    pub expires_at: i64,
+}
+
+/// Represents a pending email address verification.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmailVerificationToken {
+    /// The SHA-256 hash of the verification token.
+    pub token_hash: String,
+    /// The ID of the user whose email address is verified by the token.
+    pub user_id: String,
+    /// The expiration timestamp of the token.
+    pub expires_at: i64,
+}
+
+/// Represents a role, a named set of permissions.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Role {
+    /// The unique name of the role.
+    pub name: String,
+    /// A human readable description of the role.
+    #[serde(default)]
+    pub description: String,
+    /// The permissions granted by the role, e.g. `keys:manage`.
+    pub permissions: Vec<String>,
+}
+
+/// Represents the assignment of a role to a user.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RoleAssignment {
+    /// The ID of the user.
+    pub user_id: String,
+    /// The name of the role.
+    pub role: String,
+}
+
+/// Represents a group of users and other groups.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Group {
+    /// The group's ID.
+    pub id: Thing,
+    /// The unique name of the group.
+    pub name: String,
+    /// A human readable description of the group.
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Represents the membership of a user or a nested group in a group.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupMembership {
+    /// The ID of the group.
+    pub group_id: String,
+    /// The ID of the member, either a user or a group.
+    pub member_id: String,
+}
+
+/// Represents the assignment of a role to a group.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupRole {
+    /// The ID of the group.
+    pub group_id: String,
+    /// The name of the role.
+    pub role: String,
+}
+
+/// Represents a stored version of an authorization policy.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredPolicy {
+    /// The name of the policy.
+    pub name: String,
+    /// The version number, starting at 1.
+    pub version: i64,
+    /// The policy document as JSON.
+    pub document: String,
+    /// The ID of the user who created this version.
+    pub created_by: String,
+    /// The creation timestamp of this version.
+    pub created_at: i64,
+}
+
+/// Represents a JWT signing key stored in the keyring.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredSigningKey {
+    /// The key ID.
+    pub kid: String,
+    /// The signing algorithm, e.g. `HS256` or `ES256`.
+    pub algorithm: String,
+    /// The key state: `active`, `verify_only` or `retired`.
+    pub state: String,
+    /// The encrypted private key (PEM) or shared secret.
+    pub encrypted_private_key: String,
+    /// The PEM-encoded public key. Empty for shared secrets.
+    pub public_key: String,
+    /// The creation timestamp of the key.
+    pub created_at: i64,
+}
+
+/// Represents a tenant, an isolated realm with its own users, roles and signing keys.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tenant {
+    /// The unique ID of the tenant, used in paths and tokens.
+    pub tenant_id: String,
+    /// A human readable name of the tenant.
+    #[serde(default)]
+    pub name: String,
+    /// The host names that select the tenant.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// The settings of the tenant.
+    #[serde(default)]
+    pub settings: TenantSettings,
+    /// The creation timestamp of the tenant.
+    #[serde(default)]
+    pub created_at: i64,
+}
+
+/// Represents the settings of a tenant that override the environment.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct TenantSettings {
+    /// The IDs of the tenant's superusers. Tenants don't inherit `ADMIN_USER_IDS`.
+    #[serde(default)]
+    pub admin_user_ids: Vec<String>,
+    /// Overrides `EMAIL_VERIFICATION_POLICY` for the tenant.
+    #[serde(default)]
+    pub email_verification_policy: Option<String>,
+}
+
+/// Represents an OAuth client registered with the authorization server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OAuthClient {
+    /// The unique ID of the client.
+    pub client_id: String,
+    /// A human readable name of the client, shown to users.
+    #[serde(default)]
+    pub name: String,
+    /// The client type: `confidential` or `public`.
+    pub client_type: String,
+    /// The SHA-256 hash of the client secret. Public clients have no secret.
+    #[serde(default)]
+    pub secret_hash: Option<String>,
+    /// The redirect URIs the client may use, compared exactly.
+    pub redirect_uris: Vec<String>,
+    /// The grant types the client may use, e.g. `authorization_code` or `client_credentials`.
+    #[serde(default = "default_grant_types")]
+    pub grant_types: Vec<String>,
+    /// The scopes the client may be granted. Requests for other scopes are rejected.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// The URL of the client's logo, shown to users next to its name.
+    #[serde(default)]
+    pub logo_uri: Option<String>,
+    /// The lifetime of access tokens issued to the client in seconds, if it differs from the
+    /// server default.
+    #[serde(default)]
+    pub access_token_lifetime: Option<i64>,
+    /// The lifetime of refresh tokens issued to the client in seconds, if it differs from the
+    /// server default.
+    #[serde(default)]
+    pub refresh_token_lifetime: Option<i64>,
+    /// Whether the client belongs to the operator of the server. Users aren't asked to
+    /// consent to first-party clients.
+    #[serde(default)]
+    pub first_party: bool,
+    /// Whether the client has been disabled. Disabled clients can't get tokens and their
+    /// tokens are rejected.
+    #[serde(default)]
+    pub disabled: bool,
+    /// The creation timestamp of the client.
+    pub created_at: i64,
+}
+
+/// Represents an initial access token that allows registering clients dynamically
+/// (RFC 7591, section 3).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegistrationToken {
+    /// The SHA-256 hash of the token.
+    pub token_hash: String,
+    /// The ID of the user who created the token.
+    pub created_by: String,
+    /// The number of clients that can still be registered with the token.
+    pub remaining_uses: i64,
+    /// The expiration timestamp of the token.
+    pub expires_at: i64,
+    /// The creation timestamp of the token.
+    pub created_at: i64,
+}
+
+/// Represents an OAuth authorization code.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthorizationCode {
+    /// The SHA-256 hash of the code.
+    pub code_hash: String,
+    /// The ID of the client the code was issued to.
+    pub client_id: String,
+    /// The ID of the user who granted the authorization.
+    pub user_id: String,
+    /// The redirect URI the code was delivered to.
+    pub redirect_uri: String,
+    /// Whether the authorization request named the redirect URI, which then has to be sent
+    /// again to exchange the code.
+    #[serde(default)]
+    pub explicit_redirect_uri: bool,
+    /// The PKCE code challenge (S256).
+    pub code_challenge: String,
+    /// The space-separated scopes granted to the client.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// The methods the user authenticated with.
+    #[serde(default)]
+    pub auth_methods: Vec<String>,
+    /// The OpenID Connect nonce sent with the authorization request.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// The timestamp at which the user authenticated.
+    #[serde(default)]
+    pub auth_time: i64,
+    /// The refresh token family started by exchanging the code.
+    pub family_id: String,
+    /// The expiration timestamp of the code.
+    pub expires_at: i64,
+    /// Whether the code has already been exchanged.
+    pub used: bool,
+}
+
+/// Represents a pending or decided device authorization (RFC 8628).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceAuthorization {
+    /// The SHA-256 hash of the device code.
+    pub device_code_hash: String,
+    /// The normalized user code the user enters on the verification page.
+    pub user_code: String,
+    /// The ID of the client the device code was issued to.
+    pub client_id: String,
+    /// The space-separated scopes requested by the client.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// The state of the authorization: `pending`, `approved`, `denied` or `consumed`.
+    pub status: String,
+    /// The ID of the user who approved or denied the authorization.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// The methods the user authenticated with.
+    #[serde(default)]
+    pub auth_methods: Vec<String>,
+    /// The minimum number of seconds between two polls of the token endpoint.
+    pub poll_interval: i64,
+    /// The timestamp of the last poll of the token endpoint.
+    pub last_polled_at: i64,
+    /// The expiration timestamp of the device code.
+    pub expires_at: i64,
+}
+
+/// Represents the scopes a user allowed a client to access.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Consent {
+    /// The ID of the user.
+    pub user_id: String,
+    /// The ID of the client.
+    pub client_id: String,
+    /// The scopes the user allowed.
+    pub scopes: Vec<String>,
+    /// The timestamp of the first consent.
+    pub created_at: i64,
+    /// The timestamp of the latest consent.
+    pub updated_at: i64,
+}
+
+/// Represents an authorization request that waits for the user's consent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConsentRequest {
+    /// The SHA-256 hash of the ticket that identifies the request on the consent page.
+    pub consent_hash: String,
+    /// The ID of the client that asks for authorization.
+    pub client_id: String,
+    /// The ID of the logged-in user.
+    pub user_id: String,
+    /// The redirect URI the response is sent to.
+    pub redirect_uri: String,
+    /// Whether the authorization request named the redirect URI.
+    #[serde(default)]
+    pub explicit_redirect_uri: bool,
+    /// The space-separated scopes requested by the client.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// The state to pass back to the client.
+    #[serde(default)]
+    pub state: Option<String>,
+    /// The PKCE code challenge.
+    pub code_challenge: String,
+    /// The OpenID Connect nonce.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// The methods the user authenticated with.
+    #[serde(default)]
+    pub auth_methods: Vec<String>,
+    /// The expiration timestamp of the request.
+    pub expires_at: i64,
+}
+
+/// Represents an external OpenID Connect provider that users can log in with.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdentityProvider {
+    /// The ID of the provider, used in the login and callback URLs.
+    pub provider_id: String,
+    /// The name of the provider, shown on the login page.
+    pub name: String,
+    /// The issuer URL of the provider, where its discovery document is published.
+    pub issuer: String,
+    /// The client ID of this server at the provider.
+    pub client_id: String,
+    /// The encrypted client secret of this server at the provider.
+    pub encrypted_client_secret: String,
+    /// The scopes requested from the provider.
+    pub scopes: Vec<String>,
+    /// Which claims of the provider's ID tokens hold the user's details.
+    #[serde(default)]
+    pub claim_mapping: ClaimMapping,
+    /// Whether users without a local account get one on their first login.
+    #[serde(default)]
+    pub provision_users: bool,
+    /// Whether logins with the provider have been disabled.
+    #[serde(default)]
+    pub disabled: bool,
+    /// The creation timestamp of the provider.
+    pub created_at: i64,
+}
+
+/// Represents the names of the ID token claims that hold a user's details.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ClaimMapping {
+    /// The claim with the email address.
+    pub email: String,
+    /// The claim with the username.
+    pub username: String,
+    /// The claim with the first name.
+    pub firstname: String,
+    /// The claim with the last name.
+    pub lastname: String,
+}
+
+impl Default for ClaimMapping {
+    fn default() -> Self {
+        ClaimMapping {
+            email: "email".to_string(),
+            username: "preferred_username".to_string(),
+            firstname: "given_name".to_string(),
+            lastname: "family_name".to_string(),
+        }
+    }
+}
+
+/// Represents the link between a local user and their account at an identity provider.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FederatedIdentity {
+    /// The ID of the provider.
+    pub provider_id: String,
+    /// The subject of the user at the provider.
+    pub subject: String,
+    /// The ID of the local user.
+    pub user_id: String,
+    /// The timestamp at which the accounts were linked.
+    pub created_at: i64,
+}
+
+/// Represents a login at an identity provider that waits for the provider's callback.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FederationState {
+    /// The SHA-256 hash of the `state` sent to the provider.
+    pub state_hash: String,
+    /// The ID of the provider.
+    pub provider_id: String,
+    /// The nonce the ID token has to contain.
+    pub nonce: String,
+    /// The PKCE code verifier.
+    pub code_verifier: String,
+    /// The redirect URI the provider sends the user back to.
+    pub redirect_uri: String,
+    /// The expiration timestamp of the login.
+    pub expires_at: i64,
+}
+
+/// Represents where a database is stored.
+#[derive(Debug, Clone)]
+struct DatabaseLocation {
+    /// The path of the database.
+    path: String,
+    /// The namespace of the database.
+    namespace: String,
+    /// The name of the database.
+    name: String,
 }
 
 /// Represents the database connection.
+///
+/// Every tenant has its own SurrealDB datastore, and a `Database` is bound to the datastore of
+/// exactly one tenant, so its queries can't reach the records of another tenant.
 #[derive(Clone)]
 pub struct Database {
     /// The SurrealDB database connection.
     pub db: Surreal<Db>,
+    /// The ID of the tenant the database belongs to.
+    tenant: String,
+    /// The settings of the tenant.
+    settings: TenantSettings,
+    /// Where the database is stored.
+    location: DatabaseLocation,
 }
 
 use crate::errors::custom_errors::CustomError;
+use crate::tenants::DEFAULT_TENANT;
+use crate::tokens::ClientGrant;
 
 impl Database {
     /// Creates a new database connection.
@@ -74,18 +601,120 @@ impl Database {
             }
         };
 
-        // Connect to the database.
-        let db = Surreal::new::<RocksDb>(database_path)
-            .await
-            .map_err(|e| CustomError::DatabaseError(e.to_string()))?;
         // Use the namespace and database from the environment variables.
         let database_namespace =
             var("DATABASE_NAMESPACE").map_err(|e| CustomError::DatabaseError(e.to_string()))?;
         let database_name =
             var("DATABASE_NAME").map_err(|e| CustomError::DatabaseError(e.to_string()))?;
 
-        db.use_ns(&database_namespace)
-            .use_db(&database_name)
+        Self::connect(database_path, &database_namespace, &database_name).await
+    }
+
+    /// Opens a database connection at the given path.
+    ///
+    /// This function connects to the SurrealDB database at `database_path`, selects the given
+    /// namespace and database and defines the indexes used by the application.
+    ///
+    /// # Arguments
+    ///
+    /// * `database_path` - The path of the database.
+    /// * `database_namespace` - The namespace to use.
+    /// * `database_name` - The database to use.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new database connection or an error if the connection fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The connection to the database fails.
+    /// - Defining the indexes fails.
+    pub async fn connect(
+        database_path: String,
+        database_namespace: &str,
+        database_name: &str,
+    ) -> Result<Self, CustomError> {
+        let location = DatabaseLocation {
+            path: database_path,
+            namespace: database_namespace.to_string(),
+            name: database_name.to_string(),
+        };
+        Self::open(location, DEFAULT_TENANT, TenantSettings::default()).await
+    }
+
+    /// Opens the database of a tenant.
+    ///
+    /// The datastore of a tenant is stored in `<root path>-tenants/<tenant ID>`. Only the
+    /// database of the default tenant, which holds the tenant registry, can open the databases
+    /// of other tenants.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - The tenant whose database is opened.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the database of the tenant.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - This isn't the database of the default tenant.
+    /// - The connection to the database fails.
+    /// - Defining the indexes fails.
+    pub async fn open_tenant(&self, tenant: &Tenant) -> Result<Self, CustomError> {
+        if self.tenant != DEFAULT_TENANT {
+            return Err(CustomError::DatabaseError(
+                "Only the default tenant can open tenant databases".to_string(),
+            ));
+        }
+        let location = DatabaseLocation {
+            path: format!("{}-tenants/{}", self.location.path, tenant.tenant_id),
+            ..self.location.clone()
+        };
+        Self::open(location, &tenant.tenant_id, tenant.settings.clone()).await
+    }
+
+    /// Returns a copy of this database handle with updated tenant settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The new settings of the tenant.
+    pub fn with_settings(&self, settings: TenantSettings) -> Self {
+        Database {
+            settings,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the ID of the tenant the database belongs to.
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    /// Returns the settings of the tenant the database belongs to.
+    pub fn settings(&self) -> &TenantSettings {
+        &self.settings
+    }
+
+    /// Connects to the database at the given location and defines the indexes.
+    async fn open(
+        location: DatabaseLocation,
+        tenant: &str,
+        settings: TenantSettings,
+    ) -> Result<Self, CustomError> {
+        let database_path = location.path.clone();
+        let database_namespace = location.namespace.as_str();
+        let database_name = location.name.as_str();
+
+        // Connect to the database.
+        let db = Surreal::new::<RocksDb>(database_path)
+            .await
+            .map_err(|e| CustomError::DatabaseError(e.to_string()))?;
+
+        db.use_ns(database_namespace)
+            .use_db(database_name)
             .await
             .map_err(|e| CustomError::DatabaseError(e.to_string()))?;
 
@@ -101,7 +730,103 @@ impl Database {
             }
         };
 
-        Ok(Database { db })
+        // Define a unique index on the refresh token hashes.
+        db.query("DEFINE INDEX refresh_tokens_hash ON refresh_tokens FIELDS token_hash UNIQUE")
+            .await?;
+
+        // Define a unique index on the signing key IDs.
+        db.query("DEFINE INDEX signing_keys_kid ON signing_keys FIELDS kid UNIQUE")
+            .await?;
+
+        // Define a unique index on the revoked access token IDs.
+        db.query("DEFINE INDEX revoked_tokens_jti ON revoked_tokens FIELDS jti UNIQUE")
+            .await?;
+
+        // Define a unique index on the WebAuthn credential IDs.
+        db.query(
+            "DEFINE INDEX webauthn_credentials_id ON webauthn_credentials FIELDS credential_id UNIQUE",
+        )
+        .await?;
+
+        // Define a unique index on the password reset token hashes.
+        db.query(
+            "DEFINE INDEX password_reset_tokens_hash ON password_reset_tokens FIELDS token_hash UNIQUE",
+        )
+        .await?;
+
+        // Define a unique index on the email verification token hashes.
+        db.query(
+            "DEFINE INDEX email_verification_tokens_hash ON email_verification_tokens FIELDS token_hash UNIQUE",
+        )
+        .await?;
+
+        // Define a unique index on the group names.
+        db.query("DEFINE INDEX groups_name ON groups FIELDS name UNIQUE")
+            .await?;
+
+        // Define a unique index on the policy versions.
+        db.query("DEFINE INDEX policies_version ON policies FIELDS name, version UNIQUE")
+            .await?;
+
+        // Define a unique index on the MFA challenge hashes.
+        db.query("DEFINE INDEX mfa_challenges_hash ON mfa_challenges FIELDS challenge_hash UNIQUE")
+            .await?;
+
+        // Define a unique index on the tenant IDs.
+        db.query("DEFINE INDEX tenants_id ON tenants FIELDS tenant_id UNIQUE")
+            .await?;
+
+        // Define a unique index on the OAuth client IDs.
+        db.query("DEFINE INDEX oauth_clients_id ON oauth_clients FIELDS client_id UNIQUE")
+            .await?;
+
+        // Define a unique index on the authorization code hashes.
+        db.query("DEFINE INDEX oauth_codes_hash ON oauth_codes FIELDS code_hash UNIQUE")
+            .await?;
+
+        // Define a unique index on the IDs of DPoP proofs.
+        db.query("DEFINE INDEX dpop_proofs_jti ON dpop_proofs FIELDS jti UNIQUE")
+            .await?;
+
+        // Define a unique index on the initial access tokens for client registration.
+        db.query(
+            "DEFINE INDEX registration_tokens_hash ON registration_tokens FIELDS token_hash UNIQUE",
+        )
+        .await?;
+
+        // Define unique indexes on the identity providers, the account links and the pending
+        // federated logins.
+        db.query(
+            "DEFINE INDEX identity_providers_id ON identity_providers FIELDS provider_id UNIQUE",
+        )
+        .await?;
+        db.query("DEFINE INDEX federated_identities_subject ON federated_identities FIELDS provider_id, subject UNIQUE")
+            .await?;
+        db.query(
+            "DEFINE INDEX federation_states_hash ON federation_states FIELDS state_hash UNIQUE",
+        )
+        .await?;
+
+        // Define unique indexes on the consents and the consent requests.
+        db.query("DEFINE INDEX consents_user_client ON consents FIELDS user_id, client_id UNIQUE")
+            .await?;
+        db.query(
+            "DEFINE INDEX consent_requests_hash ON consent_requests FIELDS consent_hash UNIQUE",
+        )
+        .await?;
+
+        // Define unique indexes on the device codes and user codes.
+        db.query("DEFINE INDEX device_authorizations_hash ON device_authorizations FIELDS device_code_hash UNIQUE")
+            .await?;
+        db.query("DEFINE INDEX device_authorizations_user_code ON device_authorizations FIELDS user_code UNIQUE")
+            .await?;
+
+        Ok(Database {
+            db,
+            tenant: tenant.to_string(),
+            settings,
+            location,
+        })
     }
 
     /// Registers a new user in the database.
@@ -182,7 +907,7 @@ impl Database {
         };
 
         // Create the SQL query.
-        let sql = "CREATE users SET id = $id, encrypted_firstname = $encrypted_firstname, encrypted_lastname = $encrypted_lastname, username = $username, password_hash = $password_hash, encrypted_email = $encrypted_email, email = $email, created_at = time::now();";
+        let sql = "CREATE users SET id = $id, encrypted_firstname = $encrypted_firstname, encrypted_lastname = $encrypted_lastname, username = $username, password_hash = $password_hash, encrypted_email = $encrypted_email, email = $email, created_at = time::now(), token_generation = 0, totp_enabled = false, email_verified = false;";
 
         // Bind the parameters to the query.
         let mut vars: BTreeMap<String, Value> = BTreeMap::new();
@@ -274,6 +999,87 @@ impl Database {
         }
     }
 
+    /// Gets a user by ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user, e.g. the subject of a JWT.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the user, or `None` if no user has this ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_user_by_id(&self, user_id: &str) -> Result<Option<User>, CustomError> {
+        let user_id = match parse_record_id(user_id) {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        // Create the SQL query.
+        let sql = "SELECT * FROM $user_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut users: Vec<User> = response.take(0)?;
+        Ok(users.pop())
+    }
+
+    /// Gets a user by email address.
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - The user's email address.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the user, or `None` if no user has this email address.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_user_by_email(&self, email: &str) -> Result<Option<User>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM users WHERE email = $email";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("email".into(), Value::from(email));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut users: Vec<User> = response.take(0)?;
+        Ok(users.pop())
+    }
+
+    /// Gets the IDs of all users.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the user IDs.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_user_ids(&self) -> Result<Vec<String>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT VALUE <string> id FROM users";
+
+        // Execute the query.
+        let mut response = self.db.query(sql).await?;
+        let user_ids: Vec<String> = response.take(0)?;
+        Ok(user_ids)
+    }
+
     /// Changes the username of a user.
     ///
     /// This function updates the username of an existing user in the database.
@@ -311,7 +1117,8 @@ impl Database {
 
     /// Changes the password of a user.
     ///
-    /// This function updates the password of an existing user in the database.
+    /// This function hashes the new password and updates the password hash of an existing user
+    /// in the database.
     ///
     /// # Arguments
     ///
@@ -325,22 +1132,3573 @@ impl Database {
     /// # Errors
     ///
     /// Returns a `CustomError` if:
+    /// - The user ID is invalid.
+    /// - Hashing the password fails.
     /// - The update operation fails.
     pub async fn change_password(
         &self,
         user_id: String,
         new_password: String,
     ) -> Result<(), crate::errors::custom_errors::CustomError> {
+        let user_id = parse_record_id(&user_id).ok_or(CustomError::UserNotFound)?;
+
+        // Hash the new password.
+        let password_hash = match hash_random_salt(&new_password) {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::error!("Error hashing password: {}", e);
+                return Err(CustomError::HashingError);
+            }
+        };
+
         // Create the SQL query.
-        let sql = "UPDATE users SET username = $new_password WHERE id = $user_id;";
+        let sql = "UPDATE $user_id SET password_hash = $password_hash;";
 
         // Bind the parameters to the query.
         let mut vars: BTreeMap<String, Value> = BTreeMap::new();
-        vars.insert("user_id".into(), Value::from(user_id.as_str()));
-        vars.insert("new_password".into(), Value::from(new_password.as_str()));
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("password_hash".into(), Value::from(password_hash.as_str()));
 
         // Execute the query.
         self.db.query(sql).bind(vars).await?;
         Ok(())
     }
+
+    /// Stores a new refresh token.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user the token is issued to.
+    /// * `family_id` - The ID of the token family.
+    /// * `token_hash` - The hash of the refresh token.
+    /// * `expires_at` - The expiration timestamp of the token.
+    /// * `auth_methods` - The methods the user authenticated with.
+    /// * `grant` - The OAuth client and scopes the token is issued for, if any.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the token in the database fails.
+    pub async fn store_refresh_token(
+        &self,
+        user_id: &str,
+        family_id: &str,
+        token_hash: &str,
+        expires_at: i64,
+        auth_methods: &[String],
+        grant: Option<&ClientGrant>,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE refresh_tokens SET token_hash = $token_hash, user_id = $user_id, family_id = $family_id, expires_at = $expires_at, used = false, revoked = false, auth_methods = $auth_methods, client_id = $client_id, scope = $scope, jkt = $jkt, created_at = time::now();";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("token_hash".into(), Value::from(token_hash));
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("family_id".into(), Value::from(family_id));
+        vars.insert("expires_at".into(), Value::from(expires_at));
+        vars.insert(
+            "auth_methods".into(),
+            Value::from(
+                auth_methods
+                    .iter()
+                    .map(|method| Value::from(method.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        vars.insert(
+            "client_id".into(),
+            grant.map_or(Value::None, |grant| Value::from(grant.client_id.as_str())),
+        );
+        vars.insert(
+            "scope".into(),
+            grant
+                .and_then(|grant| grant.scope.as_deref())
+                .map_or(Value::None, Value::from),
+        );
+        vars.insert(
+            "jkt".into(),
+            grant
+                .and_then(|grant| grant.jkt.as_deref())
+                .map_or(Value::None, Value::from),
+        );
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Finds a refresh token by its hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_hash` - The hash of the refresh token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the refresh token if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn find_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<RefreshToken>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM refresh_tokens WHERE token_hash = $token_hash";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("token_hash".into(), Value::from(token_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut tokens: Vec<RefreshToken> = response.take(0)?;
+        Ok(tokens.pop())
+    }
+
+    /// Marks a refresh token as used.
+    ///
+    /// Only tokens that are neither used nor revoked are updated, so at most one caller can
+    /// successfully consume a given token.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_hash` - The hash of the refresh token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the token was consumed by this call.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn consume_refresh_token(&self, token_hash: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE refresh_tokens SET used = true WHERE token_hash = $token_hash AND used = false AND revoked = false RETURN AFTER;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("token_hash".into(), Value::from(token_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let tokens: Vec<RefreshToken> = response.take(0)?;
+        Ok(!tokens.is_empty())
+    }
+
+    /// Revokes every refresh token in a token family.
+    ///
+    /// # Arguments
+    ///
+    /// * `family_id` - The ID of the token family.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn revoke_refresh_token_family(&self, family_id: &str) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE refresh_tokens SET revoked = true WHERE family_id = $family_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("family_id".into(), Value::from(family_id));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Revokes every refresh token issued to a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn revoke_user_refresh_tokens(&self, user_id: &str) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE refresh_tokens SET revoked = true WHERE user_id = $user_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Adds an access token to the revocation store.
+    ///
+    /// Revoked tokens only need to be remembered until they expire, so expired entries are
+    /// removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `jti` - The unique ID of the access token.
+    /// * `expires_at` - The expiration timestamp of the access token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Storing the revocation fails.
+    pub async fn revoke_access_token(&self, jti: &str, expires_at: i64) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE revoked_tokens WHERE expires_at < time::unix(time::now()); IF (SELECT * FROM revoked_tokens WHERE jti = $jti) = [] { CREATE revoked_tokens SET jti = $jti, expires_at = $expires_at; };";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("jti".into(), Value::from(jti));
+        vars.insert("expires_at".into(), Value::from(expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Checks whether an access token has been revoked.
+    ///
+    /// # Arguments
+    ///
+    /// * `jti` - The unique ID of the access token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the token is in the revocation store.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn is_access_token_revoked(&self, jti: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT VALUE jti FROM revoked_tokens WHERE jti = $jti";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("jti".into(), Value::from(jti));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let revoked: Vec<String> = response.take(0)?;
+        Ok(!revoked.is_empty())
+    }
+
+    /// Records the unique ID of a DPoP proof so that the proof can't be replayed.
+    ///
+    /// Expired entries are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `jti` - The unique ID of the proof.
+    /// * `expires_at` - The timestamp after which the proof is rejected anyway.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the proof hadn't been seen before.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Storing the proof fails.
+    pub async fn record_dpop_proof(&self, jti: &str, expires_at: i64) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE dpop_proofs WHERE expires_at < time::unix(time::now()); IF (SELECT * FROM dpop_proofs WHERE jti = $jti) = [] { CREATE dpop_proofs SET jti = $jti, expires_at = $expires_at; true } ELSE { false };";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("jti".into(), Value::from(jti));
+        vars.insert("expires_at".into(), Value::from(expires_at));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let recorded: Option<bool> = response.take(1)?;
+        Ok(recorded.unwrap_or(false))
+    }
+
+    /// Stores a new initial access token for dynamic client registration.
+    ///
+    /// Expired and used up tokens are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The registration token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the token in the database fails.
+    pub async fn store_registration_token(
+        &self,
+        token: &RegistrationToken,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE registration_tokens WHERE expires_at < time::unix(time::now()) OR remaining_uses <= 0; CREATE registration_tokens SET token_hash = $token_hash, created_by = $created_by, remaining_uses = $remaining_uses, expires_at = $expires_at, created_at = $created_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("token_hash".into(), Value::from(token.token_hash.as_str()));
+        vars.insert("created_by".into(), Value::from(token.created_by.as_str()));
+        vars.insert("remaining_uses".into(), Value::from(token.remaining_uses));
+        vars.insert("expires_at".into(), Value::from(token.expires_at));
+        vars.insert("created_at".into(), Value::from(token.created_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Uses an initial access token once, if it is unexpired and not used up.
+    ///
+    /// The check and the update happen in a single statement, so that concurrent
+    /// registrations can't use a token more often than allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_hash` - The hash of the token.
+    /// * `now` - The current timestamp.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the token could be used.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn use_registration_token(
+        &self,
+        token_hash: &str,
+        now: i64,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE registration_tokens SET remaining_uses -= 1 WHERE token_hash = $token_hash AND remaining_uses > 0 AND expires_at > $now;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("token_hash".into(), Value::from(token_hash));
+        vars.insert("now".into(), Value::from(now));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let tokens: Vec<RegistrationToken> = response.take(0)?;
+        Ok(!tokens.is_empty())
+    }
+
+    /// Gets the current token generation of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the token generation, or `0` if the user doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_token_generation(&self, user_id: &str) -> Result<i64, CustomError> {
+        let user_id = match parse_record_id(user_id) {
+            Some(user_id) => user_id,
+            None => return Ok(0),
+        };
+
+        // Create the SQL query.
+        let sql = "SELECT VALUE token_generation FROM $user_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let generations: Vec<Option<i64>> = response.take(0)?;
+        Ok(generations.into_iter().flatten().next().unwrap_or(0))
+    }
+
+    /// Increments the token generation of a user, invalidating all of their access tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The user ID is invalid.
+    /// - The update operation fails.
+    pub async fn increment_token_generation(&self, user_id: &str) -> Result<(), CustomError> {
+        let user_id = parse_record_id(user_id).ok_or(CustomError::UserNotFound)?;
+
+        // Create the SQL query.
+        let sql = "UPDATE $user_id SET token_generation = (token_generation OR 0) + 1;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Stores a new TOTP secret for a user whose enrollment hasn't been confirmed yet.
+    ///
+    /// Starting a new enrollment replaces any previous unconfirmed secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `encrypted_secret` - The encrypted TOTP secret.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The user ID is invalid.
+    /// - The update operation fails.
+    pub async fn set_pending_totp_secret(
+        &self,
+        user_id: &str,
+        encrypted_secret: &str,
+    ) -> Result<(), CustomError> {
+        let user_id = parse_record_id(user_id).ok_or(CustomError::UserNotFound)?;
+
+        // Create the SQL query.
+        let sql = "UPDATE $user_id SET totp_secret = $totp_secret, totp_enabled = false, totp_last_step = NONE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("totp_secret".into(), Value::from(encrypted_secret));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Enables TOTP for a user after their first code was verified.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `step` - The time step of the verified code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The user ID is invalid.
+    /// - The update operation fails.
+    pub async fn enable_totp(&self, user_id: &str, step: i64) -> Result<(), CustomError> {
+        let user_id = parse_record_id(user_id).ok_or(CustomError::UserNotFound)?;
+
+        // Create the SQL query.
+        let sql = "UPDATE $user_id SET totp_enabled = true, totp_last_step = $step;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("step".into(), Value::from(step));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Disables TOTP for a user and removes their secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The user ID is invalid.
+    /// - The update operation fails.
+    pub async fn disable_totp(&self, user_id: &str) -> Result<(), CustomError> {
+        let user_id = parse_record_id(user_id).ok_or(CustomError::UserNotFound)?;
+
+        // Create the SQL query.
+        let sql =
+            "UPDATE $user_id SET totp_secret = NONE, totp_enabled = false, totp_last_step = NONE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Records the time step of an accepted TOTP code.
+    ///
+    /// The step is only recorded if it is newer than the last accepted one, so a code can be
+    /// used at most once even if it is submitted concurrently.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `step` - The time step of the accepted code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the step was recorded by this call.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn record_totp_step(&self, user_id: &str, step: i64) -> Result<bool, CustomError> {
+        let user_id = match parse_record_id(user_id) {
+            Some(user_id) => user_id,
+            None => return Ok(false),
+        };
+
+        // Create the SQL query.
+        let sql = "UPDATE $user_id SET totp_last_step = $step WHERE totp_last_step = NONE OR totp_last_step < $step RETURN AFTER;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("step".into(), Value::from(step));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let users: Vec<User> = response.take(0)?;
+        Ok(!users.is_empty())
+    }
+
+    /// Stores a new MFA challenge.
+    ///
+    /// Expired challenges are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge_hash` - The hash of the challenge token.
+    /// * `user_id` - The ID of the user who passed the first factor.
+    /// * `auth_methods` - The methods the user passed the first factor with.
+    /// * `expires_at` - The expiration timestamp of the challenge.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the challenge in the database fails.
+    pub async fn store_mfa_challenge(
+        &self,
+        challenge_hash: &str,
+        user_id: &str,
+        auth_methods: &[String],
+        expires_at: i64,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE mfa_challenges WHERE expires_at < time::unix(time::now()); CREATE mfa_challenges SET challenge_hash = $challenge_hash, user_id = $user_id, auth_methods = $auth_methods, expires_at = $expires_at, attempts = 0;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("challenge_hash".into(), Value::from(challenge_hash));
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert(
+            "auth_methods".into(),
+            Value::from(
+                auth_methods
+                    .iter()
+                    .map(|method| Value::from(method.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        vars.insert("expires_at".into(), Value::from(expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Finds an MFA challenge by its hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge_hash` - The hash of the challenge token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the challenge if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn find_mfa_challenge(
+        &self,
+        challenge_hash: &str,
+    ) -> Result<Option<MfaChallenge>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM mfa_challenges WHERE challenge_hash = $challenge_hash";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("challenge_hash".into(), Value::from(challenge_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut challenges: Vec<MfaChallenge> = response.take(0)?;
+        Ok(challenges.pop())
+    }
+
+    /// Records a wrong code entered for an MFA challenge.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge_hash` - The hash of the challenge token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of failed attempts so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn record_mfa_challenge_failure(
+        &self,
+        challenge_hash: &str,
+    ) -> Result<i64, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE mfa_challenges SET attempts += 1 WHERE challenge_hash = $challenge_hash RETURN AFTER;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("challenge_hash".into(), Value::from(challenge_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let challenges: Vec<MfaChallenge> = response.take(0)?;
+        Ok(challenges
+            .into_iter()
+            .map(|challenge| challenge.attempts)
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Deletes an MFA challenge.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge_hash` - The hash of the challenge token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the challenge existed and was deleted by this call.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_mfa_challenge(&self, challenge_hash: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE mfa_challenges WHERE challenge_hash = $challenge_hash RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("challenge_hash".into(), Value::from(challenge_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let challenges: Vec<MfaChallenge> = response.take(0)?;
+        Ok(!challenges.is_empty())
+    }
+
+    /// Replaces the recovery codes of a user with a new batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `code_hashes` - The Argon2 hashes of the new codes.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Replacing the codes fails.
+    pub async fn replace_recovery_codes(
+        &self,
+        user_id: &str,
+        code_hashes: &[String],
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "BEGIN TRANSACTION; DELETE recovery_codes WHERE user_id = $user_id; FOR $code_hash IN $code_hashes { CREATE recovery_codes SET user_id = $user_id, code_hash = $code_hash, used = false, created_at = time::now(); }; COMMIT TRANSACTION;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert(
+            "code_hashes".into(),
+            Value::from(
+                code_hashes
+                    .iter()
+                    .map(|code_hash| Value::from(code_hash.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Gets the unused recovery codes of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the unused recovery codes.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_unused_recovery_codes(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<RecoveryCode>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM recovery_codes WHERE user_id = $user_id AND used = false";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let codes: Vec<RecoveryCode> = response.take(0)?;
+        Ok(codes)
+    }
+
+    /// Counts the unused recovery codes of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of unused recovery codes.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn count_unused_recovery_codes(&self, user_id: &str) -> Result<i64, CustomError> {
+        // Create the SQL query.
+        let sql = "RETURN count(SELECT id FROM recovery_codes WHERE user_id = $user_id AND used = false);";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let count: Option<i64> = response.take(0)?;
+        Ok(count.unwrap_or(0))
+    }
+
+    /// Marks a recovery code as used.
+    ///
+    /// Only unused codes are updated, so at most one caller can successfully use a given code.
+    ///
+    /// # Arguments
+    ///
+    /// * `code_id` - The record ID of the code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the code was consumed by this call.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn consume_recovery_code(&self, code_id: &Thing) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE $code_id SET used = true, used_at = time::now() WHERE used = false RETURN AFTER;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("code_id".into(), Value::from(code_id.clone()));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let codes: Vec<RecoveryCode> = response.take(0)?;
+        Ok(!codes.is_empty())
+    }
+
+    /// Deletes all recovery codes of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_recovery_codes(&self, user_id: &str) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE recovery_codes WHERE user_id = $user_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Stores a new WebAuthn challenge.
+    ///
+    /// Expired challenges are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge` - The challenge to store.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the challenge in the database fails.
+    pub async fn store_webauthn_challenge(
+        &self,
+        challenge: &WebAuthnChallenge,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE webauthn_challenges WHERE expires_at < time::unix(time::now()); CREATE webauthn_challenges SET challenge_hash = $challenge_hash, ceremony = $ceremony, user_id = $user_id, user_verification_required = $user_verification_required, expires_at = $expires_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "challenge_hash".into(),
+            Value::from(challenge.challenge_hash.as_str()),
+        );
+        vars.insert("ceremony".into(), Value::from(challenge.ceremony.as_str()));
+        vars.insert(
+            "user_id".into(),
+            match &challenge.user_id {
+                Some(user_id) => Value::from(user_id.as_str()),
+                None => Value::None,
+            },
+        );
+        vars.insert(
+            "user_verification_required".into(),
+            Value::from(challenge.user_verification_required),
+        );
+        vars.insert("expires_at".into(), Value::from(challenge.expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes a WebAuthn challenge and returns it.
+    ///
+    /// Each challenge can be consumed once, so a response can't be replayed.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge_hash` - The hash of the challenge.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the challenge if it existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn consume_webauthn_challenge(
+        &self,
+        challenge_hash: &str,
+    ) -> Result<Option<WebAuthnChallenge>, CustomError> {
+        // Create the SQL query.
+        let sql =
+            "DELETE webauthn_challenges WHERE challenge_hash = $challenge_hash RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("challenge_hash".into(), Value::from(challenge_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut challenges: Vec<WebAuthnChallenge> = response.take(0)?;
+        Ok(challenges.pop())
+    }
+
+    /// Stores a new WebAuthn credential.
+    ///
+    /// # Arguments
+    ///
+    /// * `credential` - The credential to store.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the credential in the database fails, e.g. because the ID is already taken.
+    pub async fn store_webauthn_credential(
+        &self,
+        credential: &WebAuthnCredential,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE webauthn_credentials SET credential_id = $credential_id, user_id = $user_id, public_key = $public_key, algorithm = $algorithm, sign_count = $sign_count, name = $name, created_at = $created_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "credential_id".into(),
+            Value::from(credential.credential_id.as_str()),
+        );
+        vars.insert("user_id".into(), Value::from(credential.user_id.as_str()));
+        vars.insert(
+            "public_key".into(),
+            Value::from(credential.public_key.as_str()),
+        );
+        vars.insert("algorithm".into(), Value::from(credential.algorithm));
+        vars.insert("sign_count".into(), Value::from(credential.sign_count));
+        vars.insert("name".into(), Value::from(credential.name.as_str()));
+        vars.insert("created_at".into(), Value::from(credential.created_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Finds a WebAuthn credential by its ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `credential_id` - The base64url-encoded credential ID.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the credential if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn find_webauthn_credential(
+        &self,
+        credential_id: &str,
+    ) -> Result<Option<WebAuthnCredential>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM webauthn_credentials WHERE credential_id = $credential_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("credential_id".into(), Value::from(credential_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut credentials: Vec<WebAuthnCredential> = response.take(0)?;
+        Ok(credentials.pop())
+    }
+
+    /// Gets the WebAuthn credentials of a user, ordered by creation time.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the user's credentials.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_webauthn_credentials(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<WebAuthnCredential>, CustomError> {
+        // Create the SQL query.
+        let sql =
+            "SELECT * FROM webauthn_credentials WHERE user_id = $user_id ORDER BY created_at ASC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let credentials: Vec<WebAuthnCredential> = response.take(0)?;
+        Ok(credentials)
+    }
+
+    /// Records a successful assertion with a WebAuthn credential.
+    ///
+    /// # Arguments
+    ///
+    /// * `credential_id` - The base64url-encoded credential ID.
+    /// * `sign_count` - The signature counter reported by the authenticator.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn update_webauthn_credential_usage(
+        &self,
+        credential_id: &str,
+        sign_count: i64,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE webauthn_credentials SET sign_count = $sign_count, last_used_at = time::unix(time::now()) WHERE credential_id = $credential_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("credential_id".into(), Value::from(credential_id));
+        vars.insert("sign_count".into(), Value::from(sign_count));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Deletes a WebAuthn credential of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `credential_id` - The base64url-encoded credential ID.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the credential existed and belonged to the user.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_webauthn_credential(
+        &self,
+        user_id: &str,
+        credential_id: &str,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE webauthn_credentials WHERE user_id = $user_id AND credential_id = $credential_id RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("credential_id".into(), Value::from(credential_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let credentials: Vec<WebAuthnCredential> = response.take(0)?;
+        Ok(!credentials.is_empty())
+    }
+
+    /// Stores a new password reset token for a user.
+    ///
+    /// The user's previous reset tokens and all expired tokens are removed at the same time, so
+    /// only the most recent token can be used.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `token_hash` - The hash of the reset token.
+    /// * `expires_at` - The expiration timestamp of the token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the token in the database fails.
+    pub async fn store_password_reset_token(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+        expires_at: i64,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE password_reset_tokens WHERE user_id = $user_id OR expires_at < time::unix(time::now()); CREATE password_reset_tokens SET token_hash = $token_hash, user_id = $user_id, expires_at = $expires_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("token_hash".into(), Value::from(token_hash));
+        vars.insert("expires_at".into(), Value::from(expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes a password reset token and returns it.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_hash` - The hash of the reset token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the token if it existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn consume_password_reset_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<PasswordResetToken>, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE password_reset_tokens WHERE token_hash = $token_hash RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("token_hash".into(), Value::from(token_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut tokens: Vec<PasswordResetToken> = response.take(0)?;
+        Ok(tokens.pop())
+    }
+
+    /// Stores a new email verification token for a user.
+    ///
+    /// The user's previous verification tokens and all expired tokens are removed at the same
+    /// time, so only the most recent token can be used.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `token_hash` - The hash of the verification token.
+    /// * `expires_at` - The expiration timestamp of the token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the token in the database fails.
+    pub async fn store_email_verification_token(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+        expires_at: i64,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE email_verification_tokens WHERE user_id = $user_id OR expires_at < time::unix(time::now()); CREATE email_verification_tokens SET token_hash = $token_hash, user_id = $user_id, expires_at = $expires_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("token_hash".into(), Value::from(token_hash));
+        vars.insert("expires_at".into(), Value::from(expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes an email verification token and returns it.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_hash` - The hash of the verification token.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the token if it existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn consume_email_verification_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<EmailVerificationToken>, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE email_verification_tokens WHERE token_hash = $token_hash RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("token_hash".into(), Value::from(token_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut tokens: Vec<EmailVerificationToken> = response.take(0)?;
+        Ok(tokens.pop())
+    }
+
+    /// Marks the email address of a user as verified.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The user ID is invalid.
+    /// - The update operation fails.
+    pub async fn mark_email_verified(&self, user_id: &str) -> Result<(), CustomError> {
+        let user_id = parse_record_id(user_id).ok_or(CustomError::UserNotFound)?;
+
+        // Create the SQL query.
+        let sql = "UPDATE $user_id SET email_verified = true;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Creates a role or replaces the description and permissions of an existing role.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The role to store.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The upsert operation fails.
+    pub async fn upsert_role(&self, role: &Role) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "UPSERT type::thing('roles', $name) SET name = $name, description = $description, permissions = $permissions;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(role.name.as_str()));
+        vars.insert("description".into(), Value::from(role.description.as_str()));
+        vars.insert(
+            "permissions".into(),
+            Value::from(
+                role.permissions
+                    .iter()
+                    .map(|permission| Value::from(permission.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Gets a role by name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the role.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the role if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_role(&self, name: &str) -> Result<Option<Role>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM roles WHERE name = $name";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(name));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut roles: Vec<Role> = response.take(0)?;
+        Ok(roles.pop())
+    }
+
+    /// Gets all roles, ordered by name.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the roles.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_roles(&self) -> Result<Vec<Role>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM roles ORDER BY name ASC";
+
+        // Execute the query.
+        let mut response = self.db.query(sql).await?;
+        let roles: Vec<Role> = response.take(0)?;
+        Ok(roles)
+    }
+
+    /// Gets the roles with the given names. Unknown names are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The names of the roles.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the roles.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_roles_by_names(&self, names: &[String]) -> Result<Vec<Role>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM roles WHERE name IN $names ORDER BY name ASC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "names".into(),
+            Value::from(
+                names
+                    .iter()
+                    .map(|name| Value::from(name.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let roles: Vec<Role> = response.take(0)?;
+        Ok(roles)
+    }
+
+    /// Deletes a role and removes it from every user.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the role.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the role existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_role(&self, name: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "BEGIN TRANSACTION; DELETE user_roles WHERE role = $name; DELETE group_roles WHERE role = $name; DELETE roles WHERE name = $name RETURN BEFORE; COMMIT TRANSACTION;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(name));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let roles: Vec<Role> = response.take(2)?;
+        Ok(!roles.is_empty())
+    }
+
+    /// Assigns a role to a user. Assigning a role twice has no effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `role` - The name of the role.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The upsert operation fails.
+    pub async fn assign_role(&self, user_id: &str, role: &str) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "UPSERT type::thing('user_roles', [$user_id, $role]) SET user_id = $user_id, role = $role, created_at = time::now();";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("role".into(), Value::from(role));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes a role from a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `role` - The name of the role.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the user had the role.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn unassign_role(&self, user_id: &str, role: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE user_roles WHERE user_id = $user_id AND role = $role RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("role".into(), Value::from(role));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let removed: Vec<RoleAssignment> = response.take(0)?;
+        Ok(!removed.is_empty())
+    }
+
+    /// Gets the names of the roles assigned to a user, ordered by name.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the role names.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_user_roles(&self, user_id: &str) -> Result<Vec<String>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT VALUE role FROM user_roles WHERE user_id = $user_id ORDER BY role ASC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let roles: Vec<String> = response.take(0)?;
+        Ok(roles)
+    }
+
+    /// Creates a group.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The unique name of the group.
+    /// * `description` - A human readable description of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new group.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The name is already taken.
+    /// - Creating the group fails.
+    pub async fn create_group(&self, name: &str, description: &str) -> Result<Group, CustomError> {
+        // Create the SQL query.
+        let sql =
+            "CREATE groups SET name = $name, description = $description, created_at = time::now();";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(name));
+        vars.insert("description".into(), Value::from(description));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut groups: Vec<Group> = response.take(0)?;
+        groups.pop().ok_or(CustomError::Unknown)
+    }
+
+    /// Gets a group by ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The ID of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the group if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_group(&self, group_id: &str) -> Result<Option<Group>, CustomError> {
+        let group_id = match parse_record_id(group_id) {
+            Some(group_id) if group_id.tb == "groups" => group_id,
+            _ => return Ok(None),
+        };
+
+        // Create the SQL query.
+        let sql = "SELECT * FROM $group_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("group_id".into(), Value::from(group_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut groups: Vec<Group> = response.take(0)?;
+        Ok(groups.pop())
+    }
+
+    /// Gets a group by name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the group if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_group_by_name(&self, name: &str) -> Result<Option<Group>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM groups WHERE name = $name";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(name));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut groups: Vec<Group> = response.take(0)?;
+        Ok(groups.pop())
+    }
+
+    /// Gets all groups, ordered by name.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the groups.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_groups(&self) -> Result<Vec<Group>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM groups ORDER BY name ASC";
+
+        // Execute the query.
+        let mut response = self.db.query(sql).await?;
+        let groups: Vec<Group> = response.take(0)?;
+        Ok(groups)
+    }
+
+    /// Renames a group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The ID of the group.
+    /// * `name` - The new name of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the group exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The name is already taken.
+    /// - The update operation fails.
+    pub async fn rename_group(&self, group_id: &str, name: &str) -> Result<bool, CustomError> {
+        let group_id = match parse_record_id(group_id) {
+            Some(group_id) if group_id.tb == "groups" => group_id,
+            _ => return Ok(false),
+        };
+
+        // Create the SQL query.
+        let sql = "UPDATE $group_id SET name = $name RETURN AFTER;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("group_id".into(), Value::from(group_id));
+        vars.insert("name".into(), Value::from(name));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let groups: Vec<Group> = response.take(0)?;
+        Ok(!groups.is_empty())
+    }
+
+    /// Deletes a group together with its memberships and role assignments.
+    ///
+    /// Members of the group aren't deleted, they just lose what they inherited through it.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The ID of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the group existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_group(&self, group_id: &str) -> Result<bool, CustomError> {
+        let record_id = match parse_record_id(group_id) {
+            Some(record_id) if record_id.tb == "groups" => record_id,
+            _ => return Ok(false),
+        };
+
+        // Create the SQL query.
+        let sql = "BEGIN TRANSACTION; DELETE group_members WHERE group_id = $group_id OR member_id = $group_id; DELETE group_roles WHERE group_id = $group_id; DELETE $record_id RETURN BEFORE; COMMIT TRANSACTION;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("group_id".into(), Value::from(group_id));
+        vars.insert("record_id".into(), Value::from(record_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let groups: Vec<Group> = response.take(2)?;
+        Ok(!groups.is_empty())
+    }
+
+    /// Adds a user or a group to a group. Adding a member twice has no effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The ID of the group.
+    /// * `member_id` - The ID of the user or group to add.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The upsert operation fails.
+    pub async fn add_group_member(
+        &self,
+        group_id: &str,
+        member_id: &str,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "UPSERT type::thing('group_members', [$group_id, $member_id]) SET group_id = $group_id, member_id = $member_id, created_at = time::now();";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("group_id".into(), Value::from(group_id));
+        vars.insert("member_id".into(), Value::from(member_id));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes a user or a group from a group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The ID of the group.
+    /// * `member_id` - The ID of the user or group to remove.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if it was a member.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn remove_group_member(
+        &self,
+        group_id: &str,
+        member_id: &str,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE group_members WHERE group_id = $group_id AND member_id = $member_id RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("group_id".into(), Value::from(group_id));
+        vars.insert("member_id".into(), Value::from(member_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let removed: Vec<GroupMembership> = response.take(0)?;
+        Ok(!removed.is_empty())
+    }
+
+    /// Gets the direct members of the given groups.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_ids` - The IDs of the groups.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the memberships, ordered by member ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_group_members(
+        &self,
+        group_ids: &[String],
+    ) -> Result<Vec<GroupMembership>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT group_id, member_id FROM group_members WHERE group_id IN $group_ids ORDER BY member_id ASC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "group_ids".into(),
+            Value::from(
+                group_ids
+                    .iter()
+                    .map(|id| Value::from(id.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let members: Vec<GroupMembership> = response.take(0)?;
+        Ok(members)
+    }
+
+    /// Gets the groups the given users or groups are direct members of.
+    ///
+    /// # Arguments
+    ///
+    /// * `member_ids` - The IDs of the users or groups.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the memberships, ordered by group ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_parent_groups(
+        &self,
+        member_ids: &[String],
+    ) -> Result<Vec<GroupMembership>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT group_id, member_id FROM group_members WHERE member_id IN $member_ids ORDER BY group_id ASC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "member_ids".into(),
+            Value::from(
+                member_ids
+                    .iter()
+                    .map(|id| Value::from(id.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let memberships: Vec<GroupMembership> = response.take(0)?;
+        Ok(memberships)
+    }
+
+    /// Assigns a role to a group. Assigning a role twice has no effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The ID of the group.
+    /// * `role` - The name of the role.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The upsert operation fails.
+    pub async fn assign_group_role(&self, group_id: &str, role: &str) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "UPSERT type::thing('group_roles', [$group_id, $role]) SET group_id = $group_id, role = $role, created_at = time::now();";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("group_id".into(), Value::from(group_id));
+        vars.insert("role".into(), Value::from(role));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes a role from a group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The ID of the group.
+    /// * `role` - The name of the role.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the group had the role.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn unassign_group_role(
+        &self,
+        group_id: &str,
+        role: &str,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE group_roles WHERE group_id = $group_id AND role = $role RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("group_id".into(), Value::from(group_id));
+        vars.insert("role".into(), Value::from(role));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let removed: Vec<GroupRole> = response.take(0)?;
+        Ok(!removed.is_empty())
+    }
+
+    /// Gets the roles assigned to the given groups.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_ids` - The IDs of the groups.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the role assignments, ordered by role name.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_group_roles(
+        &self,
+        group_ids: &[String],
+    ) -> Result<Vec<GroupRole>, CustomError> {
+        // Create the SQL query.
+        let sql =
+            "SELECT group_id, role FROM group_roles WHERE group_id IN $group_ids ORDER BY role ASC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "group_ids".into(),
+            Value::from(
+                group_ids
+                    .iter()
+                    .map(|id| Value::from(id.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let roles: Vec<GroupRole> = response.take(0)?;
+        Ok(roles)
+    }
+
+    /// Stores a new version of a policy. The version number is one higher than the newest
+    /// existing version.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the policy.
+    /// * `document` - The policy document as JSON.
+    /// * `created_by` - The ID of the user who created the version.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the stored version.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the version fails, e.g. because of a concurrent change.
+    pub async fn store_policy_version(
+        &self,
+        name: &str,
+        document: &str,
+        created_by: &str,
+    ) -> Result<StoredPolicy, CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE policies SET name = $name, version = ((SELECT VALUE version FROM policies WHERE name = $name ORDER BY version DESC LIMIT 1)[0] ?? 0) + 1, document = $document, created_by = $created_by, created_at = time::unix(time::now());";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(name));
+        vars.insert("document".into(), Value::from(document));
+        vars.insert("created_by".into(), Value::from(created_by));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut policies: Vec<StoredPolicy> = response.take(0)?;
+        policies.pop().ok_or(CustomError::Unknown)
+    }
+
+    /// Gets every version of a policy, newest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the policy.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the versions.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_policy_versions(&self, name: &str) -> Result<Vec<StoredPolicy>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM policies WHERE name = $name ORDER BY version DESC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(name));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let policies: Vec<StoredPolicy> = response.take(0)?;
+        Ok(policies)
+    }
+
+    /// Gets every version of every policy, ordered by name and version.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the versions.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_all_policy_versions(&self) -> Result<Vec<StoredPolicy>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM policies ORDER BY name ASC, version ASC";
+
+        // Execute the query.
+        let mut response = self.db.query(sql).await?;
+        let policies: Vec<StoredPolicy> = response.take(0)?;
+        Ok(policies)
+    }
+
+    /// Deletes a policy with all of its versions.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the policy.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the policy existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_policy(&self, name: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE policies WHERE name = $name RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("name".into(), Value::from(name));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let policies: Vec<StoredPolicy> = response.take(0)?;
+        Ok(!policies.is_empty())
+    }
+
+    /// Stores a signing key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The signing key to store.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the key in the database fails.
+    pub async fn store_signing_key(&self, key: &StoredSigningKey) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE signing_keys SET kid = $kid, algorithm = $algorithm, state = $state, encrypted_private_key = $encrypted_private_key, public_key = $public_key, created_at = $created_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("kid".into(), Value::from(key.kid.as_str()));
+        vars.insert("algorithm".into(), Value::from(key.algorithm.as_str()));
+        vars.insert("state".into(), Value::from(key.state.as_str()));
+        vars.insert(
+            "encrypted_private_key".into(),
+            Value::from(key.encrypted_private_key.as_str()),
+        );
+        vars.insert("public_key".into(), Value::from(key.public_key.as_str()));
+        vars.insert("created_at".into(), Value::from(key.created_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Gets all signing keys, ordered by creation time.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the stored signing keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_signing_keys(&self) -> Result<Vec<StoredSigningKey>, CustomError> {
+        let mut response = self
+            .db
+            .query("SELECT * FROM signing_keys ORDER BY created_at ASC")
+            .await?;
+        let keys: Vec<StoredSigningKey> = response.take(0)?;
+        Ok(keys)
+    }
+
+    /// Updates the state of a signing key.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The key ID.
+    /// * `state` - The new state.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the key exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn update_signing_key_state(
+        &self,
+        kid: &str,
+        state: &str,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE signing_keys SET state = $state WHERE kid = $kid RETURN AFTER;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("kid".into(), Value::from(kid));
+        vars.insert("state".into(), Value::from(state));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let keys: Vec<StoredSigningKey> = response.take(0)?;
+        Ok(!keys.is_empty())
+    }
+
+    /// Registers a tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - The tenant to register.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - A tenant with the same ID already exists.
+    /// - Creating the tenant fails.
+    pub async fn create_tenant(&self, tenant: &Tenant) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE type::thing('tenants', $tenant_id) SET tenant_id = $tenant_id, name = $name, hosts = $hosts, settings = { admin_user_ids: $admin_user_ids, email_verification_policy: $email_verification_policy }, created_at = $created_at;";
+
+        // Bind the parameters to the query.
+        let vars = tenant_vars(tenant);
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Updates the name, hosts and settings of a tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - The updated tenant.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the tenant exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn update_tenant(&self, tenant: &Tenant) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE type::thing('tenants', $tenant_id) SET name = $name, hosts = $hosts, settings = { admin_user_ids: $admin_user_ids, email_verification_policy: $email_verification_policy };";
+
+        // Bind the parameters to the query.
+        let vars = tenant_vars(tenant);
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let tenants: Vec<Tenant> = response.take(0)?;
+        Ok(!tenants.is_empty())
+    }
+
+    /// Gets a tenant by ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant_id` - The ID of the tenant.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the tenant if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_tenant(&self, tenant_id: &str) -> Result<Option<Tenant>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM tenants WHERE tenant_id = $tenant_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("tenant_id".into(), Value::from(tenant_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut tenants: Vec<Tenant> = response.take(0)?;
+        Ok(tenants.pop())
+    }
+
+    /// Gets the tenant selected by a host name.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host name, without a port.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the tenant if one is registered for the host.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_tenant_by_host(&self, host: &str) -> Result<Option<Tenant>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM tenants WHERE $host IN hosts LIMIT 1";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("host".into(), Value::from(host));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut tenants: Vec<Tenant> = response.take(0)?;
+        Ok(tenants.pop())
+    }
+
+    /// Gets all tenants, ordered by ID.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the tenants.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_tenants(&self) -> Result<Vec<Tenant>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM tenants ORDER BY tenant_id ASC";
+
+        // Execute the query.
+        let mut response = self.db.query(sql).await?;
+        let tenants: Vec<Tenant> = response.take(0)?;
+        Ok(tenants)
+    }
+
+    /// Removes a tenant from the registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant_id` - The ID of the tenant.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the tenant existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_tenant(&self, tenant_id: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE tenants WHERE tenant_id = $tenant_id RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("tenant_id".into(), Value::from(tenant_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let tenants: Vec<Tenant> = response.take(0)?;
+        Ok(!tenants.is_empty())
+    }
+
+    /// Registers an OAuth client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client to register.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - A client with the same ID already exists.
+    /// - Creating the client fails.
+    pub async fn create_oauth_client(&self, client: &OAuthClient) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE oauth_clients SET client_id = $client_id, name = $name, client_type = $client_type, secret_hash = $secret_hash, redirect_uris = $redirect_uris, grant_types = $grant_types, scopes = $scopes, logo_uri = $logo_uri, access_token_lifetime = $access_token_lifetime, refresh_token_lifetime = $refresh_token_lifetime, first_party = $first_party, disabled = $disabled, created_at = $created_at;";
+
+        // Bind the parameters to the query.
+        let mut vars = oauth_client_vars(client);
+        vars.insert(
+            "client_type".into(),
+            Value::from(client.client_type.as_str()),
+        );
+        vars.insert(
+            "secret_hash".into(),
+            client
+                .secret_hash
+                .as_deref()
+                .map_or(Value::None, Value::from),
+        );
+        vars.insert("disabled".into(), Value::from(client.disabled));
+        vars.insert("created_at".into(), Value::from(client.created_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Updates the settings of an OAuth client.
+    ///
+    /// The client type, secret, disabled state and creation time are left unchanged; they
+    /// have their own operations.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client with its new settings.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the client exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn update_oauth_client(&self, client: &OAuthClient) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE oauth_clients SET name = $name, redirect_uris = $redirect_uris, grant_types = $grant_types, scopes = $scopes, logo_uri = $logo_uri, access_token_lifetime = $access_token_lifetime, refresh_token_lifetime = $refresh_token_lifetime, first_party = $first_party WHERE client_id = $client_id;";
+
+        // Bind the parameters to the query.
+        let vars = oauth_client_vars(client);
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let clients: Vec<OAuthClient> = response.take(0)?;
+        Ok(!clients.is_empty())
+    }
+
+    /// Gets an OAuth client by ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The ID of the client.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the client if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_oauth_client(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<OAuthClient>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM oauth_clients WHERE client_id = $client_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("client_id".into(), Value::from(client_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut clients: Vec<OAuthClient> = response.take(0)?;
+        Ok(clients.pop())
+    }
+
+    /// Gets all OAuth clients, ordered by creation time.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the clients.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_oauth_clients(&self) -> Result<Vec<OAuthClient>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM oauth_clients ORDER BY created_at ASC";
+
+        // Execute the query.
+        let mut response = self.db.query(sql).await?;
+        let clients: Vec<OAuthClient> = response.take(0)?;
+        Ok(clients)
+    }
+
+    /// Replaces the secret of an OAuth client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The ID of the client.
+    /// * `secret_hash` - The hash of the new secret.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the client exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn update_oauth_client_secret(
+        &self,
+        client_id: &str,
+        secret_hash: &str,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql =
+            "UPDATE oauth_clients SET secret_hash = $secret_hash WHERE client_id = $client_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("client_id".into(), Value::from(client_id));
+        vars.insert("secret_hash".into(), Value::from(secret_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let clients: Vec<OAuthClient> = response.take(0)?;
+        Ok(!clients.is_empty())
+    }
+
+    /// Disables or enables an OAuth client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The ID of the client.
+    /// * `disabled` - Whether the client is disabled.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the client exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn set_oauth_client_disabled(
+        &self,
+        client_id: &str,
+        disabled: bool,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE oauth_clients SET disabled = $disabled WHERE client_id = $client_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("client_id".into(), Value::from(client_id));
+        vars.insert("disabled".into(), Value::from(disabled));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let clients: Vec<OAuthClient> = response.take(0)?;
+        Ok(!clients.is_empty())
+    }
+
+    /// Removes an OAuth client together with its pending authorization codes, device codes and
+    /// consents.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The ID of the client.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the client existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_oauth_client(&self, client_id: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE oauth_clients WHERE client_id = $client_id RETURN BEFORE; DELETE oauth_codes WHERE client_id = $client_id; DELETE device_authorizations WHERE client_id = $client_id; DELETE consents WHERE client_id = $client_id; DELETE consent_requests WHERE client_id = $client_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("client_id".into(), Value::from(client_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let clients: Vec<OAuthClient> = response.take(0)?;
+        Ok(!clients.is_empty())
+    }
+
+    /// Stores a new authorization code.
+    ///
+    /// Expired codes are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The authorization code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the code in the database fails.
+    pub async fn store_authorization_code(
+        &self,
+        code: &AuthorizationCode,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE oauth_codes WHERE expires_at < time::unix(time::now()); CREATE oauth_codes SET code_hash = $code_hash, client_id = $client_id, user_id = $user_id, redirect_uri = $redirect_uri, explicit_redirect_uri = $explicit_redirect_uri, code_challenge = $code_challenge, scope = $scope, auth_methods = $auth_methods, nonce = $nonce, auth_time = $auth_time, family_id = $family_id, expires_at = $expires_at, used = false;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("code_hash".into(), Value::from(code.code_hash.as_str()));
+        vars.insert("client_id".into(), Value::from(code.client_id.as_str()));
+        vars.insert("user_id".into(), Value::from(code.user_id.as_str()));
+        vars.insert(
+            "redirect_uri".into(),
+            Value::from(code.redirect_uri.as_str()),
+        );
+        vars.insert(
+            "explicit_redirect_uri".into(),
+            Value::from(code.explicit_redirect_uri),
+        );
+        vars.insert(
+            "code_challenge".into(),
+            Value::from(code.code_challenge.as_str()),
+        );
+        vars.insert(
+            "scope".into(),
+            code.scope.as_deref().map_or(Value::None, Value::from),
+        );
+        vars.insert(
+            "auth_methods".into(),
+            Value::from(
+                code.auth_methods
+                    .iter()
+                    .map(|method| Value::from(method.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        vars.insert(
+            "nonce".into(),
+            code.nonce.as_deref().map_or(Value::None, Value::from),
+        );
+        vars.insert("auth_time".into(), Value::from(code.auth_time));
+        vars.insert("family_id".into(), Value::from(code.family_id.as_str()));
+        vars.insert("expires_at".into(), Value::from(code.expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Gets an authorization code without using it.
+    ///
+    /// # Arguments
+    ///
+    /// * `code_hash` - The hash of the code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the code if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_authorization_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<AuthorizationCode>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM oauth_codes WHERE code_hash = $code_hash";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("code_hash".into(), Value::from(code_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut codes: Vec<AuthorizationCode> = response.take(0)?;
+        Ok(codes.pop())
+    }
+
+    /// Marks an authorization code as used and returns it as it was before.
+    ///
+    /// The code is kept until it expires, so that a replayed code can be detected. Only call this
+    /// once the client and its code verifier have been checked against `get_authorization_code`.
+    ///
+    /// # Arguments
+    ///
+    /// * `code_hash` - The hash of the code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the code if it exists. If its `used` flag is set, the code had
+    /// already been exchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn consume_authorization_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<AuthorizationCode>, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE oauth_codes SET used = true WHERE code_hash = $code_hash RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("code_hash".into(), Value::from(code_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut codes: Vec<AuthorizationCode> = response.take(0)?;
+        Ok(codes.pop())
+    }
+
+    /// Stores a new device authorization.
+    ///
+    /// Expired device authorizations are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `authorization` - The device authorization.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the device authorization fails, e.g. because the user code is taken.
+    pub async fn store_device_authorization(
+        &self,
+        authorization: &DeviceAuthorization,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE device_authorizations WHERE expires_at < time::unix(time::now()); CREATE device_authorizations SET device_code_hash = $device_code_hash, user_code = $user_code, client_id = $client_id, scope = $scope, status = $status, user_id = NONE, auth_methods = [], poll_interval = $poll_interval, last_polled_at = 0, expires_at = $expires_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "device_code_hash".into(),
+            Value::from(authorization.device_code_hash.as_str()),
+        );
+        vars.insert(
+            "user_code".into(),
+            Value::from(authorization.user_code.as_str()),
+        );
+        vars.insert(
+            "client_id".into(),
+            Value::from(authorization.client_id.as_str()),
+        );
+        vars.insert(
+            "scope".into(),
+            authorization
+                .scope
+                .as_deref()
+                .map_or(Value::None, Value::from),
+        );
+        vars.insert("status".into(), Value::from(authorization.status.as_str()));
+        vars.insert(
+            "poll_interval".into(),
+            Value::from(authorization.poll_interval),
+        );
+        vars.insert("expires_at".into(), Value::from(authorization.expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Retrieves a pending, unexpired device authorization by its user code.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_code` - The normalized user code.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the device authorization if it is still pending.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_pending_device_authorization(
+        &self,
+        user_code: &str,
+    ) -> Result<Option<DeviceAuthorization>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM device_authorizations WHERE user_code = $user_code AND status = 'pending' AND expires_at > time::unix(time::now());";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_code".into(), Value::from(user_code));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut authorizations: Vec<DeviceAuthorization> = response.take(0)?;
+        Ok(authorizations.pop())
+    }
+
+    /// Approves or denies a pending, unexpired device authorization.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_code` - The normalized user code.
+    /// * `user_id` - The ID of the user who decides.
+    /// * `auth_methods` - The methods the user authenticated with.
+    /// * `approved` - Whether the user approves the authorization.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if a pending authorization was decided.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn decide_device_authorization(
+        &self,
+        user_code: &str,
+        user_id: &str,
+        auth_methods: &[String],
+        approved: bool,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE device_authorizations SET status = $status, user_id = $user_id, auth_methods = $auth_methods WHERE user_code = $user_code AND status = 'pending' AND expires_at > time::unix(time::now());";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_code".into(), Value::from(user_code));
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert(
+            "auth_methods".into(),
+            Value::from(
+                auth_methods
+                    .iter()
+                    .map(|method| Value::from(method.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        vars.insert(
+            "status".into(),
+            Value::from(if approved { "approved" } else { "denied" }),
+        );
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let authorizations: Vec<DeviceAuthorization> = response.take(0)?;
+        Ok(!authorizations.is_empty())
+    }
+
+    /// Records a poll of the token endpoint for a device code and returns the device
+    /// authorization as it was before.
+    ///
+    /// In the same atomic update, an approved authorization is marked as consumed, and the poll
+    /// interval is increased by five seconds if the client polled too early (RFC 8628,
+    /// section 3.5).
+    ///
+    /// # Arguments
+    ///
+    /// * `device_code_hash` - The hash of the device code.
+    /// * `client_id` - The ID of the polling client.
+    /// * `now` - The current timestamp.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the device authorization if it exists and was issued to the client.
+    /// Polls by other clients leave it unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn poll_device_authorization(
+        &self,
+        device_code_hash: &str,
+        client_id: &str,
+        now: i64,
+    ) -> Result<Option<DeviceAuthorization>, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE device_authorizations SET poll_interval = IF $now - last_polled_at < poll_interval THEN poll_interval + 5 ELSE poll_interval END, last_polled_at = $now, status = IF status = 'approved' THEN 'consumed' ELSE status END WHERE device_code_hash = $device_code_hash AND client_id = $client_id RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("device_code_hash".into(), Value::from(device_code_hash));
+        vars.insert("client_id".into(), Value::from(client_id));
+        vars.insert("now".into(), Value::from(now));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut authorizations: Vec<DeviceAuthorization> = response.take(0)?;
+        Ok(authorizations.pop())
+    }
+
+    /// Gets the consent a user gave a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `client_id` - The ID of the client.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the consent if the user gave one.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_consent(
+        &self,
+        user_id: &str,
+        client_id: &str,
+    ) -> Result<Option<Consent>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM consents WHERE user_id = $user_id AND client_id = $client_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("client_id".into(), Value::from(client_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut consents: Vec<Consent> = response.take(0)?;
+        Ok(consents.pop())
+    }
+
+    /// Gets the consents a user gave, ordered by creation time.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the consents.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_consents(&self, user_id: &str) -> Result<Vec<Consent>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM consents WHERE user_id = $user_id ORDER BY created_at ASC";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let consents: Vec<Consent> = response.take(0)?;
+        Ok(consents)
+    }
+
+    /// Stores the consent a user gave a client, replacing the scopes of an earlier consent.
+    ///
+    /// # Arguments
+    ///
+    /// * `consent` - The consent.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Storing the consent fails.
+    pub async fn save_consent(&self, consent: &Consent) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "IF (SELECT * FROM consents WHERE user_id = $user_id AND client_id = $client_id) = [] { CREATE consents SET user_id = $user_id, client_id = $client_id, scopes = $scopes, created_at = $created_at, updated_at = $updated_at; } ELSE { UPDATE consents SET scopes = $scopes, updated_at = $updated_at WHERE user_id = $user_id AND client_id = $client_id; };";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(consent.user_id.as_str()));
+        vars.insert("client_id".into(), Value::from(consent.client_id.as_str()));
+        vars.insert(
+            "scopes".into(),
+            Value::from(
+                consent
+                    .scopes
+                    .iter()
+                    .map(|scope| Value::from(scope.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        vars.insert("created_at".into(), Value::from(consent.created_at));
+        vars.insert("updated_at".into(), Value::from(consent.updated_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes the consent a user gave a client and revokes the refresh tokens the client got
+    /// for the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user.
+    /// * `client_id` - The ID of the client.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the consent existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete or update operation fails.
+    pub async fn delete_consent(
+        &self,
+        user_id: &str,
+        client_id: &str,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "BEGIN TRANSACTION; DELETE consents WHERE user_id = $user_id AND client_id = $client_id RETURN BEFORE; UPDATE refresh_tokens SET revoked = true WHERE user_id = $user_id AND client_id = $client_id; COMMIT TRANSACTION;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("user_id".into(), Value::from(user_id));
+        vars.insert("client_id".into(), Value::from(client_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let consents: Vec<Consent> = response.take(0)?;
+        Ok(!consents.is_empty())
+    }
+
+    /// Stores an authorization request that waits for the user's consent.
+    ///
+    /// Expired requests are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The consent request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the request in the database fails.
+    pub async fn store_consent_request(&self, request: &ConsentRequest) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE consent_requests WHERE expires_at < time::unix(time::now()); CREATE consent_requests SET consent_hash = $consent_hash, client_id = $client_id, user_id = $user_id, redirect_uri = $redirect_uri, explicit_redirect_uri = $explicit_redirect_uri, scope = $scope, state = $state, code_challenge = $code_challenge, nonce = $nonce, auth_methods = $auth_methods, expires_at = $expires_at;";
+
+        // Bind the parameters to the query.
+        let optional = |value: &Option<String>| value.as_deref().map_or(Value::None, Value::from);
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "consent_hash".into(),
+            Value::from(request.consent_hash.as_str()),
+        );
+        vars.insert("client_id".into(), Value::from(request.client_id.as_str()));
+        vars.insert("user_id".into(), Value::from(request.user_id.as_str()));
+        vars.insert(
+            "redirect_uri".into(),
+            Value::from(request.redirect_uri.as_str()),
+        );
+        vars.insert(
+            "explicit_redirect_uri".into(),
+            Value::from(request.explicit_redirect_uri),
+        );
+        vars.insert("scope".into(), optional(&request.scope));
+        vars.insert("state".into(), optional(&request.state));
+        vars.insert(
+            "code_challenge".into(),
+            Value::from(request.code_challenge.as_str()),
+        );
+        vars.insert("nonce".into(), optional(&request.nonce));
+        vars.insert(
+            "auth_methods".into(),
+            Value::from(
+                request
+                    .auth_methods
+                    .iter()
+                    .map(|method| Value::from(method.as_str()))
+                    .collect::<Vec<Value>>(),
+            ),
+        );
+        vars.insert("expires_at".into(), Value::from(request.expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Gets an unexpired consent request.
+    ///
+    /// # Arguments
+    ///
+    /// * `consent_hash` - The hash of the consent ticket.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the request if it exists and hasn't expired.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_consent_request(
+        &self,
+        consent_hash: &str,
+    ) -> Result<Option<ConsentRequest>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM consent_requests WHERE consent_hash = $consent_hash AND expires_at > time::unix(time::now())";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("consent_hash".into(), Value::from(consent_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut requests: Vec<ConsentRequest> = response.take(0)?;
+        Ok(requests.pop())
+    }
+
+    /// Removes a consent request and returns it, so that it can only be decided once.
+    ///
+    /// # Arguments
+    ///
+    /// * `consent_hash` - The hash of the consent ticket.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the request if it existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn take_consent_request(
+        &self,
+        consent_hash: &str,
+    ) -> Result<Option<ConsentRequest>, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE consent_requests WHERE consent_hash = $consent_hash RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("consent_hash".into(), Value::from(consent_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut requests: Vec<ConsentRequest> = response.take(0)?;
+        Ok(requests.pop())
+    }
+
+    /// Creates an identity provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The identity provider.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - A provider with the same ID exists.
+    /// - Creating the provider in the database fails.
+    pub async fn create_identity_provider(
+        &self,
+        provider: &IdentityProvider,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE identity_providers SET provider_id = $provider_id, name = $name, issuer = $issuer, client_id = $client_id, encrypted_client_secret = $encrypted_client_secret, scopes = $scopes, claim_mapping = { email: $email_claim, username: $username_claim, firstname: $firstname_claim, lastname: $lastname_claim }, provision_users = $provision_users, disabled = $disabled, created_at = $created_at;";
+
+        // Bind the parameters to the query.
+        let vars = identity_provider_vars(provider);
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Replaces the settings of an identity provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The updated identity provider.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the provider exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The update operation fails.
+    pub async fn update_identity_provider(
+        &self,
+        provider: &IdentityProvider,
+    ) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "UPDATE identity_providers SET name = $name, issuer = $issuer, client_id = $client_id, encrypted_client_secret = $encrypted_client_secret, scopes = $scopes, claim_mapping = { email: $email_claim, username: $username_claim, firstname: $firstname_claim, lastname: $lastname_claim }, provision_users = $provision_users, disabled = $disabled WHERE provider_id = $provider_id;";
+
+        // Bind the parameters to the query.
+        let vars = identity_provider_vars(provider);
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let providers: Vec<IdentityProvider> = response.take(0)?;
+        Ok(!providers.is_empty())
+    }
+
+    /// Gets an identity provider by ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider_id` - The ID of the provider.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the provider if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_identity_provider(
+        &self,
+        provider_id: &str,
+    ) -> Result<Option<IdentityProvider>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM identity_providers WHERE provider_id = $provider_id";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("provider_id".into(), Value::from(provider_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut providers: Vec<IdentityProvider> = response.take(0)?;
+        Ok(providers.pop())
+    }
+
+    /// Gets all identity providers, ordered by creation time.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the providers.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_identity_providers(&self) -> Result<Vec<IdentityProvider>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM identity_providers ORDER BY created_at ASC";
+
+        // Execute the query.
+        let mut response = self.db.query(sql).await?;
+        let providers: Vec<IdentityProvider> = response.take(0)?;
+        Ok(providers)
+    }
+
+    /// Removes an identity provider together with its account links and pending logins.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider_id` - The ID of the provider.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `true` if the provider existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn delete_identity_provider(&self, provider_id: &str) -> Result<bool, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE identity_providers WHERE provider_id = $provider_id RETURN BEFORE; DELETE federated_identities WHERE provider_id = $provider_id; DELETE federation_states WHERE provider_id = $provider_id;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("provider_id".into(), Value::from(provider_id));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let providers: Vec<IdentityProvider> = response.take(0)?;
+        Ok(!providers.is_empty())
+    }
+
+    /// Gets the link of an account at an identity provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider_id` - The ID of the provider.
+    /// * `subject` - The subject of the user at the provider.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the link if the account is linked to a local user.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn get_federated_identity(
+        &self,
+        provider_id: &str,
+        subject: &str,
+    ) -> Result<Option<FederatedIdentity>, CustomError> {
+        // Create the SQL query.
+        let sql = "SELECT * FROM federated_identities WHERE provider_id = $provider_id AND subject = $subject";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("provider_id".into(), Value::from(provider_id));
+        vars.insert("subject".into(), Value::from(subject));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut identities: Vec<FederatedIdentity> = response.take(0)?;
+        Ok(identities.pop())
+    }
+
+    /// Links an account at an identity provider to a local user.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The link.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The account is already linked.
+    /// - Creating the link in the database fails.
+    pub async fn create_federated_identity(
+        &self,
+        identity: &FederatedIdentity,
+    ) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "CREATE federated_identities SET provider_id = $provider_id, subject = $subject, user_id = $user_id, created_at = $created_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert(
+            "provider_id".into(),
+            Value::from(identity.provider_id.as_str()),
+        );
+        vars.insert("subject".into(), Value::from(identity.subject.as_str()));
+        vars.insert("user_id".into(), Value::from(identity.user_id.as_str()));
+        vars.insert("created_at".into(), Value::from(identity.created_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Stores a login at an identity provider until the provider calls back.
+    ///
+    /// Expired logins are removed at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The pending login.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Creating the login in the database fails.
+    pub async fn store_federation_state(&self, state: &FederationState) -> Result<(), CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE federation_states WHERE expires_at < time::unix(time::now()); CREATE federation_states SET state_hash = $state_hash, provider_id = $provider_id, nonce = $nonce, code_verifier = $code_verifier, redirect_uri = $redirect_uri, expires_at = $expires_at;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("state_hash".into(), Value::from(state.state_hash.as_str()));
+        vars.insert(
+            "provider_id".into(),
+            Value::from(state.provider_id.as_str()),
+        );
+        vars.insert("nonce".into(), Value::from(state.nonce.as_str()));
+        vars.insert(
+            "code_verifier".into(),
+            Value::from(state.code_verifier.as_str()),
+        );
+        vars.insert(
+            "redirect_uri".into(),
+            Value::from(state.redirect_uri.as_str()),
+        );
+        vars.insert("expires_at".into(), Value::from(state.expires_at));
+
+        // Execute the query.
+        self.db.query(sql).bind(vars).await?.check()?;
+        Ok(())
+    }
+
+    /// Removes a pending login at an identity provider and returns it, so that the provider's
+    /// callback can only be used once.
+    ///
+    /// # Arguments
+    ///
+    /// * `state_hash` - The hash of the `state` sent to the provider.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the login if it existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The delete operation fails.
+    pub async fn take_federation_state(
+        &self,
+        state_hash: &str,
+    ) -> Result<Option<FederationState>, CustomError> {
+        // Create the SQL query.
+        let sql = "DELETE federation_states WHERE state_hash = $state_hash RETURN BEFORE;";
+
+        // Bind the parameters to the query.
+        let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+        vars.insert("state_hash".into(), Value::from(state_hash));
+
+        // Execute the query.
+        let mut response = self.db.query(sql).bind(vars).await?;
+        let mut states: Vec<FederationState> = response.take(0)?;
+        Ok(states.pop())
+    }
+}
+
+/// Parses a record ID such as the subject of a JWT.
+///
+/// # Arguments
+///
+/// * `record_id` - The record ID as a string, e.g. `users:⟨...⟩`.
+///
+/// # Returns
+///
+/// The parsed record ID, or `None` if the string isn't a valid record ID.
+fn parse_record_id(record_id: &str) -> Option<Thing> {
+    thing(record_id).ok()
+}
+
+/// Builds the query parameters for storing a tenant.
+///
+/// # Arguments
+///
+/// * `tenant` - The tenant.
+///
+/// # Returns
+///
+/// The query parameters.
+fn tenant_vars(tenant: &Tenant) -> BTreeMap<String, Value> {
+    let strings = |values: &[String]| {
+        Value::from(
+            values
+                .iter()
+                .map(|value| Value::from(value.as_str()))
+                .collect::<Vec<Value>>(),
+        )
+    };
+
+    let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+    vars.insert("tenant_id".into(), Value::from(tenant.tenant_id.as_str()));
+    vars.insert("name".into(), Value::from(tenant.name.as_str()));
+    vars.insert("hosts".into(), strings(&tenant.hosts));
+    vars.insert(
+        "admin_user_ids".into(),
+        strings(&tenant.settings.admin_user_ids),
+    );
+    vars.insert(
+        "email_verification_policy".into(),
+        tenant
+            .settings
+            .email_verification_policy
+            .as_deref()
+            .map_or(Value::None, Value::from),
+    );
+    vars.insert("created_at".into(), Value::from(tenant.created_at));
+    vars
+}
+
+/// Builds the query parameters for an identity provider.
+fn identity_provider_vars(provider: &IdentityProvider) -> BTreeMap<String, Value> {
+    let mapping = &provider.claim_mapping;
+    let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+    vars.insert(
+        "provider_id".into(),
+        Value::from(provider.provider_id.as_str()),
+    );
+    vars.insert("name".into(), Value::from(provider.name.as_str()));
+    vars.insert("issuer".into(), Value::from(provider.issuer.as_str()));
+    vars.insert("client_id".into(), Value::from(provider.client_id.as_str()));
+    vars.insert(
+        "encrypted_client_secret".into(),
+        Value::from(provider.encrypted_client_secret.as_str()),
+    );
+    vars.insert(
+        "scopes".into(),
+        Value::from(
+            provider
+                .scopes
+                .iter()
+                .map(|scope| Value::from(scope.as_str()))
+                .collect::<Vec<Value>>(),
+        ),
+    );
+    vars.insert("email_claim".into(), Value::from(mapping.email.as_str()));
+    vars.insert(
+        "username_claim".into(),
+        Value::from(mapping.username.as_str()),
+    );
+    vars.insert(
+        "firstname_claim".into(),
+        Value::from(mapping.firstname.as_str()),
+    );
+    vars.insert(
+        "lastname_claim".into(),
+        Value::from(mapping.lastname.as_str()),
+    );
+    vars.insert(
+        "provision_users".into(),
+        Value::from(provider.provision_users),
+    );
+    vars.insert("disabled".into(), Value::from(provider.disabled));
+    vars.insert("created_at".into(), Value::from(provider.created_at));
+    vars
+}
+
+/// Builds the query parameters for the settings of an OAuth client that can be changed after
+/// its registration.
+///
+/// # Arguments
+///
+/// * `client` - The OAuth client.
+///
+/// # Returns
+///
+/// The query parameters.
+fn oauth_client_vars(client: &OAuthClient) -> BTreeMap<String, Value> {
+    let strings = |values: &[String]| {
+        Value::from(
+            values
+                .iter()
+                .map(|value| Value::from(value.as_str()))
+                .collect::<Vec<Value>>(),
+        )
+    };
+    let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+    vars.insert("client_id".into(), Value::from(client.client_id.as_str()));
+    vars.insert("name".into(), Value::from(client.name.as_str()));
+    vars.insert("redirect_uris".into(), strings(&client.redirect_uris));
+    vars.insert("grant_types".into(), strings(&client.grant_types));
+    vars.insert("scopes".into(), strings(&client.scopes));
+    vars.insert(
+        "logo_uri".into(),
+        client.logo_uri.as_deref().map_or(Value::None, Value::from),
+    );
+    vars.insert(
+        "access_token_lifetime".into(),
+        client
+            .access_token_lifetime
+            .map_or(Value::None, Value::from),
+    );
+    vars.insert(
+        "refresh_token_lifetime".into(),
+        client
+            .refresh_token_lifetime
+            .map_or(Value::None, Value::from),
+    );
+    vars.insert("first_party".into(), Value::from(client.first_party));
+    vars
+}
+
+/// Returns the first factor of MFA challenges created before federated logins existed.
+fn default_first_factor() -> Vec<String> {
+    vec!["pwd".to_string()]
+}
+
+/// Returns the grant types of clients registered before grant types could be chosen.
+fn default_grant_types() -> Vec<String> {
+    vec![
+        "authorization_code".to_string(),
+        "refresh_token".to_string(),
+    ]
 }
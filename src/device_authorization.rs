@@ -0,0 +1,363 @@
+//! src/device_authorization.rs
+//!
+//! This module implements the OAuth 2.0 device authorization grant (RFC 8628) for devices that
+//! can't open a browser, such as CLIs and TVs. The device asks for a device code and a short
+//! user code, shows the user code to the user and polls the token endpoint. The user enters the
+//! code on the verification page from any logged-in browser and approves or denies the device.
+
+use crate::database::{Database, DeviceAuthorization, OAuthClient};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::oauth::{
+    authenticate_request_client, check_client_scope, normalize_scope, oauth_error, redirect_url,
+    token_response, ClientCredentials, TokenRequest, TokenResponse, DEVICE_CODE_GRANT,
+};
+use crate::tokens::{generate_opaque_token, issue_client_token_pair, ClientGrant};
+
+use chrono::Utc;
+use rand::{rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::env;
+use uuid::Uuid;
+
+const DEVICE_CODE_LIFETIME_ENV: &str = "OAUTH_DEVICE_CODE_LIFETIME_SECONDS";
+const DEFAULT_DEVICE_CODE_LIFETIME_SECONDS: i64 = 10 * 60;
+const POLL_INTERVAL_ENV: &str = "OAUTH_DEVICE_POLL_INTERVAL_SECONDS";
+const DEFAULT_POLL_INTERVAL_SECONDS: i64 = 5;
+const VERIFICATION_URL_ENV: &str = "OAUTH_DEVICE_VERIFICATION_URL";
+
+/// The characters of user codes. Vowels are left out so that codes never spell words, and
+/// similar looking characters are left out so that codes are easy to type (RFC 8628, section
+/// 6.1).
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+/// The number of characters of a user code, about 34 bits of entropy.
+const USER_CODE_LENGTH: usize = 8;
+
+/// Represents a request to the device authorization endpoint.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceAuthorizationRequest {
+    /// The ID of the client, unless it authenticates with HTTP Basic.
+    pub client_id: Option<String>,
+    /// The secret of a confidential client, unless it authenticates with HTTP Basic.
+    pub client_secret: Option<String>,
+    /// The space-separated scopes requested by the client.
+    pub scope: Option<String>,
+}
+
+/// Represents the response of the device authorization endpoint (RFC 8628, section 3.2).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceAuthorizationResponse {
+    /// The device code the device polls the token endpoint with.
+    pub device_code: String,
+    /// The code the user enters on the verification page.
+    pub user_code: String,
+    /// The URL of the verification page.
+    pub verification_uri: String,
+    /// The URL of the verification page with the user code filled in, e.g. for a QR code.
+    pub verification_uri_complete: String,
+    /// The lifetime of the codes in seconds.
+    pub expires_in: i64,
+    /// The minimum number of seconds between two polls of the token endpoint.
+    pub interval: i64,
+}
+
+/// Returns the lifetime of device codes in seconds.
+///
+/// The lifetime is read from the `OAUTH_DEVICE_CODE_LIFETIME_SECONDS` environment variable and
+/// defaults to ten minutes if it is missing or invalid.
+pub fn device_code_lifetime() -> i64 {
+    env::var(DEVICE_CODE_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_DEVICE_CODE_LIFETIME_SECONDS)
+}
+
+/// Returns the minimum number of seconds between two polls of the token endpoint.
+///
+/// The interval is read from the `OAUTH_DEVICE_POLL_INTERVAL_SECONDS` environment variable and
+/// defaults to five seconds if it is missing or invalid.
+pub fn poll_interval() -> i64 {
+    env::var(POLL_INTERVAL_ENV)
+        .ok()
+        .and_then(|interval| interval.parse::<i64>().ok())
+        .filter(|interval| *interval > 0)
+        .unwrap_or(DEFAULT_POLL_INTERVAL_SECONDS)
+}
+
+/// Returns the URL of the verification page where users enter user codes.
+///
+/// The URL is read from the `OAUTH_DEVICE_VERIFICATION_URL` environment variable and defaults
+/// to the verification endpoint of the tenant.
+///
+/// # Arguments
+///
+/// * `issuer` - The issuer URL of the tenant.
+pub fn verification_url(issuer: &str) -> String {
+    env::var(VERIFICATION_URL_ENV)
+        .ok()
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| format!("{}/oauth/device", issuer))
+}
+
+/// Generates a user code formatted as two groups of four characters, e.g. `WDJB-MJHT`.
+fn generate_user_code() -> String {
+    let mut rng = rng();
+    let mut code = String::with_capacity(USER_CODE_LENGTH + 1);
+    for i in 0..USER_CODE_LENGTH {
+        if i == USER_CODE_LENGTH / 2 {
+            code.push('-');
+        }
+        let index = rng.random_range(0..USER_CODE_ALPHABET.len());
+        code.push(USER_CODE_ALPHABET[index] as char);
+    }
+    code
+}
+
+/// Normalizes a user code so that separators, whitespace and case don't matter.
+///
+/// # Arguments
+///
+/// * `user_code` - The user code as entered by the user.
+///
+/// # Returns
+///
+/// The user code in the form it is stored in.
+pub fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Starts a device authorization.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `request` - The device authorization request.
+/// * `basic` - The credentials from an `Authorization: Basic` header, if any.
+/// * `issuer` - The issuer URL of the tenant.
+///
+/// # Returns
+///
+/// A `Result` containing the device code, the user code and where to enter it.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The client can't be authenticated or may not use the device code grant.
+/// - The scope is malformed or not allowed for the client.
+/// - Storing the device authorization fails.
+pub async fn start_device_authorization(
+    db: &Database,
+    request: &DeviceAuthorizationRequest,
+    basic: Option<ClientCredentials>,
+    issuer: &str,
+) -> Result<DeviceAuthorizationResponse, CustomError> {
+    let client = authenticate_request_client(
+        db,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+        basic,
+    )
+    .await?;
+    if !client
+        .grant_types
+        .iter()
+        .any(|grant_type| grant_type == DEVICE_CODE_GRANT)
+    {
+        tracing::warn!(
+            "Client {} requested a device code without being allowed to",
+            client.client_id
+        );
+        return Err(oauth_error(
+            "unauthorized_client",
+            "The client may not use the device code grant",
+        ));
+    }
+    let scope = normalize_scope(request.scope.as_deref())?;
+    check_client_scope(&client, scope.as_deref())?;
+
+    let device_code = generate_opaque_token();
+    let user_code = generate_user_code();
+    let expires_in = device_code_lifetime();
+    let interval = poll_interval();
+    db.store_device_authorization(&DeviceAuthorization {
+        device_code_hash: hash_token(&device_code),
+        user_code: normalize_user_code(&user_code),
+        client_id: client.client_id.clone(),
+        scope,
+        status: "pending".to_string(),
+        user_id: None,
+        auth_methods: Vec::new(),
+        poll_interval: interval,
+        last_polled_at: 0,
+        expires_at: Utc::now().timestamp() + expires_in,
+    })
+    .await?;
+    tracing::info!("Issued device code to client {}", client.client_id);
+
+    let verification_uri = verification_url(issuer);
+    Ok(DeviceAuthorizationResponse {
+        device_code,
+        verification_uri_complete: redirect_url(&verification_uri, &[("user_code", &user_code)]),
+        user_code,
+        verification_uri,
+        expires_in,
+        interval,
+    })
+}
+
+/// Looks up the pending device authorization for a user code, so that the user can see which
+/// client is asking for access before deciding.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_code` - The user code as entered by the user.
+///
+/// # Returns
+///
+/// A `Result` containing the device authorization and its client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user code is unknown, expired or already decided.
+/// - The query fails.
+pub async fn find_device_authorization(
+    db: &Database,
+    user_code: &str,
+) -> Result<(DeviceAuthorization, OAuthClient), CustomError> {
+    let authorization = db
+        .get_pending_device_authorization(&normalize_user_code(user_code))
+        .await?
+        .ok_or(CustomError::InvalidUserCode)?;
+    let client = db
+        .get_oauth_client(&authorization.client_id)
+        .await?
+        .filter(|client| !client.disabled)
+        .ok_or(CustomError::InvalidUserCode)?;
+    Ok((authorization, client))
+}
+
+/// Approves or denies the device authorization for a user code.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_code` - The user code as entered by the user.
+/// * `user_id` - The ID of the logged-in user.
+/// * `auth_methods` - The methods the user authenticated with.
+/// * `approved` - Whether the user approves the device.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user code is unknown, expired or already decided.
+/// - The update fails.
+pub async fn decide_device_authorization(
+    db: &Database,
+    user_code: &str,
+    user_id: &str,
+    auth_methods: &[String],
+    approved: bool,
+) -> Result<(), CustomError> {
+    let user_code = normalize_user_code(user_code);
+    if !db
+        .decide_device_authorization(&user_code, user_id, auth_methods, approved)
+        .await?
+    {
+        return Err(CustomError::InvalidUserCode);
+    }
+    tracing::info!(
+        "User {} {} a device authorization",
+        user_id,
+        if approved { "approved" } else { "denied" }
+    );
+    Ok(())
+}
+
+/// Handles a poll of the token endpoint with a device code.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client` - The authenticated client.
+/// * `request` - The token request.
+///
+/// # Returns
+///
+/// A `Result` containing the tokens once the user has approved the device.
+///
+/// # Errors
+///
+/// Returns a `CustomError` with the OAuth error:
+/// - `authorization_pending` while the user hasn't decided yet.
+/// - `slow_down` if the client polls too often.
+/// - `access_denied` if the user denied the device.
+/// - `expired_token` if the device code has expired.
+/// - `invalid_grant` if the device code is unknown, used or was issued to another client.
+pub async fn exchange_device_code(
+    db: &Database,
+    client: &OAuthClient,
+    request: &TokenRequest,
+) -> Result<TokenResponse, CustomError> {
+    let device_code = request
+        .device_code
+        .as_deref()
+        .ok_or_else(|| oauth_error("invalid_request", "device_code is required"))?;
+    let now = Utc::now().timestamp();
+    let authorization = db
+        .poll_device_authorization(&hash_token(device_code), &client.client_id, now)
+        .await?
+        .ok_or_else(|| oauth_error("invalid_grant", "The device code is invalid"))?;
+    if authorization.expires_at <= now {
+        return Err(oauth_error("expired_token", "The device code has expired"));
+    }
+
+    match (authorization.status.as_str(), authorization.user_id) {
+        ("approved", Some(user_id)) => {
+            let grant = ClientGrant::new(
+                client,
+                authorization.scope.clone(),
+                request.dpop_jkt.clone(),
+            );
+            let tokens = issue_client_token_pair(
+                db,
+                &user_id,
+                &Uuid::new_v4().to_string(),
+                &authorization.auth_methods,
+                &grant,
+            )
+            .await?;
+            tracing::info!(
+                "Issued tokens for user {} to device of client {}",
+                user_id,
+                client.client_id
+            );
+            Ok(token_response(tokens, authorization.scope, None))
+        }
+        ("denied", _) => Err(oauth_error(
+            "access_denied",
+            "The user denied the authorization",
+        )),
+        ("pending", _) if now - authorization.last_polled_at < authorization.poll_interval => {
+            Err(oauth_error("slow_down", "The client polls too often"))
+        }
+        ("pending", _) => Err(oauth_error(
+            "authorization_pending",
+            "The user hasn't decided yet",
+        )),
+        _ => Err(oauth_error(
+            "invalid_grant",
+            "The device code was already used",
+        )),
+    }
+}
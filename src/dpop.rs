@@ -0,0 +1,238 @@
+//! src/dpop.rs
+//!
+//! This module implements sender-constrained tokens with DPoP (RFC 9449). A client that sends a
+//! DPoP proof to the token endpoint gets tokens bound to its public key by a `cnf.jkt` claim.
+//! Such tokens are only accepted together with a fresh proof signed by the same key, so a
+//! captured token is useless without the private key. DPoP is optional: clients that send no
+//! proof keep getting bearer tokens.
+
+use crate::database::Database;
+use crate::errors::custom_errors::CustomError;
+use crate::middleware::OriginalPath;
+use crate::oidc::public_url;
+
+use actix_web::{HttpMessage, HttpRequest};
+use base64::{engine::general_purpose, Engine as base64Engine};
+use chrono::Utc;
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::env;
+
+const PROOF_LIFETIME_ENV: &str = "DPOP_PROOF_LIFETIME_SECONDS";
+const DEFAULT_PROOF_LIFETIME_SECONDS: i64 = 60;
+
+/// The name of the header that carries DPoP proofs, and the authorization scheme and token
+/// type of DPoP-bound tokens.
+pub const DPOP: &str = "DPoP";
+
+/// The `typ` header of DPoP proofs.
+const PROOF_TYPE: &str = "dpop+jwt";
+
+/// The maximum length of a proof's `jti`, which is chosen by the client.
+const MAX_JTI_LENGTH: usize = 256;
+
+/// The asymmetric algorithms accepted for DPoP proofs.
+pub const SUPPORTED_ALGORITHMS: [Algorithm; 5] = [
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::RS256,
+    Algorithm::PS256,
+    Algorithm::EdDSA,
+];
+
+/// Represents the confirmation claim that binds a token to a key (RFC 7800).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Confirmation {
+    /// The JWK SHA-256 thumbprint of the key the token is bound to.
+    pub jkt: String,
+}
+
+/// Represents the claims of a DPoP proof.
+#[derive(Debug, Deserialize)]
+struct ProofClaims {
+    /// The unique ID of the proof.
+    jti: String,
+    /// The HTTP method of the request.
+    htm: String,
+    /// The URL of the request, without query and fragment.
+    htu: String,
+    /// The timestamp at which the proof was created.
+    iat: i64,
+    /// The hash of the access token sent with the proof.
+    #[serde(default)]
+    ath: Option<String>,
+}
+
+/// Returns how far the `iat` of a proof may be from the current time, in seconds.
+///
+/// The lifetime is read from the `DPOP_PROOF_LIFETIME_SECONDS` environment variable and
+/// defaults to one minute if it is missing or invalid.
+pub fn proof_lifetime() -> i64 {
+    env::var(PROOF_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_PROOF_LIFETIME_SECONDS)
+}
+
+/// Computes the JWK SHA-256 thumbprint of a public key (RFC 7638).
+///
+/// # Arguments
+///
+/// * `jwk` - The public key.
+///
+/// # Returns
+///
+/// A `Result` containing the base64url-encoded thumbprint.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The key is a symmetric key.
+pub fn jwk_thumbprint(jwk: &Jwk) -> Result<String, CustomError> {
+    // The required members, written in lexicographic order since serde_json keeps the order of
+    // object keys with `preserve_order`
+    let members = match &jwk.algorithm {
+        AlgorithmParameters::EllipticCurve(params) => json!({
+            "crv": params.curve,
+            "kty": "EC",
+            "x": params.x,
+            "y": params.y,
+        }),
+        AlgorithmParameters::RSA(params) => json!({
+            "e": params.e,
+            "kty": "RSA",
+            "n": params.n,
+        }),
+        AlgorithmParameters::OctetKeyPair(params) => json!({
+            "crv": params.curve,
+            "kty": "OKP",
+            "x": params.x,
+        }),
+        AlgorithmParameters::OctetKey(_) => {
+            return Err(CustomError::InvalidDpopProof(
+                "Symmetric keys can't be used".to_string(),
+            ))
+        }
+    };
+    let digest = Sha256::digest(members.to_string().as_bytes());
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(digest))
+}
+
+/// Computes the `ath` of an access token, the base64url-encoded SHA-256 hash of the token.
+///
+/// # Arguments
+///
+/// * `access_token` - The access token.
+///
+/// # Returns
+///
+/// The hash of the token.
+pub fn access_token_hash(access_token: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(access_token.as_bytes()))
+}
+
+/// Returns the URL a request was sent to, as the client sees it, without query and fragment.
+///
+/// The path is the one before the tenant prefix was removed, and the base URL is the public
+/// URL of the server.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+pub fn request_url(request: &HttpRequest) -> String {
+    let connection_info = request.connection_info();
+    let base_url = format!("{}://{}", connection_info.scheme(), connection_info.host());
+    let path = request
+        .extensions()
+        .get::<OriginalPath>()
+        .map_or_else(|| request.path().to_string(), |path| path.0.clone());
+    format!("{}{}", public_url(&base_url), path)
+}
+
+/// Verifies a DPoP proof.
+///
+/// The proof must be a JWT of type `dpop+jwt`, signed with an asymmetric algorithm by the key in
+/// its `jwk` header. It must name the method and URL of the request, be recent, and must not
+/// have been used before. Proofs sent with an access token must contain its hash.
+///
+/// # Arguments
+///
+/// * `db` - The database connection, which holds the replay cache.
+/// * `proof` - The value of the `DPoP` header.
+/// * `method` - The HTTP method of the request.
+/// * `url` - The URL of the request, without query and fragment.
+/// * `access_token` - The access token sent with the proof, if any.
+///
+/// # Returns
+///
+/// A `Result` containing the JWK thumbprint of the key that signed the proof.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The proof is malformed, its signature is invalid or it doesn't match the request.
+/// - The proof was already used.
+/// - Checking the replay cache fails.
+pub async fn verify_proof(
+    db: &Database,
+    proof: &str,
+    method: &str,
+    url: &str,
+    access_token: Option<&str>,
+) -> Result<String, CustomError> {
+    let invalid = |reason: &str| CustomError::InvalidDpopProof(reason.to_string());
+
+    let header = decode_header(proof).map_err(|_| invalid("The proof is malformed"))?;
+    if header.typ.as_deref() != Some(PROOF_TYPE) {
+        return Err(invalid("The proof has the wrong type"));
+    }
+    if !SUPPORTED_ALGORITHMS.contains(&header.alg) {
+        return Err(invalid("The signing algorithm isn't supported"));
+    }
+    let jwk = header.jwk.ok_or_else(|| invalid("The proof has no key"))?;
+    let jkt = jwk_thumbprint(&jwk)?;
+    let key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid("The key is malformed"))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+    let claims = decode::<ProofClaims>(proof, &key, &validation)
+        .map_err(|_| invalid("The signature is invalid"))?
+        .claims;
+
+    if !claims.htm.eq_ignore_ascii_case(method) {
+        return Err(invalid("The proof is for another method"));
+    }
+    let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+    if htu != url {
+        return Err(invalid("The proof is for another URL"));
+    }
+    let now = Utc::now().timestamp();
+    let lifetime = proof_lifetime();
+    if (claims.iat - now).abs() > lifetime {
+        return Err(invalid("The proof has expired"));
+    }
+    if let Some(access_token) = access_token {
+        if claims.ath.as_deref() != Some(access_token_hash(access_token).as_str()) {
+            return Err(invalid("The proof is for another access token"));
+        }
+    }
+    if claims.jti.is_empty() || claims.jti.len() > MAX_JTI_LENGTH {
+        return Err(invalid("The proof has an invalid jti"));
+    }
+
+    // Proofs are remembered until their iat is no longer accepted
+    if !db
+        .record_dpop_proof(&claims.jti, claims.iat + lifetime)
+        .await?
+    {
+        tracing::warn!("Replayed DPoP proof for key {}", jkt);
+        return Err(invalid("The proof was already used"));
+    }
+    Ok(jkt)
+}
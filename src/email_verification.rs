@@ -0,0 +1,205 @@
+//! src/email_verification.rs
+//!
+//! This module handles email address verification and the policy for unverified accounts.
+
+use crate::database::{Database, User};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::mailer::{EmailMessage, Mailer};
+use crate::tokens::generate_opaque_token;
+
+use chrono::Utc;
+use std::env;
+
+const EMAIL_VERIFICATION_TOKEN_LIFETIME_ENV: &str = "EMAIL_VERIFICATION_TOKEN_LIFETIME_SECONDS";
+const DEFAULT_EMAIL_VERIFICATION_TOKEN_LIFETIME_SECONDS: i64 = 24 * 60 * 60;
+const EMAIL_VERIFICATION_URL_ENV: &str = "EMAIL_VERIFICATION_URL";
+const EMAIL_VERIFICATION_POLICY_ENV: &str = "EMAIL_VERIFICATION_POLICY";
+
+/// Decides what happens when a user with an unverified email address logs in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailVerificationPolicy {
+    /// The login succeeds and the access token carries `email_verified=false`.
+    Allow,
+    /// The login is refused until the email address is verified.
+    Block,
+}
+
+impl EmailVerificationPolicy {
+    /// Parses a policy name, which can be `allow` or `block`.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The name of the policy.
+    ///
+    /// # Returns
+    ///
+    /// The policy, or `None` if the name is invalid.
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy.to_lowercase().as_str() {
+            "allow" => Some(EmailVerificationPolicy::Allow),
+            "block" => Some(EmailVerificationPolicy::Block),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the configured policy for unverified accounts.
+///
+/// The policy is read from the `EMAIL_VERIFICATION_POLICY` environment variable, which can be
+/// `allow` or `block`. It defaults to `allow` if it is missing or invalid.
+pub fn email_verification_policy() -> EmailVerificationPolicy {
+    env::var(EMAIL_VERIFICATION_POLICY_ENV)
+        .ok()
+        .and_then(|policy| EmailVerificationPolicy::parse(&policy))
+        .unwrap_or(EmailVerificationPolicy::Allow)
+}
+
+/// Returns the policy for unverified accounts of the database's tenant.
+///
+/// The tenant's settings take precedence over the environment.
+///
+/// # Arguments
+///
+/// * `db` - The database of the tenant.
+pub fn tenant_email_verification_policy(db: &Database) -> EmailVerificationPolicy {
+    db.settings()
+        .email_verification_policy
+        .as_deref()
+        .and_then(EmailVerificationPolicy::parse)
+        .unwrap_or_else(email_verification_policy)
+}
+
+/// Returns the lifetime of email verification tokens in seconds.
+///
+/// The lifetime is read from the `EMAIL_VERIFICATION_TOKEN_LIFETIME_SECONDS` environment
+/// variable and defaults to one day if it is missing or invalid.
+pub fn email_verification_token_lifetime() -> i64 {
+    env::var(EMAIL_VERIFICATION_TOKEN_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_EMAIL_VERIFICATION_TOKEN_LIFETIME_SECONDS)
+}
+
+/// Checks whether a user may log in under the policy of their tenant.
+///
+/// # Arguments
+///
+/// * `db` - The database of the user's tenant.
+/// * `user` - The user who is logging in.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the login may continue.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The email address isn't verified and the policy blocks unverified accounts.
+pub fn check_login_allowed(db: &Database, user: &User) -> Result<(), CustomError> {
+    if !user.email_verified
+        && tenant_email_verification_policy(db) == EmailVerificationPolicy::Block
+    {
+        tracing::warn!("Login refused for unverified user: {}", user.id);
+        return Err(CustomError::EmailNotVerified);
+    }
+    Ok(())
+}
+
+/// Sends an email verification token to a user.
+///
+/// Nothing is sent if no user has the given email address or if it is already verified, but
+/// the caller can't tell the difference. Sending a new token invalidates the previous one.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `mailer` - The mailer used to deliver the token.
+/// * `email` - The email address to verify.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the user or storing the token fails.
+/// - Sending the email fails.
+pub async fn send_verification_email(
+    db: &Database,
+    mailer: &dyn Mailer,
+    email: &str,
+) -> Result<(), CustomError> {
+    let user = match db.get_user_by_email(email).await? {
+        Some(user) if !user.email_verified => user,
+        _ => {
+            tracing::info!("No unverified account for email verification request");
+            return Ok(());
+        }
+    };
+    let user_id = user.id.to_string();
+
+    let token = generate_opaque_token();
+    let expires_in = email_verification_token_lifetime();
+    db.store_email_verification_token(
+        &user_id,
+        &hash_token(&token),
+        Utc::now().timestamp() + expires_in,
+    )
+    .await?;
+
+    let link = match env::var(EMAIL_VERIFICATION_URL_ENV) {
+        Ok(url) if !url.is_empty() => format!("{}?token={}", url, token),
+        _ => token,
+    };
+    let body = format!(
+        "Hi {},\n\nplease confirm your email address with the following link or token:\n\n{}\n\nIt expires in {} hours. If you didn't create an account, you can ignore this email.",
+        user.username,
+        link,
+        expires_in / 3600
+    );
+    mailer.send(&EmailMessage::new(
+        &user.email,
+        "Verify your email address",
+        body,
+    ))?;
+
+    tracing::info!("Email verification token sent to user: {}", user_id);
+    Ok(())
+}
+
+/// Marks an email address as verified using a verification token.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `token` - The email verification token.
+///
+/// # Returns
+///
+/// A `Result` containing the ID of the user whose email address was verified.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The token is unknown, expired or was already used.
+/// - Updating the user fails.
+pub async fn verify_email(db: &Database, token: &str) -> Result<String, CustomError> {
+    let stored = match db
+        .consume_email_verification_token(&hash_token(token))
+        .await?
+    {
+        Some(stored) if stored.expires_at > Utc::now().timestamp() => stored,
+        _ => {
+            tracing::warn!("Invalid or expired email verification token presented");
+            return Err(CustomError::InvalidEmailVerificationToken);
+        }
+    };
+
+    db.mark_email_verified(&stored.user_id).await?;
+
+    tracing::info!("Email address verified for user: {}", stored.user_id);
+    Ok(stored.user_id)
+}
@@ -47,6 +47,102 @@ pub enum CustomError {
     ParsingServerPortError(String),
     #[error("Environment variable error: {0}")]
     GovernorCreationError(String),
+    /// Represents an error while generating a token.
+    #[error("Token generation error: {0}")]
+    TokenGenerationError(String),
+    /// Represents a signing key that could not be loaded.
+    #[error("Invalid signing key: {0}")]
+    InvalidSigningKey(String),
+    /// Represents an invalid operation on the signing keyring.
+    #[error("Keyring error: {0}")]
+    KeyringError(String),
+    /// Represents an unknown, expired or revoked refresh token.
+    #[error("Invalid refresh token")]
+    InvalidRefreshToken,
+    /// Represents the replay of a refresh token that was already rotated.
+    #[error("Refresh token reuse detected")]
+    RefreshTokenReuse,
+    /// Represents a TOTP secret that could not be decoded.
+    #[error("Invalid TOTP secret: {0}")]
+    InvalidTotpSecret(String),
+    /// Represents an operation that requires two-factor authentication to be enrolled.
+    #[error("Two-factor authentication is not enabled")]
+    MfaNotEnabled,
+    /// Represents an attempt to enroll two-factor authentication twice.
+    #[error("Two-factor authentication is already enabled")]
+    MfaAlreadyEnabled,
+    /// Represents a wrong or replayed one-time code.
+    #[error("Invalid one-time code")]
+    InvalidMfaCode,
+    /// Represents an unknown or expired MFA challenge.
+    #[error("Invalid MFA challenge")]
+    InvalidMfaChallenge,
+    /// Represents a WebAuthn response that failed verification.
+    #[error("WebAuthn verification failed: {0}")]
+    WebAuthnError(String),
+    /// Represents an error while delivering an email.
+    #[error("Mailer error: {0}")]
+    MailerError(String),
+    /// Represents an unknown, expired or used password reset token.
+    #[error("Invalid password reset token")]
+    InvalidPasswordResetToken,
+    /// Represents an unknown, expired or used email verification token.
+    #[error("Invalid email verification token")]
+    InvalidEmailVerificationToken,
+    /// Represents a login that is refused until the email address is verified.
+    #[error("Email address is not verified")]
+    EmailNotVerified,
+    /// Represents an invalid role, permission or role assignment.
+    #[error("Invalid role: {0}")]
+    InvalidRole(String),
+    /// Represents an invalid group, group name or membership.
+    #[error("Invalid group: {0}")]
+    InvalidGroup(String),
+    /// Represents a group that doesn't exist.
+    #[error("Group not found")]
+    GroupNotFound,
+    /// Represents an invalid authorization policy.
+    #[error("Invalid policy: {0}")]
+    InvalidPolicy(String),
+    /// Represents a policy or policy version that doesn't exist.
+    #[error("Policy not found")]
+    PolicyNotFound,
+    /// Represents an invalid tenant.
+    #[error("Invalid tenant: {0}")]
+    InvalidTenant(String),
+    /// Represents a tenant that doesn't exist.
+    #[error("Tenant not found")]
+    TenantNotFound,
+    /// Represents an invalid OAuth client registration.
+    #[error("Invalid client: {0}")]
+    InvalidClient(String),
+    /// Represents an OAuth client that doesn't exist.
+    #[error("Client not found")]
+    ClientNotFound,
+    /// Represents an unknown, expired or already decided device user code.
+    #[error("Invalid user code")]
+    InvalidUserCode,
+    /// Represents an unknown, expired or already decided consent request.
+    #[error("Invalid consent request")]
+    InvalidConsentRequest,
+    /// Represents an invalid identity provider configuration.
+    #[error("Invalid identity provider: {0}")]
+    InvalidIdentityProvider(String),
+    /// Represents an identity provider that doesn't exist or is disabled.
+    #[error("Identity provider not found")]
+    IdentityProviderNotFound,
+    /// Represents an identity provider that can't be reached or sent an invalid response.
+    #[error("Identity provider unavailable: {0}")]
+    IdentityProviderUnavailable(String),
+    /// Represents a login at an identity provider that was rejected.
+    #[error("Federated login failed: {0}")]
+    FederationError(String),
+    /// Represents a DPoP proof that is malformed, invalid or replayed.
+    #[error("Invalid DPoP proof: {0}")]
+    InvalidDpopProof(String),
+    /// Represents an OAuth error with its error code, e.g. `invalid_grant` (RFC 6749).
+    #[error("{0}: {1}")]
+    OAuthError(&'static str, String),
 }
 
 impl From<surrealdb::Error> for CustomError {
@@ -69,3 +165,10 @@ impl From<actix_web::Error> for CustomError {
         CustomError::ActixWebRuntimeError(error.to_string())
     }
 }
+
+impl From<jsonwebtoken::errors::Error> for CustomError {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        tracing::error!("JWT error: {}", error);
+        CustomError::TokenGenerationError(error.to_string())
+    }
+}
@@ -0,0 +1,639 @@
+//! src/federation.rs
+//!
+//! This module lets users log in with an external OpenID Connect provider, e.g. their corporate
+//! identity provider. The user is sent to the provider with the authorization code flow and
+//! PKCE, and the provider's callback is completed by verifying its ID token against the keys
+//! from its discovery document.
+//!
+//! On the first login the provider account is linked to the local user with the same email
+//! address, but only if both the provider and the local user verified the address. Without a local user, one is
+//! provisioned from the mapped claims if the provider allows it. Later logins find the user by
+//! the link. Users who set up a local second factor still have to complete it, so that logging
+//! in through a provider never bypasses it.
+
+use crate::database::{
+    ClaimMapping, Database, FederatedIdentity, FederationState, IdentityProvider, User,
+};
+use crate::encryption::{decrypt_with_nonce, encrypt_with_random_nonce, generate_key};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::oauth::{
+    code_challenge, normalize_scope, redirect_url, validate_redirect_uri, ClientType,
+};
+use crate::oidc::OPENID_SCOPE;
+use crate::tokens::generate_opaque_token;
+
+use chrono::Utc;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::env;
+use std::time::Duration;
+
+const STATE_LIFETIME_ENV: &str = "FEDERATION_STATE_LIFETIME_SECONDS";
+const DEFAULT_STATE_LIFETIME_SECONDS: i64 = 10 * 60;
+
+/// How long to wait for a response of an identity provider.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The scopes requested from a provider unless others are configured.
+const DEFAULT_SCOPES: [&str; 3] = [OPENID_SCOPE, "email", "profile"];
+
+/// The asymmetric algorithms accepted for ID tokens of providers.
+const SUPPORTED_ALGORITHMS: [Algorithm; 7] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Describes an identity provider to add or change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderRegistration {
+    /// The name of the provider, shown on the login page.
+    pub name: String,
+    /// The issuer URL of the provider.
+    pub issuer: String,
+    /// The client ID of this server at the provider.
+    pub client_id: String,
+    /// The client secret of this server at the provider. Required for new providers, and kept
+    /// if it is left out when a provider is changed.
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// The scopes to request. Defaults to `openid email profile`.
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+    /// Which claims hold the user's details. Defaults to the standard claims.
+    #[serde(default)]
+    pub claim_mapping: ClaimMapping,
+    /// Whether users without a local account get one on their first login. Defaults to `true`.
+    #[serde(default)]
+    pub provision_users: Option<bool>,
+    /// Whether logins with the provider are disabled.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+/// Represents the parts of a provider's discovery document that are used.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    /// The issuer URL, which has to match the configured one.
+    issuer: String,
+    /// The URL users are sent to for logging in.
+    authorization_endpoint: String,
+    /// The URL the authorization code is exchanged at.
+    token_endpoint: String,
+    /// The URL of the provider's public keys.
+    jwks_uri: String,
+}
+
+/// Represents the parts of a provider's token response that are used.
+#[derive(Debug, Deserialize)]
+struct ProviderTokenResponse {
+    /// The ID token of the user.
+    id_token: Option<String>,
+}
+
+/// Returns how long a user has to log in at a provider in seconds.
+///
+/// The lifetime is read from the `FEDERATION_STATE_LIFETIME_SECONDS` environment variable and
+/// defaults to ten minutes if it is missing or invalid.
+pub fn state_lifetime() -> i64 {
+    env::var(STATE_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_STATE_LIFETIME_SECONDS)
+}
+
+/// Adds an identity provider.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `provider_id` - The ID of the provider, used in the login and callback URLs.
+/// * `registration` - The settings of the provider.
+///
+/// # Returns
+///
+/// A `Result` containing the new provider.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The ID is taken or a setting is invalid.
+/// - Encrypting the client secret or storing the provider fails.
+pub async fn create_provider(
+    db: &Database,
+    provider_id: &str,
+    registration: ProviderRegistration,
+) -> Result<IdentityProvider, CustomError> {
+    if provider_id.is_empty()
+        || provider_id.len() > 32
+        || provider_id.starts_with('-')
+        || !provider_id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(CustomError::InvalidIdentityProvider(format!(
+            "Invalid provider ID: {}",
+            provider_id
+        )));
+    }
+    if db.get_identity_provider(provider_id).await?.is_some() {
+        return Err(CustomError::InvalidIdentityProvider(format!(
+            "Provider already exists: {}",
+            provider_id
+        )));
+    }
+    let client_secret = registration.client_secret.clone().ok_or_else(|| {
+        CustomError::InvalidIdentityProvider("client_secret is required".to_string())
+    })?;
+
+    let mut provider = validate_provider(registration, &encrypt_secret(&client_secret)?)?;
+    provider.provider_id = provider_id.to_string();
+    provider.created_at = Utc::now().timestamp();
+    db.create_identity_provider(&provider).await?;
+    tracing::info!("Added identity provider {}", provider.provider_id);
+    Ok(provider)
+}
+
+/// Replaces the settings of an identity provider. Existing account links are kept.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `provider_id` - The ID of the provider.
+/// * `registration` - The new settings of the provider.
+///
+/// # Returns
+///
+/// A `Result` containing the updated provider.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The provider doesn't exist or a setting is invalid.
+/// - Encrypting the client secret or storing the provider fails.
+pub async fn update_provider(
+    db: &Database,
+    provider_id: &str,
+    registration: ProviderRegistration,
+) -> Result<IdentityProvider, CustomError> {
+    let existing = db
+        .get_identity_provider(provider_id)
+        .await?
+        .ok_or(CustomError::IdentityProviderNotFound)?;
+    let encrypted_client_secret = match &registration.client_secret {
+        Some(client_secret) => encrypt_secret(client_secret)?,
+        None => existing.encrypted_client_secret,
+    };
+
+    let provider = IdentityProvider {
+        provider_id: existing.provider_id,
+        created_at: existing.created_at,
+        ..validate_provider(registration, &encrypted_client_secret)?
+    };
+    if !db.update_identity_provider(&provider).await? {
+        return Err(CustomError::IdentityProviderNotFound);
+    }
+    tracing::info!("Updated identity provider {}", provider.provider_id);
+    Ok(provider)
+}
+
+/// Checks the settings of a provider to add or change.
+///
+/// The returned provider has no ID and creation time, which the caller fills in.
+fn validate_provider(
+    registration: ProviderRegistration,
+    encrypted_client_secret: &str,
+) -> Result<IdentityProvider, CustomError> {
+    let invalid = |message: String| CustomError::InvalidIdentityProvider(message);
+
+    if registration.name.trim().is_empty() {
+        return Err(invalid("Name is required".to_string()));
+    }
+    // Issuers follow the rules of redirect URIs, so plain HTTP is only allowed on loopback
+    if registration.issuer.contains('?')
+        || validate_redirect_uri(&registration.issuer, ClientType::Confidential).is_err()
+    {
+        return Err(invalid(format!("Invalid issuer: {}", registration.issuer)));
+    }
+    if registration.client_id.is_empty() {
+        return Err(invalid("client_id is required".to_string()));
+    }
+    if registration
+        .client_secret
+        .as_deref()
+        .is_some_and(str::is_empty)
+    {
+        return Err(invalid("client_secret can't be empty".to_string()));
+    }
+    let scopes = registration
+        .scopes
+        .unwrap_or_else(|| DEFAULT_SCOPES.map(str::to_string).to_vec());
+    let scopes: Vec<String> = match normalize_scope(Some(&scopes.join(" "))) {
+        Ok(scope) => scope
+            .map(|scope| scope.split(' ').map(str::to_string).collect())
+            .unwrap_or_default(),
+        Err(_) => return Err(invalid("A scope is malformed".to_string())),
+    };
+    if !scopes.iter().any(|scope| scope == OPENID_SCOPE) {
+        return Err(invalid("The openid scope is required".to_string()));
+    }
+    let mapping = &registration.claim_mapping;
+    if [
+        &mapping.email,
+        &mapping.username,
+        &mapping.firstname,
+        &mapping.lastname,
+    ]
+    .iter()
+    .any(|claim| claim.is_empty())
+    {
+        return Err(invalid("Claim names can't be empty".to_string()));
+    }
+
+    Ok(IdentityProvider {
+        provider_id: String::new(),
+        name: registration.name,
+        issuer: registration.issuer,
+        client_id: registration.client_id,
+        encrypted_client_secret: encrypted_client_secret.to_string(),
+        scopes,
+        claim_mapping: registration.claim_mapping,
+        provision_users: registration.provision_users.unwrap_or(true),
+        disabled: registration.disabled,
+        created_at: 0,
+    })
+}
+
+/// Starts a login at an identity provider.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `provider_id` - The ID of the provider.
+/// * `redirect_uri` - The URL of the callback the provider sends the user back to.
+///
+/// # Returns
+///
+/// A `Result` containing the URL of the provider's login page.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The provider doesn't exist or is disabled.
+/// - The provider's discovery document can't be loaded.
+/// - Storing the pending login fails.
+pub async fn start_login(
+    db: &Database,
+    provider_id: &str,
+    redirect_uri: &str,
+) -> Result<String, CustomError> {
+    let provider = enabled_provider(db, provider_id).await?;
+    let http = http_client()?;
+    let metadata = discover(&http, &provider).await?;
+
+    let state = generate_opaque_token();
+    let nonce = generate_opaque_token();
+    let code_verifier = generate_opaque_token();
+    db.store_federation_state(&FederationState {
+        state_hash: hash_token(&state),
+        provider_id: provider.provider_id.clone(),
+        nonce: nonce.clone(),
+        code_verifier: code_verifier.clone(),
+        redirect_uri: redirect_uri.to_string(),
+        expires_at: Utc::now().timestamp() + state_lifetime(),
+    })
+    .await?;
+
+    let scope = provider.scopes.join(" ");
+    let challenge = code_challenge(&code_verifier);
+    Ok(redirect_url(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &provider.client_id),
+            ("redirect_uri", redirect_uri),
+            ("scope", &scope),
+            ("state", &state),
+            ("nonce", &nonce),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    ))
+}
+
+/// Completes a login at an identity provider when the provider calls back.
+///
+/// The authorization code is exchanged for an ID token, which has to be signed by the provider
+/// and contain the nonce of the login. The user is then found by the account link, linked by
+/// email address or provisioned.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `provider_id` - The ID of the provider.
+/// * `code` - The authorization code issued by the provider.
+/// * `state` - The state passed back by the provider.
+///
+/// # Returns
+///
+/// A `Result` containing the local user.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The state is unknown, expired or already used, or the provider doesn't exist.
+/// - The provider can't be reached or rejects the code.
+/// - The ID token is invalid.
+/// - The user can't be linked or provisioned.
+pub async fn complete_login(
+    db: &Database,
+    provider_id: &str,
+    code: &str,
+    state: &str,
+) -> Result<User, CustomError> {
+    let now = Utc::now().timestamp();
+    let pending = db
+        .take_federation_state(&hash_token(state))
+        .await?
+        .filter(|pending| pending.provider_id == provider_id && pending.expires_at > now)
+        .ok_or_else(|| federation_error("The login is invalid or has expired"))?;
+    let provider = enabled_provider(db, provider_id).await?;
+    let http = http_client()?;
+    let metadata = discover(&http, &provider).await?;
+
+    let key_bytes: [u8; 32] = generate_key()?.into();
+    let client_secret = decrypt_with_nonce(&key_bytes, &provider.encrypted_client_secret)?;
+    let response = http
+        .post(&metadata.token_endpoint)
+        .basic_auth(&provider.client_id, Some(client_secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &pending.redirect_uri),
+            ("code_verifier", &pending.code_verifier),
+        ])
+        .send()
+        .await
+        .map_err(unavailable)?;
+    if !response.status().is_success() {
+        tracing::warn!(
+            "Identity provider {} rejected an authorization code with status {}",
+            provider.provider_id,
+            response.status()
+        );
+        return Err(federation_error(
+            "The provider rejected the authorization code",
+        ));
+    }
+    let tokens: ProviderTokenResponse = response.json().await.map_err(unavailable)?;
+    let id_token = tokens
+        .id_token
+        .ok_or_else(|| federation_error("The provider didn't return an ID token"))?;
+
+    let claims = verify_id_token(&http, &provider, &metadata, &id_token, &pending.nonce).await?;
+    resolve_user(db, &provider, &claims).await
+}
+
+/// Verifies an ID token of a provider and returns its claims.
+async fn verify_id_token(
+    http: &reqwest::Client,
+    provider: &IdentityProvider,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+    nonce: &str,
+) -> Result<Map<String, Value>, CustomError> {
+    let header =
+        decode_header(id_token).map_err(|_| federation_error("The ID token is malformed"))?;
+    if !SUPPORTED_ALGORITHMS.contains(&header.alg) {
+        return Err(federation_error("The ID token's algorithm isn't supported"));
+    }
+    let jwks: JwkSet = fetch_json(http, &metadata.jwks_uri).await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| federation_error("The ID token's key is unknown"))?;
+    let key = DecodingKey::from_jwk(jwk)
+        .map_err(|_| federation_error("The provider's key is invalid"))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&provider.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<Map<String, Value>>(id_token, &key, &validation)
+        .map_err(|error| {
+            tracing::warn!(
+                "Invalid ID token from identity provider {}: {}",
+                provider.provider_id,
+                error
+            );
+            federation_error("The ID token is invalid")
+        })?
+        .claims;
+    if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+        return Err(federation_error("The ID token is for another login"));
+    }
+    Ok(claims)
+}
+
+/// Finds, links or provisions the local user for the claims of an ID token.
+async fn resolve_user(
+    db: &Database,
+    provider: &IdentityProvider,
+    claims: &Map<String, Value>,
+) -> Result<User, CustomError> {
+    let claim = |name: &str| {
+        claims
+            .get(name)
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+    let subject = claim("sub").ok_or_else(|| federation_error("The ID token has no subject"))?;
+    if let Some(identity) = db
+        .get_federated_identity(&provider.provider_id, subject)
+        .await?
+    {
+        return db
+            .get_user_by_id(&identity.user_id)
+            .await?
+            .ok_or(CustomError::UserNotFound);
+    }
+
+    let mapping = &provider.claim_mapping;
+    let email = claim(&mapping.email)
+        .filter(|email| email.contains('@'))
+        .map(str::to_lowercase)
+        .ok_or_else(|| federation_error("The provider didn't return an email address"))?;
+    let email_verified = claims.get("email_verified").and_then(Value::as_bool) == Some(true);
+    let user = match db.get_user_by_email(&email).await? {
+        // Otherwise anyone who can set the address at the provider could take over the account
+        Some(_) if !email_verified => {
+            tracing::warn!(
+                "Identity provider {} returned an unverified address of an existing user",
+                provider.provider_id
+            );
+            return Err(federation_error(
+                "The email address isn't verified by the provider",
+            ));
+        }
+        // Otherwise whoever registered the address locally, without proving they own it, would
+        // keep access to the account the owner now uses
+        Some(user) if !user.email_verified => {
+            tracing::warn!(
+                "Identity provider {} returned the address of user {}, who didn't verify it",
+                provider.provider_id,
+                user.id
+            );
+            return Err(federation_error(
+                "The email address of the existing account isn't verified",
+            ));
+        }
+        Some(user) => user,
+        None if !provider.provision_users => {
+            return Err(federation_error("No account exists for this email address"));
+        }
+        None => provision_user(db, provider, claims, &email, email_verified).await?,
+    };
+
+    db.create_federated_identity(&FederatedIdentity {
+        provider_id: provider.provider_id.clone(),
+        subject: subject.to_string(),
+        user_id: user.id.to_string(),
+        created_at: Utc::now().timestamp(),
+    })
+    .await?;
+    tracing::info!(
+        "Linked user {} to identity provider {}",
+        user.id,
+        provider.provider_id
+    );
+    Ok(user)
+}
+
+/// Creates a local user from the claims of an ID token.
+///
+/// The user gets a random password, which they can replace with the password reset.
+async fn provision_user(
+    db: &Database,
+    provider: &IdentityProvider,
+    claims: &Map<String, Value>,
+    email: &str,
+    email_verified: bool,
+) -> Result<User, CustomError> {
+    let mapping = &provider.claim_mapping;
+    let claim = |name: &str| {
+        claims
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    let username = Some(claim(&mapping.username))
+        .filter(|username| !username.is_empty())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+
+    db.register(
+        claim(&mapping.firstname),
+        claim(&mapping.lastname),
+        username,
+        generate_opaque_token(),
+        email.to_string(),
+    )
+    .await?;
+    let mut user = db
+        .get_user_by_email(email)
+        .await?
+        .ok_or(CustomError::UserNotFound)?;
+    if email_verified {
+        db.mark_email_verified(&user.id.to_string()).await?;
+        user.email_verified = true;
+    }
+    tracing::info!(
+        "Provisioned user {} from identity provider {}",
+        user.id,
+        provider.provider_id
+    );
+    Ok(user)
+}
+
+/// Gets a provider that users can log in with.
+async fn enabled_provider(
+    db: &Database,
+    provider_id: &str,
+) -> Result<IdentityProvider, CustomError> {
+    db.get_identity_provider(provider_id)
+        .await?
+        .filter(|provider| !provider.disabled)
+        .ok_or(CustomError::IdentityProviderNotFound)
+}
+
+/// Loads the discovery document of a provider and checks that it belongs to the provider.
+async fn discover(
+    http: &reqwest::Client,
+    provider: &IdentityProvider,
+) -> Result<ProviderMetadata, CustomError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.trim_end_matches('/')
+    );
+    let metadata: ProviderMetadata = fetch_json(http, &url).await?;
+    if metadata.issuer != provider.issuer {
+        return Err(CustomError::IdentityProviderUnavailable(format!(
+            "The discovery document is for another issuer: {}",
+            metadata.issuer
+        )));
+    }
+    Ok(metadata)
+}
+
+/// Loads a JSON document from a provider.
+async fn fetch_json<T: serde::de::DeserializeOwned>(
+    http: &reqwest::Client,
+    url: &str,
+) -> Result<T, CustomError> {
+    http.get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(unavailable)?
+        .json()
+        .await
+        .map_err(unavailable)
+}
+
+/// Builds the HTTP client for requests to providers. Redirects aren't followed.
+fn http_client() -> Result<reqwest::Client, CustomError> {
+    reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(unavailable)
+}
+
+/// Encrypts a client secret for storage.
+fn encrypt_secret(client_secret: &str) -> Result<String, CustomError> {
+    let key_bytes: [u8; 32] = generate_key()?.into();
+    encrypt_with_random_nonce(&key_bytes, client_secret)
+}
+
+/// Reports a failed request to a provider.
+fn unavailable(error: reqwest::Error) -> CustomError {
+    tracing::error!("Error calling identity provider: {}", error);
+    CustomError::IdentityProviderUnavailable(error.to_string())
+}
+
+/// Creates the error for a rejected login.
+fn federation_error(message: &str) -> CustomError {
+    CustomError::FederationError(message.to_string())
+}
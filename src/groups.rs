@@ -0,0 +1,285 @@
+//! src/groups.rs
+//!
+//! This module manages groups of users. Groups can be nested inside other groups, and members
+//! inherit the roles of every group they belong to, directly or transitively.
+
+use crate::database::{Database, Group};
+use crate::errors::custom_errors::CustomError;
+
+use std::collections::{BTreeMap, BTreeSet};
+use surrealdb::sql::thing;
+
+/// Represents a group a user belongs to and how they got there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupPath {
+    /// The ID of the group.
+    pub group_id: String,
+    /// The IDs of the groups from the user's direct group up to and including this group.
+    pub path: Vec<String>,
+}
+
+/// Checks that a group name is well-formed.
+///
+/// # Arguments
+///
+/// * `name` - The group name to check.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the name is valid.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The name is empty, longer than 64 characters or has leading or trailing whitespace.
+fn validate_group_name(name: &str) -> Result<(), CustomError> {
+    if name.is_empty() || name.chars().count() > 64 || name.trim() != name {
+        return Err(CustomError::InvalidGroup(format!(
+            "Invalid group name: {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Creates a group.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `name` - The unique name of the group.
+/// * `description` - A human readable description of the group.
+///
+/// # Returns
+///
+/// A `Result` containing the new group.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The name is invalid or already taken.
+/// - Creating the group fails.
+pub async fn create_group(
+    db: &Database,
+    name: &str,
+    description: &str,
+) -> Result<Group, CustomError> {
+    validate_group_name(name)?;
+    if db.get_group_by_name(name).await?.is_some() {
+        return Err(CustomError::InvalidGroup(format!(
+            "Group already exists: {}",
+            name
+        )));
+    }
+
+    let group = db.create_group(name, description).await?;
+    tracing::info!("Created group {} ({})", group.name, group.id);
+    Ok(group)
+}
+
+/// Renames a group.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `group_id` - The ID of the group.
+/// * `name` - The new name of the group.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The group doesn't exist.
+/// - The name is invalid or taken by another group.
+/// - Updating the group fails.
+pub async fn rename_group(db: &Database, group_id: &str, name: &str) -> Result<(), CustomError> {
+    validate_group_name(name)?;
+    if let Some(existing) = db.get_group_by_name(name).await? {
+        if existing.id.to_string() != group_id {
+            return Err(CustomError::InvalidGroup(format!(
+                "Group already exists: {}",
+                name
+            )));
+        }
+    }
+
+    if !db.rename_group(group_id, name).await? {
+        return Err(CustomError::GroupNotFound);
+    }
+    tracing::info!("Renamed group {} to {}", group_id, name);
+    Ok(())
+}
+
+/// Adds a user or a group to a group.
+///
+/// Nesting a group is refused if it would create a cycle, i.e. if the group would end up
+/// being a member of itself.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `group_id` - The ID of the group.
+/// * `member_id` - The ID of the user or group to add.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The group doesn't exist.
+/// - The member isn't an existing user or group.
+/// - Nesting the group would create a cycle.
+/// - Storing the membership fails.
+pub async fn add_member(db: &Database, group_id: &str, member_id: &str) -> Result<(), CustomError> {
+    if db.get_group(group_id).await?.is_none() {
+        return Err(CustomError::GroupNotFound);
+    }
+
+    match thing(member_id).map(|record_id| record_id.tb) {
+        Ok(table) if table == "users" => {
+            if db.get_user_by_id(member_id).await?.is_none() {
+                return Err(CustomError::UserNotFound);
+            }
+        }
+        Ok(table) if table == "groups" => {
+            if db.get_group(member_id).await?.is_none() {
+                return Err(CustomError::GroupNotFound);
+            }
+            if is_nested_in(db, group_id, member_id).await? {
+                tracing::warn!(
+                    "Refused to nest group {} in {}, it would create a cycle",
+                    member_id,
+                    group_id
+                );
+                return Err(CustomError::InvalidGroup(
+                    "Nesting this group would create a cycle".to_string(),
+                ));
+            }
+        }
+        _ => {
+            return Err(CustomError::InvalidGroup(format!(
+                "Invalid member: {}",
+                member_id
+            )))
+        }
+    }
+
+    db.add_group_member(group_id, member_id).await?;
+    tracing::info!("Added {} to group {}", member_id, group_id);
+    Ok(())
+}
+
+/// Checks whether a group is the same as, or transitively nested in, another group.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `group_id` - The group that might be nested.
+/// * `ancestor_id` - The group that might contain it.
+///
+/// # Returns
+///
+/// A `Result` containing `true` if `group_id` is `ancestor_id` or one of its descendants.
+async fn is_nested_in(
+    db: &Database,
+    group_id: &str,
+    ancestor_id: &str,
+) -> Result<bool, CustomError> {
+    let mut visited = BTreeSet::from([ancestor_id.to_string()]);
+    let mut frontier = vec![ancestor_id.to_string()];
+    while !frontier.is_empty() {
+        if frontier.iter().any(|id| id == group_id) {
+            return Ok(true);
+        }
+        frontier = db
+            .get_group_members(&frontier)
+            .await?
+            .into_iter()
+            .map(|membership| membership.member_id)
+            .filter(|member_id| member_id.starts_with("groups:"))
+            .filter(|member_id| visited.insert(member_id.clone()))
+            .collect();
+    }
+    Ok(false)
+}
+
+/// Returns every group a user belongs to, directly or through nested groups.
+///
+/// The groups are walked breadth-first, so each group is reported with one of the shortest
+/// paths leading to it. Groups that were already visited are skipped, which also guards against
+/// cycles that might exist in the data.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the groups and how the user is a member of them.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the memberships fails.
+pub async fn user_groups(db: &Database, user_id: &str) -> Result<Vec<GroupPath>, CustomError> {
+    let mut paths: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut groups = Vec::new();
+    let mut frontier = vec![user_id.to_string()];
+    while !frontier.is_empty() {
+        let mut next = Vec::new();
+        for membership in db.get_parent_groups(&frontier).await? {
+            if paths.contains_key(&membership.group_id) {
+                continue;
+            }
+            let mut path = paths
+                .get(&membership.member_id)
+                .cloned()
+                .unwrap_or_default();
+            path.push(membership.group_id.clone());
+            paths.insert(membership.group_id.clone(), path.clone());
+            groups.push(GroupPath {
+                group_id: membership.group_id.clone(),
+                path,
+            });
+            next.push(membership.group_id);
+        }
+        frontier = next;
+    }
+    Ok(groups)
+}
+
+/// Assigns a role to a group, so that every member of the group inherits it.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `group_id` - The ID of the group.
+/// * `role` - The name of the role.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The group doesn't exist.
+/// - The role doesn't exist.
+/// - Storing the assignment fails.
+pub async fn assign_role(db: &Database, group_id: &str, role: &str) -> Result<(), CustomError> {
+    if db.get_group(group_id).await?.is_none() {
+        return Err(CustomError::GroupNotFound);
+    }
+    if db.get_role(role).await?.is_none() {
+        return Err(CustomError::InvalidRole(format!("Unknown role: {}", role)));
+    }
+    db.assign_group_role(group_id, role).await?;
+    tracing::info!("Assigned role {} to group {}", role, group_id);
+    Ok(())
+}
@@ -10,6 +10,7 @@ use argon2::{
     Argon2,
 };
 
+use sha2::{Digest, Sha256};
 use std::error::Error as StdError;
 
 /// Hashes the given string with a random salt using Argon2.
@@ -68,3 +69,19 @@ pub fn verify_password(unhashed: &str, password_hash: &str) -> Result<(), Argon2
         Err(_) => Err(Argon2Error::Password),
     }
 }
+
+/// Hashes a high-entropy token with SHA-256.
+///
+/// Opaque tokens such as refresh tokens are random and long enough that a salted, slow hash is
+/// not needed, and a deterministic hash lets them be looked up directly.
+///
+/// # Arguments
+///
+/// * `token` - The token to hash.
+///
+/// # Returns
+///
+/// The hex-encoded SHA-256 hash of the token.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,304 @@
+//! src/introspection.rs
+//!
+//! This module implements token introspection (RFC 7662) and token revocation (RFC 7009) for
+//! OAuth clients. Confidential clients such as API gateways ask whether an access or refresh
+//! token is active instead of validating JWTs themselves, which also takes revocations into
+//! account. Clients revoke the tokens they were issued, e.g. when the user signs out.
+
+use crate::database::{Database, OAuthClient};
+use crate::dpop::{Confirmation, DPOP};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::jwt::validate_issued_tenant_jwt;
+use crate::oauth::oauth_error;
+use crate::tokens::is_access_token_revoked;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+/// The hint for access tokens.
+const ACCESS_TOKEN_HINT: &str = "access_token";
+/// The hint for refresh tokens.
+const REFRESH_TOKEN_HINT: &str = "refresh_token";
+
+/// Represents a request to the introspection or revocation endpoint.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntrospectionRequest {
+    /// The token to introspect or revoke.
+    pub token: String,
+    /// Whether the token is an `access_token` or a `refresh_token`. Only used to look the
+    /// token up faster.
+    pub token_type_hint: Option<String>,
+    /// The ID of the client, unless it authenticates with HTTP Basic.
+    pub client_id: Option<String>,
+    /// The secret of the client, unless it authenticates with HTTP Basic.
+    pub client_secret: Option<String>,
+}
+
+/// Represents a request to the revocation endpoint, which has the same parameters.
+pub type RevocationRequest = IntrospectionRequest;
+
+/// Represents the response of the introspection endpoint (RFC 7662, section 2.2).
+///
+/// Inactive tokens are described by `active` alone, so that nothing about them is revealed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntrospectionResponse {
+    /// Whether the token is active.
+    pub active: bool,
+    /// The space-separated scopes of the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// The ID of the client the token was issued to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// The username of the user the token was issued for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// The type of the token: `access_token` or `refresh_token`, or `DPoP` for tokens bound to
+    /// a key (RFC 9449, section 6.2).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    /// The expiration timestamp of the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    /// The issued at timestamp of the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    /// The subject of the token, a user ID or the ID of a client acting on its own behalf.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /// The audience of the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// The issuer of the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// The unique ID of the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// The key the token is bound to, which the presenter has to prove possession of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+/// Introspects a token of the tenant.
+///
+/// Access tokens are active if they are validly signed, unexpired and not revoked. Refresh
+/// tokens are active if they are unexpired, unused and not revoked. Tokens issued to a disabled
+/// or deleted client are inactive.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client` - The authenticated client that asks.
+/// * `request` - The introspection request.
+///
+/// # Returns
+///
+/// A `Result` containing the introspection response.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The client is public.
+/// - Looking up the token fails.
+pub async fn introspect_token(
+    db: &Database,
+    client: &OAuthClient,
+    request: &IntrospectionRequest,
+) -> Result<IntrospectionResponse, CustomError> {
+    // Public clients can't keep a secret, so anyone could introspect tokens with their ID
+    if client.secret_hash.is_none() {
+        return Err(oauth_error(
+            "invalid_client",
+            "Public clients can't introspect tokens",
+        ));
+    }
+
+    let response = if request.token_type_hint.as_deref() == Some(REFRESH_TOKEN_HINT) {
+        match introspect_refresh_token(db, &request.token).await? {
+            Some(response) => Some(response),
+            None => introspect_access_token(db, &request.token).await?,
+        }
+    } else {
+        match introspect_access_token(db, &request.token).await? {
+            Some(response) => Some(response),
+            None => introspect_refresh_token(db, &request.token).await?,
+        }
+    };
+    tracing::info!(
+        "Client {} introspected an {} token",
+        client.client_id,
+        if response.is_some() {
+            "active"
+        } else {
+            "inactive"
+        }
+    );
+    Ok(response.unwrap_or_default())
+}
+
+/// Describes an access token if it is active.
+async fn introspect_access_token(
+    db: &Database,
+    token: &str,
+) -> Result<Option<IntrospectionResponse>, CustomError> {
+    let claims = match validate_issued_tenant_jwt(db.tenant(), token) {
+        Ok(claims) => claims,
+        Err(_) => return Ok(None),
+    };
+    if is_access_token_revoked(db, &claims).await? {
+        return Ok(None);
+    }
+
+    Ok(Some(IntrospectionResponse {
+        active: true,
+        scope: claims.scope,
+        client_id: claims.client_id,
+        username: claims.username,
+        token_type: Some(token_type(ACCESS_TOKEN_HINT, claims.cnf.as_ref())),
+        exp: Some(claims.exp as i64),
+        iat: Some(claims.iat as i64),
+        sub: Some(claims.sub),
+        aud: claims.aud,
+        iss: claims.iss,
+        jti: Some(claims.jti),
+        cnf: claims.cnf,
+    }))
+}
+
+/// Describes a refresh token if it is active.
+async fn introspect_refresh_token(
+    db: &Database,
+    token: &str,
+) -> Result<Option<IntrospectionResponse>, CustomError> {
+    let stored = match db.find_refresh_token(&hash_token(token)).await? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
+    if stored.used || stored.revoked || stored.expires_at <= Utc::now().timestamp() {
+        return Ok(None);
+    }
+    if let Some(client_id) = &stored.client_id {
+        let client = db.get_oauth_client(client_id).await?;
+        if client.is_none_or(|client| client.disabled) {
+            return Ok(None);
+        }
+    }
+
+    let username = db
+        .get_user_by_id(&stored.user_id)
+        .await?
+        .map(|user| user.username);
+    let cnf = stored.jkt.map(|jkt| Confirmation { jkt });
+    Ok(Some(IntrospectionResponse {
+        active: true,
+        scope: stored.scope,
+        client_id: stored.client_id,
+        username,
+        token_type: Some(token_type(REFRESH_TOKEN_HINT, cnf.as_ref())),
+        exp: Some(stored.expires_at),
+        sub: Some(stored.user_id),
+        cnf,
+        ..IntrospectionResponse::default()
+    }))
+}
+
+/// Returns the reported type of a token, which is `DPoP` if it is bound to a key.
+fn token_type(kind: &str, cnf: Option<&Confirmation>) -> String {
+    match cnf {
+        Some(_) => DPOP.to_string(),
+        None => kind.to_string(),
+    }
+}
+
+/// Revokes a token that was issued to the client.
+///
+/// Revoking a refresh token revokes its whole token family. Revoking an access token adds it to
+/// the revocation store. Unknown and invalid tokens are ignored, since the client's goal is
+/// reached anyway (RFC 7009, section 2.2).
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client` - The authenticated client.
+/// * `request` - The revocation request.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The token was issued to another client.
+/// - Revoking the token fails.
+pub async fn revoke_token(
+    db: &Database,
+    client: &OAuthClient,
+    request: &RevocationRequest,
+) -> Result<(), CustomError> {
+    // Access tokens are recognized by their signature without a lookup, so the hint isn't needed
+    let revoked = revoke_access_token(db, client, &request.token).await?
+        || revoke_refresh_token(db, client, &request.token).await?;
+    if !revoked {
+        tracing::warn!(
+            "Client {} tried to revoke an unknown token",
+            client.client_id
+        );
+    }
+    Ok(())
+}
+
+/// Revokes the family of a refresh token issued to the client.
+///
+/// Returns `false` if the token isn't a known refresh token.
+async fn revoke_refresh_token(
+    db: &Database,
+    client: &OAuthClient,
+    token: &str,
+) -> Result<bool, CustomError> {
+    let stored = match db.find_refresh_token(&hash_token(token)).await? {
+        Some(stored) => stored,
+        None => return Ok(false),
+    };
+    if stored.client_id.as_deref() != Some(client.client_id.as_str()) {
+        return Err(foreign_token_error(client));
+    }
+    db.revoke_refresh_token_family(&stored.family_id).await?;
+    tracing::info!("Client {} revoked a refresh token", client.client_id);
+    Ok(true)
+}
+
+/// Revokes an access token issued to the client.
+///
+/// Returns `false` if the token isn't a valid access token of the tenant.
+async fn revoke_access_token(
+    db: &Database,
+    client: &OAuthClient,
+    token: &str,
+) -> Result<bool, CustomError> {
+    let claims = match validate_issued_tenant_jwt(db.tenant(), token) {
+        Ok(claims) => claims,
+        Err(_) => return Ok(false),
+    };
+    if claims.client_id.as_deref() != Some(client.client_id.as_str()) {
+        return Err(foreign_token_error(client));
+    }
+    db.revoke_access_token(&claims.jti, claims.exp as i64)
+        .await?;
+    tracing::info!("Client {} revoked an access token", client.client_id);
+    Ok(true)
+}
+
+/// Creates the error for a client that tries to revoke a token of another client.
+fn foreign_token_error(client: &OAuthClient) -> CustomError {
+    tracing::warn!(
+        "Client {} tried to revoke a token of another client",
+        client.client_id
+    );
+    oauth_error(
+        "unauthorized_client",
+        "The token was issued to another client",
+    )
+}
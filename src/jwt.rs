@@ -2,31 +2,377 @@
 //!
 //! This module provides JWT (JSON Web Token) generation and validation functionalities.
 
+use crate::dpop::Confirmation;
+use crate::errors::custom_errors::CustomError;
+use crate::keyring::keyring;
+use crate::tenants::DEFAULT_TENANT;
+
+use base64::{engine::general_purpose, Engine as base64Engine};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, errors::Error, DecodingKey, EncodingKey, Header, Validation};
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{
+    decode, encode,
+    errors::{Error, ErrorKind},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
+use std::str::FromStr;
+use uuid::Uuid;
 
 /// Represents the claims stored within a JWT.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     /// The subject of the JWT (typically the user ID).
     pub sub: String,
     /// The expiration timestamp of the JWT.
-    exp: usize,
+    pub exp: usize,
     /// The issued at timestamp of the JWT.
-    iat: usize,
+    pub iat: usize,
+    /// The unique ID of the JWT, used for revocation.
+    pub jti: String,
+    /// The token generation of the subject at the time the JWT was issued.
+    #[serde(default)]
+    pub gen: i64,
+    /// The issuer of the JWT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// The audience the JWT is intended for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// The username of the subject.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// The roles of the subject.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// The tenant the subject belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Whether the subject has verified their email address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    /// The methods used to authenticate the subject, e.g. `pwd` or `otp` (RFC 8176).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<String>,
+    /// The ID of the OAuth client the JWT was issued to (RFC 9068).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// The space-separated scopes granted to the OAuth client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// The client acting on behalf of the subject, for tokens from a token exchange (RFC 8693).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    /// The key the JWT is bound to, for DPoP-bound tokens (RFC 9449).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+    /// Any additional custom claims.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+/// Represents the party acting on behalf of the subject of a JWT (RFC 8693, section 4.1).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Actor {
+    /// The ID of the acting client.
+    pub sub: String,
+    /// The previous actor, if the token was exchanged more than once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Box<Actor>>,
+}
+
+impl Claims {
+    /// Creates the claims for a new access token.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The ID of the user the token is issued to.
+    /// * `generation` - The current token generation of the user.
+    pub fn new(user_id: String, generation: i64) -> Self {
+        let now = Utc::now();
+        let expiration = now
+            .checked_add_signed(Duration::seconds(access_token_lifetime()))
+            .expect("valid timestamp")
+            .timestamp();
+
+        Claims {
+            sub: user_id,
+            exp: expiration as usize,
+            iat: now.timestamp() as usize,
+            jti: Uuid::new_v4().to_string(),
+            gen: generation,
+            iss: configured_issuer(),
+            aud: configured_audience(),
+            username: None,
+            roles: Vec::new(),
+            tenant: None,
+            email_verified: None,
+            amr: Vec::new(),
+            client_id: None,
+            scope: None,
+            act: None,
+            cnf: None,
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+const ISSUER_ENV: &str = "JWT_ISSUER";
+const AUDIENCE_ENV: &str = "JWT_AUDIENCE";
+const ACCESS_TOKEN_LIFETIME_ENV: &str = "ACCESS_TOKEN_LIFETIME_SECONDS";
+const DEFAULT_ACCESS_TOKEN_LIFETIME_SECONDS: i64 = 15 * 60;
+
+/// Represents a key used to sign and verify JWTs.
+#[derive(Clone)]
+pub struct SigningKey {
+    /// The key ID, sent as `kid` in the JWT header.
+    pub kid: String,
+    /// The signing algorithm.
+    pub algorithm: Algorithm,
+    /// The key used to sign tokens.
+    encoding_key: EncodingKey,
+    /// The key used to verify tokens.
+    decoding_key: DecodingKey,
+    /// The public key as a JWK. `None` for symmetric keys, which must never be published.
+    public_jwk: Option<Jwk>,
 }
 
-const SECRET_KEY_ENV: &str = "JWT_SECRET";
+impl SigningKey {
+    /// Creates an HS256 signing key from a shared secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The key ID.
+    /// * `secret` - The shared secret.
+    pub fn from_secret(kid: &str, secret: &[u8]) -> Self {
+        SigningKey {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            public_jwk: None,
+        }
+    }
+
+    /// Creates an asymmetric signing key from a PEM-encoded key pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The key ID.
+    /// * `algorithm` - The signing algorithm. Must be `RS256`, `ES256` or `EdDSA`.
+    /// * `private_pem` - The PKCS#8 PEM-encoded private key.
+    /// * `public_pem` - The PEM-encoded public key.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signing key or a `CustomError` if a key can't be parsed.
+    pub fn from_pem(
+        kid: &str,
+        algorithm: Algorithm,
+        private_pem: &[u8],
+        public_pem: &[u8],
+    ) -> Result<Self, CustomError> {
+        let invalid = |error: &dyn std::fmt::Display| {
+            tracing::error!("Invalid {:?} key {}: {}", algorithm, kid, error);
+            CustomError::InvalidSigningKey(format!("{}: {}", kid, error))
+        };
+        let public_pem_str = std::str::from_utf8(public_pem).map_err(|e| invalid(&e))?;
+
+        let (encoding_key, decoding_key, parameters) = match algorithm {
+            Algorithm::RS256 => {
+                let public_key =
+                    RsaPublicKey::from_public_key_pem(public_pem_str).map_err(|e| invalid(&e))?;
+                (
+                    EncodingKey::from_rsa_pem(private_pem).map_err(|e| invalid(&e))?,
+                    DecodingKey::from_rsa_pem(public_pem).map_err(|e| invalid(&e))?,
+                    AlgorithmParameters::RSA(RSAKeyParameters {
+                        key_type: RSAKeyType::RSA,
+                        n: base64_url(&public_key.n().to_bytes_be()),
+                        e: base64_url(&public_key.e().to_bytes_be()),
+                    }),
+                )
+            }
+            Algorithm::ES256 => {
+                let public_key = p256::PublicKey::from_public_key_pem(public_pem_str)
+                    .map_err(|e| invalid(&e))?;
+                let point = public_key.to_encoded_point(false);
+                let (x, y) = match (point.x(), point.y()) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => return Err(invalid(&"missing curve point coordinates")),
+                };
+                (
+                    EncodingKey::from_ec_pem(private_pem).map_err(|e| invalid(&e))?,
+                    DecodingKey::from_ec_pem(public_pem).map_err(|e| invalid(&e))?,
+                    AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                        key_type: EllipticCurveKeyType::EC,
+                        curve: EllipticCurve::P256,
+                        x: base64_url(x),
+                        y: base64_url(y),
+                    }),
+                )
+            }
+            Algorithm::EdDSA => {
+                let public_key = ed25519_dalek::VerifyingKey::from_public_key_pem(public_pem_str)
+                    .map_err(|e| invalid(&e))?;
+                (
+                    EncodingKey::from_ed_pem(private_pem).map_err(|e| invalid(&e))?,
+                    DecodingKey::from_ed_pem(public_pem).map_err(|e| invalid(&e))?,
+                    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x: base64_url(public_key.as_bytes()),
+                    }),
+                )
+            }
+            other => return Err(invalid(&format!("unsupported algorithm {:?}", other))),
+        };
+
+        let public_jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: KeyAlgorithm::from_str(&format!("{:?}", algorithm)).ok(),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm: parameters,
+        };
+
+        Ok(SigningKey {
+            kid: kid.to_string(),
+            algorithm,
+            encoding_key,
+            decoding_key,
+            public_jwk: Some(public_jwk),
+        })
+    }
+
+    /// Returns the public key as a JWK, or `None` for symmetric keys.
+    pub fn public_jwk(&self) -> Option<&Jwk> {
+        self.public_jwk.as_ref()
+    }
+
+    /// Signs the given claims with this key.
+    ///
+    /// # Arguments
+    ///
+    /// * `claims` - The claims to sign.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signed JWT or an error if signing fails.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &self.encoding_key)
+    }
 
-/// Retrieves the secret key used for JWT signing and validation from the environment.
+    /// Verifies the given JWT with this key, enforcing the configured issuer and audience.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The JWT to verify.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the claims if the JWT is valid or an error if validation fails.
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        self.verify_with(
+            token,
+            configured_issuer().as_deref(),
+            configured_audience().as_deref(),
+        )
+    }
+
+    /// Verifies the given JWT with this key.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The JWT to verify.
+    /// * `issuer` - The required issuer, or `None` to accept any issuer.
+    /// * `audience` - The required audience, or `None` to accept any audience.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the claims if the JWT is valid or an error if validation fails.
+    pub fn verify_with(
+        &self,
+        token: &str,
+        issuer: Option<&str>,
+        audience: Option<&str>,
+    ) -> Result<Claims, Error> {
+        let mut validation = Validation::new(self.algorithm);
+        let mut required_claims = vec!["exp"];
+        if let Some(issuer) = issuer {
+            validation.set_issuer(&[issuer]);
+            required_claims.push("iss");
+        }
+        match audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required_claims.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required_claims);
+        let token_data = decode::<Claims>(token, &self.decoding_key, &validation)?;
+        Ok(token_data.claims)
+    }
+}
+
+/// Encodes bytes as unpadded URL-safe base64, as used in JWKs.
+fn base64_url(bytes: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Returns the public signing keys of a tenant as a JWK set.
+///
+/// The set is empty when tokens are signed with a shared secret.
+///
+/// # Arguments
 ///
-/// # Panics
+/// * `tenant` - The ID of the tenant.
 ///
-/// This function panics if the `JWT_SECRET` environment variable is not set.
-fn get_secret_key() -> String {
-    env::var(SECRET_KEY_ENV).expect("JWT_SECRET not found in environment")
+/// # Returns
+///
+/// A `Result` containing the JWK set or an error if the keyring can't be loaded.
+pub fn public_jwks(tenant: &str) -> Result<JwkSet, Error> {
+    Ok(keyring(tenant)?.public_jwks())
+}
+
+/// Returns the issuer configured in `JWT_ISSUER`, if any.
+pub fn configured_issuer() -> Option<String> {
+    env::var(ISSUER_ENV)
+        .ok()
+        .filter(|issuer| !issuer.is_empty())
+}
+
+/// Returns the audience configured in `JWT_AUDIENCE`, if any.
+pub fn configured_audience() -> Option<String> {
+    env::var(AUDIENCE_ENV)
+        .ok()
+        .filter(|audience| !audience.is_empty())
+}
+
+/// Returns the lifetime of access tokens in seconds.
+///
+/// The lifetime is read from the `ACCESS_TOKEN_LIFETIME_SECONDS` environment variable and
+/// defaults to 15 minutes if it is missing or invalid.
+pub fn access_token_lifetime() -> i64 {
+    env::var(ACCESS_TOKEN_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_ACCESS_TOKEN_LIFETIME_SECONDS)
 }
 
 /// Generates a new JWT for the given user ID.
@@ -39,24 +385,23 @@ fn get_secret_key() -> String {
 ///
 /// A `Result` containing the generated JWT or an error if generation fails.
 pub fn generate_jwt(user_id: String) -> Result<String, Error> {
-    let secret_key = get_secret_key();
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::days(1))
-        .expect("valid timestamp")
-        .timestamp();
-
-    let claims = Claims {
-        sub: user_id,
-        exp: expiration as usize,
-        iat: Utc::now().timestamp() as usize,
-    };
+    encode_jwt(&Claims::new(user_id, 0))
+}
 
-    let header = Header::default();
-    let encoding_key = EncodingKey::from_secret(secret_key.as_bytes());
-    encode(&header, &claims, &encoding_key)
+/// Signs the given claims with the keyring of the tenant named in the claims.
+///
+/// # Arguments
+///
+/// * `claims` - The claims to sign.
+///
+/// # Returns
+///
+/// A `Result` containing the signed JWT or an error if signing fails.
+pub fn encode_jwt(claims: &Claims) -> Result<String, Error> {
+    keyring(claims.tenant.as_deref().unwrap_or(DEFAULT_TENANT))?.sign(claims)
 }
 
-/// Validates the given JWT.
+/// Validates the given JWT of the default tenant.
 ///
 /// # Arguments
 ///
@@ -66,13 +411,49 @@ pub fn generate_jwt(user_id: String) -> Result<String, Error> {
 ///
 /// A `Result` containing the claims if the JWT is valid or an error if validation fails.
 pub fn validate_jwt(token: &str) -> Result<Claims, Error> {
-    let secret_key = get_secret_key();
-    let decoding_key = DecodingKey::from_secret(secret_key.as_bytes());
+    validate_tenant_jwt(DEFAULT_TENANT, token)
+}
 
-    let validation = Validation::default();
-    let token_data = decode::<Claims>(token, &decoding_key, &validation)?;
+/// Validates the given JWT of a tenant.
+///
+/// The token must be signed with a key of the tenant and name the tenant in its `tenant`
+/// claim. Tokens without the claim belong to the default tenant.
+///
+/// # Arguments
+///
+/// * `tenant` - The ID of the tenant.
+/// * `token` - The JWT to validate.
+///
+/// # Returns
+///
+/// A `Result` containing the claims if the JWT is valid or an error if validation fails.
+pub fn validate_tenant_jwt(tenant: &str, token: &str) -> Result<Claims, Error> {
+    let claims = keyring(tenant)?.verify(token)?;
+    if claims.tenant.as_deref().unwrap_or(DEFAULT_TENANT) != tenant {
+        return Err(Error::from(ErrorKind::InvalidToken));
+    }
+    Ok(claims)
+}
 
-    Ok(token_data.claims)
+/// Validates the given JWT of a tenant whatever its audience.
+///
+/// Unlike `validate_tenant_jwt`, this doesn't enforce `JWT_AUDIENCE`, so that introspection and
+/// revocation also recognize tokens exchanged for downstream services.
+///
+/// # Arguments
+///
+/// * `tenant` - The ID of the tenant.
+/// * `token` - The JWT to validate.
+///
+/// # Returns
+///
+/// A `Result` containing the claims if the JWT is valid or an error if validation fails.
+pub fn validate_issued_tenant_jwt(tenant: &str, token: &str) -> Result<Claims, Error> {
+    let claims = keyring(tenant)?.verify_with(token, configured_issuer().as_deref(), None)?;
+    if claims.tenant.as_deref().unwrap_or(DEFAULT_TENANT) != tenant {
+        return Err(Error::from(ErrorKind::InvalidToken));
+    }
+    Ok(claims)
 }
 
 /// Extracts the user ID from the given JWT.
@@ -0,0 +1,652 @@
+//! src/keyring.rs
+//!
+//! This module manages the keyring of JWT signing keys and rotates them.
+//!
+//! Every key has an ID and a state. New tokens are signed with the active key, while
+//! verify-only keys are still accepted so that outstanding tokens stay valid after a rotation.
+//! Retired keys are neither used nor published.
+//!
+//! Every tenant has its own keyring. The default tenant uses the key configured in the
+//! environment, while other tenants get generated keys that are never shared.
+
+use crate::database::{Database, StoredSigningKey};
+use crate::encryption::{decrypt_with_nonce, encrypt_with_random_nonce, generate_key};
+use crate::errors::custom_errors::CustomError;
+use crate::jwt::{configured_audience, configured_issuer, Claims, SigningKey};
+use crate::tenants::DEFAULT_TENANT;
+
+use base64::{engine::general_purpose, Engine as base64Engine};
+use chrono::Utc;
+use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey, EncodePublicKey};
+use jsonwebtoken::errors::{Error, ErrorKind};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode_header, Algorithm};
+use rand::{rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::{env, fs};
+use uuid::Uuid;
+
+const SECRET_KEY_ENV: &str = "JWT_SECRET";
+const ALGORITHM_ENV: &str = "JWT_ALGORITHM";
+const PRIVATE_KEY_PATH_ENV: &str = "JWT_PRIVATE_KEY_PATH";
+const PUBLIC_KEY_PATH_ENV: &str = "JWT_PUBLIC_KEY_PATH";
+const KEY_ID_ENV: &str = "JWT_KEY_ID";
+const DEFAULT_KEY_ID: &str = "default";
+const RSA_KEY_BITS: usize = 2048;
+
+/// The keyrings used by the `jwt` module by tenant ID. The keyring of the default tenant is
+/// loaded from the environment on first use.
+static KEYRINGS: OnceLock<RwLock<BTreeMap<String, Arc<Keyring>>>> = OnceLock::new();
+
+/// Represents the state of a signing key.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyState {
+    /// The key signs new tokens and verifies existing ones.
+    Active,
+    /// The key only verifies existing tokens.
+    VerifyOnly,
+    /// The key is no longer used.
+    Retired,
+}
+
+impl KeyState {
+    /// Returns the name of the state as stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyState::Active => "active",
+            KeyState::VerifyOnly => "verify_only",
+            KeyState::Retired => "retired",
+        }
+    }
+}
+
+impl FromStr for KeyState {
+    type Err = CustomError;
+
+    fn from_str(state: &str) -> Result<Self, Self::Err> {
+        match state {
+            "active" => Ok(KeyState::Active),
+            "verify_only" => Ok(KeyState::VerifyOnly),
+            "retired" => Ok(KeyState::Retired),
+            other => Err(CustomError::KeyringError(format!(
+                "Unknown key state: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Represents the raw material of a signing key.
+pub struct KeyMaterial {
+    /// The key ID.
+    pub kid: String,
+    /// The signing algorithm.
+    pub algorithm: Algorithm,
+    /// The PEM-encoded private key, or the shared secret for `HS256`.
+    pub private_key: Vec<u8>,
+    /// The PEM-encoded public key. Empty for `HS256`.
+    pub public_key: Vec<u8>,
+}
+
+impl KeyMaterial {
+    /// Builds the signing key for this material.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signing key or a `CustomError` if the key can't be parsed.
+    pub fn signing_key(&self) -> Result<SigningKey, CustomError> {
+        if self.algorithm == Algorithm::HS256 {
+            return Ok(SigningKey::from_secret(&self.kid, &self.private_key));
+        }
+        SigningKey::from_pem(
+            &self.kid,
+            self.algorithm,
+            &self.private_key,
+            &self.public_key,
+        )
+    }
+}
+
+/// Represents a key in the keyring together with its state.
+pub struct KeyringEntry {
+    /// The signing key.
+    pub key: SigningKey,
+    /// The state of the key.
+    pub state: KeyState,
+}
+
+/// Represents the set of keys used to sign and verify JWTs.
+#[derive(Default)]
+pub struct Keyring {
+    entries: Vec<KeyringEntry>,
+}
+
+impl Keyring {
+    /// Creates a keyring from the given entries.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The keys and their states, oldest first.
+    pub fn new(entries: Vec<KeyringEntry>) -> Self {
+        Keyring { entries }
+    }
+
+    /// Loads the keyring from the database.
+    ///
+    /// If the key configured in the environment isn't in the database yet, it is imported as
+    /// the new active key and the previously active keys become verify-only. Changing the
+    /// configured key together with `JWT_KEY_ID` therefore rotates keys without invalidating
+    /// outstanding tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `db` - The database connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the keyring.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The configured key can't be loaded, or a key for a new tenant can't be generated.
+    /// - A stored key can't be decrypted or parsed.
+    /// - A database operation fails.
+    pub async fn load(db: &Database) -> Result<Self, CustomError> {
+        let stored = db.get_signing_keys().await?;
+        if db.tenant() == DEFAULT_TENANT {
+            let configured = load_key_material()?;
+            if !stored.iter().any(|key| key.kid == configured.kid) {
+                tracing::info!("Importing configured signing key: {}", configured.kid);
+                store_active_key(db, &stored, &configured).await?;
+            }
+        } else if stored.is_empty() {
+            // Tenants never share the configured key, they get a key of their own.
+            let material = generate_key_material(configured_algorithm()?)?;
+            tracing::info!(
+                "Generated signing key {} for tenant {}",
+                material.kid,
+                db.tenant()
+            );
+            store_active_key(db, &stored, &material).await?;
+        }
+
+        let mut entries = Vec::new();
+        for stored_key in db.get_signing_keys().await? {
+            let state = KeyState::from_str(&stored_key.state)?;
+            let key = decrypt_key_material(&stored_key)?.signing_key()?;
+            entries.push(KeyringEntry { key, state });
+        }
+        Ok(Keyring::new(entries))
+    }
+
+    /// Returns the keys in the keyring, oldest first.
+    pub fn entries(&self) -> &[KeyringEntry] {
+        &self.entries
+    }
+
+    /// Returns the active key, i.e. the key used to sign new tokens.
+    pub fn active_key(&self) -> Option<&SigningKey> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.state == KeyState::Active)
+            .map(|entry| &entry.key)
+    }
+
+    /// Returns the key that may verify a token with the given key ID.
+    ///
+    /// Tokens without a key ID were issued before the keyring existed and are verified with the
+    /// active key. Retired keys never verify tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The key ID from the token header.
+    pub fn verification_key(&self, kid: Option<&str>) -> Option<&SigningKey> {
+        match kid {
+            Some(kid) => self
+                .entries
+                .iter()
+                .find(|entry| entry.key.kid == kid && entry.state != KeyState::Retired)
+                .map(|entry| &entry.key),
+            None => self.active_key(),
+        }
+    }
+
+    /// Signs the given claims with the active key.
+    ///
+    /// # Arguments
+    ///
+    /// * `claims` - The claims to sign.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the signed JWT or an error if there is no active key.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+        match self.active_key() {
+            Some(key) => key.sign(claims),
+            None => {
+                tracing::error!("No active signing key");
+                Err(Error::from(ErrorKind::InvalidKeyFormat))
+            }
+        }
+    }
+
+    /// Verifies the given JWT with the key named by its `kid` header, enforcing the configured
+    /// issuer and audience.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The JWT to verify.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the claims if the JWT is valid or an error if validation fails.
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        self.verify_with(
+            token,
+            configured_issuer().as_deref(),
+            configured_audience().as_deref(),
+        )
+    }
+
+    /// Verifies the given JWT with the key named by its `kid` header.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The JWT to verify.
+    /// * `issuer` - The required issuer, or `None` to accept any issuer.
+    /// * `audience` - The required audience, or `None` to accept any audience.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the claims if the JWT is valid or an error if validation fails.
+    pub fn verify_with(
+        &self,
+        token: &str,
+        issuer: Option<&str>,
+        audience: Option<&str>,
+    ) -> Result<Claims, Error> {
+        let header = decode_header(token)?;
+        match self.verification_key(header.kid.as_deref()) {
+            Some(key) => key.verify_with(token, issuer, audience),
+            None => Err(Error::from(ErrorKind::InvalidSignature)),
+        }
+    }
+
+    /// Returns the public keys of all active and verify-only keys as a JWK set.
+    pub fn public_jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .entries
+                .iter()
+                .filter(|entry| entry.state != KeyState::Retired)
+                .filter_map(|entry| entry.key.public_jwk().cloned())
+                .collect(),
+        }
+    }
+}
+
+/// Returns the keyring of a tenant.
+///
+/// The keyring of the default tenant is loaded from the environment on first use. The keyrings
+/// of other tenants are installed when their database is opened.
+///
+/// # Arguments
+///
+/// * `tenant` - The ID of the tenant.
+///
+/// # Returns
+///
+/// A `Result` containing the keyring or an error if no key can be loaded.
+pub fn keyring(tenant: &str) -> Result<Arc<Keyring>, Error> {
+    let keyrings = KEYRINGS.get_or_init(Default::default);
+    if let Some(keyring) = keyrings
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(tenant)
+    {
+        return Ok(Arc::clone(keyring));
+    }
+    if tenant != DEFAULT_TENANT {
+        tracing::error!("No keyring installed for tenant: {}", tenant);
+        return Err(Error::from(ErrorKind::InvalidKeyFormat));
+    }
+
+    let key = load_key_material()
+        .and_then(|material| material.signing_key())
+        .map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?;
+    let keyring = Arc::new(Keyring::new(vec![KeyringEntry {
+        key,
+        state: KeyState::Active,
+    }]));
+    Ok(Arc::clone(
+        keyrings
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(tenant.to_string())
+            .or_insert(keyring),
+    ))
+}
+
+/// Replaces the keyring used to sign and verify the tokens of a tenant.
+///
+/// # Arguments
+///
+/// * `tenant` - The ID of the tenant.
+/// * `new_keyring` - The new keyring.
+pub fn install_keyring(tenant: &str, new_keyring: Keyring) {
+    KEYRINGS
+        .get_or_init(Default::default)
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(tenant.to_string(), Arc::new(new_keyring));
+}
+
+/// Removes the keyring of a tenant, so that its tokens are no longer accepted.
+///
+/// # Arguments
+///
+/// * `tenant` - The ID of the tenant.
+pub fn remove_keyring(tenant: &str) {
+    if let Some(keyrings) = KEYRINGS.get() {
+        keyrings
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(tenant);
+    }
+}
+
+/// Loads the keyring of the database's tenant and installs it.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if the keyring can't be loaded.
+pub async fn reload_keyring(db: &Database) -> Result<(), CustomError> {
+    install_keyring(db.tenant(), Keyring::load(db).await?);
+    Ok(())
+}
+
+/// Generates a new active signing key and stores it in the database.
+///
+/// The previously active keys become verify-only, so tokens signed with them stay valid until
+/// they expire.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `algorithm` - The algorithm of the new key.
+///
+/// # Returns
+///
+/// A `Result` containing the ID of the new key.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The algorithm isn't supported.
+/// - Generating, encrypting or storing the key fails.
+pub async fn rotate_signing_key(
+    db: &Database,
+    algorithm: Algorithm,
+) -> Result<String, CustomError> {
+    let material = generate_key_material(algorithm)?;
+    // Make sure the generated key is usable before it becomes active.
+    material.signing_key()?;
+
+    let stored = db.get_signing_keys().await?;
+    store_active_key(db, &stored, &material).await?;
+    tracing::info!("Rotated signing key, new active key: {}", material.kid);
+    Ok(material.kid)
+}
+
+/// Changes the state of a signing key.
+///
+/// Activating a key makes the previously active keys verify-only. The active key can't be
+/// deactivated directly, since new tokens always need a key to be signed with; rotate or
+/// activate another key instead.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `kid` - The key ID.
+/// * `state` - The new state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The key doesn't exist.
+/// - The key is the active key and would be deactivated.
+/// - A database operation fails.
+pub async fn set_key_state(db: &Database, kid: &str, state: KeyState) -> Result<(), CustomError> {
+    let stored = db.get_signing_keys().await?;
+    let key = match stored.iter().find(|key| key.kid == kid) {
+        Some(key) => key,
+        None => return Err(CustomError::KeyringError(format!("Unknown key: {}", kid))),
+    };
+
+    if key.state == KeyState::Active.as_str() && state != KeyState::Active {
+        return Err(CustomError::KeyringError(
+            "The active key can't be deactivated".to_string(),
+        ));
+    }
+
+    if state == KeyState::Active {
+        demote_active_keys(db, &stored).await?;
+    }
+    db.update_signing_key_state(kid, state.as_str()).await?;
+    tracing::info!("Signing key {} is now {}", kid, state.as_str());
+    Ok(())
+}
+
+/// Stores the given key as the active key, demoting the currently active keys.
+async fn store_active_key(
+    db: &Database,
+    stored: &[StoredSigningKey],
+    material: &KeyMaterial,
+) -> Result<(), CustomError> {
+    let stored_key = encrypt_key_material(material)?;
+    demote_active_keys(db, stored).await?;
+    db.store_signing_key(&stored_key).await
+}
+
+/// Makes all active keys verify-only.
+async fn demote_active_keys(db: &Database, stored: &[StoredSigningKey]) -> Result<(), CustomError> {
+    for key in stored
+        .iter()
+        .filter(|key| key.state == KeyState::Active.as_str())
+    {
+        db.update_signing_key_state(&key.kid, KeyState::VerifyOnly.as_str())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Encrypts key material for storage.
+fn encrypt_key_material(material: &KeyMaterial) -> Result<StoredSigningKey, CustomError> {
+    let key_bytes: [u8; 32] = generate_key()?.into();
+    let encoded_private_key = general_purpose::STANDARD.encode(&material.private_key);
+    Ok(StoredSigningKey {
+        kid: material.kid.clone(),
+        algorithm: format!("{:?}", material.algorithm),
+        state: KeyState::Active.as_str().to_string(),
+        encrypted_private_key: encrypt_with_random_nonce(&key_bytes, &encoded_private_key)?,
+        public_key: String::from_utf8_lossy(&material.public_key).into_owned(),
+        created_at: Utc::now().timestamp(),
+    })
+}
+
+/// Decrypts stored key material.
+fn decrypt_key_material(stored: &StoredSigningKey) -> Result<KeyMaterial, CustomError> {
+    let key_bytes: [u8; 32] = generate_key()?.into();
+    let encoded_private_key = decrypt_with_nonce(&key_bytes, &stored.encrypted_private_key)?;
+    let private_key = general_purpose::STANDARD
+        .decode(encoded_private_key)
+        .map_err(|_| CustomError::DecryptionError)?;
+    let algorithm = Algorithm::from_str(&stored.algorithm)
+        .map_err(|e| CustomError::KeyringError(format!("{}: {}", stored.kid, e)))?;
+    Ok(KeyMaterial {
+        kid: stored.kid.clone(),
+        algorithm,
+        private_key,
+        public_key: stored.public_key.as_bytes().to_vec(),
+    })
+}
+
+/// Returns the signing algorithm configured in `JWT_ALGORITHM`, defaulting to `HS256`.
+///
+/// # Returns
+///
+/// A `Result` containing the algorithm or a `CustomError` if it is invalid.
+pub fn configured_algorithm() -> Result<Algorithm, CustomError> {
+    match env::var(ALGORITHM_ENV) {
+        Ok(algorithm) => Algorithm::from_str(&algorithm).map_err(|e| {
+            tracing::error!("Invalid JWT_ALGORITHM {}: {}", algorithm, e);
+            CustomError::EnvironmentVariableError(format!("Invalid JWT_ALGORITHM: {}", algorithm))
+        }),
+        Err(_) => Ok(Algorithm::HS256),
+    }
+}
+
+/// Loads the signing key configured in the environment.
+///
+/// `JWT_ALGORITHM` selects the algorithm and defaults to `HS256`, which signs with
+/// `JWT_SECRET`. For `RS256`, `ES256` and `EdDSA` the key pair is read from the PEM files at
+/// `JWT_PRIVATE_KEY_PATH` and `JWT_PUBLIC_KEY_PATH`. `JWT_KEY_ID` sets the `kid`.
+///
+/// # Returns
+///
+/// A `Result` containing the key material or a `CustomError` if it can't be loaded.
+pub fn load_key_material() -> Result<KeyMaterial, CustomError> {
+    let kid = env::var(KEY_ID_ENV).unwrap_or_else(|_| DEFAULT_KEY_ID.to_string());
+    let algorithm = configured_algorithm()?;
+
+    if algorithm == Algorithm::HS256 {
+        let secret = env::var(SECRET_KEY_ENV).map_err(|error| {
+            tracing::error!("Couldn't find JWT_SECRET | {}", error);
+            CustomError::EnvironmentVariableError(error.to_string())
+        })?;
+        return Ok(KeyMaterial {
+            kid,
+            algorithm,
+            private_key: secret.into_bytes(),
+            public_key: Vec::new(),
+        });
+    }
+
+    Ok(KeyMaterial {
+        kid,
+        algorithm,
+        private_key: read_pem(PRIVATE_KEY_PATH_ENV)?,
+        public_key: read_pem(PUBLIC_KEY_PATH_ENV)?,
+    })
+}
+
+/// Reads the PEM file whose path is stored in the given environment variable.
+fn read_pem(path_env: &str) -> Result<Vec<u8>, CustomError> {
+    let path = env::var(path_env).map_err(|error| {
+        tracing::error!("Couldn't find {} | {}", path_env, error);
+        CustomError::EnvironmentVariableError(format!("{}: {}", path_env, error))
+    })?;
+    fs::read(&path).map_err(|error| {
+        tracing::error!("Couldn't read key file {} | {}", path, error);
+        CustomError::InvalidSigningKey(format!("{}: {}", path, error))
+    })
+}
+
+/// Generates new key material for the given algorithm.
+///
+/// # Arguments
+///
+/// * `algorithm` - The signing algorithm. Must be `HS256`, `RS256`, `ES256` or `EdDSA`.
+///
+/// # Returns
+///
+/// A `Result` containing the key material with a random key ID.
+pub fn generate_key_material(algorithm: Algorithm) -> Result<KeyMaterial, CustomError> {
+    let generation_error = |error: &dyn std::fmt::Display| {
+        tracing::error!("Error generating {:?} key: {}", algorithm, error);
+        CustomError::KeyringError(format!("Key generation failed: {}", error))
+    };
+
+    let (private_key, public_key) = match algorithm {
+        Algorithm::HS256 => {
+            let mut secret = [0u8; 32];
+            rng().fill_bytes(&mut secret);
+            (secret.to_vec(), Vec::new())
+        }
+        Algorithm::RS256 => {
+            let private_key =
+                rsa::RsaPrivateKey::new(&mut argon2::password_hash::rand_core::OsRng, RSA_KEY_BITS)
+                    .map_err(|e| generation_error(&e))?;
+            let private_pem = private_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|e| generation_error(&e))?;
+            let public_pem = private_key
+                .to_public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|e| generation_error(&e))?;
+            (private_pem.as_bytes().to_vec(), public_pem.into_bytes())
+        }
+        Algorithm::ES256 => {
+            let secret_key = loop {
+                let mut bytes = [0u8; 32];
+                rng().fill_bytes(&mut bytes);
+                // Retry in the negligible case that the bytes aren't a valid scalar.
+                if let Ok(secret_key) = p256::SecretKey::from_slice(&bytes) {
+                    break secret_key;
+                }
+            };
+            let private_pem = secret_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|e| generation_error(&e))?;
+            let public_pem = secret_key
+                .public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|e| generation_error(&e))?;
+            (private_pem.as_bytes().to_vec(), public_pem.into_bytes())
+        }
+        Algorithm::EdDSA => {
+            let mut bytes = [0u8; 32];
+            rng().fill_bytes(&mut bytes);
+            let signing_key = ed25519_dalek::SigningKey::from_bytes(&bytes);
+            let private_pem = signing_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|e| generation_error(&e))?;
+            let public_pem = signing_key
+                .verifying_key()
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|e| generation_error(&e))?;
+            (private_pem.as_bytes().to_vec(), public_pem.into_bytes())
+        }
+        other => {
+            return Err(CustomError::KeyringError(format!(
+                "Unsupported algorithm: {:?}",
+                other
+            )))
+        }
+    };
+
+    Ok(KeyMaterial {
+        kid: Uuid::new_v4().to_string(),
+        algorithm,
+        private_key,
+        public_key,
+    })
+}
@@ -6,19 +6,63 @@
 #[cfg(test)]
 pub mod tests;
 
+/// The client registration module
+pub mod client_registration;
+/// The consent module
+pub mod consent;
 /// The database module
 pub mod database;
+/// The device authorization module
+pub mod device_authorization;
+/// The DPoP module
+pub mod dpop;
+/// The email verification module
+pub mod email_verification;
 /// The encryption module
 pub mod encryption;
 /// The errors module
 pub mod errors;
+/// The federation module
+pub mod federation;
+/// The groups module
+pub mod groups;
 /// The hashing module
 pub mod hashing;
+/// The introspection module
+pub mod introspection;
 /// The jwt module
 pub mod jwt;
+/// The keyring module
+pub mod keyring;
 /// The logging module
 pub mod logging;
+/// The mailer module
+pub mod mailer;
+/// The mfa module
+pub mod mfa;
 /// The middleware module
 pub mod middleware;
+/// The OAuth module
+pub mod oauth;
+/// The OpenID Connect module
+pub mod oidc;
+/// The password reset module
+pub mod password_reset;
+/// The policy module
+pub mod policy;
+/// The rbac module
+pub mod rbac;
 /// The server module
 pub mod server;
+/// The simulation module
+pub mod simulation;
+/// The tenants module
+pub mod tenants;
+/// The token exchange module
+pub mod token_exchange;
+/// The tokens module
+pub mod tokens;
+/// The totp module
+pub mod totp;
+/// The webauthn module
+pub mod webauthn;
@@ -0,0 +1,157 @@
+//! src/mailer.rs
+//!
+//! This module defines the pluggable mailer used to deliver emails such as password reset links.
+
+use crate::errors::custom_errors::CustomError;
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const MAILER_ENV: &str = "MAILER";
+const MAILER_FILE_PATH_ENV: &str = "MAILER_FILE_PATH";
+const DEFAULT_MAILER_FILE_PATH: &str = "mail.log";
+const MAIL_FROM_ENV: &str = "MAIL_FROM";
+const DEFAULT_MAIL_FROM: &str = "no-reply@localhost";
+
+/// Represents an email to be delivered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailMessage {
+    /// The sender address.
+    pub from: String,
+    /// The recipient address.
+    pub to: String,
+    /// The subject line.
+    pub subject: String,
+    /// The plain text body.
+    pub body: String,
+}
+
+impl EmailMessage {
+    /// Creates a new email from the configured sender address.
+    ///
+    /// # Arguments
+    ///
+    /// * `to` - The recipient address.
+    /// * `subject` - The subject line.
+    /// * `body` - The plain text body.
+    ///
+    /// # Returns
+    ///
+    /// The new email.
+    pub fn new(to: &str, subject: &str, body: String) -> Self {
+        EmailMessage {
+            from: env::var(MAIL_FROM_ENV)
+                .ok()
+                .filter(|from| !from.is_empty())
+                .unwrap_or_else(|| DEFAULT_MAIL_FROM.to_string()),
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body,
+        }
+    }
+
+    /// Formats the email as an RFC 5322 style message.
+    fn to_rfc5322(&self) -> String {
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            self.from, self.to, self.subject, self.body
+        )
+    }
+}
+
+/// Delivers emails.
+///
+/// Implement this trait to plug in a real delivery mechanism such as SMTP or an email API.
+pub trait Mailer: Send + Sync {
+    /// Delivers an email.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The email to deliver.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if the email couldn't be delivered.
+    fn send(&self, message: &EmailMessage) -> Result<(), CustomError>;
+}
+
+/// A mailer for development that prints emails to stdout.
+#[derive(Debug, Default, Clone)]
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send(&self, message: &EmailMessage) -> Result<(), CustomError> {
+        println!("{}", message.to_rfc5322());
+        Ok(())
+    }
+}
+
+/// A mailer for development that appends emails to a file.
+#[derive(Debug, Clone)]
+pub struct FileMailer {
+    /// The file emails are appended to.
+    pub path: PathBuf,
+}
+
+impl FileMailer {
+    /// Creates a mailer that appends to the given file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file emails are appended to.
+    ///
+    /// # Returns
+    ///
+    /// The new mailer.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileMailer { path: path.into() }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: &EmailMessage) -> Result<(), CustomError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|error| CustomError::MailerError(error.to_string()))?;
+        file.write_all(message.to_rfc5322().as_bytes())
+            .map_err(|error| CustomError::MailerError(error.to_string()))
+    }
+}
+
+/// Builds the mailer configured in the environment.
+///
+/// The `MAILER` environment variable selects `stdout` (the default) or `file`. The file mailer
+/// writes to `MAILER_FILE_PATH`, which defaults to `mail.log`.
+///
+/// # Returns
+///
+/// A `Result` containing the mailer.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - `MAILER` names an unknown mailer.
+pub fn mailer_from_env() -> Result<Arc<dyn Mailer>, CustomError> {
+    match env::var(MAILER_ENV).unwrap_or_default().as_str() {
+        "" | "stdout" => Ok(Arc::new(StdoutMailer)),
+        "file" => Ok(Arc::new(FileMailer::new(
+            env::var(MAILER_FILE_PATH_ENV)
+                .ok()
+                .filter(|path| !path.is_empty())
+                .unwrap_or_else(|| DEFAULT_MAILER_FILE_PATH.to_string()),
+        ))),
+        other => Err(CustomError::MailerError(format!(
+            "Unknown mailer: {}",
+            other
+        ))),
+    }
+}
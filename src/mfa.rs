@@ -0,0 +1,785 @@
+//! src/mfa.rs
+//!
+//! This module handles TOTP enrollment, recovery codes and the second step of a two-factor login.
+
+use crate::database::{Database, MfaChallenge, User};
+use crate::encryption::{decrypt_with_nonce, encrypt_with_random_nonce, generate_key};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::{hash_random_salt, hash_token, verify_password};
+use crate::tokens::{generate_opaque_token, issue_token_pair, TokenPair};
+use crate::totp;
+use crate::webauthn::{self, AssertionCredential};
+
+use chrono::Utc;
+use rand::{rng, Rng};
+use serde::Serialize;
+use std::env;
+
+const MFA_CHALLENGE_LIFETIME_ENV: &str = "MFA_CHALLENGE_LIFETIME_SECONDS";
+const DEFAULT_MFA_CHALLENGE_LIFETIME_SECONDS: i64 = 5 * 60;
+
+/// The number of wrong codes after which a challenge is discarded.
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+/// The number of recovery codes in a batch.
+const RECOVERY_CODE_COUNT: usize = 10;
+/// The number of characters in a recovery code, excluding the separator.
+const RECOVERY_CODE_LENGTH: usize = 10;
+/// The characters recovery codes are made of. Look-alikes such as `0`/`o` and `1`/`l` are left
+/// out so that codes can be copied from paper without mistakes.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Represents a started TOTP enrollment.
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    /// The base32-encoded secret, for manual entry.
+    pub secret: String,
+    /// The `otpauth://` URI, usually shown as a QR code.
+    pub otpauth_uri: String,
+}
+
+/// Represents a pending MFA challenge handed out by a password or federated login.
+#[derive(Debug, Serialize)]
+pub struct PendingMfaChallenge {
+    /// The opaque challenge token.
+    pub mfa_token: String,
+    /// The lifetime of the challenge in seconds.
+    pub expires_in: i64,
+}
+
+/// Represents an MFA challenge the user completed.
+#[derive(Debug)]
+pub struct CompletedMfaChallenge {
+    /// The ID of the user who completed the challenge.
+    pub user_id: String,
+    /// The methods the user authenticated with, first and second factor.
+    pub auth_methods: Vec<String>,
+}
+
+/// Represents the outcome of a login after the first factor.
+#[derive(Debug)]
+pub enum FirstFactorOutcome {
+    /// The user has no second factor and was issued a token pair.
+    Tokens(TokenPair),
+    /// The user has to complete an MFA challenge with one of the methods.
+    MfaRequired {
+        /// The second factors the user has set up.
+        methods: Vec<String>,
+        /// The challenge to complete.
+        challenge: PendingMfaChallenge,
+    },
+}
+
+/// Returns the lifetime of MFA challenges in seconds.
+///
+/// The lifetime is read from the `MFA_CHALLENGE_LIFETIME_SECONDS` environment variable and
+/// defaults to 5 minutes if it is missing or invalid.
+pub fn mfa_challenge_lifetime() -> i64 {
+    env::var(MFA_CHALLENGE_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_MFA_CHALLENGE_LIFETIME_SECONDS)
+}
+
+/// Starts TOTP enrollment for a user.
+///
+/// A new secret is generated and stored encrypted. TOTP isn't required on login until the
+/// enrollment is confirmed with a first code.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the secret and the provisioning URI.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user doesn't exist.
+/// - TOTP is already enabled.
+/// - Encrypting or storing the secret fails.
+pub async fn begin_totp_enrollment(
+    db: &Database,
+    user_id: &str,
+) -> Result<TotpEnrollment, CustomError> {
+    let user = db
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or(CustomError::UserNotFound)?;
+    if user.totp_enabled {
+        return Err(CustomError::MfaAlreadyEnabled);
+    }
+
+    let secret = totp::generate_secret();
+    let otpauth_uri = totp::provisioning_uri(&secret, &user.email)?;
+
+    let key_bytes: [u8; 32] = generate_key()?.into();
+    let encrypted_secret = encrypt_with_random_nonce(&key_bytes, &secret)?;
+    db.set_pending_totp_secret(user_id, &encrypted_secret)
+        .await?;
+
+    tracing::info!("Started TOTP enrollment for user: {}", user_id);
+    Ok(TotpEnrollment {
+        secret,
+        otpauth_uri,
+    })
+}
+
+/// Confirms TOTP enrollment with the first code from the authenticator app.
+///
+/// A batch of recovery codes is generated at the same time. They are only returned here, so
+/// the user has to store them now.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `code` - The code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` containing the recovery codes.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user doesn't exist.
+/// - TOTP is already enabled or enrollment wasn't started.
+/// - The code is invalid.
+/// - Generating the recovery codes fails.
+pub async fn confirm_totp_enrollment(
+    db: &Database,
+    user_id: &str,
+    code: &str,
+) -> Result<Vec<String>, CustomError> {
+    let user = db
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or(CustomError::UserNotFound)?;
+    if user.totp_enabled {
+        return Err(CustomError::MfaAlreadyEnabled);
+    }
+
+    let secret = decrypted_totp_secret(&user)?;
+    match totp::verify_code(&secret, code, Utc::now().timestamp() as u64, None)? {
+        Some(step) => {
+            let recovery_codes = generate_recovery_codes(db, user_id).await?;
+            db.enable_totp(user_id, step).await?;
+            tracing::info!("Enabled TOTP for user: {}", user_id);
+            Ok(recovery_codes)
+        }
+        None => {
+            tracing::warn!("Invalid TOTP code during enrollment for user: {}", user_id);
+            Err(CustomError::InvalidMfaCode)
+        }
+    }
+}
+
+/// Disables TOTP for a user after verifying a current code or a recovery code.
+///
+/// The user's recovery codes are deleted as well.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `code` - The TOTP code or recovery code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user doesn't exist.
+/// - TOTP isn't enabled.
+/// - The code is invalid.
+pub async fn disable_totp(db: &Database, user_id: &str, code: &str) -> Result<(), CustomError> {
+    let user = db
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or(CustomError::UserNotFound)?;
+    verify_second_factor(db, &user, code).await?;
+    db.disable_totp(user_id).await?;
+    db.delete_recovery_codes(user_id).await?;
+    tracing::info!("Disabled TOTP for user: {}", user_id);
+    Ok(())
+}
+
+/// Verifies the second factor of a user with TOTP enabled.
+///
+/// Six-digit codes are checked as TOTP codes, anything else as a recovery code.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user` - The user.
+/// * `code` - The TOTP code or recovery code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` containing the authentication methods (RFC 8176) the code proves.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - TOTP isn't enabled.
+/// - The code is invalid or was already used.
+pub async fn verify_second_factor(
+    db: &Database,
+    user: &User,
+    code: &str,
+) -> Result<Vec<String>, CustomError> {
+    let code = code.trim();
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        verify_totp(db, user, code).await?;
+        Ok(vec!["otp".to_string(), "mfa".to_string()])
+    } else {
+        verify_recovery_code(db, user, code).await?;
+        Ok(vec!["mfa".to_string()])
+    }
+}
+
+/// Verifies a TOTP code for a user with TOTP enabled and marks it as used.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user` - The user.
+/// * `code` - The code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - TOTP isn't enabled.
+/// - The code is invalid or was already used.
+pub async fn verify_totp(db: &Database, user: &User, code: &str) -> Result<(), CustomError> {
+    if !user.totp_enabled {
+        return Err(CustomError::MfaNotEnabled);
+    }
+
+    let user_id = user.id.to_string();
+    let secret = decrypted_totp_secret(user)?;
+    let step = totp::verify_code(
+        &secret,
+        code,
+        Utc::now().timestamp() as u64,
+        user.totp_last_step,
+    )?;
+    match step {
+        Some(step) if db.record_totp_step(&user_id, step).await? => Ok(()),
+        _ => {
+            tracing::warn!("Invalid TOTP code for user: {}", user_id);
+            Err(CustomError::InvalidMfaCode)
+        }
+    }
+}
+
+/// Generates a new batch of recovery codes for a user, replacing the previous batch.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the new recovery codes in plain text.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Hashing the codes fails.
+/// - Storing the codes fails.
+pub async fn generate_recovery_codes(
+    db: &Database,
+    user_id: &str,
+) -> Result<Vec<String>, CustomError> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    let mut code_hashes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = generate_recovery_code();
+        let code_hash = hash_random_salt(&normalize_recovery_code(&code))
+            .map_err(|_| CustomError::HashingError)?;
+        codes.push(code);
+        code_hashes.push(code_hash);
+    }
+
+    db.replace_recovery_codes(user_id, &code_hashes).await?;
+    tracing::info!("Generated recovery codes for user: {}", user_id);
+    Ok(codes)
+}
+
+/// Regenerates the recovery codes of a user after verifying their second factor.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `code` - The TOTP code or recovery code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` containing the new recovery codes.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user doesn't exist.
+/// - TOTP isn't enabled.
+/// - The code is invalid.
+/// - Generating the recovery codes fails.
+pub async fn regenerate_recovery_codes(
+    db: &Database,
+    user_id: &str,
+    code: &str,
+) -> Result<Vec<String>, CustomError> {
+    let user = db
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or(CustomError::UserNotFound)?;
+    verify_second_factor(db, &user, code).await?;
+    generate_recovery_codes(db, user_id).await
+}
+
+/// Verifies a recovery code for a user with TOTP enabled and marks it as used.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user` - The user.
+/// * `code` - The recovery code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - TOTP isn't enabled.
+/// - The code is invalid or was already used.
+pub async fn verify_recovery_code(
+    db: &Database,
+    user: &User,
+    code: &str,
+) -> Result<(), CustomError> {
+    if !user.totp_enabled {
+        return Err(CustomError::MfaNotEnabled);
+    }
+
+    let user_id = user.id.to_string();
+    let code = normalize_recovery_code(code);
+    for stored in db.get_unused_recovery_codes(&user_id).await? {
+        if verify_password(&code, &stored.code_hash).is_ok() {
+            if db.consume_recovery_code(&stored.id).await? {
+                let remaining = db.count_unused_recovery_codes(&user_id).await?;
+                tracing::info!(
+                    "Recovery code used by user {}, {} remaining",
+                    user_id,
+                    remaining
+                );
+                return Ok(());
+            }
+            break;
+        }
+    }
+
+    tracing::warn!("Invalid recovery code for user: {}", user_id);
+    Err(CustomError::InvalidMfaCode)
+}
+
+/// Creates an MFA challenge for a user who passed the first factor, i.e. the password check or
+/// a federated login.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `auth_methods` - The methods the user passed the first factor with.
+///
+/// # Returns
+///
+/// A `Result` containing the challenge token.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Storing the challenge fails.
+pub async fn create_mfa_challenge(
+    db: &Database,
+    user_id: &str,
+    auth_methods: &[String],
+) -> Result<PendingMfaChallenge, CustomError> {
+    let mfa_token = generate_opaque_token();
+    let expires_in = mfa_challenge_lifetime();
+    db.store_mfa_challenge(
+        &hash_token(&mfa_token),
+        user_id,
+        auth_methods,
+        Utc::now().timestamp() + expires_in,
+    )
+    .await?;
+
+    Ok(PendingMfaChallenge {
+        mfa_token,
+        expires_in,
+    })
+}
+
+/// Continues a login after the user passed the first factor, i.e. the password check or a
+/// federated login.
+///
+/// Users who set up a second factor get an MFA challenge, all others a token pair.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user` - The authenticated user.
+/// * `auth_methods` - The methods the user passed the first factor with.
+///
+/// # Returns
+///
+/// A `Result` containing the token pair or the MFA challenge.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the user's second factors fails.
+/// - Storing the challenge or the tokens fails.
+pub async fn complete_first_factor(
+    db: &Database,
+    user: &User,
+    auth_methods: &[String],
+) -> Result<FirstFactorOutcome, CustomError> {
+    match challenge_second_factor(db, user, auth_methods).await? {
+        Some((methods, challenge)) => Ok(FirstFactorOutcome::MfaRequired { methods, challenge }),
+        None => Ok(FirstFactorOutcome::Tokens(
+            issue_token_pair(db, &user.id.to_string(), auth_methods).await?,
+        )),
+    }
+}
+
+/// Starts an MFA challenge after the first factor if the user set up a second factor.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user` - The authenticated user.
+/// * `auth_methods` - The methods the user passed the first factor with.
+///
+/// # Returns
+///
+/// A `Result` containing the user's second factors and the challenge to complete, or `None` if
+/// the first factor is enough.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the user's second factors fails.
+/// - Storing the challenge fails.
+pub async fn challenge_second_factor(
+    db: &Database,
+    user: &User,
+    auth_methods: &[String],
+) -> Result<Option<(Vec<String>, PendingMfaChallenge)>, CustomError> {
+    let methods = mfa_methods(db, user).await?;
+    if methods.is_empty() {
+        return Ok(None);
+    }
+
+    let user_id = user.id.to_string();
+    tracing::info!("Second factor required for user: {}", user_id);
+    let challenge = create_mfa_challenge(db, &user_id, auth_methods).await?;
+    Ok(Some((methods, challenge)))
+}
+
+/// Completes an MFA challenge with a TOTP code or a recovery code and issues a token pair.
+///
+/// A challenge can be completed once. After too many wrong codes it is discarded and the user
+/// has to log in with their password again.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `mfa_token` - The challenge token returned by the login.
+/// * `code` - The TOTP code or recovery code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` containing the new token pair.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The challenge is unknown or expired.
+/// - The code is invalid.
+/// - Generating or storing the tokens fails.
+pub async fn complete_mfa_challenge(
+    db: &Database,
+    mfa_token: &str,
+    code: &str,
+) -> Result<TokenPair, CustomError> {
+    let completed = pass_mfa_challenge(db, mfa_token, code).await?;
+    issue_token_pair(db, &completed.user_id, &completed.auth_methods).await
+}
+
+/// Completes an MFA challenge with a TOTP code or a recovery code without issuing tokens.
+///
+/// This is used where the login results in something other than a token pair, such as an OAuth
+/// authorization code.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `mfa_token` - The challenge token returned by the login.
+/// * `code` - The TOTP code or recovery code entered by the user.
+///
+/// # Returns
+///
+/// A `Result` containing the user and the methods they authenticated with.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The challenge is unknown or expired.
+/// - The code is invalid.
+pub async fn pass_mfa_challenge(
+    db: &Database,
+    mfa_token: &str,
+    code: &str,
+) -> Result<CompletedMfaChallenge, CustomError> {
+    let challenge_hash = hash_token(mfa_token);
+    let challenge = pending_mfa_challenge(db, &challenge_hash).await?;
+
+    let user = db
+        .get_user_by_id(&challenge.user_id)
+        .await?
+        .ok_or(CustomError::InvalidMfaChallenge)?;
+
+    match verify_second_factor(db, &user, code).await {
+        Ok(second_factor) => {
+            finish_mfa_challenge(db, &challenge_hash, &challenge, second_factor).await
+        }
+        Err(error) => {
+            record_mfa_challenge_failure(db, &challenge_hash, &challenge.user_id).await?;
+            Err(error)
+        }
+    }
+}
+
+/// Starts a passkey assertion for the second step of a login.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `mfa_token` - The challenge token returned by the login.
+///
+/// # Returns
+///
+/// A `Result` containing the options to pass to `navigator.credentials.get()`.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The challenge is unknown or expired.
+/// - Starting the WebAuthn ceremony fails.
+pub async fn start_mfa_passkey_assertion(
+    db: &Database,
+    mfa_token: &str,
+) -> Result<serde_json::Value, CustomError> {
+    let challenge = pending_mfa_challenge(db, &hash_token(mfa_token)).await?;
+    webauthn::start_authentication(db, Some(&challenge.user_id), false).await
+}
+
+/// Completes an MFA challenge with a passkey assertion and issues a token pair.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `mfa_token` - The challenge token returned by the login.
+/// * `credential` - The assertion returned by the browser.
+///
+/// # Returns
+///
+/// A `Result` containing the new token pair.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The challenge is unknown or expired.
+/// - The assertion fails verification or was made with another user's passkey.
+/// - Generating or storing the tokens fails.
+pub async fn complete_mfa_challenge_with_passkey(
+    db: &Database,
+    mfa_token: &str,
+    credential: &AssertionCredential,
+) -> Result<TokenPair, CustomError> {
+    let completed = pass_mfa_challenge_with_passkey(db, mfa_token, credential).await?;
+    issue_token_pair(db, &completed.user_id, &completed.auth_methods).await
+}
+
+/// Completes an MFA challenge with a passkey assertion without issuing tokens.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `mfa_token` - The challenge token returned by the login.
+/// * `credential` - The assertion returned by the browser.
+///
+/// # Returns
+///
+/// A `Result` containing the user and the methods they authenticated with.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The challenge is unknown or expired.
+/// - The assertion fails verification or was made with another user's passkey.
+pub async fn pass_mfa_challenge_with_passkey(
+    db: &Database,
+    mfa_token: &str,
+    credential: &AssertionCredential,
+) -> Result<CompletedMfaChallenge, CustomError> {
+    let challenge_hash = hash_token(mfa_token);
+    let challenge = pending_mfa_challenge(db, &challenge_hash).await?;
+
+    let result = match webauthn::finish_authentication(db, credential).await {
+        Ok(assertion) if assertion.user_id == challenge.user_id => Ok(assertion),
+        Ok(_) => Err(CustomError::WebAuthnError(
+            "Credential belongs to another user".to_string(),
+        )),
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(_) => {
+            let second_factor = vec!["hwk".to_string(), "mfa".to_string()];
+            finish_mfa_challenge(db, &challenge_hash, &challenge, second_factor).await
+        }
+        Err(error) => {
+            record_mfa_challenge_failure(db, &challenge_hash, &challenge.user_id).await?;
+            Err(error)
+        }
+    }
+}
+
+/// Returns the second factors a user has set up.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user` - The user.
+///
+/// # Returns
+///
+/// A `Result` containing the methods, `totp` and `webauthn`. An empty list means that the
+/// password alone is enough to log in.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the user's passkeys fails.
+pub async fn mfa_methods(db: &Database, user: &User) -> Result<Vec<String>, CustomError> {
+    let mut methods = Vec::new();
+    if user.totp_enabled {
+        methods.push("totp".to_string());
+    }
+    if !db
+        .get_webauthn_credentials(&user.id.to_string())
+        .await?
+        .is_empty()
+    {
+        methods.push("webauthn".to_string());
+    }
+    Ok(methods)
+}
+
+/// Looks up an MFA challenge that hasn't expired yet.
+async fn pending_mfa_challenge(
+    db: &Database,
+    challenge_hash: &str,
+) -> Result<MfaChallenge, CustomError> {
+    match db.find_mfa_challenge(challenge_hash).await? {
+        Some(challenge) if challenge.expires_at > Utc::now().timestamp() => Ok(challenge),
+        _ => {
+            tracing::warn!("Unknown or expired MFA challenge presented");
+            Err(CustomError::InvalidMfaChallenge)
+        }
+    }
+}
+
+/// Records a failed attempt and discards the challenge after too many of them.
+async fn record_mfa_challenge_failure(
+    db: &Database,
+    challenge_hash: &str,
+    user_id: &str,
+) -> Result<(), CustomError> {
+    if db.record_mfa_challenge_failure(challenge_hash).await? >= MAX_CHALLENGE_ATTEMPTS {
+        tracing::warn!(
+            "Too many invalid attempts, discarding MFA challenge for user: {}",
+            user_id
+        );
+        db.delete_mfa_challenge(challenge_hash).await?;
+    }
+    Ok(())
+}
+
+/// Consumes a completed challenge.
+async fn finish_mfa_challenge(
+    db: &Database,
+    challenge_hash: &str,
+    challenge: &MfaChallenge,
+    second_factor: Vec<String>,
+) -> Result<CompletedMfaChallenge, CustomError> {
+    if !db.delete_mfa_challenge(challenge_hash).await? {
+        return Err(CustomError::InvalidMfaChallenge);
+    }
+
+    let mut auth_methods = challenge.auth_methods.clone();
+    auth_methods.extend(second_factor);
+    Ok(CompletedMfaChallenge {
+        user_id: challenge.user_id.clone(),
+        auth_methods,
+    })
+}
+
+/// Decrypts the TOTP secret of a user.
+fn decrypted_totp_secret(user: &User) -> Result<String, CustomError> {
+    let encrypted_secret = user
+        .totp_secret
+        .as_deref()
+        .ok_or(CustomError::MfaNotEnabled)?;
+    let key_bytes: [u8; 32] = generate_key()?.into();
+    decrypt_with_nonce(&key_bytes, encrypted_secret)
+}
+
+/// Generates a single recovery code formatted as two groups of five characters.
+fn generate_recovery_code() -> String {
+    let mut rng = rng();
+    let mut code = String::with_capacity(RECOVERY_CODE_LENGTH + 1);
+    for i in 0..RECOVERY_CODE_LENGTH {
+        if i == RECOVERY_CODE_LENGTH / 2 {
+            code.push('-');
+        }
+        let index = rng.random_range(0..RECOVERY_CODE_ALPHABET.len());
+        code.push(RECOVERY_CODE_ALPHABET[index] as char);
+    }
+    code
+}
+
+/// Normalizes a recovery code so that separators, whitespace and case don't matter.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
@@ -1,14 +1,21 @@
 //! src/middleware.rs
 //!
-//! This module provides authentication middleware for Actix Web applications.
+//! This module provides authentication and authorization middleware for Actix Web applications.
 
-use crate::jwt::{extract_user_id_from_jwt, validate_jwt};
-use actix_web::dev::Transform;
+use crate::dpop::{request_url, verify_proof, DPOP};
+use crate::errors::custom_errors::CustomError;
+use crate::jwt::{validate_tenant_jwt, Claims};
+use crate::rbac;
+use crate::server::AppState;
+use crate::tenants::{split_tenant_path, DEFAULT_TENANT};
+use crate::tokens::is_access_token_revoked;
+use actix_web::dev::{Extensions, Transform};
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse},
-    error::ErrorUnauthorized,
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized},
+    http::uri::{PathAndQuery, Uri},
     http::Method,
-    Error, HttpMessage,
+    web, Error, HttpMessage,
 };
 use futures::future::err;
 use std::future::Future;
@@ -16,6 +23,10 @@ use std::pin::Pin;
 use std::rc::Rc;
 use tracing::info;
 
+/// The routes that accept access tokens issued to OAuth clients. All other routes act on the
+/// user's own account, so they only accept tokens from the user's own sessions.
+const CLIENT_TOKEN_PATHS: [&str; 1] = ["/userinfo"];
+
 /// Authentication middleware that checks for a valid JWT in the request header.
 pub struct AuthenticationMiddleware<S> {
     service: Rc<S>,
@@ -57,6 +68,25 @@ where
         if *req.method() == Method::OPTIONS
             || req.path() == "/register"
             || req.path() == "/login"
+            || req.path() == "/login/mfa"
+            || req.path() == "/login/mfa/webauthn"
+            || req.path() == "/webauthn/login/begin"
+            || req.path() == "/webauthn/login/finish"
+            || req.path() == "/token/refresh"
+            || req.path() == "/password/forgot"
+            || req.path() == "/password/reset"
+            || req.path() == "/verify_email"
+            || req.path() == "/verify_email/resend"
+            || req.path() == "/.well-known/jwks.json"
+            || req.path() == "/.well-known/openid-configuration"
+            || req.path() == "/oauth/authorize"
+            || req.path() == "/oauth/token"
+            || req.path() == "/oauth/device/code"
+            || req.path() == "/oauth/introspect"
+            || req.path() == "/oauth/revoke"
+            || req.path() == "/oauth/register"
+            || req.path() == "/oauth/consent"
+            || req.path().starts_with("/federation/")
             || req.path() == "/ping"
         {
             return Box::pin(self.service.call(req));
@@ -79,35 +109,104 @@ where
             }
         };
 
-        let token = match auth_value.strip_prefix("Bearer ") {
-            Some(token) => token.trim(),
-            None => {
-                tracing::error!("Invalid authorization format");
-                return Box::pin(err(ErrorUnauthorized("Invalid authorization format")));
-            }
+        let (token, dpop_scheme) = match auth_value.strip_prefix("Bearer ") {
+            Some(token) => (token.trim(), false),
+            None => match auth_value.strip_prefix("DPoP ") {
+                Some(token) => (token.trim(), true),
+                None => {
+                    tracing::error!("Invalid authorization format");
+                    return Box::pin(err(ErrorUnauthorized("Invalid authorization format")));
+                }
+            },
         };
 
-        match validate_jwt(token) {
-            Ok(_) => {}
+        // Only tokens issued by the tenant of the request are accepted
+        let app_state = req.app_data::<web::Data<AppState>>().cloned();
+        let tenant = app_state
+            .as_ref()
+            .map_or(DEFAULT_TENANT, |app_state| app_state.db.tenant());
+        let claims = match validate_tenant_jwt(tenant, token) {
+            Ok(claims) => claims,
             Err(e) => {
                 tracing::error!("Invalid token: {}", e);
                 return Box::pin(err(ErrorUnauthorized("Invalid token")));
             }
         };
 
-        let user_id = match extract_user_id_from_jwt(token) {
-            Ok(user_id) => user_id,
-            Err(e) => {
-                tracing::error!("Failed to extract user ID: {}", e);
-                return Box::pin(err(ErrorUnauthorized("Invalid token")));
+        // Tokens issued to a client don't let the client act as the user on the user's account
+        if let Some(client_id) = &claims.client_id {
+            if !CLIENT_TOKEN_PATHS.contains(&req.path()) {
+                tracing::error!(
+                    "Token issued to client {} used for {}",
+                    client_id,
+                    req.path()
+                );
+                return Box::pin(err(ErrorForbidden("Token was issued to an OAuth client")));
+            }
+        }
+
+        // Tokens bound to a key are only accepted with a proof of possession of the key
+        if claims.cnf.is_some() != dpop_scheme {
+            tracing::error!("Token used with the wrong authorization scheme");
+            return Box::pin(err(ErrorUnauthorized("Invalid authorization format")));
+        }
+        let proof = match (&claims.cnf, req.headers().get(DPOP)) {
+            (None, _) => None,
+            (Some(_), Some(proof)) => match proof.to_str() {
+                Ok(proof) => Some((proof.to_string(), token.to_string())),
+                Err(_) => return Box::pin(err(ErrorUnauthorized("Invalid DPoP proof"))),
+            },
+            (Some(_), None) => {
+                tracing::error!("Missing DPoP proof for a bound token");
+                return Box::pin(err(ErrorUnauthorized("Missing DPoP proof")));
             }
         };
+        let method = req.method().to_string();
+        let url = request_url(req.request());
 
-        info!("Authenticated user with ID: {}", user_id);
-        req.extensions_mut().insert(user_id.clone()); // Store user_id in extensions
-        let fut = self.service.call(req);
+        let user_id = claims.sub.clone();
+        let service = Rc::clone(&self.service);
         Box::pin(async move {
-            let res = fut.await?;
+            if let (Some(cnf), Some((proof, token))) = (&claims.cnf, proof) {
+                let app_state = app_state
+                    .as_ref()
+                    .ok_or_else(|| ErrorInternalServerError("Failed to verify DPoP proof"))?;
+                let jkt = verify_proof(&app_state.db, &proof, &method, &url, Some(&token))
+                    .await
+                    .map_err(|e| {
+                        tracing::error!("Invalid DPoP proof: {}", e);
+                        match e {
+                            CustomError::InvalidDpopProof(_) => {
+                                ErrorUnauthorized("Invalid DPoP proof")
+                            }
+                            _ => ErrorInternalServerError("Failed to verify DPoP proof"),
+                        }
+                    })?;
+                if jkt != cnf.jkt {
+                    tracing::error!("DPoP proof signed with another key than the token's");
+                    return Err(ErrorUnauthorized("Invalid DPoP proof"));
+                }
+            }
+
+            // Reject tokens that were revoked or issued before the user logged out everywhere
+            let app_state = app_state.ok_or_else(|| {
+                tracing::error!("Missing application state for revocation check");
+                ErrorInternalServerError("Failed to check token revocation")
+            })?;
+            let revoked = is_access_token_revoked(&app_state.db, &claims)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to check token revocation: {}", e);
+                    ErrorInternalServerError("Failed to check token revocation")
+                })?;
+            if revoked {
+                return Err(ErrorUnauthorized("Token has been revoked"));
+            }
+
+            info!("Authenticated user with ID: {}", user_id);
+            req.extensions_mut().insert(user_id); // Store user_id in extensions
+            req.extensions_mut().insert(claims);
+            let res = service.call(req).await?;
             Ok(res)
         })
     }
@@ -144,3 +243,229 @@ where
         std::future::ready(Ok(AuthenticationMiddleware::new(Rc::new(service))))
     }
 }
+
+/// Authorization middleware that rejects requests from users without a permission.
+///
+/// It has to run inside `AuthenticationMiddleware`, which provides the claims of the caller.
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: Rc<str>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    /// Processes the service request and checks the required permission.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The service request to process.
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let user_id = match req.extensions().get::<Claims>() {
+            // Permissions belong to users, never to the clients they authorized
+            Some(claims) if claims.client_id.is_some() => {
+                tracing::warn!("Token issued to an OAuth client used for a permission check");
+                return Box::pin(err(ErrorForbidden("Missing permission")));
+            }
+            Some(claims) => claims.sub.clone(),
+            None => {
+                tracing::error!("Missing claims for permission check");
+                return Box::pin(err(ErrorUnauthorized("Missing authentication")));
+            }
+        };
+
+        let app_state = req.app_data::<web::Data<AppState>>().cloned();
+        let permission = Rc::clone(&self.permission);
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let app_state = app_state.ok_or_else(|| {
+                tracing::error!("Missing application state for permission check");
+                ErrorInternalServerError("Failed to check permissions")
+            })?;
+            let allowed = rbac::has_permission(&app_state.db, &user_id, &permission)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to check permissions: {}", e);
+                    ErrorInternalServerError("Failed to check permissions")
+                })?;
+            if !allowed {
+                tracing::warn!("User {} is missing permission {}", user_id, permission);
+                return Err(ErrorForbidden("Missing permission"));
+            }
+
+            service.call(req).await
+        })
+    }
+}
+
+/// Factory for creating `RequirePermissionMiddleware` instances.
+///
+/// Routes declare the permission they require with
+/// `#[get("/path", wrap = "RequirePermission::new(rbac::ROLES_READ)")]`.
+pub struct RequirePermission {
+    permission: Rc<str>,
+}
+
+impl RequirePermission {
+    /// Creates a new `RequirePermission` instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `permission` - The permission the wrapped route requires.
+    pub fn new(permission: &str) -> Self {
+        RequirePermission {
+            permission: Rc::from(permission),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequirePermissionMiddleware<S>;
+    type Future = std::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    /// Creates a new `RequirePermissionMiddleware` instance for each service.
+    ///
+    /// # Arguments
+    ///
+    /// * `service` - The service to wrap with the permission check.
+    fn new_transform(&self, service: S) -> Self::Future {
+        std::future::ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: Rc::clone(&self.permission),
+        }))
+    }
+}
+
+/// The path of a request before `TenantMiddleware` removed its tenant prefix.
+#[derive(Debug, Clone)]
+pub struct OriginalPath(pub String);
+
+/// Tenant middleware that selects the tenant of a request.
+///
+/// The tenant is taken from a `/realms/<tenant ID>` path prefix, which is removed before
+/// routing, or else from the host header. Requests for other tenants than the default tenant
+/// see an `AppState` whose database belongs to their tenant. It has to wrap
+/// `AuthenticationMiddleware`.
+pub struct TenantMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for TenantMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    /// Processes the service request and selects its tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The service request to process.
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let app_state = match req.app_data::<web::Data<AppState>>().cloned() {
+            Some(app_state) => app_state,
+            None => return Box::pin(self.service.call(req)),
+        };
+
+        let tenant_path =
+            split_tenant_path(req.path()).map(|(tenant_id, path)| (tenant_id.to_string(), path));
+        let host = req.connection_info().host().to_string();
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let db = match &tenant_path {
+                Some((tenant_id, _)) => app_state.tenants.database(tenant_id).await,
+                None => app_state.tenants.database_for_host(&host).await,
+            }
+            .map_err(|e| match e {
+                CustomError::TenantNotFound => ErrorNotFound("Unknown tenant"),
+                e => {
+                    tracing::error!("Failed to select tenant: {}", e);
+                    ErrorInternalServerError("Failed to select tenant")
+                }
+            })?;
+
+            // Route the request as if it had no tenant prefix
+            if let Some((_, path)) = tenant_path {
+                let original_path = OriginalPath(req.path().to_string());
+                req.extensions_mut().insert(original_path);
+                let mut parts = req.head().uri.clone().into_parts();
+                let path_and_query = match parts.path_and_query.as_ref().and_then(|pq| pq.query()) {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path,
+                };
+                parts.path_and_query = Some(
+                    PathAndQuery::try_from(path_and_query)
+                        .map_err(|_| ErrorNotFound("Invalid path"))?,
+                );
+                let uri = Uri::from_parts(parts).map_err(|_| ErrorNotFound("Invalid path"))?;
+                req.match_info_mut().get_mut().update(&uri);
+                req.head_mut().uri = uri;
+            }
+
+            if db.tenant() != DEFAULT_TENANT {
+                info!("Selected tenant: {}", db.tenant());
+                let mut extensions = Extensions::new();
+                extensions.insert(web::Data::new(AppState {
+                    db,
+                    ..app_state.get_ref().clone()
+                }));
+                req.add_data_container(Rc::new(extensions));
+            }
+            service.call(req).await
+        })
+    }
+}
+
+/// Factory for creating `TenantMiddleware` instances.
+#[derive(Default)]
+pub struct TenantMiddlewareFactory;
+
+impl TenantMiddlewareFactory {
+    /// Creates a new `TenantMiddlewareFactory` instance.
+    pub fn new() -> Self {
+        TenantMiddlewareFactory
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for TenantMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TenantMiddleware<S>;
+    type Future = std::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    /// Creates a new `TenantMiddleware` instance for each service.
+    ///
+    /// # Arguments
+    ///
+    /// * `service` - The service to wrap with tenant selection.
+    fn new_transform(&self, service: S) -> Self::Future {
+        std::future::ready(Ok(TenantMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
@@ -0,0 +1,1210 @@
+//! src/oauth.rs
+//!
+//! This module implements an OAuth 2.0 authorization server (RFC 6749) for the authorization
+//! code grant. PKCE (RFC 7636) with the `S256` method is required from every client, so that an
+//! intercepted code is useless without the verifier that only the client knows.
+//!
+//! Clients are registered per tenant. Confidential clients authenticate at the token endpoint
+//! with a secret, public clients such as single-page and mobile apps have none and are
+//! protected by PKCE alone. Confidential clients can also act on their own behalf with the
+//! client credentials grant, e.g. backend jobs. Devices without a browser, such as CLIs and
+//! TVs, use the device authorization grant implemented in [`crate::device_authorization`].
+//! Services calling other services for a user swap tokens with the token exchange grant
+//! implemented in [`crate::token_exchange`].
+
+use crate::database::{AuthorizationCode, Database, OAuthClient};
+use crate::device_authorization::exchange_device_code;
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::oidc::{has_scope, issue_id_token, OPENID_SCOPE};
+use crate::token_exchange::exchange_subject_token;
+use crate::tokens::{
+    self, generate_opaque_token, issue_client_token_pair, rotate_client_refresh_token, ClientGrant,
+    TokenPair,
+};
+
+use base64::{engine::general_purpose, Engine as base64Engine};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+const AUTHORIZATION_CODE_LIFETIME_ENV: &str = "OAUTH_CODE_LIFETIME_SECONDS";
+const DEFAULT_AUTHORIZATION_CODE_LIFETIME_SECONDS: i64 = 60;
+const LOGIN_URL_ENV: &str = "OAUTH_LOGIN_URL";
+
+/// The only supported PKCE method.
+const CODE_CHALLENGE_METHOD: &str = "S256";
+
+/// The grant type that exchanges an authorization code for tokens.
+pub const AUTHORIZATION_CODE_GRANT: &str = "authorization_code";
+/// The grant type that exchanges a refresh token for new tokens.
+pub const REFRESH_TOKEN_GRANT: &str = "refresh_token";
+/// The grant type that lets a client get a token for itself.
+pub const CLIENT_CREDENTIALS_GRANT: &str = "client_credentials";
+/// The grant type that exchanges an approved device code for tokens (RFC 8628).
+pub const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// The grant type that exchanges a token for a narrower one (RFC 8693).
+pub const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+/// The grant types clients can be registered for.
+pub const SUPPORTED_GRANT_TYPES: [&str; 5] = [
+    AUTHORIZATION_CODE_GRANT,
+    REFRESH_TOKEN_GRANT,
+    CLIENT_CREDENTIALS_GRANT,
+    DEVICE_CODE_GRANT,
+    TOKEN_EXCHANGE_GRANT,
+];
+
+/// Represents the type of an OAuth client (RFC 6749, section 2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientType {
+    /// A client that can keep a secret, e.g. a server-side web app.
+    Confidential,
+    /// A client that can't keep a secret, e.g. a single-page or mobile app.
+    Public,
+}
+
+impl ClientType {
+    /// Returns the name of the client type as stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientType::Confidential => "confidential",
+            ClientType::Public => "public",
+        }
+    }
+}
+
+/// Represents a newly registered client.
+#[derive(Debug)]
+pub struct RegisteredClient {
+    /// The registered client.
+    pub client: OAuthClient,
+    /// The client secret of a confidential client. It is only returned once.
+    pub client_secret: Option<String>,
+}
+
+/// Describes a client to register.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientRegistration {
+    /// The name of the client, shown to users.
+    pub name: String,
+    /// Whether the client is confidential or public.
+    pub client_type: ClientType,
+    /// The redirect URIs the client may use.
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    /// The grant types the client may use. Defaults to the authorization code and refresh
+    /// token grants.
+    #[serde(default)]
+    pub grant_types: Option<Vec<String>>,
+    /// The scopes the client may be granted. Requests for other scopes are rejected.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// The URL of the client's logo, shown to users next to its name.
+    #[serde(default)]
+    pub logo_uri: Option<String>,
+    /// The lifetime of access tokens issued to the client in seconds. Defaults to the server
+    /// setting.
+    #[serde(default)]
+    pub access_token_lifetime: Option<i64>,
+    /// The lifetime of refresh tokens issued to the client in seconds. Defaults to the server
+    /// setting.
+    #[serde(default)]
+    pub refresh_token_lifetime: Option<i64>,
+    /// Whether the client belongs to the operator of the server, so that users aren't asked
+    /// to consent.
+    #[serde(default)]
+    pub first_party: bool,
+}
+
+/// Represents the parameters of an authorization request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthorizationParams {
+    /// The requested response type, which has to be `code`.
+    pub response_type: Option<String>,
+    /// The ID of the client.
+    pub client_id: Option<String>,
+    /// The redirect URI. It can be left out if the client has exactly one.
+    pub redirect_uri: Option<String>,
+    /// The space-separated scopes requested by the client.
+    pub scope: Option<String>,
+    /// An opaque value that is passed back to the client unchanged.
+    pub state: Option<String>,
+    /// The PKCE code challenge.
+    pub code_challenge: Option<String>,
+    /// The PKCE method, which has to be `S256`.
+    pub code_challenge_method: Option<String>,
+    /// The OpenID Connect nonce, which is copied into the ID token.
+    pub nonce: Option<String>,
+}
+
+/// Represents a valid authorization request.
+#[derive(Debug, Clone)]
+pub struct ValidatedAuthorization {
+    /// The client that asks for authorization.
+    pub client: OAuthClient,
+    /// The redirect URI the response is sent to.
+    pub redirect_uri: String,
+    /// Whether the request named the redirect URI, which then has to be sent again to exchange
+    /// the code (RFC 6749, section 4.1.3).
+    pub explicit_redirect_uri: bool,
+    /// The normalized scopes requested by the client.
+    pub scope: Option<String>,
+    /// The state to pass back to the client.
+    pub state: Option<String>,
+    /// The PKCE code challenge.
+    pub code_challenge: String,
+    /// The OpenID Connect nonce.
+    pub nonce: Option<String>,
+}
+
+/// Represents a failed authorization request.
+#[derive(Debug)]
+pub enum AuthorizationError {
+    /// The client or the redirect URI is invalid, so the user must not be redirected.
+    InvalidClient(String),
+    /// The error has to be reported to the client at this redirect URL.
+    Redirect(String),
+    /// An internal error occurred.
+    Failed(CustomError),
+}
+
+impl From<CustomError> for AuthorizationError {
+    fn from(error: CustomError) -> Self {
+        AuthorizationError::Failed(error)
+    }
+}
+
+/// Represents the body of a token request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TokenRequest {
+    /// The grant type: `authorization_code`, `refresh_token`, `client_credentials` or the
+    /// device code grant.
+    pub grant_type: String,
+    /// The authorization code.
+    pub code: Option<String>,
+    /// The redirect URI the code was delivered to. Required if the authorization request named
+    /// it.
+    pub redirect_uri: Option<String>,
+    /// The PKCE code verifier.
+    pub code_verifier: Option<String>,
+    /// The refresh token.
+    pub refresh_token: Option<String>,
+    /// The space-separated scopes requested with the client credentials or token exchange
+    /// grant.
+    pub scope: Option<String>,
+    /// The device code, for the device code grant.
+    pub device_code: Option<String>,
+    /// The token to exchange, for the token exchange grant.
+    pub subject_token: Option<String>,
+    /// The type of the token to exchange.
+    pub subject_token_type: Option<String>,
+    /// The type of the token to issue. Only access tokens are supported.
+    pub requested_token_type: Option<String>,
+    /// The service the exchanged token is intended for.
+    pub audience: Option<String>,
+    /// The ID of the client, unless it authenticates with HTTP Basic.
+    pub client_id: Option<String>,
+    /// The secret of a confidential client, unless it authenticates with HTTP Basic.
+    pub client_secret: Option<String>,
+    /// The thumbprint of the key of a verified DPoP proof sent with the request. It is set by
+    /// the token endpoint, never deserialized from the request body.
+    #[serde(skip)]
+    pub dpop_jkt: Option<String>,
+}
+
+/// Represents the credentials a client presents at the token endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCredentials {
+    /// The ID of the client.
+    pub client_id: String,
+    /// The secret of the client, if any.
+    pub client_secret: Option<String>,
+}
+
+/// Represents a successful token response (RFC 6749, section 5.1).
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    /// The JWT access token.
+    pub access_token: String,
+    /// The type of the access token.
+    pub token_type: String,
+    /// The lifetime of the access token in seconds.
+    pub expires_in: i64,
+    /// The opaque refresh token. Service tokens come without one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// The granted scopes, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// The OpenID Connect ID token, if the `openid` scope was granted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    /// The type of the issued token, for the token exchange grant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issued_token_type: Option<String>,
+}
+
+/// Returns the lifetime of authorization codes in seconds.
+///
+/// The lifetime is read from the `OAUTH_CODE_LIFETIME_SECONDS` environment variable and
+/// defaults to one minute if it is missing or invalid.
+pub fn authorization_code_lifetime() -> i64 {
+    env::var(AUTHORIZATION_CODE_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_AUTHORIZATION_CODE_LIFETIME_SECONDS)
+}
+
+/// Returns the URL of the login page that handles authorization requests.
+///
+/// The URL is read from the `OAUTH_LOGIN_URL` environment variable. If it is set, valid
+/// authorization requests are redirected to it with their parameters.
+pub fn login_url() -> Option<String> {
+    env::var(LOGIN_URL_ENV).ok().filter(|url| !url.is_empty())
+}
+
+/// Creates an OAuth error.
+pub(crate) fn oauth_error(code: &'static str, description: &str) -> CustomError {
+    CustomError::OAuthError(code, description.to_string())
+}
+
+/// Registers a new client.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `registration` - The client to register.
+///
+/// # Returns
+///
+/// A `Result` containing the client and, for confidential clients, its secret.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - A grant type, redirect URI, scope, logo URI or token lifetime is invalid.
+/// - The authorization code grant is used without a redirect URI.
+/// - A public client uses the client credentials or token exchange grant.
+/// - Storing the client fails.
+pub async fn register_client(
+    db: &Database,
+    registration: ClientRegistration,
+) -> Result<RegisteredClient, CustomError> {
+    let client_type = registration.client_type;
+    let client_secret = match client_type {
+        ClientType::Confidential => Some(generate_opaque_token()),
+        ClientType::Public => None,
+    };
+    let client = OAuthClient {
+        client_id: Uuid::new_v4().to_string(),
+        client_type: client_type.as_str().to_string(),
+        secret_hash: client_secret.as_deref().map(hash_token),
+        disabled: false,
+        created_at: Utc::now().timestamp(),
+        ..validate_registration(registration)?
+    };
+    db.create_oauth_client(&client).await?;
+    tracing::info!(
+        "Registered {} client {}",
+        client.client_type,
+        client.client_id
+    );
+
+    Ok(RegisteredClient {
+        client,
+        client_secret,
+    })
+}
+
+/// Replaces the settings of a client. Its ID, type, secret and tokens stay the same.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client_id` - The ID of the client.
+/// * `registration` - The new settings of the client.
+///
+/// # Returns
+///
+/// A `Result` containing the updated client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The client doesn't exist.
+/// - The client type would change.
+/// - The settings are invalid, like for [`register_client`].
+/// - Storing the client fails.
+pub async fn update_client(
+    db: &Database,
+    client_id: &str,
+    registration: ClientRegistration,
+) -> Result<OAuthClient, CustomError> {
+    let existing = db
+        .get_oauth_client(client_id)
+        .await?
+        .ok_or(CustomError::ClientNotFound)?;
+    if registration.client_type.as_str() != existing.client_type {
+        return Err(CustomError::InvalidClient(
+            "The client type can't be changed".to_string(),
+        ));
+    }
+
+    let client = OAuthClient {
+        client_id: existing.client_id,
+        client_type: existing.client_type,
+        secret_hash: existing.secret_hash,
+        disabled: existing.disabled,
+        created_at: existing.created_at,
+        ..validate_registration(registration)?
+    };
+    if !db.update_oauth_client(&client).await? {
+        return Err(CustomError::ClientNotFound);
+    }
+    tracing::info!("Updated client {}", client.client_id);
+    Ok(client)
+}
+
+/// Checks the settings of a client to register or update.
+///
+/// # Returns
+///
+/// A `Result` containing a client with the normalized settings. Its ID, secret and creation
+/// time are left empty for the caller to fill in.
+pub(crate) fn validate_registration(
+    registration: ClientRegistration,
+) -> Result<OAuthClient, CustomError> {
+    let client_type = registration.client_type;
+    let grant_types = registration.grant_types.unwrap_or_else(|| {
+        vec![
+            AUTHORIZATION_CODE_GRANT.to_string(),
+            REFRESH_TOKEN_GRANT.to_string(),
+        ]
+    });
+    if let Some(grant_type) = grant_types
+        .iter()
+        .find(|grant_type| !SUPPORTED_GRANT_TYPES.contains(&grant_type.as_str()))
+    {
+        return Err(CustomError::InvalidClient(format!(
+            "Unsupported grant type: {}",
+            grant_type
+        )));
+    }
+    let has_grant = |name: &str| grant_types.iter().any(|grant_type| grant_type == name);
+    if let Some(grant_type) = [CLIENT_CREDENTIALS_GRANT, TOKEN_EXCHANGE_GRANT]
+        .into_iter()
+        .find(|grant_type| has_grant(grant_type) && client_type == ClientType::Public)
+    {
+        return Err(CustomError::InvalidClient(format!(
+            "Public clients can't use the {} grant",
+            grant_type
+        )));
+    }
+    if has_grant(AUTHORIZATION_CODE_GRANT) && registration.redirect_uris.is_empty() {
+        return Err(CustomError::InvalidClient(
+            "At least one redirect URI is required".to_string(),
+        ));
+    }
+    for uri in &registration.redirect_uris {
+        validate_redirect_uri(uri, client_type)?;
+    }
+    let scopes = match normalize_scope(Some(&registration.scopes.join(" "))) {
+        Ok(scope) => scope
+            .map(|scope| scope.split(' ').map(str::to_string).collect())
+            .unwrap_or_default(),
+        Err(_) => {
+            return Err(CustomError::InvalidClient(
+                "A scope is malformed".to_string(),
+            ))
+        }
+    };
+    if let Some(uri) = &registration.logo_uri {
+        // Logos are loaded by the user's browser, so they have to be served over HTTPS
+        if !uri.starts_with("https://") || validate_redirect_uri(uri, client_type).is_err() {
+            return Err(CustomError::InvalidClient(format!(
+                "Invalid logo URI: {}",
+                uri
+            )));
+        }
+    }
+    if [
+        registration.access_token_lifetime,
+        registration.refresh_token_lifetime,
+    ]
+    .into_iter()
+    .flatten()
+    .any(|lifetime| lifetime <= 0)
+    {
+        return Err(CustomError::InvalidClient(
+            "Token lifetimes must be positive".to_string(),
+        ));
+    }
+
+    Ok(OAuthClient {
+        client_id: String::new(),
+        name: registration.name,
+        client_type: client_type.as_str().to_string(),
+        secret_hash: None,
+        redirect_uris: registration.redirect_uris,
+        grant_types,
+        scopes,
+        logo_uri: registration.logo_uri,
+        access_token_lifetime: registration.access_token_lifetime,
+        refresh_token_lifetime: registration.refresh_token_lifetime,
+        first_party: registration.first_party,
+        disabled: false,
+        created_at: 0,
+    })
+}
+
+/// Replaces the secret of a confidential client. The old secret stops working immediately.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client_id` - The ID of the client.
+///
+/// # Returns
+///
+/// A `Result` containing the new secret.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The client doesn't exist or is public.
+/// - Storing the secret fails.
+pub async fn rotate_client_secret(db: &Database, client_id: &str) -> Result<String, CustomError> {
+    let client = db
+        .get_oauth_client(client_id)
+        .await?
+        .ok_or(CustomError::ClientNotFound)?;
+    if client.secret_hash.is_none() {
+        return Err(CustomError::InvalidClient(
+            "Public clients have no secret".to_string(),
+        ));
+    }
+
+    let client_secret = generate_opaque_token();
+    if !db
+        .update_oauth_client_secret(client_id, &hash_token(&client_secret))
+        .await?
+    {
+        return Err(CustomError::ClientNotFound);
+    }
+    tracing::info!("Rotated the secret of client {}", client_id);
+    Ok(client_secret)
+}
+
+/// Checks that a redirect URI can be registered.
+///
+/// Redirect URIs have to be absolute and can't have a fragment. `https` can be used by all
+/// clients and `http` only on the loopback interface. Public clients may also use a private
+/// scheme in reverse domain notation, e.g. `com.example.app:/callback` (RFC 8252).
+///
+/// # Arguments
+///
+/// * `uri` - The redirect URI.
+/// * `client_type` - The type of the client that registers the URI.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the URI is valid.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The URI isn't allowed for the client type.
+pub fn validate_redirect_uri(uri: &str, client_type: ClientType) -> Result<(), CustomError> {
+    let invalid = || CustomError::InvalidClient(format!("Invalid redirect URI: {}", uri));
+
+    if uri.contains('#') || uri.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(invalid());
+    }
+    let (scheme, rest) = uri.split_once(':').ok_or_else(invalid)?;
+    let mut scheme_chars = scheme.chars();
+    if !scheme_chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        || !scheme_chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+.-".contains(c))
+    {
+        return Err(invalid());
+    }
+
+    match scheme {
+        "https" | "http" => {
+            let authority = rest
+                .strip_prefix("//")
+                .ok_or_else(invalid)?
+                .split(['/', '?'])
+                .next()
+                .unwrap_or_default();
+            let host = match authority.rsplit_once(':') {
+                Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+                _ => authority,
+            };
+            if host.is_empty() || authority.contains('@') {
+                return Err(invalid());
+            }
+            if scheme == "http" && !["localhost", "127.0.0.1", "[::1]"].contains(&host) {
+                return Err(invalid());
+            }
+            Ok(())
+        }
+        _ if client_type == ClientType::Public && scheme.contains('.') && !rest.is_empty() => {
+            Ok(())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Normalizes the requested scopes.
+///
+/// # Returns
+///
+/// The space-separated scopes without duplicates, `None` if no scope was requested, or an
+/// error if a scope contains invalid characters (RFC 6749, section 3.3).
+pub(crate) fn normalize_scope(scope: Option<&str>) -> Result<Option<String>, CustomError> {
+    let mut scopes: Vec<&str> = Vec::new();
+    for token in scope
+        .unwrap_or_default()
+        .split(' ')
+        .filter(|s| !s.is_empty())
+    {
+        if !token
+            .chars()
+            .all(|c| c == '!' || ('#'..='[').contains(&c) || (']'..='~').contains(&c))
+        {
+            return Err(oauth_error("invalid_scope", "The scope is malformed"));
+        }
+        if !scopes.contains(&token) {
+            scopes.push(token);
+        }
+    }
+    Ok((!scopes.is_empty()).then(|| scopes.join(" ")))
+}
+
+/// Checks that a client may be granted the requested scopes.
+///
+/// # Arguments
+///
+/// * `client` - The client.
+/// * `scope` - The normalized scopes requested by the client.
+///
+/// # Returns
+///
+/// A `Result` indicating whether all scopes are among the client's scopes, with the OAuth
+/// error `invalid_scope` if not.
+pub(crate) fn check_client_scope(
+    client: &OAuthClient,
+    scope: Option<&str>,
+) -> Result<(), CustomError> {
+    if let Some(denied) = scope
+        .unwrap_or_default()
+        .split(' ')
+        .filter(|requested| !requested.is_empty())
+        .find(|requested| !client.scopes.iter().any(|allowed| allowed == requested))
+    {
+        tracing::warn!(
+            "Client {} requested the scope {} without being allowed to",
+            client.client_id,
+            denied
+        );
+        return Err(oauth_error(
+            "invalid_scope",
+            "The client may not request this scope",
+        ));
+    }
+    Ok(())
+}
+
+/// Validates an authorization request.
+///
+/// The client and the redirect URI are checked first. Errors about them are returned as
+/// [`AuthorizationError::InvalidClient`], since the user must never be sent to an unverified
+/// URI. All other errors are reported to the client at its redirect URI.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `params` - The parameters of the authorization request.
+///
+/// # Returns
+///
+/// A `Result` containing the validated request.
+///
+/// # Errors
+///
+/// Returns an `AuthorizationError` if:
+/// - The client is unknown or the redirect URI isn't registered for it.
+/// - The response type isn't `code`, the scope is malformed or not allowed for the client, or
+///   PKCE with `S256` is missing.
+/// - Looking up the client fails.
+pub async fn validate_authorization_request(
+    db: &Database,
+    params: &AuthorizationParams,
+) -> Result<ValidatedAuthorization, AuthorizationError> {
+    let client_id = params
+        .client_id
+        .as_deref()
+        .ok_or_else(|| AuthorizationError::InvalidClient("client_id is required".to_string()))?;
+    let client = db
+        .get_oauth_client(client_id)
+        .await?
+        .filter(|client| !client.disabled)
+        .ok_or_else(|| AuthorizationError::InvalidClient("Unknown client".to_string()))?;
+    if !client
+        .grant_types
+        .iter()
+        .any(|grant_type| grant_type == AUTHORIZATION_CODE_GRANT)
+    {
+        return Err(AuthorizationError::InvalidClient(
+            "The client may not use the authorization code grant".to_string(),
+        ));
+    }
+
+    let redirect_uri = match (&params.redirect_uri, client.redirect_uris.as_slice()) {
+        (Some(uri), registered) if registered.contains(uri) => uri.clone(),
+        (None, [only]) => only.clone(),
+        (Some(_), _) => {
+            return Err(AuthorizationError::InvalidClient(
+                "The redirect URI isn't registered for the client".to_string(),
+            ))
+        }
+        (None, _) => {
+            return Err(AuthorizationError::InvalidClient(
+                "redirect_uri is required".to_string(),
+            ))
+        }
+    };
+
+    // From here on errors are sent back to the client.
+    let state = params.state.as_deref();
+    let redirect_error = |code: &str, description: &str| {
+        let mut query = vec![("error", code), ("error_description", description)];
+        if let Some(state) = state {
+            query.push(("state", state));
+        }
+        AuthorizationError::Redirect(redirect_url(&redirect_uri, &query))
+    };
+
+    if params.response_type.as_deref() != Some("code") {
+        return Err(redirect_error(
+            "unsupported_response_type",
+            "Only the code response type is supported",
+        ));
+    }
+    let scope = match normalize_scope(params.scope.as_deref()) {
+        Ok(scope) => scope,
+        Err(_) => return Err(redirect_error("invalid_scope", "The scope is malformed")),
+    };
+    if check_client_scope(&client, scope.as_deref()).is_err() {
+        return Err(redirect_error(
+            "invalid_scope",
+            "The client may not request this scope",
+        ));
+    }
+    let code_challenge = match params.code_challenge.as_deref() {
+        Some(challenge) if is_valid_code_challenge(challenge) => challenge.to_string(),
+        _ => {
+            return Err(redirect_error(
+                "invalid_request",
+                "A PKCE code challenge is required",
+            ))
+        }
+    };
+    if params.code_challenge_method.as_deref() != Some(CODE_CHALLENGE_METHOD) {
+        return Err(redirect_error(
+            "invalid_request",
+            "The code challenge method must be S256",
+        ));
+    }
+
+    Ok(ValidatedAuthorization {
+        client,
+        redirect_uri,
+        explicit_redirect_uri: params.redirect_uri.is_some(),
+        scope,
+        state: params.state.clone(),
+        code_challenge,
+        nonce: params.nonce.clone(),
+    })
+}
+
+/// Issues an authorization code for a user who approved an authorization request.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `authorization` - The validated authorization request.
+/// * `user_id` - The ID of the authenticated user.
+/// * `auth_methods` - The methods the user authenticated with.
+///
+/// # Returns
+///
+/// A `Result` containing the redirect URL that delivers the code to the client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Storing the code fails.
+pub async fn issue_authorization_code(
+    db: &Database,
+    authorization: &ValidatedAuthorization,
+    user_id: &str,
+    auth_methods: &[String],
+) -> Result<String, CustomError> {
+    let code = generate_opaque_token();
+    let now = Utc::now().timestamp();
+    db.store_authorization_code(&AuthorizationCode {
+        code_hash: hash_token(&code),
+        client_id: authorization.client.client_id.clone(),
+        user_id: user_id.to_string(),
+        redirect_uri: authorization.redirect_uri.clone(),
+        explicit_redirect_uri: authorization.explicit_redirect_uri,
+        code_challenge: authorization.code_challenge.clone(),
+        scope: authorization.scope.clone(),
+        auth_methods: auth_methods.to_vec(),
+        nonce: authorization.nonce.clone(),
+        auth_time: now,
+        family_id: Uuid::new_v4().to_string(),
+        expires_at: now + authorization_code_lifetime(),
+        used: false,
+    })
+    .await?;
+    tracing::info!(
+        "Issued authorization code to client {} for user {}",
+        authorization.client.client_id,
+        user_id
+    );
+
+    let mut query = vec![("code", code.as_str())];
+    if let Some(state) = &authorization.state {
+        query.push(("state", state));
+    }
+    Ok(redirect_url(&authorization.redirect_uri, &query))
+}
+
+/// Appends query parameters to a redirect URI.
+///
+/// # Arguments
+///
+/// * `redirect_uri` - The redirect URI, which may already have a query.
+/// * `query` - The parameters to append.
+///
+/// # Returns
+///
+/// The redirect URL.
+pub fn redirect_url(redirect_uri: &str, query: &[(&str, &str)]) -> String {
+    let mut url = redirect_uri.to_string();
+    let mut separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    for (name, value) in query {
+        url.push(separator);
+        url.push_str(name);
+        url.push('=');
+        url.push_str(&encode_query_component(value));
+        separator = '&';
+    }
+    url
+}
+
+/// Percent-encodes everything but the unreserved characters (RFC 3986, section 2.3).
+fn encode_query_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Computes the `S256` code challenge of a code verifier.
+///
+/// # Arguments
+///
+/// * `code_verifier` - The code verifier.
+///
+/// # Returns
+///
+/// The base64url-encoded SHA-256 hash of the verifier.
+pub fn code_challenge(code_verifier: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Checks that a code challenge looks like a base64url-encoded SHA-256 hash.
+fn is_valid_code_challenge(challenge: &str) -> bool {
+    challenge.len() == 43
+        && challenge
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Checks that a code verifier is well-formed and matches a code challenge.
+///
+/// # Arguments
+///
+/// * `code_verifier` - The verifier presented by the client.
+/// * `challenge` - The challenge sent with the authorization request.
+///
+/// # Returns
+///
+/// `true` if the verifier has 43 to 128 unreserved characters and its hash is the challenge.
+pub fn verify_code_challenge(code_verifier: &str, challenge: &str) -> bool {
+    let well_formed = (43..=128).contains(&code_verifier.len())
+        && code_verifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+    well_formed
+        && code_challenge(code_verifier)
+            .as_bytes()
+            .ct_eq(challenge.as_bytes())
+            .into()
+}
+
+/// Parses client credentials from an `Authorization: Basic` header.
+///
+/// # Arguments
+///
+/// * `header` - The value of the authorization header.
+///
+/// # Returns
+///
+/// The credentials, or `None` if the header isn't a valid Basic header.
+pub fn basic_credentials(header: &str) -> Option<ClientCredentials> {
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some(ClientCredentials {
+        client_id: client_id.to_string(),
+        client_secret: Some(client_secret.to_string()),
+    })
+}
+
+/// Authenticates a client at the token endpoint.
+///
+/// Confidential clients have to present their secret. Public clients only identify
+/// themselves.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `credentials` - The credentials presented by the client.
+///
+/// # Returns
+///
+/// A `Result` containing the authenticated client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The client is unknown or the secret is missing or wrong.
+/// - Looking up the client fails.
+pub async fn authenticate_client(
+    db: &Database,
+    credentials: &ClientCredentials,
+) -> Result<OAuthClient, CustomError> {
+    let invalid_client = || oauth_error("invalid_client", "Client authentication failed");
+
+    let client = db
+        .get_oauth_client(&credentials.client_id)
+        .await?
+        .filter(|client| !client.disabled)
+        .ok_or_else(invalid_client)?;
+    let authenticated = match (&client.secret_hash, &credentials.client_secret) {
+        (Some(secret_hash), Some(secret)) => hash_token(secret)
+            .as_bytes()
+            .ct_eq(secret_hash.as_bytes())
+            .into(),
+        (None, None) => true,
+        _ => false,
+    };
+    if !authenticated {
+        tracing::warn!("Authentication of client {} failed", client.client_id);
+        return Err(invalid_client());
+    }
+    Ok(client)
+}
+
+/// Authenticates the client of a request to the token or device authorization endpoint.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client_id` - The `client_id` parameter of the request.
+/// * `client_secret` - The `client_secret` parameter of the request.
+/// * `basic` - The credentials from an `Authorization: Basic` header, if any.
+///
+/// # Returns
+///
+/// A `Result` containing the authenticated client.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The client uses more than one authentication method.
+/// - The `client_id` parameter names another client than the `Authorization` header.
+/// - The client can't be authenticated.
+pub async fn authenticate_request_client(
+    db: &Database,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+    basic: Option<ClientCredentials>,
+) -> Result<OAuthClient, CustomError> {
+    let credentials = match (basic, client_id) {
+        (Some(_), Some(_)) if client_secret.is_some() => {
+            return Err(oauth_error(
+                "invalid_request",
+                "Only one client authentication method may be used",
+            ))
+        }
+        (Some(credentials), Some(client_id)) if credentials.client_id != client_id => {
+            return Err(oauth_error(
+                "invalid_request",
+                "The client_id doesn't match the authenticated client",
+            ))
+        }
+        (Some(credentials), _) => credentials,
+        (None, Some(client_id)) => ClientCredentials {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.map(str::to_string),
+        },
+        (None, None) => {
+            return Err(oauth_error(
+                "invalid_client",
+                "Client authentication failed",
+            ));
+        }
+    };
+    authenticate_client(db, &credentials).await
+}
+
+/// Handles a token request.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `request` - The token request.
+/// * `basic` - The credentials from an `Authorization: Basic` header, if any.
+/// * `issuer` - The issuer URL of the tenant, used in ID tokens.
+///
+/// # Returns
+///
+/// A `Result` containing the token response.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The client can't be authenticated.
+/// - The grant type is unsupported or a parameter is missing.
+/// - The code or refresh token is invalid, expired, used or was issued to another client.
+/// - The code verifier doesn't match the code challenge.
+/// - Issuing the tokens fails.
+pub async fn exchange_token(
+    db: &Database,
+    request: &TokenRequest,
+    basic: Option<ClientCredentials>,
+    issuer: &str,
+) -> Result<TokenResponse, CustomError> {
+    let client = authenticate_request_client(
+        db,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+        basic,
+    )
+    .await?;
+
+    let grant_type = request.grant_type.as_str();
+    if !SUPPORTED_GRANT_TYPES.contains(&grant_type) {
+        return Err(oauth_error(
+            "unsupported_grant_type",
+            "The grant type isn't supported",
+        ));
+    }
+    if !client
+        .grant_types
+        .iter()
+        .any(|allowed| allowed == grant_type)
+    {
+        tracing::warn!(
+            "Client {} used the {} grant without being allowed to",
+            client.client_id,
+            grant_type
+        );
+        return Err(oauth_error(
+            "unauthorized_client",
+            "The client may not use this grant type",
+        ));
+    }
+
+    match grant_type {
+        AUTHORIZATION_CODE_GRANT => exchange_authorization_code(db, &client, request, issuer).await,
+        REFRESH_TOKEN_GRANT => exchange_refresh_token(db, &client, request).await,
+        DEVICE_CODE_GRANT => exchange_device_code(db, &client, request).await,
+        TOKEN_EXCHANGE_GRANT => exchange_subject_token(db, &client, request).await,
+        _ => issue_service_token(db, &client, request),
+    }
+}
+
+/// Builds the token response for a token pair.
+pub(crate) fn token_response(
+    tokens: TokenPair,
+    scope: Option<String>,
+    id_token: Option<String>,
+) -> TokenResponse {
+    TokenResponse {
+        access_token: tokens.access_token,
+        token_type: tokens.token_type,
+        expires_in: tokens.expires_in,
+        refresh_token: Some(tokens.refresh_token),
+        scope,
+        id_token,
+        issued_token_type: None,
+    }
+}
+
+/// Exchanges a refresh token issued to a client for a new token pair with the same scopes.
+async fn exchange_refresh_token(
+    db: &Database,
+    client: &OAuthClient,
+    request: &TokenRequest,
+) -> Result<TokenResponse, CustomError> {
+    let refresh_token = request
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| oauth_error("invalid_request", "refresh_token is required"))?;
+    let scope = db
+        .find_refresh_token(&hash_token(refresh_token))
+        .await?
+        .and_then(|stored| stored.scope);
+    let tokens =
+        rotate_client_refresh_token(db, refresh_token, Some(client), request.dpop_jkt.as_deref())
+            .await
+            .map_err(|error| match error {
+                CustomError::InvalidRefreshToken | CustomError::RefreshTokenReuse => {
+                    oauth_error("invalid_grant", "The refresh token is invalid")
+                }
+                error => error,
+            })?;
+    Ok(token_response(tokens, scope, None))
+}
+
+/// Issues an access token to a client acting on its own behalf (RFC 6749, section 4.4).
+///
+/// The client is granted the requested scopes, or all of its scopes if none are requested. No
+/// refresh token is issued, since the client can always ask for a new access token.
+fn issue_service_token(
+    db: &Database,
+    client: &OAuthClient,
+    request: &TokenRequest,
+) -> Result<TokenResponse, CustomError> {
+    let scope = match normalize_scope(request.scope.as_deref())? {
+        Some(scope) => {
+            check_client_scope(client, Some(&scope))?;
+            Some(scope)
+        }
+        None => (!client.scopes.is_empty()).then(|| client.scopes.join(" ")),
+    };
+
+    let grant = ClientGrant::new(client, scope.clone(), request.dpop_jkt.clone());
+    let access_token = tokens::issue_service_token(db, &grant)?;
+    tracing::info!("Issued service token to client {}", client.client_id);
+    Ok(TokenResponse {
+        access_token,
+        token_type: tokens::token_type(grant.jkt.as_deref()),
+        expires_in: grant.access_token_lifetime,
+        refresh_token: None,
+        scope,
+        id_token: None,
+        issued_token_type: None,
+    })
+}
+
+/// Exchanges an authorization code for a token pair, and an ID token if `openid` was granted.
+///
+/// A code can only be exchanged once. If it is presented again, the tokens issued for it are
+/// revoked (RFC 6749, section 4.1.2).
+async fn exchange_authorization_code(
+    db: &Database,
+    client: &OAuthClient,
+    request: &TokenRequest,
+    issuer: &str,
+) -> Result<TokenResponse, CustomError> {
+    let invalid_grant = || oauth_error("invalid_grant", "The authorization code is invalid");
+
+    let code = request
+        .code
+        .as_deref()
+        .ok_or_else(|| oauth_error("invalid_request", "code is required"))?;
+    let code_verifier = request
+        .code_verifier
+        .as_deref()
+        .ok_or_else(|| oauth_error("invalid_request", "code_verifier is required"))?;
+
+    // The code is only used once the client proved it is the one the code was issued to, so
+    // that others who intercepted it can't burn it
+    let code_hash = hash_token(code);
+    let stored = db
+        .get_authorization_code(&code_hash)
+        .await?
+        .ok_or_else(invalid_grant)?;
+    // The redirect URI has to be repeated exactly if the authorization request named it
+    let redirect_uri_matches = match &request.redirect_uri {
+        Some(uri) => uri == &stored.redirect_uri,
+        None => !stored.explicit_redirect_uri,
+    };
+    if stored.expires_at <= Utc::now().timestamp()
+        || stored.client_id != client.client_id
+        || !redirect_uri_matches
+    {
+        return Err(invalid_grant());
+    }
+    if !verify_code_challenge(code_verifier, &stored.code_challenge) {
+        tracing::warn!("PKCE verification failed for client {}", stored.client_id);
+        return Err(invalid_grant());
+    }
+
+    let stored = db
+        .consume_authorization_code(&code_hash)
+        .await?
+        .ok_or_else(invalid_grant)?;
+    if stored.used {
+        tracing::warn!(
+            "Authorization code replayed for client {}, revoking its tokens",
+            stored.client_id
+        );
+        db.revoke_refresh_token_family(&stored.family_id).await?;
+        return Err(invalid_grant());
+    }
+    if db.get_user_by_id(&stored.user_id).await?.is_none() {
+        return Err(invalid_grant());
+    }
+
+    let grant = ClientGrant::new(client, stored.scope.clone(), request.dpop_jkt.clone());
+    let tokens = issue_client_token_pair(
+        db,
+        &stored.user_id,
+        &stored.family_id,
+        &stored.auth_methods,
+        &grant,
+    )
+    .await?;
+    let id_token = if has_scope(stored.scope.as_deref(), OPENID_SCOPE) {
+        Some(issue_id_token(db, issuer, &stored).await?)
+    } else {
+        None
+    };
+    tracing::info!(
+        "Exchanged authorization code of client {} for user {}",
+        client.client_id,
+        stored.user_id
+    );
+    Ok(token_response(tokens, stored.scope, id_token))
+}
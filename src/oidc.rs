@@ -0,0 +1,254 @@
+//! src/oidc.rs
+//!
+//! This module adds OpenID Connect on top of the OAuth authorization server: ID tokens,
+//! the discovery document and the claims released by the userinfo endpoint. Which claims are
+//! released depends on the granted scopes: `openid` is required, `profile` adds the name and
+//! `email` adds the email address.
+
+use crate::database::{AuthorizationCode, Database, User};
+use crate::dpop;
+use crate::encryption::{decrypt_with_nonce, generate_key};
+use crate::errors::custom_errors::CustomError;
+use crate::jwt::access_token_lifetime;
+use crate::keyring::keyring;
+use crate::oauth::SUPPORTED_GRANT_TYPES;
+use crate::tenants::DEFAULT_TENANT;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::env;
+
+const PUBLIC_URL_ENV: &str = "PUBLIC_URL";
+
+/// The scope that turns an OAuth request into an OpenID Connect request.
+pub const OPENID_SCOPE: &str = "openid";
+/// The scope that releases the name claims.
+pub const PROFILE_SCOPE: &str = "profile";
+/// The scope that releases the email claims.
+pub const EMAIL_SCOPE: &str = "email";
+
+/// Represents the claims of an ID token.
+///
+/// ID tokens have no `jti`, so they are never accepted in place of an access token.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdTokenClaims {
+    /// The issuer, the URL of the tenant.
+    pub iss: String,
+    /// The ID of the user.
+    pub sub: String,
+    /// The ID of the client the token is issued to.
+    pub aud: String,
+    /// The expiration timestamp of the token.
+    pub exp: i64,
+    /// The issued at timestamp of the token.
+    pub iat: i64,
+    /// The timestamp at which the user authenticated.
+    pub auth_time: i64,
+    /// The nonce sent with the authorization request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// The methods used to authenticate the user (RFC 8176).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<String>,
+    /// The authorized party, the client the token is issued to.
+    pub azp: String,
+    /// The user claims released by the granted scopes.
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+/// Returns the public base URL of the server.
+///
+/// The base URL is read from the `PUBLIC_URL` environment variable and defaults to the URL the
+/// request was sent to.
+///
+/// # Arguments
+///
+/// * `request_base_url` - The scheme and host of the request, e.g. `https://id.example.com`.
+///
+/// # Returns
+///
+/// The base URL, without a trailing slash.
+pub fn public_url(request_base_url: &str) -> String {
+    env::var(PUBLIC_URL_ENV)
+        .ok()
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| request_base_url.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Returns the issuer URL of a tenant.
+///
+/// The base URL is read from the `PUBLIC_URL` environment variable and defaults to the URL the
+/// request was sent to. Tenants other than the default one are addressed by their path
+/// prefix.
+///
+/// # Arguments
+///
+/// * `request_base_url` - The scheme and host of the request, e.g. `https://id.example.com`.
+/// * `tenant` - The ID of the tenant.
+///
+/// # Returns
+///
+/// The issuer URL, without a trailing slash.
+pub fn issuer(request_base_url: &str, tenant: &str) -> String {
+    let base_url = public_url(request_base_url);
+    if tenant == DEFAULT_TENANT {
+        base_url
+    } else {
+        format!("{}/realms/{}", base_url, tenant)
+    }
+}
+
+/// Checks whether a space-separated list of scopes contains a scope.
+///
+/// # Arguments
+///
+/// * `scope` - The granted scopes.
+/// * `name` - The scope to look for.
+///
+/// # Returns
+///
+/// `true` if the scope was granted.
+pub fn has_scope(scope: Option<&str>, name: &str) -> bool {
+    scope.is_some_and(|scope| scope.split(' ').any(|granted| granted == name))
+}
+
+/// Returns the claims about a user that the granted scopes release.
+///
+/// The name and email address are decrypted from the user record.
+///
+/// # Arguments
+///
+/// * `user` - The user.
+/// * `scope` - The granted scopes.
+///
+/// # Returns
+///
+/// A `Result` containing the claims.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The encryption key is missing or a field can't be decrypted.
+pub fn scope_claims(user: &User, scope: Option<&str>) -> Result<Map<String, Value>, CustomError> {
+    let mut claims = Map::new();
+    let profile = has_scope(scope, PROFILE_SCOPE);
+    let email = has_scope(scope, EMAIL_SCOPE);
+    if !profile && !email {
+        return Ok(claims);
+    }
+
+    let key_bytes: [u8; 32] = generate_key()?.into();
+    if profile {
+        let given_name = decrypt_with_nonce(&key_bytes, &user.encrypted_firstname)?;
+        let family_name = decrypt_with_nonce(&key_bytes, &user.encrypted_lastname)?;
+        claims.insert(
+            "name".to_string(),
+            json!(format!("{} {}", given_name, family_name)),
+        );
+        claims.insert("given_name".to_string(), json!(given_name));
+        claims.insert("family_name".to_string(), json!(family_name));
+        claims.insert("preferred_username".to_string(), json!(user.username));
+    }
+    if email {
+        claims.insert(
+            "email".to_string(),
+            json!(decrypt_with_nonce(&key_bytes, &user.encrypted_email)?),
+        );
+        claims.insert("email_verified".to_string(), json!(user.email_verified));
+    }
+    Ok(claims)
+}
+
+/// Issues an ID token for an exchanged authorization code.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `issuer` - The issuer URL of the tenant.
+/// * `code` - The exchanged authorization code.
+///
+/// # Returns
+///
+/// A `Result` containing the signed ID token.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user doesn't exist.
+/// - Decrypting the user's claims or signing the token fails.
+pub async fn issue_id_token(
+    db: &Database,
+    issuer: &str,
+    code: &AuthorizationCode,
+) -> Result<String, CustomError> {
+    let user = db
+        .get_user_by_id(&code.user_id)
+        .await?
+        .ok_or(CustomError::UserNotFound)?;
+
+    let now = Utc::now().timestamp();
+    let claims = IdTokenClaims {
+        iss: issuer.to_string(),
+        sub: code.user_id.clone(),
+        aud: code.client_id.clone(),
+        exp: now + access_token_lifetime(),
+        iat: now,
+        auth_time: code.auth_time,
+        nonce: code.nonce.clone(),
+        amr: code.auth_methods.clone(),
+        azp: code.client_id.clone(),
+        claims: scope_claims(&user, code.scope.as_deref())?,
+    };
+    Ok(keyring(db.tenant())?.sign(&claims)?)
+}
+
+/// Builds the OpenID Connect discovery document of a tenant.
+///
+/// # Arguments
+///
+/// * `issuer` - The issuer URL of the tenant.
+/// * `tenant` - The ID of the tenant.
+///
+/// # Returns
+///
+/// A `Result` containing the discovery document.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The keyring of the tenant can't be loaded.
+pub fn discovery_document(issuer: &str, tenant: &str) -> Result<Value, CustomError> {
+    let signing_algorithms: Vec<String> = keyring(tenant)?
+        .active_key()
+        .map(|key| format!("{:?}", key.algorithm))
+        .into_iter()
+        .collect();
+
+    Ok(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "device_authorization_endpoint": format!("{}/oauth/device/code", issuer),
+        "introspection_endpoint": format!("{}/oauth/introspect", issuer),
+        "revocation_endpoint": format!("{}/oauth/revoke", issuer),
+        "registration_endpoint": format!("{}/oauth/register", issuer),
+        "userinfo_endpoint": format!("{}/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "scopes_supported": [OPENID_SCOPE, PROFILE_SCOPE, EMAIL_SCOPE],
+        "response_types_supported": ["code"],
+        "grant_types_supported": SUPPORTED_GRANT_TYPES,
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": signing_algorithms,
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "dpop_signing_alg_values_supported": dpop::SUPPORTED_ALGORITHMS,
+        "claims_supported": [
+            "sub", "iss", "aud", "exp", "iat", "auth_time", "nonce", "amr", "azp",
+            "name", "given_name", "family_name", "preferred_username", "email", "email_verified",
+        ],
+    }))
+}
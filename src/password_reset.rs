@@ -0,0 +1,132 @@
+//! src/password_reset.rs
+//!
+//! This module handles password reset requests and single-use reset tokens.
+
+use crate::database::Database;
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::mailer::{EmailMessage, Mailer};
+use crate::tokens::generate_opaque_token;
+
+use chrono::Utc;
+use std::env;
+
+const PASSWORD_RESET_TOKEN_LIFETIME_ENV: &str = "PASSWORD_RESET_TOKEN_LIFETIME_SECONDS";
+const DEFAULT_PASSWORD_RESET_TOKEN_LIFETIME_SECONDS: i64 = 60 * 60;
+const PASSWORD_RESET_URL_ENV: &str = "PASSWORD_RESET_URL";
+
+/// Returns the lifetime of password reset tokens in seconds.
+///
+/// The lifetime is read from the `PASSWORD_RESET_TOKEN_LIFETIME_SECONDS` environment variable
+/// and defaults to one hour if it is missing or invalid.
+pub fn password_reset_token_lifetime() -> i64 {
+    env::var(PASSWORD_RESET_TOKEN_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_PASSWORD_RESET_TOKEN_LIFETIME_SECONDS)
+}
+
+/// Sends a password reset token to a user.
+///
+/// Nothing is sent if no user has the given email address, but the caller can't tell the
+/// difference, so the endpoint can't be used to find out who has an account. Requesting a new
+/// token invalidates the previous one.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `mailer` - The mailer used to deliver the token.
+/// * `email` - The email address entered by the user.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the user or storing the token fails.
+/// - Sending the email fails.
+pub async fn request_password_reset(
+    db: &Database,
+    mailer: &dyn Mailer,
+    email: &str,
+) -> Result<(), CustomError> {
+    // Generate the token up front so that known and unknown addresses take similar time.
+    let token = generate_opaque_token();
+    let token_hash = hash_token(&token);
+
+    let user = match db.get_user_by_email(email).await? {
+        Some(user) => user,
+        None => {
+            tracing::info!("Password reset requested for unknown email");
+            return Ok(());
+        }
+    };
+    let user_id = user.id.to_string();
+
+    let expires_in = password_reset_token_lifetime();
+    db.store_password_reset_token(&user_id, &token_hash, Utc::now().timestamp() + expires_in)
+        .await?;
+
+    let link = match env::var(PASSWORD_RESET_URL_ENV) {
+        Ok(url) if !url.is_empty() => format!("{}?token={}", url, token),
+        _ => token,
+    };
+    let body = format!(
+        "Hi {},\n\nsomeone asked to reset the password of your account. Use the following link or token to choose a new password:\n\n{}\n\nIt expires in {} minutes. If you didn't ask for this, you can ignore this email.",
+        user.username,
+        link,
+        expires_in / 60
+    );
+    mailer.send(&EmailMessage::new(&user.email, "Reset your password", body))?;
+
+    tracing::info!("Password reset token sent to user: {}", user_id);
+    Ok(())
+}
+
+/// Sets a new password using a password reset token.
+///
+/// The token is consumed, and all of the user's sessions are revoked, so anyone who had access
+/// to the account is logged out.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `token` - The password reset token.
+/// * `new_password` - The new password.
+///
+/// # Returns
+///
+/// A `Result` containing the ID of the user whose password was reset.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The token is unknown, expired or was already used.
+/// - Hashing or storing the new password fails.
+/// - Revoking the sessions fails.
+pub async fn reset_password(
+    db: &Database,
+    token: &str,
+    new_password: &str,
+) -> Result<String, CustomError> {
+    let stored = match db.consume_password_reset_token(&hash_token(token)).await? {
+        Some(stored) if stored.expires_at > Utc::now().timestamp() => stored,
+        _ => {
+            tracing::warn!("Invalid or expired password reset token presented");
+            return Err(CustomError::InvalidPasswordResetToken);
+        }
+    };
+
+    db.change_password(stored.user_id.clone(), new_password.to_string())
+        .await?;
+
+    // Log out every existing session.
+    db.increment_token_generation(&stored.user_id).await?;
+    db.revoke_user_refresh_tokens(&stored.user_id).await?;
+
+    tracing::info!("Password reset for user: {}", stored.user_id);
+    Ok(stored.user_id)
+}
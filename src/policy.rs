@@ -0,0 +1,619 @@
+//! src/policy.rs
+//!
+//! This module implements an attribute-based policy engine. Policies are lists of rules written
+//! in JSON that allow or deny an action on a resource depending on attributes of the subject,
+//! the resource and the environment. Every change to a policy is stored as a new version.
+
+use crate::database::{Database, StoredPolicy};
+use crate::errors::custom_errors::CustomError;
+use crate::groups::user_groups;
+use crate::rbac::{effective_roles, permission_matches};
+
+use actix_web::HttpRequest;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+const TRUSTED_PROXIES_ENV: &str = "TRUSTED_PROXIES";
+
+/// The effect of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    /// The rule allows the action.
+    Allow,
+    /// The rule denies the action. A matching deny rule always wins.
+    Deny,
+}
+
+/// A value in a condition, either an attribute path or a literal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Operand {
+    /// An attribute of the request, e.g. `{"attr": "resource.owner"}`.
+    Attribute {
+        /// The dot-separated path of the attribute, starting with `subject`, `action`,
+        /// `resource` or `environment`.
+        attr: String,
+    },
+    /// A literal JSON value.
+    Literal(Value),
+}
+
+/// A condition that has to hold for a rule to match.
+///
+/// Conditions are written as JSON objects with a single key, e.g.
+/// `{"eq": [{"attr": "resource.owner"}, {"attr": "subject.id"}]}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// Every condition holds.
+    All(Vec<Condition>),
+    /// At least one condition holds.
+    Any(Vec<Condition>),
+    /// The condition doesn't hold.
+    Not(Box<Condition>),
+    /// Both values are equal.
+    Eq(Operand, Operand),
+    /// The values are different.
+    Ne(Operand, Operand),
+    /// The first value is greater than the second.
+    Gt(Operand, Operand),
+    /// The first value is greater than or equal to the second.
+    Gte(Operand, Operand),
+    /// The first value is less than the second.
+    Lt(Operand, Operand),
+    /// The first value is less than or equal to the second.
+    Lte(Operand, Operand),
+    /// The first value is an element of the second, which must be an array.
+    In(Operand, Operand),
+    /// The first value, an array or string, contains the second.
+    Contains(Operand, Operand),
+    /// The attribute is present and not null.
+    Exists(String),
+}
+
+/// A single rule of a policy.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// The ID of the rule, unique within its policy.
+    pub id: String,
+    /// A human readable description of the rule.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Whether the rule allows or denies.
+    pub effect: Effect,
+    /// The actions the rule applies to, e.g. `document:edit` or `document:*`. Empty means all.
+    #[serde(default)]
+    pub actions: Vec<String>,
+    /// The resource types the rule applies to, e.g. `document`. Empty means all.
+    #[serde(default)]
+    pub resources: Vec<String>,
+    /// The condition that has to hold. No condition always holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+}
+
+/// The body of a policy as written by an administrator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolicyDocument {
+    /// A human readable description of the policy.
+    #[serde(default)]
+    pub description: String,
+    /// The rules of the policy, evaluated in order.
+    pub rules: Vec<Rule>,
+}
+
+/// A version of a policy.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Policy {
+    /// The unique name of the policy.
+    pub name: String,
+    /// The version number, starting at 1.
+    pub version: i64,
+    /// The policy document.
+    #[serde(flatten)]
+    pub document: PolicyDocument,
+    /// The ID of the user who created this version.
+    pub created_by: String,
+    /// The creation timestamp of this version.
+    pub created_at: i64,
+}
+
+/// The input of an authorization decision.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    /// The attributes of the subject, e.g. `id`, `roles` and `tenant`.
+    pub subject: Value,
+    /// The action, e.g. `document:edit`.
+    pub action: String,
+    /// The attributes of the resource. `type` selects the rules that apply.
+    pub resource: Value,
+    /// The attributes of the environment, e.g. `hour` and `weekday`.
+    #[serde(default)]
+    pub environment: Value,
+}
+
+/// The outcome of an authorization decision.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Decision {
+    /// Whether the action is allowed.
+    pub allowed: bool,
+    /// The name of the policy containing the deciding rule.
+    pub policy: Option<String>,
+    /// The version of the policy containing the deciding rule.
+    pub version: Option<i64>,
+    /// The ID of the deciding rule. `None` if no rule matched and access was denied by default.
+    pub rule: Option<String>,
+}
+
+impl Decision {
+    /// Creates the decision for a matched rule.
+    fn matched(policy: &Policy, rule: &Rule) -> Self {
+        Decision {
+            allowed: rule.effect == Effect::Allow,
+            policy: Some(policy.name.clone()),
+            version: Some(policy.version),
+            rule: Some(rule.id.clone()),
+        }
+    }
+}
+
+/// Checks that a policy name and document are well-formed.
+///
+/// # Arguments
+///
+/// * `name` - The name of the policy.
+/// * `document` - The policy document.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the policy is valid.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The name is empty or contains characters other than letters, digits, `-`, `_` and `.`.
+/// - A rule has an empty or duplicate ID.
+pub fn validate_policy(name: &str, document: &PolicyDocument) -> Result<(), CustomError> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(CustomError::InvalidPolicy(format!(
+            "Invalid policy name: {}",
+            name
+        )));
+    }
+
+    let mut rule_ids = BTreeSet::new();
+    for rule in &document.rules {
+        if rule.id.is_empty() || !rule_ids.insert(rule.id.as_str()) {
+            return Err(CustomError::InvalidPolicy(format!(
+                "Rule IDs must be unique and non-empty: {:?}",
+                rule.id
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates an authorization request against a set of policies.
+///
+/// Deny overrides allow: the first matching deny rule decides, otherwise the first matching
+/// allow rule decides. If no rule matches, the request is denied. Policies are evaluated in the
+/// given order and rules in the order they are written.
+///
+/// # Arguments
+///
+/// * `policies` - The policies in effect.
+/// * `request` - The authorization request.
+///
+/// # Returns
+///
+/// The decision.
+pub fn evaluate(policies: &[Policy], request: &AuthorizationRequest) -> Decision {
+    let input = json!({
+        "subject": request.subject,
+        "action": request.action,
+        "resource": request.resource,
+        "environment": request.environment,
+    });
+    let resource_type = request.resource.get("type").and_then(Value::as_str);
+
+    let mut allowed_by = None;
+    for policy in policies {
+        for rule in &policy.document.rules {
+            if !rule_applies(rule, &request.action, resource_type) {
+                continue;
+            }
+            if let Some(condition) = &rule.condition {
+                if !condition_holds(condition, &input) {
+                    continue;
+                }
+            }
+            match rule.effect {
+                Effect::Deny => return Decision::matched(policy, rule),
+                Effect::Allow => {
+                    allowed_by.get_or_insert_with(|| Decision::matched(policy, rule));
+                }
+            }
+        }
+    }
+
+    allowed_by.unwrap_or(Decision {
+        allowed: false,
+        policy: None,
+        version: None,
+        rule: None,
+    })
+}
+
+/// Checks whether a rule targets an action and resource type.
+fn rule_applies(rule: &Rule, action: &str, resource_type: Option<&str>) -> bool {
+    let action_matches = rule.actions.is_empty()
+        || rule
+            .actions
+            .iter()
+            .any(|pattern| permission_matches(pattern, action));
+    let resource_matches = rule.resources.is_empty()
+        || resource_type.is_some_and(|resource_type| {
+            rule.resources
+                .iter()
+                .any(|pattern| permission_matches(pattern, resource_type))
+        });
+    action_matches && resource_matches
+}
+
+/// Evaluates a condition against the request attributes.
+fn condition_holds(condition: &Condition, input: &Value) -> bool {
+    match condition {
+        Condition::All(conditions) => conditions.iter().all(|c| condition_holds(c, input)),
+        Condition::Any(conditions) => conditions.iter().any(|c| condition_holds(c, input)),
+        Condition::Not(condition) => !condition_holds(condition, input),
+        Condition::Eq(left, right) => values_equal(&resolve(left, input), &resolve(right, input)),
+        Condition::Ne(left, right) => !values_equal(&resolve(left, input), &resolve(right, input)),
+        Condition::Gt(left, right) => {
+            compare(&resolve(left, input), &resolve(right, input)) == Some(Ordering::Greater)
+        }
+        Condition::Gte(left, right) => matches!(
+            compare(&resolve(left, input), &resolve(right, input)),
+            Some(Ordering::Greater | Ordering::Equal)
+        ),
+        Condition::Lt(left, right) => {
+            compare(&resolve(left, input), &resolve(right, input)) == Some(Ordering::Less)
+        }
+        Condition::Lte(left, right) => matches!(
+            compare(&resolve(left, input), &resolve(right, input)),
+            Some(Ordering::Less | Ordering::Equal)
+        ),
+        Condition::In(element, collection) => {
+            contains(&resolve(collection, input), &resolve(element, input))
+        }
+        Condition::Contains(collection, element) => {
+            contains(&resolve(collection, input), &resolve(element, input))
+        }
+        Condition::Exists(path) => !lookup(input, path).is_null(),
+    }
+}
+
+/// Returns the value of an operand. Missing attributes are `null`.
+fn resolve(operand: &Operand, input: &Value) -> Value {
+    match operand {
+        Operand::Attribute { attr } => lookup(input, attr),
+        Operand::Literal(value) => value.clone(),
+    }
+}
+
+/// Looks up a dot-separated attribute path. Array elements are addressed by index.
+fn lookup(input: &Value, path: &str) -> Value {
+    let mut current = input;
+    for segment in path.split('.') {
+        let next = match current {
+            Value::Object(map) => map.get(segment),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        };
+        match next {
+            Some(value) => current = value,
+            None => return Value::Null,
+        }
+    }
+    current.clone()
+}
+
+/// Compares two values for equality, treating numbers of different types as equal.
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(left), Some(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+/// Orders two numbers or two strings. Other values can't be ordered.
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64()?.partial_cmp(&right.as_f64()?),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        _ => None,
+    }
+}
+
+/// Checks whether an array contains an element or a string contains a substring.
+fn contains(collection: &Value, element: &Value) -> bool {
+    match (collection, element) {
+        (Value::Array(items), element) => items.iter().any(|item| values_equal(item, element)),
+        (Value::String(haystack), Value::String(needle)) => haystack.contains(needle.as_str()),
+        _ => false,
+    }
+}
+
+/// Returns the attributes of the environment at the given time.
+///
+/// The hour and weekday are in UTC, e.g. `{"hour": 14, "weekday": "mon"}`.
+///
+/// # Arguments
+///
+/// * `now` - The current time.
+///
+/// # Returns
+///
+/// The environment attributes.
+pub fn environment_attributes(now: DateTime<Utc>) -> Map<String, Value> {
+    let mut environment = Map::new();
+    environment.insert("time".into(), json!(now.to_rfc3339()));
+    environment.insert("timestamp".into(), json!(now.timestamp()));
+    environment.insert("hour".into(), json!(now.hour()));
+    environment.insert(
+        "weekday".into(),
+        json!(now.weekday().to_string().to_lowercase()),
+    );
+    environment
+}
+
+/// Returns the IP address of the client that sent a request, for the `ip` environment attribute.
+///
+/// Anyone can send `Forwarded` and `X-Forwarded-For` headers, so they are only honored if the
+/// request comes from one of the proxies in the comma-separated `TRUSTED_PROXIES` environment
+/// variable. Otherwise the address of the peer is used.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+///
+/// # Returns
+///
+/// The IP address, or `None` if the peer address is unknown.
+pub fn client_ip(request: &HttpRequest) -> Option<String> {
+    let peer = request.peer_addr()?.ip();
+    let trusted = env::var(TRUSTED_PROXIES_ENV)
+        .unwrap_or_default()
+        .split(',')
+        .any(|proxy| {
+            proxy
+                .trim()
+                .parse::<IpAddr>()
+                .is_ok_and(|proxy| proxy == peer)
+        });
+    if !trusted {
+        return Some(peer.to_string());
+    }
+
+    let forwarded = request.connection_info().realip_remote_addr()?.to_string();
+    Some(match forwarded.parse::<SocketAddr>() {
+        Ok(address) => address.ip().to_string(),
+        Err(_) => forwarded,
+    })
+}
+
+/// Returns the attributes of a user for use as the subject of a request.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the subject attributes. Unknown users only get an `id`.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the user, their roles or their groups fails.
+pub async fn subject_attributes(db: &Database, user_id: &str) -> Result<Value, CustomError> {
+    let mut subject = Map::new();
+    subject.insert("id".into(), json!(user_id));
+
+    if let Some(user) = db.get_user_by_id(user_id).await? {
+        let group_ids: BTreeSet<String> = user_groups(db, user_id)
+            .await?
+            .into_iter()
+            .map(|group| group.group_id)
+            .collect();
+        let groups: Vec<String> = db
+            .get_groups()
+            .await?
+            .into_iter()
+            .filter(|group| group_ids.contains(&group.id.to_string()))
+            .map(|group| group.name)
+            .collect();
+
+        subject.insert("username".into(), json!(user.username));
+        subject.insert("email".into(), json!(user.email));
+        subject.insert("email_verified".into(), json!(user.email_verified));
+        subject.insert("roles".into(), json!(effective_roles(db, user_id).await?));
+        subject.insert("groups".into(), json!(groups));
+    }
+    Ok(Value::Object(subject))
+}
+
+/// Converts a stored policy version.
+fn policy_from_stored(stored: StoredPolicy) -> Result<Policy, CustomError> {
+    let document: PolicyDocument = serde_json::from_str(&stored.document).map_err(|error| {
+        CustomError::InvalidPolicy(format!(
+            "Stored policy {} is invalid: {}",
+            stored.name, error
+        ))
+    })?;
+    Ok(Policy {
+        name: stored.name,
+        version: stored.version,
+        document,
+        created_by: stored.created_by,
+        created_at: stored.created_at,
+    })
+}
+
+/// Stores a new version of a policy.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `name` - The name of the policy.
+/// * `document` - The policy document.
+/// * `created_by` - The ID of the user making the change.
+///
+/// # Returns
+///
+/// A `Result` containing the new version.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The policy is invalid.
+/// - Storing the version fails.
+pub async fn save_policy(
+    db: &Database,
+    name: &str,
+    document: &PolicyDocument,
+    created_by: &str,
+) -> Result<Policy, CustomError> {
+    validate_policy(name, document)?;
+    let serialized = serde_json::to_string(document)
+        .map_err(|error| CustomError::InvalidPolicy(error.to_string()))?;
+    let stored = db
+        .store_policy_version(name, &serialized, created_by)
+        .await?;
+    tracing::info!("Stored version {} of policy {}", stored.version, name);
+    policy_from_stored(stored)
+}
+
+/// Stores an earlier version of a policy as its newest version.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `name` - The name of the policy.
+/// * `version` - The version to restore.
+/// * `created_by` - The ID of the user making the change.
+///
+/// # Returns
+///
+/// A `Result` containing the new version.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The version doesn't exist.
+/// - Storing the version fails.
+pub async fn rollback_policy(
+    db: &Database,
+    name: &str,
+    version: i64,
+    created_by: &str,
+) -> Result<Policy, CustomError> {
+    let previous = policy_versions(db, name)
+        .await?
+        .into_iter()
+        .find(|policy| policy.version == version)
+        .ok_or(CustomError::PolicyNotFound)?;
+    save_policy(db, name, &previous.document, created_by).await
+}
+
+/// Returns every version of a policy, newest first.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `name` - The name of the policy.
+///
+/// # Returns
+///
+/// A `Result` containing the versions.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The query fails or a stored version is invalid.
+pub async fn policy_versions(db: &Database, name: &str) -> Result<Vec<Policy>, CustomError> {
+    db.get_policy_versions(name)
+        .await?
+        .into_iter()
+        .map(policy_from_stored)
+        .collect()
+}
+
+/// Returns the newest version of every policy, ordered by name.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+///
+/// # Returns
+///
+/// A `Result` containing the policies in effect.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The query fails or a stored version is invalid.
+pub async fn active_policies(db: &Database) -> Result<Vec<Policy>, CustomError> {
+    let mut latest: BTreeMap<String, StoredPolicy> = BTreeMap::new();
+    for stored in db.get_all_policy_versions().await? {
+        match latest.get(&stored.name) {
+            Some(current) if current.version >= stored.version => {}
+            _ => {
+                latest.insert(stored.name.clone(), stored);
+            }
+        }
+    }
+    latest.into_values().map(policy_from_stored).collect()
+}
+
+/// Decides an authorization request using the policies in effect.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `request` - The authorization request.
+///
+/// # Returns
+///
+/// A `Result` containing the decision.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Loading the policies fails.
+pub async fn authorize(
+    db: &Database,
+    request: &AuthorizationRequest,
+) -> Result<Decision, CustomError> {
+    let policies = active_policies(db).await?;
+    let decision = evaluate(&policies, request);
+    let resource_type = request.resource.get("type").and_then(Value::as_str);
+    tracing::info!(
+        "Authorization decision for {} on {}: {} ({:?})",
+        request.action,
+        resource_type.unwrap_or("?"),
+        if decision.allowed { "allow" } else { "deny" },
+        decision.rule
+    );
+    Ok(decision)
+}
@@ -0,0 +1,391 @@
+//! src/rbac.rs
+//!
+//! This module implements role-based access control. Roles are named sets of permissions that
+//! are assigned to users or groups, and routes declare the permission they require.
+
+use crate::database::{Database, Role};
+use crate::errors::custom_errors::CustomError;
+use crate::groups::user_groups;
+use crate::tenants::DEFAULT_TENANT;
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+
+const ADMIN_USER_IDS_ENV: &str = "ADMIN_USER_IDS";
+
+/// The permission that grants every other permission.
+pub const ALL_PERMISSIONS: &str = "*";
+/// The permission to list the JWT signing keys.
+pub const KEYS_READ: &str = "keys:read";
+/// The permission to rotate the JWT signing keys and change their states.
+pub const KEYS_MANAGE: &str = "keys:manage";
+/// The permission to list roles and role assignments.
+pub const ROLES_READ: &str = "roles:read";
+/// The permission to create and delete roles and to assign them to users.
+pub const ROLES_MANAGE: &str = "roles:manage";
+/// The permission to list groups and their members.
+pub const GROUPS_READ: &str = "groups:read";
+/// The permission to create, change and delete groups.
+pub const GROUPS_MANAGE: &str = "groups:manage";
+/// The permission to list authorization policies and their versions.
+pub const POLICIES_READ: &str = "policies:read";
+/// The permission to change authorization policies.
+pub const POLICIES_MANAGE: &str = "policies:manage";
+/// The permission to ask for authorization decisions on behalf of other subjects.
+pub const POLICIES_EVALUATE: &str = "policies:evaluate";
+/// The permission to simulate role and policy changes against all users.
+pub const CHANGES_SIMULATE: &str = "changes:simulate";
+/// The permission to list tenants. Only granted in the default tenant.
+pub const TENANTS_READ: &str = "tenants:read";
+/// The permission to create, change and delete tenants. Only granted in the default tenant.
+pub const TENANTS_MANAGE: &str = "tenants:manage";
+/// The permission to list OAuth clients.
+pub const CLIENTS_READ: &str = "clients:read";
+/// The permission to register and delete OAuth clients.
+pub const CLIENTS_MANAGE: &str = "clients:manage";
+/// The permission to list external identity providers.
+pub const IDENTITY_PROVIDERS_READ: &str = "identity_providers:read";
+/// The permission to add, change and delete external identity providers.
+pub const IDENTITY_PROVIDERS_MANAGE: &str = "identity_providers:manage";
+
+/// Describes how a permission was granted to a user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum GrantSource {
+    /// The user is listed in `ADMIN_USER_IDS`.
+    Superuser,
+    /// The role is assigned to the user directly.
+    Direct {
+        /// The name of the role.
+        role: String,
+    },
+    /// The role is assigned to a group the user belongs to.
+    Group {
+        /// The name of the role.
+        role: String,
+        /// The names of the groups from the user's direct group to the group holding the role.
+        path: Vec<String>,
+    },
+}
+
+/// Represents a permission together with where it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PermissionGrant {
+    /// The granted permission.
+    pub permission: String,
+    /// How the permission was granted.
+    #[serde(flatten)]
+    pub source: GrantSource,
+}
+
+/// Checks whether a user is a superuser.
+///
+/// Superusers of the default tenant are listed by user ID in the comma-separated
+/// `ADMIN_USER_IDS` environment variable, so that a fresh installation can be bootstrapped.
+/// Superusers of other tenants are listed in the tenant's settings. Superusers have every
+/// permission.
+///
+/// # Arguments
+///
+/// * `db` - The database of the user's tenant.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// `true` if the user is a superuser.
+pub fn is_superuser(db: &Database, user_id: &str) -> bool {
+    if db.tenant() != DEFAULT_TENANT {
+        return db
+            .settings()
+            .admin_user_ids
+            .iter()
+            .any(|admin| admin == user_id);
+    }
+    env::var(ADMIN_USER_IDS_ENV)
+        .unwrap_or_default()
+        .split(',')
+        .any(|admin| !admin.trim().is_empty() && admin.trim() == user_id)
+}
+
+/// Checks whether a granted permission covers a required permission.
+///
+/// A granted permission matches if it is equal to the required one, if it is `*`, or if it ends
+/// with `:*` and the required permission starts with the same prefix, e.g. `keys:*` covers
+/// `keys:manage`.
+///
+/// # Arguments
+///
+/// * `granted` - The permission granted by a role.
+/// * `required` - The permission required by a route.
+///
+/// # Returns
+///
+/// `true` if the granted permission covers the required one.
+pub fn permission_matches(granted: &str, required: &str) -> bool {
+    if granted == ALL_PERMISSIONS || granted == required {
+        return true;
+    }
+    match granted.strip_suffix('*') {
+        Some(prefix) if prefix.ends_with(':') => required.starts_with(prefix),
+        _ => false,
+    }
+}
+
+/// Checks that a role name and its permissions are well-formed.
+///
+/// # Arguments
+///
+/// * `role` - The role to check.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the role is valid.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The name is empty or contains characters other than letters, digits, `-`, `_` and `.`.
+/// - A permission is empty or contains whitespace.
+pub fn validate_role(role: &Role) -> Result<(), CustomError> {
+    if role.name.is_empty()
+        || !role
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(CustomError::InvalidRole(format!(
+            "Invalid role name: {}",
+            role.name
+        )));
+    }
+    if let Some(permission) = role
+        .permissions
+        .iter()
+        .find(|permission| permission.is_empty() || permission.chars().any(char::is_whitespace))
+    {
+        return Err(CustomError::InvalidRole(format!(
+            "Invalid permission: {:?}",
+            permission
+        )));
+    }
+    Ok(())
+}
+
+/// Assigns a role to a user.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `role` - The name of the role.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user doesn't exist.
+/// - The role doesn't exist.
+/// - Storing the assignment fails.
+pub async fn assign_role(db: &Database, user_id: &str, role: &str) -> Result<(), CustomError> {
+    if db.get_user_by_id(user_id).await?.is_none() {
+        return Err(CustomError::UserNotFound);
+    }
+    if db.get_role(role).await?.is_none() {
+        return Err(CustomError::InvalidRole(format!("Unknown role: {}", role)));
+    }
+    db.assign_role(user_id, role).await?;
+    tracing::info!("Assigned role {} to user {}", role, user_id);
+    Ok(())
+}
+
+/// Returns the names of every role a user has, directly or through their groups.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the sorted role names.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the roles or groups fails.
+pub async fn effective_roles(db: &Database, user_id: &str) -> Result<Vec<String>, CustomError> {
+    let mut roles: BTreeSet<String> = db.get_user_roles(user_id).await?.into_iter().collect();
+    let group_ids: Vec<String> = user_groups(db, user_id)
+        .await?
+        .into_iter()
+        .map(|group| group.group_id)
+        .collect();
+    roles.extend(
+        db.get_group_roles(&group_ids)
+            .await?
+            .into_iter()
+            .map(|group_role| group_role.role),
+    );
+    Ok(roles.into_iter().collect())
+}
+
+/// Explains every permission a user has and where it came from.
+///
+/// A permission that is granted in several ways is listed once for each of them.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the grants, ordered by permission.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the roles or groups fails.
+pub async fn explain_permissions(
+    db: &Database,
+    user_id: &str,
+) -> Result<Vec<PermissionGrant>, CustomError> {
+    let mut grants = Vec::new();
+    if is_superuser(db, user_id) {
+        grants.push(PermissionGrant {
+            permission: ALL_PERMISSIONS.to_string(),
+            source: GrantSource::Superuser,
+        });
+    }
+
+    let direct_roles = db.get_user_roles(user_id).await?;
+    let groups = user_groups(db, user_id).await?;
+    let group_ids: Vec<String> = groups.iter().map(|group| group.group_id.clone()).collect();
+    let group_roles = db.get_group_roles(&group_ids).await?;
+
+    // Load every role that is involved at once.
+    let mut role_names: BTreeSet<String> = direct_roles.iter().cloned().collect();
+    role_names.extend(group_roles.iter().map(|group_role| group_role.role.clone()));
+    let role_names: Vec<String> = role_names.into_iter().collect();
+    let roles: BTreeMap<String, Role> = db
+        .get_roles_by_names(&role_names)
+        .await?
+        .into_iter()
+        .map(|role| (role.name.clone(), role))
+        .collect();
+
+    for role_name in &direct_roles {
+        if let Some(role) = roles.get(role_name) {
+            for permission in &role.permissions {
+                grants.push(PermissionGrant {
+                    permission: permission.clone(),
+                    source: GrantSource::Direct {
+                        role: role.name.clone(),
+                    },
+                });
+            }
+        }
+    }
+
+    if !group_roles.is_empty() {
+        let group_names: BTreeMap<String, String> = db
+            .get_groups()
+            .await?
+            .into_iter()
+            .map(|group| (group.id.to_string(), group.name))
+            .collect();
+        let paths: BTreeMap<&str, &Vec<String>> = groups
+            .iter()
+            .map(|group| (group.group_id.as_str(), &group.path))
+            .collect();
+
+        for group_role in &group_roles {
+            let (Some(role), Some(path)) = (
+                roles.get(&group_role.role),
+                paths.get(group_role.group_id.as_str()),
+            ) else {
+                continue;
+            };
+            let path: Vec<String> = path
+                .iter()
+                .map(|group_id| {
+                    group_names
+                        .get(group_id)
+                        .cloned()
+                        .unwrap_or_else(|| group_id.clone())
+                })
+                .collect();
+            for permission in &role.permissions {
+                grants.push(PermissionGrant {
+                    permission: permission.clone(),
+                    source: GrantSource::Group {
+                        role: role.name.clone(),
+                        path: path.clone(),
+                    },
+                });
+            }
+        }
+    }
+
+    grants.sort_by(|a, b| a.permission.cmp(&b.permission));
+    Ok(grants)
+}
+
+/// Returns every permission a user has.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the permissions. Superusers get `*`.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the roles or groups fails.
+pub async fn effective_permissions(
+    db: &Database,
+    user_id: &str,
+) -> Result<BTreeSet<String>, CustomError> {
+    Ok(explain_permissions(db, user_id)
+        .await?
+        .into_iter()
+        .map(|grant| grant.permission)
+        .collect())
+}
+/// Checks whether a user has a permission.
+///
+/// Roles are looked up on every call rather than taken from the access token, so removing a
+/// role takes effect immediately.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `permission` - The required permission.
+///
+/// # Returns
+///
+/// A `Result` containing `true` if the user has the permission.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the roles or groups fails.
+pub async fn has_permission(
+    db: &Database,
+    user_id: &str,
+    permission: &str,
+) -> Result<bool, CustomError> {
+    Ok(effective_permissions(db, user_id)
+        .await?
+        .iter()
+        .any(|granted| permission_matches(granted, permission)))
+}
@@ -2,15 +2,48 @@
 //!
 //! This module defines the Actix Web server and its routes for the IAM project.
 
-use crate::database::Database;
+use crate::client_registration::{self, ClientMetadata};
+use crate::consent;
+use crate::database::{
+    Database, Group, IdentityProvider, OAuthClient, Role, Tenant, TenantSettings, User,
+};
+use crate::device_authorization::{self, DeviceAuthorizationRequest};
+use crate::dpop;
+use crate::email_verification;
 use crate::errors::custom_errors::CustomError;
-use crate::middleware::AuthenticationMiddlewareFactory;
+use crate::federation::{self, ProviderRegistration};
+use crate::groups;
+use crate::hashing::hash_token;
+use crate::introspection::{self, IntrospectionRequest, RevocationRequest};
+use crate::jwt::{self, Claims};
+use crate::keyring::{self, reload_keyring, KeyState};
+use crate::mailer::{mailer_from_env, Mailer};
+use crate::mfa::{self, FirstFactorOutcome, PendingMfaChallenge};
+use crate::middleware::{
+    AuthenticationMiddlewareFactory, RequirePermission, TenantMiddlewareFactory,
+};
+use crate::oauth::{
+    self, AuthorizationError, AuthorizationParams, ClientRegistration, ClientType, TokenRequest,
+    ValidatedAuthorization,
+};
+use crate::oidc::{self, OPENID_SCOPE};
+use crate::password_reset;
+use crate::policy::{self, AuthorizationRequest, PolicyDocument};
+use crate::rbac;
+use crate::simulation::{self, Simulation};
+use crate::tenants::{Tenants, DEFAULT_TENANT};
+use crate::tokens::{issue_token_pair, rotate_refresh_token, TokenPair};
+use crate::webauthn::{self, AssertionCredential, RegistrationCredential};
 use actix_governor::{Governor, GovernorConfigBuilder};
 use actix_web::HttpRequest;
-use actix_web::{post, web, App, HttpMessage, HttpResponse, Responder};
+use actix_web::{delete, get, post, route, web, App, HttpMessage, HttpResponse, Responder};
+use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::BTreeSet;
 use std::env::var;
+use std::str::FromStr;
+use std::sync::Arc;
 use tracing_appender::rolling::Rotation;
 use validator::Validate;
 use validator_derive::Validate;
@@ -38,6 +71,58 @@ struct RegisterRequest {
     email: String,
 }
 
+/// Struct representing the MFA login request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct MfaLoginRequest {
+    #[validate(length(min = 1, message = "MFA token is required"))]
+    mfa_token: String,
+    #[validate(length(min = 1, message = "Code is required"))]
+    code: Option<String>,
+    webauthn: Option<AssertionCredential>,
+}
+
+/// Struct representing the MFA passkey request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct MfaPasskeyRequest {
+    #[validate(length(min = 1, message = "MFA token is required"))]
+    mfa_token: String,
+}
+
+/// Struct representing the passkey registration request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct PasskeyRegistrationRequest {
+    #[serde(flatten)]
+    credential: RegistrationCredential,
+    name: Option<String>,
+}
+
+/// Struct representing the passkey login request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct PasskeyLoginRequest {
+    #[validate(email(message = "Email is invalid"))]
+    email: Option<String>,
+}
+
+/// Struct representing a request body containing a one-time code
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct TotpCodeRequest {
+    #[validate(length(min = 1, message = "Code is required"))]
+    code: String,
+}
+
+/// Struct representing the refresh token request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct RefreshTokenRequest {
+    #[validate(length(min = 1, message = "Refresh token is required"))]
+    refresh_token: String,
+}
+
+/// Struct representing the logout request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct LogoutRequest {
+    refresh_token: Option<String>,
+}
+
 /// Struct representing the change username request body
 #[derive(Debug, Deserialize, Serialize, Validate)]
 struct ChangeUsernameRequest {
@@ -52,11 +137,256 @@ struct ChangePasswordRequest {
     password: String,
 }
 
+/// Struct representing the forgot password request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct ForgotPasswordRequest {
+    #[validate(email(message = "Email is invalid"))]
+    email: String,
+}
+
+/// Struct representing the reset password request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct ResetPasswordRequest {
+    #[validate(length(min = 1, message = "Token is required"))]
+    token: String,
+    #[validate(length(min = 8, message = "Password must be at least 8 characters long"))]
+    password: String,
+}
+
+/// Struct representing the verify email request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct VerifyEmailRequest {
+    #[validate(length(min = 1, message = "Token is required"))]
+    token: String,
+}
+
+/// Struct representing the resend verification email request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct ResendVerificationRequest {
+    #[validate(email(message = "Email is invalid"))]
+    email: String,
+}
+
+/// Struct representing the role request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct RoleRequest {
+    #[validate(length(
+        min = 1,
+        max = 64,
+        message = "Role name must be 1 to 64 characters long"
+    ))]
+    name: String,
+    #[serde(default)]
+    description: String,
+    permissions: Vec<String>,
+}
+
+/// Struct representing the role assignment request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct RoleAssignmentRequest {
+    #[validate(length(min = 1, message = "Role is required"))]
+    role: String,
+}
+
+/// Struct representing the group request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct GroupRequest {
+    #[validate(length(
+        min = 1,
+        max = 64,
+        message = "Group name must be 1 to 64 characters long"
+    ))]
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+/// Struct representing the rename group request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct RenameGroupRequest {
+    #[validate(length(
+        min = 1,
+        max = 64,
+        message = "Group name must be 1 to 64 characters long"
+    ))]
+    name: String,
+}
+
+/// Struct representing the group member request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct GroupMemberRequest {
+    #[validate(length(min = 1, message = "Member ID is required"))]
+    member_id: String,
+}
+
+/// Struct representing the authorize request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct AuthorizeRequest {
+    #[validate(length(min = 1, message = "Action is required"))]
+    action: String,
+    resource: serde_json::Value,
+    #[serde(default)]
+    environment: serde_json::Map<String, serde_json::Value>,
+    subject_id: Option<String>,
+}
+
+/// Struct representing the tenant creation request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct CreateTenantRequest {
+    tenant_id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    hosts: Vec<String>,
+    #[serde(default)]
+    settings: TenantSettings,
+}
+
+/// Struct representing the tenant update request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct UpdateTenantRequest {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    hosts: Vec<String>,
+    #[serde(default)]
+    settings: TenantSettings,
+}
+
+/// Struct representing the OAuth login request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct OAuthLoginRequest {
+    #[serde(flatten)]
+    params: AuthorizationParams,
+    #[validate(email(message = "Email is invalid"))]
+    email: String,
+    #[validate(length(min = 8, message = "Password must be at least 8 characters long"))]
+    password: String,
+}
+
+/// Struct representing the OAuth MFA login request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct OAuthMfaLoginRequest {
+    #[serde(flatten)]
+    params: AuthorizationParams,
+    #[validate(length(min = 1, message = "MFA token is required"))]
+    mfa_token: String,
+    #[validate(length(min = 1, message = "Code is required"))]
+    code: Option<String>,
+    webauthn: Option<AssertionCredential>,
+}
+
+/// Struct representing the device verification query
+#[derive(Debug, Deserialize, Serialize)]
+struct DeviceVerificationQuery {
+    user_code: String,
+}
+
+/// Struct representing the device decision request body
+#[derive(Debug, Deserialize, Serialize)]
+struct DeviceDecisionRequest {
+    user_code: String,
+    approve: bool,
+}
+
+/// Struct representing the consent page query
+#[derive(Debug, Deserialize, Serialize)]
+struct ConsentQuery {
+    consent: String,
+}
+
+/// Struct representing the consent form
+#[derive(Debug, Deserialize, Serialize)]
+struct ConsentDecisionForm {
+    consent: String,
+    decision: String,
+}
+
+/// Struct representing the OAuth client registration request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct CreateClientRequest {
+    #[validate(length(min = 1, message = "Name is required"))]
+    name: String,
+    client_type: ClientType,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    grant_types: Option<Vec<String>>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    logo_uri: Option<String>,
+    access_token_lifetime: Option<i64>,
+    refresh_token_lifetime: Option<i64>,
+    #[serde(default)]
+    first_party: bool,
+}
+
+impl From<CreateClientRequest> for ClientRegistration {
+    fn from(req: CreateClientRequest) -> Self {
+        ClientRegistration {
+            name: req.name,
+            client_type: req.client_type,
+            redirect_uris: req.redirect_uris,
+            grant_types: req.grant_types,
+            scopes: req.scopes,
+            logo_uri: req.logo_uri,
+            access_token_lifetime: req.access_token_lifetime,
+            refresh_token_lifetime: req.refresh_token_lifetime,
+            first_party: req.first_party,
+        }
+    }
+}
+
+/// Struct representing the identity provider creation request body
+#[derive(Debug, Deserialize, Serialize)]
+struct CreateIdentityProviderRequest {
+    provider_id: String,
+    #[serde(flatten)]
+    provider: ProviderRegistration,
+}
+
+/// Struct representing the query of an identity provider's callback
+#[derive(Debug, Deserialize, Serialize)]
+struct FederationCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Struct representing the initial access token request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct RegistrationTokenRequest {
+    #[validate(range(min = 1, max = 1000, message = "Uses must be between 1 and 1000"))]
+    uses: Option<i64>,
+}
+
+/// Struct representing the policy rollback request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct PolicyRollbackRequest {
+    #[validate(range(min = 1, message = "Version must be positive"))]
+    version: i64,
+}
+
+/// Struct representing the signing key rotation request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct RotateSigningKeyRequest {
+    algorithm: Option<String>,
+}
+
+/// Struct representing the signing key state request body
+#[derive(Debug, Deserialize, Serialize, Validate)]
+struct SigningKeyStateRequest {
+    state: KeyState,
+}
+
 /// Application state shared across all routes
 #[derive(Clone)]
 pub struct AppState {
     /// Database connection
     pub db: Database,
+    /// Mailer used to deliver emails
+    pub mailer: Arc<dyn Mailer>,
+    /// Databases of all tenants
+    pub tenants: Tenants,
 }
 
 /// Starts the Actix Web server.
@@ -80,9 +410,19 @@ pub async fn start() -> Result<(), Box<dyn std::error::Error>> {
     // Create a new database connection
     let database = Database::new().await?;
 
+    tracing::info!("Loading JWT signing keys");
+    // Load the keyring so that a misconfiguration is caught on startup
+    reload_keyring(&database).await?;
+
+    tracing::info!("Setting up mailer");
+    // Create the mailer configured in the environment
+    let mailer = mailer_from_env()?;
+
     // Create the application state
     let app_state = AppState {
         db: database.clone(),
+        mailer,
+        tenants: Tenants::new(database.clone()),
     };
 
     tracing::info!("Getting IP");
@@ -119,10 +459,96 @@ pub async fn start() -> Result<(), Box<dyn std::error::Error>> {
             .app_data(web::Data::new(app_state.clone()))
             .wrap(Governor::new(&governor_conf))
             .wrap(AuthenticationMiddlewareFactory::new())
+            // Select the tenant before authenticating against it
+            .wrap(TenantMiddlewareFactory::new())
             .service(register)
             .service(login)
+            .service(login_mfa)
+            .service(login_mfa_passkey)
+            .service(passkey_login_begin)
+            .service(passkey_login_finish)
+            .service(passkey_register_begin)
+            .service(passkey_register_finish)
+            .service(list_passkeys)
+            .service(delete_passkey)
+            .service(list_consents)
+            .service(revoke_consent)
+            .service(enroll_totp)
+            .service(confirm_totp)
+            .service(disable_totp)
+            .service(recovery_codes_remaining)
+            .service(regenerate_recovery_codes)
+            .service(refresh_token)
+            .service(logout)
+            .service(logout_all)
+            .service(jwks)
+            .service(list_signing_keys)
+            .service(rotate_signing_key)
+            .service(set_signing_key_state)
+            .service(list_roles)
+            .service(upsert_role)
+            .service(delete_role)
+            .service(list_user_roles)
+            .service(assign_user_role)
+            .service(unassign_user_role)
+            .service(explain_user_permissions)
+            .service(list_groups)
+            .service(create_group)
+            .service(get_group)
+            .service(rename_group)
+            .service(delete_group)
+            .service(add_group_member)
+            .service(remove_group_member)
+            .service(assign_group_role)
+            .service(unassign_group_role)
+            .service(authorize)
+            .service(list_policies)
+            .service(save_policy)
+            .service(list_policy_versions)
+            .service(rollback_policy)
+            .service(delete_policy)
+            .service(simulate_change)
+            .service(list_tenants)
+            .service(create_tenant)
+            .service(update_tenant)
+            .service(delete_tenant)
+            .service(oauth_authorize)
+            .service(oauth_login)
+            .service(oauth_login_mfa)
+            .service(consent_page)
+            .service(consent_decision)
+            .service(oauth_token)
+            .service(device_code)
+            .service(device_verification)
+            .service(device_decision)
+            .service(oauth_introspect)
+            .service(oauth_revoke)
+            .service(openid_configuration)
+            .service(userinfo)
+            .service(oauth_register)
+            .service(federation_providers)
+            .service(federation_login)
+            .service(federation_callback)
+            .service(list_clients)
+            .service(create_client)
+            .service(create_registration_token)
+            .service(get_client)
+            .service(update_client)
+            .service(delete_client)
+            .service(rotate_client_secret)
+            .service(disable_client)
+            .service(enable_client)
+            .service(list_identity_providers)
+            .service(create_identity_provider)
+            .service(get_identity_provider)
+            .service(update_identity_provider)
+            .service(delete_identity_provider)
             .service(change_username)
             .service(change_password)
+            .service(forgot_password)
+            .service(reset_password)
+            .service(verify_email)
+            .service(resend_verification_email)
     })
     // Bind the server to the specified IP address and port
     .bind((server_ip, server_port))?
@@ -256,11 +682,17 @@ async fn register(
 
     // hashing is handled in the db.register function
     match db
-        .register(firstname, lastname, username, password, email)
+        .register(firstname, lastname, username, password, email.clone())
         .await
     {
         Ok(_) => {
             tracing::info!("User registered successfully");
+            // The account exists either way, the user can ask for a new email later.
+            if let Err(error) =
+                email_verification::send_verification_email(db, data.mailer.as_ref(), &email).await
+            {
+                tracing::error!("Error sending verification email: {}", error);
+            }
             HttpResponse::Created().body("User registered successfully")
         }
         Err(error) => {
@@ -301,45 +733,136 @@ async fn login(req: web::Json<LoginRequest>, data: web::Data<AppState>) -> impl
     let db = &data.db;
 
     // Authenticate the user
-    match db.authenticate_user(email, password).await {
-        Ok(user) => {
-            tracing::info!("User authenticated successfully");
-            // Generate JWT
-            match crate::jwt::generate_jwt(user.id.to_string()) {
-                Ok(token) => HttpResponse::Ok().json(json!({"success": true, "token": token})),
-                Err(error) => {
-                    tracing::error!("Error generating JWT: {}", error);
-                    HttpResponse::InternalServerError()
-                        .json(json!({"success": false, "error": "Failed to generate token"}))
-                }
-            }
-        }
+    let user = match db.authenticate_user(email, password).await {
+        Ok(user) => user,
         Err(error) => {
             tracing::error!("Error authenticating user: {}", error);
-            match error {
+            return match error {
                 CustomError::InvalidPassword => HttpResponse::Ok().json(json!({"success": false})),
                 CustomError::UserNotFound => HttpResponse::Ok().json(json!({"success": false})),
                 _ => HttpResponse::InternalServerError().json(json!({"success": false})),
-            }
+            };
         }
+    };
+    // Apply the policy for unverified email addresses
+    if let Err(error) = email_verification::check_login_allowed(&data.db, &user) {
+        return HttpResponse::Forbidden()
+            .json(json!({"success": false, "error": error.to_string()}));
     }
+
+    tracing::info!("User authenticated successfully");
+    first_factor_response(db, &user, &["pwd".to_string()]).await
 }
 
-/// Changes the Username of a user.
+/// Builds the response for a user who passed the first factor.
+///
+/// Users who set up a second factor get an MFA challenge that has to be completed at
+/// `/login/mfa`, all others get a token pair.
 ///
 /// # Arguments
 ///
-/// * `req` - The change username request.
+/// * `db` - The database connection.
+/// * `user` - The authenticated user.
+/// * `auth_methods` - The methods the user passed the first factor with.
 ///
-/// * `http_req` - The http request.
+/// # Returns
+///
+/// The HTTP response.
+async fn first_factor_response(
+    db: &Database,
+    user: &User,
+    auth_methods: &[String],
+) -> HttpResponse {
+    match mfa::complete_first_factor(db, user, auth_methods).await {
+        Ok(FirstFactorOutcome::Tokens(tokens)) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "no-store"))
+            .json(token_pair_response(tokens)),
+        Ok(FirstFactorOutcome::MfaRequired { methods, challenge }) => {
+            mfa_required_response(methods, challenge)
+        }
+        Err(error) => {
+            tracing::error!("Error completing login: {}", error);
+            HttpResponse::InternalServerError()
+                .json(json!({"success": false, "error": "Failed to complete login"}))
+        }
+    }
+}
+
+/// Builds the response asking the user for a second factor.
+fn mfa_required_response(methods: Vec<String>, challenge: PendingMfaChallenge) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "mfa_required": true,
+        "mfa_methods": methods,
+        "mfa_token": challenge.mfa_token,
+        "expires_in": challenge.expires_in,
+    }))
+}
+
+/// Maps a failed MFA challenge to a response.
+fn mfa_error_response(error: CustomError) -> HttpResponse {
+    tracing::error!("Error completing MFA login: {}", error);
+    match error {
+        CustomError::InvalidMfaChallenge
+        | CustomError::InvalidMfaCode
+        | CustomError::WebAuthnError(_) => {
+            HttpResponse::Unauthorized().json(json!({"success": false, "error": error.to_string()}))
+        }
+        _ => HttpResponse::InternalServerError().json(json!({"success": false})),
+    }
+}
+
+/// Completes a login that requires a second factor.
+///
+/// The second factor can be a TOTP code, one of the user's recovery codes or a passkey
+/// assertion started at `/login/mfa/webauthn`.
+///
+/// # Arguments
+///
+/// * `req` - The MFA login request.
+/// * `data` - The application state.
 ///
 /// # Returns
 ///
 /// A `Result` indicating success or failure.
-#[post("/change_username")]
-async fn change_username(
-    http_req: HttpRequest,
-    req: web::Json<ChangeUsernameRequest>,
+#[post("/login/mfa")]
+async fn login_mfa(req: web::Json<MfaLoginRequest>, data: web::Data<AppState>) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    let result = match (&req.0.code, &req.0.webauthn) {
+        (Some(code), None) => mfa::complete_mfa_challenge(&data.db, &req.0.mfa_token, code).await,
+        (None, Some(credential)) => {
+            mfa::complete_mfa_challenge_with_passkey(&data.db, &req.0.mfa_token, credential).await
+        }
+        _ => return HttpResponse::BadRequest().json(
+            json!({"success": false, "error": "Either a code or a passkey assertion is required"}),
+        ),
+    };
+    match result {
+        Ok(tokens) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "no-store"))
+            .json(token_pair_response(tokens)),
+        Err(error) => mfa_error_response(error),
+    }
+}
+
+/// Starts a passkey assertion for a login that requires a second factor.
+///
+/// # Arguments
+///
+/// * `req` - The MFA passkey request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/login/mfa/webauthn")]
+async fn login_mfa_passkey(
+    req: web::Json<MfaPasskeyRequest>,
     data: web::Data<AppState>,
 ) -> impl Responder {
     // Validate the request body
@@ -347,50 +870,3326 @@ async fn change_username(
         tracing::warn!("Validation error: {:?}", validation_errors);
         return HttpResponse::BadRequest().json(validation_errors);
     }
-    let new_username = req.0.username;
-    let user_id = http_req
-        .extensions()
-        .get::<String>()
-        .cloned()
-        .unwrap_or_else(|| "Unknown".to_string());
 
-    match data.db.change_username(user_id, new_username).await {
-        Ok(_) => HttpResponse::Ok().json("Successfully changed username"),
-        Err(_error) => HttpResponse::InternalServerError().finish(),
+    match mfa::start_mfa_passkey_assertion(&data.db, &req.0.mfa_token).await {
+        Ok(options) => HttpResponse::Ok().json(json!({"success": true, "publicKey": options})),
+        Err(CustomError::InvalidMfaChallenge) => HttpResponse::Unauthorized()
+            .json(json!({"success": false, "error": "Invalid MFA challenge"})),
+        Err(error) => {
+            tracing::error!("Error starting passkey assertion: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
     }
 }
 
-/// Changes the Password of a user.
+/// Starts a passwordless login with a passkey.
+///
+/// If an email address is passed, only that user's passkeys are offered. Otherwise the browser
+/// lets the user pick any discoverable passkey for this site.
 ///
 /// # Arguments
 ///
-/// * `req` - The change username request.
+/// * `req` - The optional passkey login request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/webauthn/login/begin")]
+async fn passkey_login_begin(
+    req: Option<web::Json<PasskeyLoginRequest>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let email = match req {
+        Some(req) => {
+            // Validate the request body
+            if let Err(validation_errors) = req.0.validate() {
+                tracing::warn!("Validation error: {:?}", validation_errors);
+                return HttpResponse::BadRequest().json(validation_errors);
+            }
+            req.0.email.map(|email| email.to_lowercase())
+        }
+        None => None,
+    };
+
+    // An unknown email address gets the same response as a discoverable login, so that the
+    // endpoint can't be used to find out who has an account.
+    let user_id = match email {
+        Some(email) => match data.db.get_user_by_email(&email).await {
+            Ok(user) => user.map(|user| user.id.to_string()),
+            Err(error) => {
+                tracing::error!("Error looking up user: {}", error);
+                return HttpResponse::InternalServerError().json(json!({"success": false}));
+            }
+        },
+        None => None,
+    };
+
+    match webauthn::start_authentication(&data.db, user_id.as_deref(), true).await {
+        Ok(options) => HttpResponse::Ok().json(json!({"success": true, "publicKey": options})),
+        Err(error) => {
+            tracing::error!("Error starting passkey login: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Finishes a passwordless login with a passkey.
+///
+/// The authenticator has to verify the user, so the passkey counts as multi-factor.
+///
+/// # Arguments
+///
+/// * `req` - The assertion returned by the browser.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/webauthn/login/finish")]
+async fn passkey_login_finish(
+    req: web::Json<AssertionCredential>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let assertion = match webauthn::finish_authentication(&data.db, &req.0).await {
+        Ok(assertion) => assertion,
+        Err(CustomError::WebAuthnError(message)) => {
+            tracing::warn!("Passkey login failed: {}", message);
+            return HttpResponse::Unauthorized().json(json!({"success": false, "error": message}));
+        }
+        Err(error) => {
+            tracing::error!("Error finishing passkey login: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    };
+
+    // Apply the policy for unverified email addresses
+    match data.db.get_user_by_id(&assertion.user_id).await {
+        Ok(Some(user)) => {
+            if let Err(error) = email_verification::check_login_allowed(&data.db, &user) {
+                return HttpResponse::Forbidden()
+                    .json(json!({"success": false, "error": error.to_string()}));
+            }
+        }
+        Ok(None) => return HttpResponse::Unauthorized().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error looking up user: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    }
+
+    let auth_methods = ["hwk".to_string(), "mfa".to_string()];
+    match issue_token_pair(&data.db, &assertion.user_id, &auth_methods).await {
+        Ok(tokens) => {
+            tracing::info!("User logged in with passkey: {}", assertion.user_id);
+            HttpResponse::Ok()
+                .insert_header(("Cache-Control", "no-store"))
+                .json(token_pair_response(tokens))
+        }
+        Err(error) => {
+            tracing::error!("Error generating tokens: {}", error);
+            HttpResponse::InternalServerError()
+                .json(json!({"success": false, "error": "Failed to generate token"}))
+        }
+    }
+}
+
+/// Starts registering a passkey for the current user.
+///
+/// # Arguments
 ///
 /// * `http_req` - The http request.
+/// * `data` - The application state.
 ///
 /// # Returns
 ///
 /// A `Result` indicating success or failure.
-#[post("/change_password")]
-async fn change_password(
+#[post("/webauthn/register/begin")]
+async fn passkey_register_begin(
     http_req: HttpRequest,
-    req: web::Json<ChangePasswordRequest>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    // Validate the request body
-    if let Err(validation_errors) = req.0.validate() {
-        tracing::warn!("Validation error: {:?}", validation_errors);
-        return HttpResponse::BadRequest().json(validation_errors);
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match webauthn::start_registration(&data.db, &user_id).await {
+        Ok(options) => HttpResponse::Ok().json(json!({"success": true, "publicKey": options})),
+        Err(error) => {
+            tracing::error!("Error starting passkey registration: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
     }
-    let new_password = req.0.password;
-    let user_id = http_req
-        .extensions()
-        .get::<String>()
-        .cloned()
-        .unwrap_or_else(|| "Unknown".to_string());
+}
 
-    match data.db.change_password(user_id, new_password).await {
-        Ok(_) => HttpResponse::Ok().json("Successfully changed password"),
-        Err(_error) => HttpResponse::InternalServerError().finish(),
+/// Finishes registering a passkey for the current user.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The credential returned by the browser and an optional name.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/webauthn/register/finish")]
+async fn passkey_register_finish(
+    http_req: HttpRequest,
+    req: web::Json<PasskeyRegistrationRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match webauthn::finish_registration(
+        &data.db,
+        &user_id,
+        &req.0.credential,
+        req.0.name.as_deref(),
+    )
+    .await
+    {
+        Ok(credential) => HttpResponse::Created().json(json!({
+            "success": true,
+            "credential_id": credential.credential_id,
+            "name": credential.name,
+        })),
+        Err(CustomError::WebAuthnError(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => {
+            tracing::error!("Error finishing passkey registration: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Lists the passkeys of the current user.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/webauthn/credentials")]
+async fn list_passkeys(http_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match data.db.get_webauthn_credentials(&user_id).await {
+        Ok(credentials) => {
+            let credentials: Vec<serde_json::Value> = credentials
+                .into_iter()
+                .map(|credential| {
+                    json!({
+                        "credential_id": credential.credential_id,
+                        "name": credential.name,
+                        "created_at": credential.created_at,
+                        "last_used_at": credential.last_used_at,
+                    })
+                })
+                .collect();
+            HttpResponse::Ok().json(json!({"success": true, "credentials": credentials}))
+        }
+        Err(error) => {
+            tracing::error!("Error listing passkeys: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Deletes a passkey of the current user.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `path` - The credential ID.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete("/webauthn/credentials/{credential_id}")]
+async fn delete_passkey(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match data
+        .db
+        .delete_webauthn_credential(&user_id, &path.into_inner())
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(json!({"success": true})),
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error deleting passkey: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Starts TOTP enrollment for the current user.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/mfa/totp/enroll")]
+async fn enroll_totp(http_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match mfa::begin_totp_enrollment(&data.db, &user_id).await {
+        Ok(enrollment) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "secret": enrollment.secret,
+            "otpauth_uri": enrollment.otpauth_uri,
+        })),
+        Err(CustomError::MfaAlreadyEnabled) => HttpResponse::Conflict()
+            .json(json!({"success": false, "error": "TOTP is already enabled"})),
+        Err(error) => {
+            tracing::error!("Error starting TOTP enrollment: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Confirms TOTP enrollment for the current user with a first code.
+///
+/// The response contains the user's recovery codes, which are not shown again.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The code request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/mfa/totp/confirm")]
+async fn confirm_totp(
+    http_req: HttpRequest,
+    req: web::Json<TotpCodeRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match mfa::confirm_totp_enrollment(&data.db, &user_id, &req.0.code).await {
+        Ok(recovery_codes) => {
+            HttpResponse::Ok().json(json!({"success": true, "recovery_codes": recovery_codes}))
+        }
+        Err(error) => totp_error_response(error),
+    }
+}
+
+/// Disables TOTP for the current user.
+///
+/// A current code is required so that a stolen access token alone can't remove the second
+/// factor.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The code request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/mfa/totp/disable")]
+async fn disable_totp(
+    http_req: HttpRequest,
+    req: web::Json<TotpCodeRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match mfa::disable_totp(&data.db, &user_id, &req.0.code).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(error) => totp_error_response(error),
+    }
+}
+
+/// Returns the number of unused recovery codes of the current user.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/mfa/recovery_codes")]
+async fn recovery_codes_remaining(
+    http_req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match data.db.count_unused_recovery_codes(&user_id).await {
+        Ok(remaining) => HttpResponse::Ok().json(json!({"success": true, "remaining": remaining})),
+        Err(error) => {
+            tracing::error!("Error counting recovery codes: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Replaces the recovery codes of the current user with a new batch.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The code request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/mfa/recovery_codes/regenerate")]
+async fn regenerate_recovery_codes(
+    http_req: HttpRequest,
+    req: web::Json<TotpCodeRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match mfa::regenerate_recovery_codes(&data.db, &user_id, &req.0.code).await {
+        Ok(recovery_codes) => {
+            HttpResponse::Ok().json(json!({"success": true, "recovery_codes": recovery_codes}))
+        }
+        Err(error) => totp_error_response(error),
+    }
+}
+
+/// Builds the response for a failed TOTP enrollment operation.
+///
+/// # Arguments
+///
+/// * `error` - The error that occurred.
+///
+/// # Returns
+///
+/// The HTTP response.
+fn totp_error_response(error: CustomError) -> HttpResponse {
+    match error {
+        CustomError::InvalidMfaCode => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": error.to_string()}))
+        }
+        CustomError::MfaAlreadyEnabled | CustomError::MfaNotEnabled => {
+            HttpResponse::Conflict().json(json!({"success": false, "error": error.to_string()}))
+        }
+        _ => {
+            tracing::error!("TOTP error: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Exchanges a refresh token for a new access and refresh token.
+///
+/// The presented refresh token is rotated: it can't be used again, and presenting it a second
+/// time revokes every token issued from the same login.
+///
+/// # Arguments
+///
+/// * `req` - The refresh token request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/token/refresh")]
+async fn refresh_token(
+    req: web::Json<RefreshTokenRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match rotate_refresh_token(&data.db, &req.0.refresh_token).await {
+        Ok(tokens) => HttpResponse::Ok().json(token_pair_response(tokens)),
+        Err(error) => {
+            tracing::error!("Error refreshing token: {}", error);
+            match error {
+                CustomError::InvalidRefreshToken | CustomError::RefreshTokenReuse => {
+                    HttpResponse::Unauthorized()
+                        .json(json!({"success": false, "error": "Invalid refresh token"}))
+                }
+                _ => HttpResponse::InternalServerError().json(json!({"success": false})),
+            }
+        }
+    }
+}
+
+/// Logs out the current session.
+///
+/// The presented access token is added to the revocation store. If a refresh token is passed
+/// in the body, its whole token family is revoked as well.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The optional logout request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/logout")]
+async fn logout(
+    http_req: HttpRequest,
+    req: Option<web::Json<LogoutRequest>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match http_req.extensions().get::<Claims>().cloned() {
+        Some(claims) => claims,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    if let Err(error) = data
+        .db
+        .revoke_access_token(&claims.jti, claims.exp as i64)
+        .await
+    {
+        tracing::error!("Error revoking access token: {}", error);
+        return HttpResponse::InternalServerError().json(json!({"success": false}));
+    }
+
+    if let Some(presented_token) = req.and_then(|req| req.0.refresh_token) {
+        match data
+            .db
+            .find_refresh_token(&hash_token(&presented_token))
+            .await
+        {
+            Ok(Some(stored)) if stored.user_id == claims.sub => {
+                if let Err(error) = data.db.revoke_refresh_token_family(&stored.family_id).await {
+                    tracing::error!("Error revoking refresh token: {}", error);
+                    return HttpResponse::InternalServerError().json(json!({"success": false}));
+                }
+            }
+            Ok(_) => tracing::warn!("Unknown refresh token passed to logout"),
+            Err(error) => {
+                tracing::error!("Error looking up refresh token: {}", error);
+                return HttpResponse::InternalServerError().json(json!({"success": false}));
+            }
+        }
+    }
+
+    tracing::info!("User logged out: {}", claims.sub);
+    HttpResponse::Ok().json(json!({"success": true}))
+}
+
+/// Logs out every session of the current user.
+///
+/// The user's token generation is incremented, which invalidates all access tokens issued so
+/// far, and all of the user's refresh tokens are revoked.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/logout/all")]
+async fn logout_all(http_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    if let Err(error) = data.db.increment_token_generation(&user_id).await {
+        tracing::error!("Error incrementing token generation: {}", error);
+        return HttpResponse::InternalServerError().json(json!({"success": false}));
+    }
+
+    if let Err(error) = data.db.revoke_user_refresh_tokens(&user_id).await {
+        tracing::error!("Error revoking refresh tokens: {}", error);
+        return HttpResponse::InternalServerError().json(json!({"success": false}));
+    }
+
+    tracing::info!("User logged out everywhere: {}", user_id);
+    HttpResponse::Ok().json(json!({"success": true}))
+}
+
+/// Publishes the public keys the tenant uses to sign access tokens.
+///
+/// Downstream services can use this JWK set to verify tokens without being able to mint them.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/.well-known/jwks.json")]
+async fn jwks(data: web::Data<AppState>) -> impl Responder {
+    match jwt::public_jwks(data.db.tenant()) {
+        Ok(jwks) => HttpResponse::Ok().json(jwks),
+        Err(error) => {
+            tracing::error!("Error loading signing keys: {}", error);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Lists the JWT signing keys and their states.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/admin/keys", wrap = "RequirePermission::new(rbac::KEYS_READ)")]
+async fn list_signing_keys(data: web::Data<AppState>) -> impl Responder {
+    match data.db.get_signing_keys().await {
+        Ok(keys) => {
+            let keys: Vec<serde_json::Value> = keys
+                .into_iter()
+                .map(|key| {
+                    json!({
+                        "kid": key.kid,
+                        "algorithm": key.algorithm,
+                        "state": key.state,
+                        "created_at": key.created_at,
+                    })
+                })
+                .collect();
+            HttpResponse::Ok().json(json!({"success": true, "keys": keys}))
+        }
+        Err(error) => {
+            tracing::error!("Error listing signing keys: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Rotates the JWT signing key.
+///
+/// A new active key is generated. The previous key stays valid for verification, so nobody is
+/// logged out by the rotation.
+///
+/// # Arguments
+///
+/// * `req` - The rotation request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/keys/rotate",
+    wrap = "RequirePermission::new(rbac::KEYS_MANAGE)"
+)]
+async fn rotate_signing_key(
+    req: Option<web::Json<RotateSigningKeyRequest>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let algorithm = match req.and_then(|req| req.0.algorithm) {
+        Some(algorithm) => match Algorithm::from_str(&algorithm) {
+            Ok(algorithm) => algorithm,
+            Err(_) => {
+                return HttpResponse::BadRequest()
+                    .json(json!({"success": false, "error": "Unknown algorithm"}))
+            }
+        },
+        None => match keyring::configured_algorithm() {
+            Ok(algorithm) => algorithm,
+            Err(error) => {
+                tracing::error!("Error reading configured algorithm: {}", error);
+                return HttpResponse::InternalServerError().json(json!({"success": false}));
+            }
+        },
+    };
+
+    let result = match keyring::rotate_signing_key(&data.db, algorithm).await {
+        Ok(kid) => reload_keyring(&data.db).await.map(|_| kid),
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(kid) => HttpResponse::Ok().json(json!({"success": true, "kid": kid})),
+        Err(CustomError::KeyringError(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => {
+            tracing::error!("Error rotating signing key: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Changes the state of a JWT signing key.
+///
+/// # Arguments
+///
+/// * `path` - The key ID.
+/// * `req` - The key state request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/keys/{kid}/state",
+    wrap = "RequirePermission::new(rbac::KEYS_MANAGE)"
+)]
+async fn set_signing_key_state(
+    path: web::Path<String>,
+    req: web::Json<SigningKeyStateRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let kid = path.into_inner();
+    let result = match keyring::set_key_state(&data.db, &kid, req.0.state).await {
+        Ok(_) => reload_keyring(&data.db).await,
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(CustomError::KeyringError(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => {
+            tracing::error!("Error changing signing key state: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Builds the response body for a successfully issued token pair.
+///
+/// # Arguments
+///
+/// * `tokens` - The issued token pair.
+///
+/// # Returns
+///
+/// The JSON response body.
+fn token_pair_response(tokens: TokenPair) -> serde_json::Value {
+    json!({
+        "success": true,
+        "token": tokens.access_token,
+        "refresh_token": tokens.refresh_token,
+        "token_type": tokens.token_type,
+        "expires_in": tokens.expires_in,
+    })
+}
+
+/// Changes the Username of a user.
+///
+/// # Arguments
+///
+/// * `req` - The change username request.
+///
+/// * `http_req` - The http request.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/change_username")]
+async fn change_username(
+    http_req: HttpRequest,
+    req: web::Json<ChangeUsernameRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+    let new_username = req.0.username;
+    let user_id = http_req
+        .extensions()
+        .get::<String>()
+        .cloned()
+        .unwrap_or_else(|| "Unknown".to_string());
+
+    match data.db.change_username(user_id, new_username).await {
+        Ok(_) => HttpResponse::Ok().json("Successfully changed username"),
+        Err(_error) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Changes the Password of a user.
+///
+/// # Arguments
+///
+/// * `req` - The change username request.
+///
+/// * `http_req` - The http request.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/change_password")]
+async fn change_password(
+    http_req: HttpRequest,
+    req: web::Json<ChangePasswordRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+    let new_password = req.0.password;
+    let user_id = http_req
+        .extensions()
+        .get::<String>()
+        .cloned()
+        .unwrap_or_else(|| "Unknown".to_string());
+
+    match data.db.change_password(user_id, new_password).await {
+        Ok(_) => HttpResponse::Ok().json("Successfully changed password"),
+        Err(_error) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Sends a password reset token to the given email address.
+///
+/// The response is the same whether or not an account exists for the address, so the endpoint
+/// can't be used to find out who has an account.
+///
+/// # Arguments
+///
+/// * `req` - The forgot password request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/password/forgot")]
+async fn forgot_password(
+    req: web::Json<ForgotPasswordRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    let email = req.0.email.to_lowercase();
+    if let Err(error) =
+        password_reset::request_password_reset(&data.db, data.mailer.as_ref(), &email).await
+    {
+        // Failures are only logged, since reporting them would reveal that the account exists.
+        tracing::error!("Error sending password reset token: {}", error);
+    }
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "message": "If an account exists for this email address, a password reset link has been sent",
+    }))
+}
+
+/// Sets a new password using a password reset token.
+///
+/// All existing sessions of the user are revoked.
+///
+/// # Arguments
+///
+/// * `req` - The reset password request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/password/reset")]
+async fn reset_password(
+    req: web::Json<ResetPasswordRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match password_reset::reset_password(&data.db, &req.0.token, &req.0.password).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(CustomError::InvalidPasswordResetToken) => HttpResponse::BadRequest()
+            .json(json!({"success": false, "error": "Invalid or expired token"})),
+        Err(error) => {
+            tracing::error!("Error resetting password: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Verifies the email address of a user with the token sent to them.
+///
+/// # Arguments
+///
+/// * `req` - The verify email request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/verify_email")]
+async fn verify_email(
+    req: web::Json<VerifyEmailRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match email_verification::verify_email(&data.db, &req.0.token).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(CustomError::InvalidEmailVerificationToken) => HttpResponse::BadRequest()
+            .json(json!({"success": false, "error": "Invalid or expired token"})),
+        Err(error) => {
+            tracing::error!("Error verifying email address: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Sends a new email verification token to the given email address.
+///
+/// The response is the same whether or not an unverified account exists for the address.
+///
+/// # Arguments
+///
+/// * `req` - The resend verification email request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/verify_email/resend")]
+async fn resend_verification_email(
+    req: web::Json<ResendVerificationRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    let email = req.0.email.to_lowercase();
+    if let Err(error) =
+        email_verification::send_verification_email(&data.db, data.mailer.as_ref(), &email).await
+    {
+        // Failures are only logged, since reporting them would reveal that the account exists.
+        tracing::error!("Error sending verification email: {}", error);
+    }
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "message": "If an unverified account exists for this email address, a verification link has been sent",
+    }))
+}
+
+/// Lists all roles and their permissions.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/admin/roles", wrap = "RequirePermission::new(rbac::ROLES_READ)")]
+async fn list_roles(data: web::Data<AppState>) -> impl Responder {
+    match data.db.get_roles().await {
+        Ok(roles) => HttpResponse::Ok().json(json!({"success": true, "roles": roles})),
+        Err(error) => {
+            tracing::error!("Error listing roles: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Creates a role or replaces the permissions of an existing role.
+///
+/// # Arguments
+///
+/// * `req` - The role request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/admin/roles", wrap = "RequirePermission::new(rbac::ROLES_MANAGE)")]
+async fn upsert_role(req: web::Json<RoleRequest>, data: web::Data<AppState>) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    let role = Role {
+        name: req.0.name,
+        description: req.0.description,
+        permissions: req.0.permissions,
+    };
+    if let Err(error) = rbac::validate_role(&role) {
+        return HttpResponse::BadRequest()
+            .json(json!({"success": false, "error": error.to_string()}));
+    }
+
+    match data.db.upsert_role(&role).await {
+        Ok(_) => {
+            tracing::info!("Stored role: {}", role.name);
+            HttpResponse::Ok().json(json!({"success": true, "role": role}))
+        }
+        Err(error) => {
+            tracing::error!("Error storing role: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Deletes a role and removes it from every user.
+///
+/// # Arguments
+///
+/// * `path` - The name of the role.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/roles/{name}",
+    wrap = "RequirePermission::new(rbac::ROLES_MANAGE)"
+)]
+async fn delete_role(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let name = path.into_inner();
+    match data.db.delete_role(&name).await {
+        Ok(true) => {
+            tracing::info!("Deleted role: {}", name);
+            HttpResponse::Ok().json(json!({"success": true}))
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error deleting role: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Lists the roles and effective permissions of a user.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the user.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/users/{user_id}/roles",
+    wrap = "RequirePermission::new(rbac::ROLES_READ)"
+)]
+async fn list_user_roles(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let user_id = path.into_inner();
+    let roles = match data.db.get_user_roles(&user_id).await {
+        Ok(roles) => roles,
+        Err(error) => {
+            tracing::error!("Error listing user roles: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    };
+    match rbac::effective_permissions(&data.db, &user_id).await {
+        Ok(permissions) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "roles": roles,
+            "permissions": permissions,
+        })),
+        Err(error) => {
+            tracing::error!("Error listing user permissions: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Assigns a role to a user.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the user.
+/// * `req` - The role assignment request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/users/{user_id}/roles",
+    wrap = "RequirePermission::new(rbac::ROLES_MANAGE)"
+)]
+async fn assign_user_role(
+    path: web::Path<String>,
+    req: web::Json<RoleAssignmentRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match rbac::assign_role(&data.db, &path.into_inner(), &req.0.role).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(CustomError::UserNotFound) => {
+            HttpResponse::NotFound().json(json!({"success": false, "error": "User not found"}))
+        }
+        Err(CustomError::InvalidRole(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => {
+            tracing::error!("Error assigning role: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Removes a role from a user.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the user and the name of the role.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/users/{user_id}/roles/{role}",
+    wrap = "RequirePermission::new(rbac::ROLES_MANAGE)"
+)]
+async fn unassign_user_role(
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (user_id, role) = path.into_inner();
+    match data.db.unassign_role(&user_id, &role).await {
+        Ok(true) => {
+            tracing::info!("Removed role {} from user {}", role, user_id);
+            HttpResponse::Ok().json(json!({"success": true}))
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error removing role: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Explains the effective permissions of a user and where each one came from.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the user.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/users/{user_id}/permissions",
+    wrap = "RequirePermission::new(rbac::ROLES_READ)"
+)]
+async fn explain_user_permissions(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    match data.db.get_user_by_id(&user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return HttpResponse::NotFound()
+                .json(json!({"success": false, "error": "User not found"}))
+        }
+        Err(error) => {
+            tracing::error!("Error looking up user: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    }
+
+    match rbac::explain_permissions(&data.db, &user_id).await {
+        Ok(grants) => {
+            let permissions: BTreeSet<&str> = grants
+                .iter()
+                .map(|grant| grant.permission.as_str())
+                .collect();
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "permissions": permissions,
+                "grants": grants,
+            }))
+        }
+        Err(error) => {
+            tracing::error!("Error explaining permissions: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Builds the JSON representation of a group.
+///
+/// # Arguments
+///
+/// * `group` - The group.
+///
+/// # Returns
+///
+/// The JSON value.
+fn group_json(group: &Group) -> serde_json::Value {
+    json!({
+        "id": group.id.to_string(),
+        "name": group.name,
+        "description": group.description,
+    })
+}
+
+/// Builds the response for a failed group operation.
+///
+/// # Arguments
+///
+/// * `error` - The error that occurred.
+///
+/// # Returns
+///
+/// The HTTP response.
+fn group_error_response(error: CustomError) -> HttpResponse {
+    match error {
+        CustomError::GroupNotFound | CustomError::UserNotFound => {
+            HttpResponse::NotFound().json(json!({"success": false, "error": error.to_string()}))
+        }
+        CustomError::InvalidGroup(message) | CustomError::InvalidRole(message) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        _ => {
+            tracing::error!("Group error: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Lists all groups.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/admin/groups", wrap = "RequirePermission::new(rbac::GROUPS_READ)")]
+async fn list_groups(data: web::Data<AppState>) -> impl Responder {
+    match data.db.get_groups().await {
+        Ok(groups) => {
+            let groups: Vec<serde_json::Value> = groups.iter().map(group_json).collect();
+            HttpResponse::Ok().json(json!({"success": true, "groups": groups}))
+        }
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Creates a group.
+///
+/// # Arguments
+///
+/// * `req` - The group request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/admin/groups", wrap = "RequirePermission::new(rbac::GROUPS_MANAGE)")]
+async fn create_group(req: web::Json<GroupRequest>, data: web::Data<AppState>) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match groups::create_group(&data.db, &req.0.name, &req.0.description).await {
+        Ok(group) => {
+            HttpResponse::Created().json(json!({"success": true, "group": group_json(&group)}))
+        }
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Shows a group with its direct members and roles.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the group.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/groups/{group_id}",
+    wrap = "RequirePermission::new(rbac::GROUPS_READ)"
+)]
+async fn get_group(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let group_id = path.into_inner();
+    let group = match data.db.get_group(&group_id).await {
+        Ok(Some(group)) => group,
+        Ok(None) => return group_error_response(CustomError::GroupNotFound),
+        Err(error) => return group_error_response(error),
+    };
+
+    let ids = [group_id];
+    let members = match data.db.get_group_members(&ids).await {
+        Ok(members) => members,
+        Err(error) => return group_error_response(error),
+    };
+    let roles = match data.db.get_group_roles(&ids).await {
+        Ok(roles) => roles,
+        Err(error) => return group_error_response(error),
+    };
+
+    let members: Vec<String> = members.into_iter().map(|member| member.member_id).collect();
+    let roles: Vec<String> = roles.into_iter().map(|role| role.role).collect();
+    let mut group = group_json(&group);
+    group["members"] = json!(members);
+    group["roles"] = json!(roles);
+    HttpResponse::Ok().json(json!({"success": true, "group": group}))
+}
+
+/// Renames a group.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the group.
+/// * `req` - The rename group request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/groups/{group_id}/rename",
+    wrap = "RequirePermission::new(rbac::GROUPS_MANAGE)"
+)]
+async fn rename_group(
+    path: web::Path<String>,
+    req: web::Json<RenameGroupRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match groups::rename_group(&data.db, &path.into_inner(), &req.0.name).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Deletes a group. Its members lose everything they inherited through it.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the group.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/groups/{group_id}",
+    wrap = "RequirePermission::new(rbac::GROUPS_MANAGE)"
+)]
+async fn delete_group(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let group_id = path.into_inner();
+    match data.db.delete_group(&group_id).await {
+        Ok(true) => {
+            tracing::info!("Deleted group: {}", group_id);
+            HttpResponse::Ok().json(json!({"success": true}))
+        }
+        Ok(false) => group_error_response(CustomError::GroupNotFound),
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Adds a user or a nested group to a group.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the group.
+/// * `req` - The group member request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/groups/{group_id}/members",
+    wrap = "RequirePermission::new(rbac::GROUPS_MANAGE)"
+)]
+async fn add_group_member(
+    path: web::Path<String>,
+    req: web::Json<GroupMemberRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match groups::add_member(&data.db, &path.into_inner(), &req.0.member_id).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Removes a user or a nested group from a group.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the group and the ID of the member.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/groups/{group_id}/members/{member_id}",
+    wrap = "RequirePermission::new(rbac::GROUPS_MANAGE)"
+)]
+async fn remove_group_member(
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, member_id) = path.into_inner();
+    match data.db.remove_group_member(&group_id, &member_id).await {
+        Ok(true) => {
+            tracing::info!("Removed {} from group {}", member_id, group_id);
+            HttpResponse::Ok().json(json!({"success": true}))
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Assigns a role to a group.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the group.
+/// * `req` - The role assignment request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/groups/{group_id}/roles",
+    wrap = "RequirePermission::new(rbac::ROLES_MANAGE)"
+)]
+async fn assign_group_role(
+    path: web::Path<String>,
+    req: web::Json<RoleAssignmentRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match groups::assign_role(&data.db, &path.into_inner(), &req.0.role).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Removes a role from a group.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the group and the name of the role.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/groups/{group_id}/roles/{role}",
+    wrap = "RequirePermission::new(rbac::ROLES_MANAGE)"
+)]
+async fn unassign_group_role(
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (group_id, role) = path.into_inner();
+    match data.db.unassign_group_role(&group_id, &role).await {
+        Ok(true) => {
+            tracing::info!("Removed role {} from group {}", role, group_id);
+            HttpResponse::Ok().json(json!({"success": true}))
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => group_error_response(error),
+    }
+}
+
+/// Decides whether a subject may perform an action on a resource.
+///
+/// The subject is the caller unless `subject_id` names someone else, which requires the
+/// `policies:evaluate` permission. The time and the client IP address are added to the
+/// environment by the server and can't be overridden.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The authorize request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/authorize")]
+async fn authorize(
+    http_req: HttpRequest,
+    req: web::Json<AuthorizeRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let claims = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+    let req = req.into_inner();
+    if !req.resource.is_object() {
+        return HttpResponse::BadRequest()
+            .json(json!({"success": false, "error": "Resource must be an object"}));
+    }
+
+    let subject_id = req.subject_id.unwrap_or_else(|| claims.sub.clone());
+    if subject_id != claims.sub {
+        match rbac::has_permission(&data.db, &claims.sub, rbac::POLICIES_EVALUATE).await {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::Forbidden().finish(),
+            Err(error) => {
+                tracing::error!("Error checking permissions: {}", error);
+                return HttpResponse::InternalServerError().json(json!({"success": false}));
+            }
+        }
+    }
+
+    let mut subject = match policy::subject_attributes(&data.db, &subject_id).await {
+        Ok(subject) => subject,
+        Err(error) => {
+            tracing::error!("Error loading subject attributes: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    };
+    if subject_id == claims.sub {
+        // Attributes of the current session are only known for the caller.
+        subject["amr"] = json!(claims.amr);
+        if let Some(tenant) = &claims.tenant {
+            subject["tenant"] = json!(tenant);
+        }
+    }
+
+    let mut environment = req.environment;
+    environment.extend(policy::environment_attributes(chrono::Utc::now()));
+    if let Some(ip) = policy::client_ip(&http_req) {
+        environment.insert("ip".into(), json!(ip));
+    }
+
+    let request = AuthorizationRequest {
+        subject,
+        action: req.action,
+        resource: req.resource,
+        environment: serde_json::Value::Object(environment),
+    };
+    match policy::authorize(&data.db, &request).await {
+        Ok(decision) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "decision": if decision.allowed { "allow" } else { "deny" },
+            "allowed": decision.allowed,
+            "policy": decision.policy,
+            "version": decision.version,
+            "rule": decision.rule,
+        })),
+        Err(error) => {
+            tracing::error!("Error evaluating policies: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Builds the response for a failed policy operation.
+///
+/// # Arguments
+///
+/// * `error` - The error that occurred.
+///
+/// # Returns
+///
+/// The HTTP response.
+fn policy_error_response(error: CustomError) -> HttpResponse {
+    match error {
+        CustomError::PolicyNotFound => {
+            HttpResponse::NotFound().json(json!({"success": false, "error": error.to_string()}))
+        }
+        CustomError::InvalidPolicy(message) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        _ => {
+            tracing::error!("Policy error: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Lists the policies in effect, i.e. the newest version of each policy.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/policies",
+    wrap = "RequirePermission::new(rbac::POLICIES_READ)"
+)]
+async fn list_policies(data: web::Data<AppState>) -> impl Responder {
+    match policy::active_policies(&data.db).await {
+        Ok(policies) => HttpResponse::Ok().json(json!({"success": true, "policies": policies})),
+        Err(error) => policy_error_response(error),
+    }
+}
+
+/// Stores a new version of a policy, creating the policy if it doesn't exist.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `path` - The name of the policy.
+/// * `req` - The policy document.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/policies/{name}",
+    wrap = "RequirePermission::new(rbac::POLICIES_MANAGE)"
+)]
+async fn save_policy(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<PolicyDocument>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match policy::save_policy(&data.db, &path.into_inner(), &req.0, &user_id).await {
+        Ok(policy) => HttpResponse::Created().json(json!({"success": true, "policy": policy})),
+        Err(error) => policy_error_response(error),
+    }
+}
+
+/// Lists every version of a policy, newest first.
+///
+/// # Arguments
+///
+/// * `path` - The name of the policy.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/policies/{name}/versions",
+    wrap = "RequirePermission::new(rbac::POLICIES_READ)"
+)]
+async fn list_policy_versions(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match policy::policy_versions(&data.db, &path.into_inner()).await {
+        Ok(versions) if versions.is_empty() => policy_error_response(CustomError::PolicyNotFound),
+        Ok(versions) => HttpResponse::Ok().json(json!({"success": true, "versions": versions})),
+        Err(error) => policy_error_response(error),
+    }
+}
+
+/// Restores an earlier version of a policy by storing it as the newest version.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `path` - The name of the policy.
+/// * `req` - The policy rollback request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/policies/{name}/rollback",
+    wrap = "RequirePermission::new(rbac::POLICIES_MANAGE)"
+)]
+async fn rollback_policy(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<PolicyRollbackRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match policy::rollback_policy(&data.db, &path.into_inner(), req.0.version, &user_id).await {
+        Ok(policy) => HttpResponse::Ok().json(json!({"success": true, "policy": policy})),
+        Err(error) => policy_error_response(error),
+    }
+}
+
+/// Deletes a policy with all of its versions.
+///
+/// # Arguments
+///
+/// * `path` - The name of the policy.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/policies/{name}",
+    wrap = "RequirePermission::new(rbac::POLICIES_MANAGE)"
+)]
+async fn delete_policy(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let name = path.into_inner();
+    match data.db.delete_policy(&name).await {
+        Ok(true) => {
+            tracing::info!("Deleted policy: {}", name);
+            HttpResponse::Ok().json(json!({"success": true}))
+        }
+        Ok(false) => policy_error_response(CustomError::PolicyNotFound),
+        Err(error) => policy_error_response(error),
+    }
+}
+
+/// Simulates a role or policy change and reports which users would gain or lose access.
+///
+/// Nothing is stored, so the change can be reviewed before it is made.
+///
+/// # Arguments
+///
+/// * `req` - The simulation.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/simulate",
+    wrap = "RequirePermission::new(rbac::CHANGES_SIMULATE)"
+)]
+async fn simulate_change(req: web::Json<Simulation>, data: web::Data<AppState>) -> impl Responder {
+    match simulation::simulate(&data.db, &req.0).await {
+        Ok(report) => HttpResponse::Ok().json(json!({"success": true, "report": report})),
+        Err(CustomError::UserNotFound) => HttpResponse::NotFound()
+            .json(json!({"success": false, "error": CustomError::UserNotFound.to_string()})),
+        Err(CustomError::InvalidRole(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => policy_error_response(error),
+    }
+}
+
+/// Builds the response for a failed tenant operation.
+///
+/// Tenants are managed from the default tenant only, so requests from other tenants are
+/// answered as if the routes didn't exist.
+///
+/// # Arguments
+///
+/// * `error` - The error that occurred.
+///
+/// # Returns
+///
+/// The HTTP response.
+fn tenant_error_response(error: CustomError) -> HttpResponse {
+    match error {
+        CustomError::TenantNotFound => {
+            HttpResponse::NotFound().json(json!({"success": false, "error": error.to_string()}))
+        }
+        CustomError::InvalidTenant(message) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        _ => {
+            tracing::error!("Tenant error: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Lists the tenants.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/admin/tenants", wrap = "RequirePermission::new(rbac::TENANTS_READ)")]
+async fn list_tenants(data: web::Data<AppState>) -> impl Responder {
+    if data.db.tenant() != DEFAULT_TENANT {
+        return HttpResponse::NotFound().finish();
+    }
+    match data.tenants.list().await {
+        Ok(tenants) => HttpResponse::Ok().json(json!({"success": true, "tenants": tenants})),
+        Err(error) => tenant_error_response(error),
+    }
+}
+
+/// Creates a tenant with its own database and signing key.
+///
+/// # Arguments
+///
+/// * `req` - The tenant creation request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/tenants",
+    wrap = "RequirePermission::new(rbac::TENANTS_MANAGE)"
+)]
+async fn create_tenant(
+    req: web::Json<CreateTenantRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if data.db.tenant() != DEFAULT_TENANT {
+        return HttpResponse::NotFound().finish();
+    }
+    let req = req.into_inner();
+    let tenant = Tenant {
+        tenant_id: req.tenant_id,
+        name: req.name,
+        hosts: req.hosts,
+        settings: req.settings,
+        created_at: 0,
+    };
+    match data.tenants.create(tenant).await {
+        Ok(tenant) => HttpResponse::Created().json(json!({"success": true, "tenant": tenant})),
+        Err(error) => tenant_error_response(error),
+    }
+}
+
+/// Changes the name, hosts and settings of a tenant.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the tenant.
+/// * `req` - The tenant update request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/tenants/{tenant_id}",
+    wrap = "RequirePermission::new(rbac::TENANTS_MANAGE)"
+)]
+async fn update_tenant(
+    path: web::Path<String>,
+    req: web::Json<UpdateTenantRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if data.db.tenant() != DEFAULT_TENANT {
+        return HttpResponse::NotFound().finish();
+    }
+    let req = req.into_inner();
+    let tenant = Tenant {
+        tenant_id: path.into_inner(),
+        name: req.name,
+        hosts: req.hosts,
+        settings: req.settings,
+        created_at: 0,
+    };
+    match data.tenants.update(tenant).await {
+        Ok(tenant) => HttpResponse::Ok().json(json!({"success": true, "tenant": tenant})),
+        Err(error) => tenant_error_response(error),
+    }
+}
+
+/// Deletes a tenant. Its data is kept on disk.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the tenant.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/tenants/{tenant_id}",
+    wrap = "RequirePermission::new(rbac::TENANTS_MANAGE)"
+)]
+async fn delete_tenant(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    if data.db.tenant() != DEFAULT_TENANT {
+        return HttpResponse::NotFound().finish();
+    }
+    match data.tenants.delete(&path.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(error) => tenant_error_response(error),
+    }
+}
+
+/// Starts an OAuth authorization request.
+///
+/// Valid requests are redirected to the login page configured in `OAUTH_LOGIN_URL` with their
+/// parameters, or described in the response if there is none. The login page completes the
+/// request at `POST /oauth/authorize`.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `query` - The parameters of the authorization request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/oauth/authorize")]
+async fn oauth_authorize(
+    http_req: HttpRequest,
+    query: web::Query<AuthorizationParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let authorization = match oauth::validate_authorization_request(&data.db, &query.0).await {
+        Ok(authorization) => authorization,
+        Err(AuthorizationError::InvalidClient(message)) => {
+            tracing::warn!("Invalid authorization request: {}", message);
+            return HttpResponse::BadRequest().json(json!({"success": false, "error": message}));
+        }
+        Err(AuthorizationError::Redirect(url)) => {
+            return HttpResponse::Found()
+                .insert_header(("Location", url))
+                .finish();
+        }
+        Err(AuthorizationError::Failed(error)) => {
+            tracing::error!("Error validating authorization request: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    };
+
+    match oauth::login_url() {
+        Some(login_url) => HttpResponse::Found()
+            .insert_header((
+                "Location",
+                format!("{}?{}", login_url, http_req.query_string()),
+            ))
+            .finish(),
+        None => HttpResponse::Ok().json(json!({
+            "success": true,
+            "client_id": authorization.client.client_id,
+            "client_name": authorization.client.name,
+            "redirect_uri": authorization.redirect_uri,
+            "scope": authorization.scope,
+        })),
+    }
+}
+
+/// Completes an OAuth authorization request by logging the user in.
+///
+/// Users with a second factor get an MFA challenge instead, which they complete at
+/// `POST /oauth/authorize/mfa`. On success the response contains the redirect URL that delivers
+/// the authorization code to the client. If the user hasn't yet allowed a third-party client the
+/// requested scopes, it contains the URL of the consent page with `consent_required` instead.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The OAuth login request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/authorize")]
+async fn oauth_login(
+    http_req: HttpRequest,
+    req: web::Json<OAuthLoginRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    let db = &data.db;
+    let authorization = match validated_oauth_login(db, &req.0.params).await {
+        Ok(authorization) => authorization,
+        Err(response) => return response,
+    };
+
+    // Authenticate the user
+    let email = req.0.email.to_lowercase();
+    let user = match db.authenticate_user(email, req.0.password.clone()).await {
+        Ok(user) => user,
+        Err(CustomError::InvalidPassword) | Err(CustomError::UserNotFound) => {
+            return HttpResponse::Unauthorized().json(json!({"success": false}));
+        }
+        Err(error) => {
+            tracing::error!("Error authenticating user: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    };
+
+    // Apply the policy for unverified email addresses
+    if let Err(error) = email_verification::check_login_allowed(db, &user) {
+        return HttpResponse::Forbidden()
+            .json(json!({"success": false, "error": error.to_string()}));
+    }
+
+    let auth_methods = vec!["pwd".to_string()];
+    match mfa::challenge_second_factor(db, &user, &auth_methods).await {
+        Ok(None) => {}
+        Ok(Some((methods, challenge))) => return mfa_required_response(methods, challenge),
+        Err(error) => {
+            tracing::error!("Error completing login: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    }
+
+    oauth_login_response(
+        &http_req,
+        db,
+        &authorization,
+        &user.id.to_string(),
+        &auth_methods,
+    )
+    .await
+}
+
+/// Completes an OAuth authorization request for a user who has to pass a second factor.
+///
+/// The request repeats the parameters of the authorization request along with the MFA token
+/// returned by `POST /oauth/authorize`. The second factor can be a TOTP code, one of the user's
+/// recovery codes or a passkey assertion started at `/login/mfa/webauthn`.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The OAuth MFA login request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/authorize/mfa")]
+async fn oauth_login_mfa(
+    http_req: HttpRequest,
+    req: web::Json<OAuthMfaLoginRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    let db = &data.db;
+    let authorization = match validated_oauth_login(db, &req.0.params).await {
+        Ok(authorization) => authorization,
+        Err(response) => return response,
+    };
+
+    let result = match (&req.0.code, &req.0.webauthn) {
+        (Some(code), None) => mfa::pass_mfa_challenge(db, &req.0.mfa_token, code).await,
+        (None, Some(credential)) => {
+            mfa::pass_mfa_challenge_with_passkey(db, &req.0.mfa_token, credential).await
+        }
+        _ => return HttpResponse::BadRequest().json(
+            json!({"success": false, "error": "Either a code or a passkey assertion is required"}),
+        ),
+    };
+    match result {
+        Ok(completed) => {
+            oauth_login_response(
+                &http_req,
+                db,
+                &authorization,
+                &completed.user_id,
+                &completed.auth_methods,
+            )
+            .await
+        }
+        Err(error) => mfa_error_response(error),
+    }
+}
+
+/// Validates the authorization request repeated by the login page.
+async fn validated_oauth_login(
+    db: &Database,
+    params: &AuthorizationParams,
+) -> Result<ValidatedAuthorization, HttpResponse> {
+    oauth::validate_authorization_request(db, params)
+        .await
+        .map_err(|error| match error {
+            AuthorizationError::InvalidClient(message) => {
+                tracing::warn!("Invalid authorization request: {}", message);
+                HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+            }
+            AuthorizationError::Redirect(url) => {
+                HttpResponse::BadRequest().json(json!({"success": false, "redirect_uri": url}))
+            }
+            AuthorizationError::Failed(error) => {
+                tracing::error!("Error validating authorization request: {}", error);
+                HttpResponse::InternalServerError().json(json!({"success": false}))
+            }
+        })
+}
+
+/// Issues the authorization code for a logged-in user, or asks for consent first.
+async fn oauth_login_response(
+    http_req: &HttpRequest,
+    db: &Database,
+    authorization: &ValidatedAuthorization,
+    user_id: &str,
+    auth_methods: &[String],
+) -> HttpResponse {
+    // Ask for consent unless the user already allowed the client the requested scopes
+    let scope = authorization.scope.as_deref();
+    match consent::has_consent(db, &authorization.client, user_id, scope).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return match consent::request_consent(db, authorization, user_id, auth_methods).await {
+                Ok(ticket) => HttpResponse::Ok().json(json!({
+                    "success": true,
+                    "consent_required": true,
+                    "redirect_uri": oauth::redirect_url(
+                        &format!("{}/oauth/consent", request_issuer(http_req, db)),
+                        &[("consent", &ticket)],
+                    ),
+                })),
+                Err(error) => {
+                    tracing::error!("Error requesting consent: {}", error);
+                    HttpResponse::InternalServerError().json(json!({"success": false}))
+                }
+            };
+        }
+        Err(error) => {
+            tracing::error!("Error looking up consent: {}", error);
+            return HttpResponse::InternalServerError().json(json!({"success": false}));
+        }
+    }
+
+    match oauth::issue_authorization_code(db, authorization, user_id, auth_methods).await {
+        Ok(url) => HttpResponse::Ok().json(json!({"success": true, "redirect_uri": url})),
+        Err(error) => {
+            tracing::error!("Error issuing authorization code: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Shows the consent page for an authorization request.
+///
+/// The page can't be framed, so that users can't be tricked into approving a request.
+///
+/// # Arguments
+///
+/// * `query` - The consent ticket.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/oauth/consent")]
+async fn consent_page(
+    query: web::Query<ConsentQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match consent::find_consent_request(&data.db, &query.consent).await {
+        Ok((request, client)) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .insert_header(("Cache-Control", "no-store"))
+            .insert_header(("X-Frame-Options", "DENY"))
+            .insert_header((
+                "Content-Security-Policy",
+                "default-src 'none'; img-src https:; style-src 'unsafe-inline'; frame-ancestors 'none'",
+            ))
+            .body(consent::render_consent_page(
+                &client,
+                request.scope.as_deref(),
+                &query.consent,
+            )),
+        Err(CustomError::InvalidConsentRequest) => HttpResponse::NotFound()
+            .json(json!({"success": false, "error": "Invalid or expired consent request"})),
+        Err(error) => {
+            tracing::error!("Error looking up consent request: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Applies the decision the user made on the consent page and redirects to the client.
+///
+/// # Arguments
+///
+/// * `form` - The consent ticket and the decision.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/consent")]
+async fn consent_decision(
+    form: web::Form<ConsentDecisionForm>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let approved = match form.decision.as_str() {
+        "approve" => true,
+        "deny" => false,
+        _ => return HttpResponse::BadRequest().json(json!({"success": false})),
+    };
+
+    match consent::decide_consent(&data.db, &form.consent, approved).await {
+        Ok(url) => HttpResponse::SeeOther()
+            .insert_header(("Location", url))
+            .insert_header(("Cache-Control", "no-store"))
+            .finish(),
+        Err(CustomError::InvalidConsentRequest) => HttpResponse::NotFound()
+            .json(json!({"success": false, "error": "Invalid or expired consent request"})),
+        Err(error) => {
+            tracing::error!("Error deciding consent: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Lists the clients the current user consented to.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/me/consents")]
+async fn list_consents(http_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match data.db.get_consents(&user_id).await {
+        Ok(consents) => {
+            let consents: Vec<serde_json::Value> = consents
+                .into_iter()
+                .map(|consent| {
+                    json!({
+                        "client_id": consent.client_id,
+                        "scopes": consent.scopes,
+                        "created_at": consent.created_at,
+                        "updated_at": consent.updated_at,
+                    })
+                })
+                .collect();
+            HttpResponse::Ok().json(json!({"success": true, "consents": consents}))
+        }
+        Err(error) => {
+            tracing::error!("Error listing consents: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Revokes the consent the current user gave a client, together with the client's refresh
+/// tokens.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `path` - The client ID.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete("/me/consents/{client_id}")]
+async fn revoke_consent(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match consent::revoke_consent(&data.db, &user_id, &path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json(json!({"success": true})),
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error revoking consent: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Exchanges an authorization code or a refresh token for tokens.
+///
+/// The request body is form-encoded. Confidential clients authenticate with HTTP Basic or
+/// with `client_id` and `client_secret` in the body, public clients only pass `client_id`.
+/// Clients that send a DPoP proof in the `DPoP` header get tokens bound to the proof's key.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The token request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/token")]
+async fn oauth_token(
+    http_req: HttpRequest,
+    req: web::Form<TokenRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let basic = http_req
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(oauth::basic_credentials);
+
+    let mut request = req.into_inner();
+    if let Some(proof) = http_req.headers().get(dpop::DPOP) {
+        let proof = proof.to_str().unwrap_or_default();
+        let url = dpop::request_url(&http_req);
+        match dpop::verify_proof(&data.db, proof, "POST", &url, None).await {
+            Ok(jkt) => request.dpop_jkt = Some(jkt),
+            Err(CustomError::InvalidDpopProof(reason)) => {
+                return oauth_error_response(
+                    "token request",
+                    CustomError::OAuthError("invalid_dpop_proof", reason),
+                )
+            }
+            Err(error) => return oauth_error_response("token request", error),
+        }
+    }
+
+    let issuer = request_issuer(&http_req, &data.db);
+    match oauth::exchange_token(&data.db, &request, basic, &issuer).await {
+        Ok(tokens) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "no-store"))
+            .insert_header(("Pragma", "no-cache"))
+            .json(tokens),
+        Err(error) => oauth_error_response("token request", error),
+    }
+}
+
+/// Builds the response for an error of the token, device authorization or client registration
+/// endpoint (RFC 6749, section 5.2).
+fn oauth_error_response(request: &str, error: CustomError) -> HttpResponse {
+    match error {
+        CustomError::OAuthError(code, description) => {
+            tracing::warn!("OAuth {} rejected: {}: {}", request, code, description);
+            let mut response = if code == "invalid_client" || code == "invalid_token" {
+                HttpResponse::Unauthorized()
+            } else {
+                HttpResponse::BadRequest()
+            };
+            response
+                .insert_header(("Cache-Control", "no-store"))
+                .json(json!({"error": code, "error_description": description}))
+        }
+        error => {
+            tracing::error!("Error handling OAuth {}: {}", request, error);
+            HttpResponse::InternalServerError().json(json!({"error": "server_error"}))
+        }
+    }
+}
+
+/// Registers an OAuth client with the metadata it sends itself (RFC 7591).
+///
+/// The request body is JSON and the request carries an initial access token issued at
+/// `/admin/registration_tokens` as a bearer token.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The metadata of the client.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/register")]
+async fn oauth_register(
+    http_req: HttpRequest,
+    req: web::Json<ClientMetadata>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let initial_access_token = http_req
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match client_registration::register_dynamic_client(
+        &data.db,
+        initial_access_token,
+        req.into_inner(),
+    )
+    .await
+    {
+        Ok(client) => HttpResponse::Created()
+            .insert_header(("Cache-Control", "no-store"))
+            .json(client),
+        Err(error) => oauth_error_response("client registration", error),
+    }
+}
+
+/// Starts a device authorization for a device without a browser (RFC 8628).
+///
+/// The request body is form-encoded and authenticates the client like a token request. The
+/// device shows the returned user code and polls `/oauth/token` with the device code.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The device authorization request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/device/code")]
+async fn device_code(
+    http_req: HttpRequest,
+    req: web::Form<DeviceAuthorizationRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let basic = http_req
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(oauth::basic_credentials);
+
+    let issuer = request_issuer(&http_req, &data.db);
+    match device_authorization::start_device_authorization(&data.db, &req.0, basic, &issuer).await {
+        Ok(response) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "no-store"))
+            .json(response),
+        Err(error) => oauth_error_response("device authorization request", error),
+    }
+}
+
+/// Tells a confidential client whether a token is active (RFC 7662).
+///
+/// The request body is form-encoded and authenticates the client like a token request.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The introspection request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/introspect")]
+async fn oauth_introspect(
+    http_req: HttpRequest,
+    req: web::Form<IntrospectionRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let result = match authenticate_form_client(&http_req, &data.db, &req.0).await {
+        Ok(client) => introspection::introspect_token(&data.db, &client, &req.0).await,
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(response) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "no-store"))
+            .json(response),
+        Err(error) => oauth_error_response("introspection request", error),
+    }
+}
+
+/// Revokes an access or refresh token issued to the client (RFC 7009).
+///
+/// The request body is form-encoded and authenticates the client like a token request. Unknown
+/// tokens are accepted as well, since they can't be used anyway.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The revocation request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/revoke")]
+async fn oauth_revoke(
+    http_req: HttpRequest,
+    req: web::Form<RevocationRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let result = match authenticate_form_client(&http_req, &data.db, &req.0).await {
+        Ok(client) => introspection::revoke_token(&data.db, &client, &req.0).await,
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error) => oauth_error_response("revocation request", error),
+    }
+}
+
+/// Authenticates the client of an introspection or revocation request.
+async fn authenticate_form_client(
+    http_req: &HttpRequest,
+    db: &Database,
+    req: &IntrospectionRequest,
+) -> Result<OAuthClient, CustomError> {
+    let basic = http_req
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(oauth::basic_credentials);
+    oauth::authenticate_request_client(
+        db,
+        req.client_id.as_deref(),
+        req.client_secret.as_deref(),
+        basic,
+    )
+    .await
+}
+
+/// Shows the logged-in user which client a user code belongs to.
+///
+/// # Arguments
+///
+/// * `query` - The user code.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/oauth/device")]
+async fn device_verification(
+    query: web::Query<DeviceVerificationQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match device_authorization::find_device_authorization(&data.db, &query.user_code).await {
+        Ok((authorization, client)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "client_id": client.client_id,
+            "client_name": client.name,
+            "scope": authorization.scope,
+        })),
+        Err(CustomError::InvalidUserCode) => HttpResponse::NotFound()
+            .json(json!({"success": false, "error": "Invalid or expired user code"})),
+        Err(error) => {
+            tracing::error!("Error looking up device authorization: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Lets the logged-in user approve or deny the device that shows a user code.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The user code and the decision.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post("/oauth/device")]
+async fn device_decision(
+    http_req: HttpRequest,
+    req: web::Json<DeviceDecisionRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (user_id, auth_methods) = match http_req.extensions().get::<Claims>() {
+        Some(claims) => (claims.sub.clone(), claims.amr.clone()),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match device_authorization::decide_device_authorization(
+        &data.db,
+        &req.user_code,
+        &user_id,
+        &auth_methods,
+        req.approve,
+    )
+    .await
+    {
+        Ok(()) => HttpResponse::Ok().json(json!({"success": true})),
+        Err(CustomError::InvalidUserCode) => HttpResponse::NotFound()
+            .json(json!({"success": false, "error": "Invalid or expired user code"})),
+        Err(error) => {
+            tracing::error!("Error deciding device authorization: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Builds the response for a failed identity provider operation or federated login.
+///
+/// # Arguments
+///
+/// * `error` - The error that occurred.
+///
+/// # Returns
+///
+/// The HTTP response.
+fn identity_provider_error_response(error: CustomError) -> HttpResponse {
+    match error {
+        CustomError::IdentityProviderNotFound => {
+            HttpResponse::NotFound().json(json!({"success": false, "error": error.to_string()}))
+        }
+        CustomError::InvalidIdentityProvider(message) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        CustomError::FederationError(message) => {
+            tracing::warn!("Federated login rejected: {}", message);
+            HttpResponse::Unauthorized().json(json!({"success": false, "error": message}))
+        }
+        CustomError::IdentityProviderUnavailable(_) => HttpResponse::BadGateway()
+            .json(json!({"success": false, "error": "The identity provider is unavailable"})),
+        _ => {
+            tracing::error!("Identity provider error: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Describes an identity provider without its client secret.
+fn identity_provider_json(provider: &IdentityProvider) -> serde_json::Value {
+    json!({
+        "provider_id": provider.provider_id,
+        "name": provider.name,
+        "issuer": provider.issuer,
+        "client_id": provider.client_id,
+        "scopes": provider.scopes,
+        "claim_mapping": provider.claim_mapping,
+        "provision_users": provider.provision_users,
+        "disabled": provider.disabled,
+        "created_at": provider.created_at,
+    })
+}
+
+/// Lists the external identity providers.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/identity_providers",
+    wrap = "RequirePermission::new(rbac::IDENTITY_PROVIDERS_READ)"
+)]
+async fn list_identity_providers(data: web::Data<AppState>) -> impl Responder {
+    match data.db.get_identity_providers().await {
+        Ok(providers) => {
+            let providers: Vec<serde_json::Value> =
+                providers.iter().map(identity_provider_json).collect();
+            HttpResponse::Ok().json(json!({"success": true, "identity_providers": providers}))
+        }
+        Err(error) => identity_provider_error_response(error),
+    }
+}
+
+/// Adds an external identity provider.
+///
+/// # Arguments
+///
+/// * `req` - The identity provider creation request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/identity_providers",
+    wrap = "RequirePermission::new(rbac::IDENTITY_PROVIDERS_MANAGE)"
+)]
+async fn create_identity_provider(
+    req: web::Json<CreateIdentityProviderRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let req = req.into_inner();
+    match federation::create_provider(&data.db, &req.provider_id, req.provider).await {
+        Ok(provider) => HttpResponse::Created()
+            .json(json!({"success": true, "identity_provider": identity_provider_json(&provider)})),
+        Err(error) => identity_provider_error_response(error),
+    }
+}
+
+/// Gets an external identity provider.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the provider.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/identity_providers/{provider_id}",
+    wrap = "RequirePermission::new(rbac::IDENTITY_PROVIDERS_READ)"
+)]
+async fn get_identity_provider(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.get_identity_provider(&path.into_inner()).await {
+        Ok(Some(provider)) => HttpResponse::Ok()
+            .json(json!({"success": true, "identity_provider": identity_provider_json(&provider)})),
+        Ok(None) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => identity_provider_error_response(error),
+    }
+}
+
+/// Replaces the settings of an external identity provider. The client secret is kept if it is
+/// left out.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the provider.
+/// * `req` - The new settings of the provider.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/identity_providers/{provider_id}",
+    wrap = "RequirePermission::new(rbac::IDENTITY_PROVIDERS_MANAGE)"
+)]
+async fn update_identity_provider(
+    path: web::Path<String>,
+    req: web::Json<ProviderRegistration>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match federation::update_provider(&data.db, &path.into_inner(), req.into_inner()).await {
+        Ok(provider) => HttpResponse::Ok()
+            .json(json!({"success": true, "identity_provider": identity_provider_json(&provider)})),
+        Err(error) => identity_provider_error_response(error),
+    }
+}
+
+/// Deletes an external identity provider. Users linked to it keep their local accounts.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the provider.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/identity_providers/{provider_id}",
+    wrap = "RequirePermission::new(rbac::IDENTITY_PROVIDERS_MANAGE)"
+)]
+async fn delete_identity_provider(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.delete_identity_provider(&path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json(json!({"success": true})),
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => identity_provider_error_response(error),
+    }
+}
+
+/// Lists the identity providers users can log in with, for the login page.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/federation/providers")]
+async fn federation_providers(data: web::Data<AppState>) -> impl Responder {
+    match data.db.get_identity_providers().await {
+        Ok(providers) => {
+            let providers: Vec<serde_json::Value> = providers
+                .iter()
+                .filter(|provider| !provider.disabled)
+                .map(|provider| json!({"provider_id": provider.provider_id, "name": provider.name}))
+                .collect();
+            HttpResponse::Ok().json(json!({"success": true, "identity_providers": providers}))
+        }
+        Err(error) => identity_provider_error_response(error),
+    }
+}
+
+/// Sends the user to an external identity provider to log in.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `path` - The ID of the provider.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/federation/{provider_id}/login")]
+async fn federation_login(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let provider_id = path.into_inner();
+    let redirect_uri = format!(
+        "{}/federation/{}/callback",
+        request_issuer(&http_req, &data.db),
+        provider_id
+    );
+    match federation::start_login(&data.db, &provider_id, &redirect_uri).await {
+        Ok(url) => HttpResponse::Found()
+            .insert_header(("Location", url))
+            .insert_header(("Cache-Control", "no-store"))
+            .finish(),
+        Err(error) => identity_provider_error_response(error),
+    }
+}
+
+/// Completes a login at an external identity provider and issues a token pair.
+///
+/// Users who set up a second factor get an MFA challenge instead, like after a password login.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the provider.
+/// * `query` - The authorization code and state sent by the provider.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/federation/{provider_id}/callback")]
+async fn federation_callback(
+    path: web::Path<String>,
+    query: web::Query<FederationCallbackQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        tracing::warn!("Identity provider returned an error: {:?}", query.error);
+        return HttpResponse::Unauthorized().json(json!({"success": false, "error": query.error}));
+    };
+
+    let db = &data.db;
+    let user = match federation::complete_login(db, &path.into_inner(), &code, &state).await {
+        Ok(user) => user,
+        Err(error) => return identity_provider_error_response(error),
+    };
+
+    // Apply the policy for unverified email addresses
+    if let Err(error) = email_verification::check_login_allowed(db, &user) {
+        return HttpResponse::Forbidden()
+            .json(json!({"success": false, "error": error.to_string()}));
+    }
+
+    first_factor_response(db, &user, &["fed".to_string()]).await
+}
+
+/// Returns the OpenID Connect issuer URL of the tenant that handles a request.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `db` - The database of the tenant.
+///
+/// # Returns
+///
+/// The issuer URL.
+fn request_issuer(http_req: &HttpRequest, db: &Database) -> String {
+    let connection_info = http_req.connection_info();
+    let base_url = format!("{}://{}", connection_info.scheme(), connection_info.host());
+    oidc::issuer(&base_url, db.tenant())
+}
+
+/// Publishes the OpenID Connect discovery document of the tenant.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/.well-known/openid-configuration")]
+async fn openid_configuration(http_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let issuer = request_issuer(&http_req, &data.db);
+    match oidc::discovery_document(&issuer, data.db.tenant()) {
+        Ok(document) => HttpResponse::Ok().json(document),
+        Err(error) => {
+            tracing::error!("Error building discovery document: {}", error);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Returns the claims about the current user that the access token's scopes release.
+///
+/// The access token has to be issued to an OAuth client with the `openid` scope.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[route("/userinfo", method = "GET", method = "POST")]
+async fn userinfo(http_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let (user_id, scope) = match http_req.extensions().get::<Claims>() {
+        Some(claims) => (claims.sub.clone(), claims.scope.clone()),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if !oidc::has_scope(scope.as_deref(), OPENID_SCOPE) {
+        return HttpResponse::Forbidden()
+            .insert_header(("WWW-Authenticate", "Bearer error=\"insufficient_scope\""))
+            .json(json!({"error": "insufficient_scope"}));
+    }
+
+    let user = match data.db.get_user_by_id(&user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(error) => {
+            tracing::error!("Error looking up user: {}", error);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match oidc::scope_claims(&user, scope.as_deref()) {
+        Ok(mut claims) => {
+            claims.insert("sub".to_string(), json!(user_id));
+            HttpResponse::Ok().json(claims)
+        }
+        Err(error) => {
+            tracing::error!("Error releasing user claims: {}", error);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Lists the registered OAuth clients.
+///
+/// # Arguments
+///
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get("/admin/clients", wrap = "RequirePermission::new(rbac::CLIENTS_READ)")]
+async fn list_clients(data: web::Data<AppState>) -> impl Responder {
+    match data.db.get_oauth_clients().await {
+        Ok(clients) => {
+            let clients: Vec<serde_json::Value> = clients.iter().map(client_json).collect();
+            HttpResponse::Ok().json(json!({"success": true, "clients": clients}))
+        }
+        Err(error) => {
+            tracing::error!("Error listing clients: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Describes an OAuth client without its secret hash.
+fn client_json(client: &OAuthClient) -> serde_json::Value {
+    json!({
+        "client_id": client.client_id,
+        "name": client.name,
+        "client_type": client.client_type,
+        "redirect_uris": client.redirect_uris,
+        "grant_types": client.grant_types,
+        "scopes": client.scopes,
+        "logo_uri": client.logo_uri,
+        "access_token_lifetime": client.access_token_lifetime,
+        "refresh_token_lifetime": client.refresh_token_lifetime,
+        "first_party": client.first_party,
+        "disabled": client.disabled,
+        "created_at": client.created_at,
+    })
+}
+
+/// Registers an OAuth client. The secret of a confidential client is only returned here.
+///
+/// # Arguments
+///
+/// * `req` - The client registration request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/clients",
+    wrap = "RequirePermission::new(rbac::CLIENTS_MANAGE)"
+)]
+async fn create_client(
+    req: web::Json<CreateClientRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match oauth::register_client(&data.db, req.into_inner().into()).await {
+        Ok(registered) => {
+            let mut client = client_json(&registered.client);
+            if let Some(secret) = registered.client_secret {
+                client["client_secret"] = json!(secret);
+            }
+            HttpResponse::Created().json(json!({"success": true, "client": client}))
+        }
+        Err(CustomError::InvalidClient(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => {
+            tracing::error!("Error registering client: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Gets an OAuth client.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the client.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[get(
+    "/admin/clients/{client_id}",
+    wrap = "RequirePermission::new(rbac::CLIENTS_READ)"
+)]
+async fn get_client(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    match data.db.get_oauth_client(&path.into_inner()).await {
+        Ok(Some(client)) => {
+            HttpResponse::Ok().json(json!({"success": true, "client": client_json(&client)}))
+        }
+        Ok(None) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error getting client: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Replaces the settings of an OAuth client. Its type can't be changed and its secret is kept.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the client.
+/// * `req` - The new settings of the client.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/clients/{client_id}",
+    wrap = "RequirePermission::new(rbac::CLIENTS_MANAGE)"
+)]
+async fn update_client(
+    path: web::Path<String>,
+    req: web::Json<CreateClientRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+
+    match oauth::update_client(&data.db, &path.into_inner(), req.into_inner().into()).await {
+        Ok(client) => {
+            HttpResponse::Ok().json(json!({"success": true, "client": client_json(&client)}))
+        }
+        Err(CustomError::ClientNotFound) => {
+            HttpResponse::NotFound().json(json!({"success": false}))
+        }
+        Err(CustomError::InvalidClient(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => {
+            tracing::error!("Error updating client: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Deletes an OAuth client. Its refresh tokens can no longer be used.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the client.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[delete(
+    "/admin/clients/{client_id}",
+    wrap = "RequirePermission::new(rbac::CLIENTS_MANAGE)"
+)]
+async fn delete_client(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    match data.db.delete_oauth_client(&path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json(json!({"success": true})),
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error deleting client: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Replaces the secret of a confidential OAuth client. The old secret stops working immediately.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the client.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/clients/{client_id}/secret",
+    wrap = "RequirePermission::new(rbac::CLIENTS_MANAGE)"
+)]
+async fn rotate_client_secret(
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match oauth::rotate_client_secret(&data.db, &path.into_inner()).await {
+        Ok(client_secret) => {
+            HttpResponse::Ok().json(json!({"success": true, "client_secret": client_secret}))
+        }
+        Err(CustomError::ClientNotFound) => {
+            HttpResponse::NotFound().json(json!({"success": false}))
+        }
+        Err(CustomError::InvalidClient(message)) => {
+            HttpResponse::BadRequest().json(json!({"success": false, "error": message}))
+        }
+        Err(error) => {
+            tracing::error!("Error rotating client secret: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Disables an OAuth client. It can no longer get tokens and its tokens are rejected.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the client.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/clients/{client_id}/disable",
+    wrap = "RequirePermission::new(rbac::CLIENTS_MANAGE)"
+)]
+async fn disable_client(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    set_client_disabled(&data.db, &path.into_inner(), true).await
+}
+
+/// Enables a disabled OAuth client again.
+///
+/// # Arguments
+///
+/// * `path` - The ID of the client.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/clients/{client_id}/enable",
+    wrap = "RequirePermission::new(rbac::CLIENTS_MANAGE)"
+)]
+async fn enable_client(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    set_client_disabled(&data.db, &path.into_inner(), false).await
+}
+
+/// Issues an initial access token that allows registering clients at `/oauth/register`.
+///
+/// # Arguments
+///
+/// * `http_req` - The http request.
+/// * `req` - The initial access token request.
+/// * `data` - The application state.
+///
+/// # Returns
+///
+/// A `Result` indicating success or failure.
+#[post(
+    "/admin/registration_tokens",
+    wrap = "RequirePermission::new(rbac::CLIENTS_MANAGE)"
+)]
+async fn create_registration_token(
+    http_req: HttpRequest,
+    req: web::Json<RegistrationTokenRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Validate the request body
+    if let Err(validation_errors) = req.0.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return HttpResponse::BadRequest().json(validation_errors);
+    }
+    let user_id = match http_req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    let uses = req.uses.unwrap_or(1);
+    match client_registration::issue_registration_token(&data.db, &user_id, uses).await {
+        Ok(token) => HttpResponse::Created().json(json!({
+            "success": true,
+            "token": token.token,
+            "remaining_uses": token.remaining_uses,
+            "expires_at": token.expires_at,
+        })),
+        Err(error) => {
+            tracing::error!("Error issuing initial access token: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
+    }
+}
+
+/// Disables or enables an OAuth client.
+async fn set_client_disabled(db: &Database, client_id: &str, disabled: bool) -> HttpResponse {
+    match db.set_oauth_client_disabled(client_id, disabled).await {
+        Ok(true) => {
+            tracing::info!(
+                "Client {} {}",
+                client_id,
+                if disabled { "disabled" } else { "enabled" }
+            );
+            HttpResponse::Ok().json(json!({"success": true}))
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({"success": false})),
+        Err(error) => {
+            tracing::error!("Error updating client: {}", error);
+            HttpResponse::InternalServerError().json(json!({"success": false}))
+        }
     }
 }
@@ -0,0 +1,377 @@
+//! src/simulation.rs
+//!
+//! This module simulates proposed role and policy changes. It reports which users would gain or
+//! lose permissions, or get a different authorization decision, without storing anything.
+
+use crate::database::{Database, Role};
+use crate::errors::custom_errors::CustomError;
+use crate::groups::user_groups;
+use crate::policy::{
+    self, active_policies, evaluate, AuthorizationRequest, Decision, Policy, PolicyDocument,
+};
+use crate::rbac::{self, is_superuser};
+
+use chrono::Utc;
+use rand::rng;
+use rand::seq::IndexedRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A role or policy change to simulate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProposedChange {
+    /// Creates a role or replaces its permissions.
+    UpsertRole(Role),
+    /// Deletes a role together with its assignments.
+    DeleteRole {
+        /// The name of the role.
+        name: String,
+    },
+    /// Assigns a role to a user or a group.
+    AssignRole {
+        /// The ID of the user or group.
+        member_id: String,
+        /// The name of the role.
+        role: String,
+    },
+    /// Removes a role from a user or a group.
+    UnassignRole {
+        /// The ID of the user or group.
+        member_id: String,
+        /// The name of the role.
+        role: String,
+    },
+    /// Stores a new version of a policy.
+    SavePolicy {
+        /// The name of the policy.
+        name: String,
+        /// The proposed policy document.
+        document: PolicyDocument,
+    },
+    /// Deletes a policy with all of its versions.
+    DeletePolicy {
+        /// The name of the policy.
+        name: String,
+    },
+}
+
+/// An action on a resource whose authorization decision is compared before and after a change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessCheck {
+    /// The action, e.g. `document:edit`.
+    pub action: String,
+    /// The attributes of the resource.
+    pub resource: Value,
+}
+
+/// Describes a simulation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Simulation {
+    /// The change to simulate.
+    pub change: ProposedChange,
+    /// The users to evaluate. Empty means all users.
+    #[serde(default)]
+    pub user_ids: Vec<String>,
+    /// If set, only this many randomly chosen users are evaluated.
+    #[serde(default)]
+    pub sample: Option<usize>,
+    /// The authorization decisions to compare for every user.
+    #[serde(default)]
+    pub checks: Vec<AccessCheck>,
+    /// Environment attributes that override the current ones, e.g. `{"hour": 3}`.
+    #[serde(default)]
+    pub environment: Value,
+}
+
+/// An authorization decision that would change.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecisionChange {
+    /// The action of the check.
+    pub action: String,
+    /// The resource of the check.
+    pub resource: Value,
+    /// The decision before the change.
+    pub before: Decision,
+    /// The decision after the change.
+    pub after: Decision,
+}
+
+/// How a change would affect a user.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UserImpact {
+    /// The ID of the user.
+    pub user_id: String,
+    /// The username of the user.
+    pub username: String,
+    /// The permissions the user would gain.
+    pub gained: Vec<String>,
+    /// The permissions the user would lose.
+    pub lost: Vec<String>,
+    /// The authorization decisions that would change.
+    pub decisions: Vec<DecisionChange>,
+}
+
+/// The outcome of a simulation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulationReport {
+    /// The number of users that were evaluated.
+    pub users_evaluated: usize,
+    /// The users that would be affected, ordered by ID.
+    pub affected: Vec<UserImpact>,
+}
+
+/// Checks that a proposed change refers to existing roles and policies and is well-formed.
+fn validate_change(
+    change: &ProposedChange,
+    roles: &BTreeMap<String, Role>,
+    policies: &[Policy],
+) -> Result<(), CustomError> {
+    match change {
+        ProposedChange::UpsertRole(role) => rbac::validate_role(role),
+        ProposedChange::DeleteRole { name }
+        | ProposedChange::AssignRole { role: name, .. }
+        | ProposedChange::UnassignRole { role: name, .. } => {
+            if roles.contains_key(name) {
+                Ok(())
+            } else {
+                Err(CustomError::InvalidRole(format!("Unknown role: {}", name)))
+            }
+        }
+        ProposedChange::SavePolicy { name, document } => policy::validate_policy(name, document),
+        ProposedChange::DeletePolicy { name } => {
+            if policies.iter().any(|policy| &policy.name == name) {
+                Ok(())
+            } else {
+                Err(CustomError::PolicyNotFound)
+            }
+        }
+    }
+}
+
+/// Applies a proposed change to the roles and policies in effect.
+fn apply_to_definitions(
+    change: &ProposedChange,
+    roles: &mut BTreeMap<String, Role>,
+    policies: &mut Vec<Policy>,
+) {
+    match change {
+        ProposedChange::UpsertRole(role) => {
+            roles.insert(role.name.clone(), role.clone());
+        }
+        ProposedChange::DeleteRole { name } => {
+            roles.remove(name);
+        }
+        ProposedChange::SavePolicy { name, document } => {
+            let version = policies
+                .iter()
+                .find(|policy| &policy.name == name)
+                .map_or(1, |policy| policy.version + 1);
+            policies.retain(|policy| &policy.name != name);
+            policies.push(Policy {
+                name: name.clone(),
+                version,
+                document: document.clone(),
+                created_by: String::new(),
+                created_at: Utc::now().timestamp(),
+            });
+            policies.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        ProposedChange::DeletePolicy { name } => {
+            policies.retain(|policy| &policy.name != name);
+        }
+        ProposedChange::AssignRole { .. } | ProposedChange::UnassignRole { .. } => {}
+    }
+}
+
+/// Applies a proposed change to the role assignments that reach a user.
+///
+/// Assignments are `(holder, role)` pairs, where the holder is the user or one of their groups.
+fn apply_to_assignments(
+    change: &ProposedChange,
+    holders: &BTreeSet<String>,
+    assignments: &mut BTreeSet<(String, String)>,
+) {
+    match change {
+        ProposedChange::AssignRole { member_id, role } if holders.contains(member_id) => {
+            assignments.insert((member_id.clone(), role.clone()));
+        }
+        ProposedChange::UnassignRole { member_id, role } => {
+            assignments.remove(&(member_id.clone(), role.clone()));
+        }
+        ProposedChange::DeleteRole { name } => {
+            assignments.retain(|(_, role)| role != name);
+        }
+        _ => {}
+    }
+}
+
+/// Returns the permissions granted to a user by a set of roles.
+fn permissions_of(
+    db: &Database,
+    user_id: &str,
+    role_names: &BTreeSet<String>,
+    roles: &BTreeMap<String, Role>,
+) -> BTreeSet<String> {
+    let mut permissions: BTreeSet<String> = role_names
+        .iter()
+        .filter_map(|name| roles.get(name))
+        .flat_map(|role| role.permissions.iter().cloned())
+        .collect();
+    if is_superuser(db, user_id) {
+        permissions.insert(rbac::ALL_PERMISSIONS.to_string());
+    }
+    permissions
+}
+
+/// Simulates a role or policy change and reports the users it would affect.
+///
+/// Nothing is stored. Roles, assignments and policies are loaded from the database and the
+/// change is applied to copies of them. The environment of the checks is the current one,
+/// overridden by the attributes given in the simulation.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `simulation` - The simulation to run.
+///
+/// # Returns
+///
+/// A `Result` containing the report.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The change refers to an unknown role or policy, or is invalid.
+/// - A listed user doesn't exist.
+/// - Loading the roles, groups or policies fails.
+pub async fn simulate(
+    db: &Database,
+    simulation: &Simulation,
+) -> Result<SimulationReport, CustomError> {
+    let roles_before: BTreeMap<String, Role> = db
+        .get_roles()
+        .await?
+        .into_iter()
+        .map(|role| (role.name.clone(), role))
+        .collect();
+    let policies_before = active_policies(db).await?;
+    validate_change(&simulation.change, &roles_before, &policies_before)?;
+
+    let mut roles_after = roles_before.clone();
+    let mut policies_after = policies_before.clone();
+    apply_to_definitions(&simulation.change, &mut roles_after, &mut policies_after);
+
+    let mut user_ids = if simulation.user_ids.is_empty() {
+        db.get_user_ids().await?
+    } else {
+        simulation.user_ids.clone()
+    };
+    if let Some(sample) = simulation.sample {
+        user_ids = user_ids
+            .choose_multiple(&mut rng(), sample)
+            .cloned()
+            .collect();
+    }
+    user_ids.sort();
+    user_ids.dedup();
+
+    let mut environment = policy::environment_attributes(Utc::now());
+    if let Value::Object(overrides) = &simulation.environment {
+        environment.extend(overrides.clone());
+    }
+    let environment = Value::Object(environment);
+
+    let mut affected = Vec::new();
+    for user_id in &user_ids {
+        let user = db
+            .get_user_by_id(user_id)
+            .await?
+            .ok_or(CustomError::UserNotFound)?;
+
+        let mut holders: BTreeSet<String> = user_groups(db, user_id)
+            .await?
+            .into_iter()
+            .map(|group| group.group_id)
+            .collect();
+        let group_ids: Vec<String> = holders.iter().cloned().collect();
+        holders.insert(user_id.clone());
+
+        let mut assignments: BTreeSet<(String, String)> = db
+            .get_user_roles(user_id)
+            .await?
+            .into_iter()
+            .map(|role| (user_id.clone(), role))
+            .collect();
+        assignments.extend(
+            db.get_group_roles(&group_ids)
+                .await?
+                .into_iter()
+                .map(|group_role| (group_role.group_id, group_role.role)),
+        );
+        let role_names_before: BTreeSet<String> =
+            assignments.iter().map(|(_, role)| role.clone()).collect();
+        apply_to_assignments(&simulation.change, &holders, &mut assignments);
+        let role_names_after: BTreeSet<String> =
+            assignments.into_iter().map(|(_, role)| role).collect();
+
+        let permissions_before = permissions_of(db, user_id, &role_names_before, &roles_before);
+        let permissions_after = permissions_of(db, user_id, &role_names_after, &roles_after);
+
+        let mut decisions = Vec::new();
+        if !simulation.checks.is_empty() {
+            let subject_before = policy::subject_attributes(db, user_id).await?;
+            let mut subject_after = subject_before.clone();
+            subject_after["roles"] = json!(role_names_after);
+
+            for check in &simulation.checks {
+                let mut request = AuthorizationRequest {
+                    subject: subject_before.clone(),
+                    action: check.action.clone(),
+                    resource: check.resource.clone(),
+                    environment: environment.clone(),
+                };
+                let before = evaluate(&policies_before, &request);
+                request.subject = subject_after.clone();
+                let after = evaluate(&policies_after, &request);
+                if before.allowed != after.allowed {
+                    decisions.push(DecisionChange {
+                        action: check.action.clone(),
+                        resource: check.resource.clone(),
+                        before,
+                        after,
+                    });
+                }
+            }
+        }
+
+        let gained: Vec<String> = permissions_after
+            .difference(&permissions_before)
+            .cloned()
+            .collect();
+        let lost: Vec<String> = permissions_before
+            .difference(&permissions_after)
+            .cloned()
+            .collect();
+        if !gained.is_empty() || !lost.is_empty() || !decisions.is_empty() {
+            affected.push(UserImpact {
+                user_id: user_id.clone(),
+                username: user.username,
+                gained,
+                lost,
+                decisions,
+            });
+        }
+    }
+
+    tracing::info!(
+        "Simulated change affects {} of {} users",
+        affected.len(),
+        user_ids.len()
+    );
+    Ok(SimulationReport {
+        users_evaluated: user_ids.len(),
+        affected,
+    })
+}
@@ -0,0 +1,325 @@
+//! src/tenants.rs
+//!
+//! This module manages tenants, also called realms. Every tenant has its own users, roles,
+//! signing keys and settings, stored in a datastore of its own. Requests select a tenant with a
+//! `/realms/<tenant ID>` path prefix or with the host header, and tokens carry the ID of the
+//! tenant that issued them.
+//!
+//! The registry of tenants lives in the database of the default tenant, which is the database
+//! configured in the environment.
+
+use crate::database::{Database, Tenant};
+use crate::email_verification::EmailVerificationPolicy;
+use crate::errors::custom_errors::CustomError;
+use crate::keyring::{reload_keyring, remove_keyring};
+
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The ID of the default tenant, whose database is configured in the environment.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// The path prefix that selects a tenant, followed by the tenant ID.
+const TENANT_PATH_PREFIX: &str = "/realms/";
+
+/// Provides the databases of all tenants.
+#[derive(Clone)]
+pub struct Tenants {
+    /// The database of the default tenant, which holds the tenant registry.
+    root: Database,
+    /// The open tenant databases by tenant ID. Connections are kept open, since a datastore
+    /// can't be opened twice.
+    connections: Arc<Mutex<BTreeMap<String, Database>>>,
+}
+
+impl Tenants {
+    /// Creates the tenant registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The database of the default tenant.
+    pub fn new(root: Database) -> Self {
+        Tenants {
+            root,
+            connections: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Returns the database of the default tenant.
+    pub fn root(&self) -> &Database {
+        &self.root
+    }
+
+    /// Returns the database of a tenant, opening it on first use.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant_id` - The ID of the tenant.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the database of the tenant.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The tenant doesn't exist.
+    /// - Opening the database or loading its keyring fails.
+    pub async fn database(&self, tenant_id: &str) -> Result<Database, CustomError> {
+        if tenant_id == DEFAULT_TENANT {
+            return Ok(self.root.clone());
+        }
+        // The registry is checked on every call, so that changes take effect immediately.
+        match self.root.get_tenant(tenant_id).await? {
+            Some(tenant) => self.connection(&tenant).await,
+            None => Err(CustomError::TenantNotFound),
+        }
+    }
+
+    /// Returns the database of the tenant selected by a host name.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The value of the host header. A port is ignored.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the database of the tenant registered for the host, or the
+    /// database of the default tenant if there is none.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - Looking up the tenant or opening its database fails.
+    pub async fn database_for_host(&self, host: &str) -> Result<Database, CustomError> {
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => {
+                name
+            }
+            _ => host,
+        };
+        match self
+            .root
+            .get_tenant_by_host(&host.to_ascii_lowercase())
+            .await?
+        {
+            Some(tenant) => self.connection(&tenant).await,
+            None => Ok(self.root.clone()),
+        }
+    }
+
+    /// Returns the open database of a tenant with its current settings.
+    async fn connection(&self, tenant: &Tenant) -> Result<Database, CustomError> {
+        let mut connections = self.connections.lock().await;
+        if let Some(db) = connections.get(&tenant.tenant_id) {
+            return Ok(db.with_settings(tenant.settings.clone()));
+        }
+
+        let db = self.root.open_tenant(tenant).await?;
+        reload_keyring(&db).await?;
+        tracing::info!("Opened database of tenant {}", tenant.tenant_id);
+        connections.insert(tenant.tenant_id.clone(), db.clone());
+        Ok(db)
+    }
+
+    /// Lists the registered tenants. The default tenant isn't registered.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the tenants, ordered by ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The query fails.
+    pub async fn list(&self) -> Result<Vec<Tenant>, CustomError> {
+        self.root.get_tenants().await
+    }
+
+    /// Creates a tenant together with its database and first signing key.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - The tenant to create. Its creation timestamp is set automatically.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the created tenant.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The tenant is invalid or its ID is taken.
+    /// - One of its hosts is used by another tenant.
+    /// - Storing the tenant or opening its database fails.
+    pub async fn create(&self, mut tenant: Tenant) -> Result<Tenant, CustomError> {
+        validate_tenant(&tenant)?;
+        if self.root.get_tenant(&tenant.tenant_id).await?.is_some() {
+            return Err(CustomError::InvalidTenant(format!(
+                "Tenant already exists: {}",
+                tenant.tenant_id
+            )));
+        }
+        self.check_hosts(&tenant).await?;
+
+        tenant.created_at = Utc::now().timestamp();
+        self.root.create_tenant(&tenant).await?;
+        // A tenant that is recreated gets its keyring back, since it was removed on deletion.
+        let db = self.connection(&tenant).await?;
+        reload_keyring(&db).await?;
+        tracing::info!("Created tenant {}", tenant.tenant_id);
+        Ok(tenant)
+    }
+
+    /// Changes the name, hosts and settings of a tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - The updated tenant.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the updated tenant.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The tenant doesn't exist or is invalid.
+    /// - One of its hosts is used by another tenant.
+    /// - Storing the tenant fails.
+    pub async fn update(&self, mut tenant: Tenant) -> Result<Tenant, CustomError> {
+        validate_tenant(&tenant)?;
+        let existing = self
+            .root
+            .get_tenant(&tenant.tenant_id)
+            .await?
+            .ok_or(CustomError::TenantNotFound)?;
+        self.check_hosts(&tenant).await?;
+
+        tenant.created_at = existing.created_at;
+        if !self.root.update_tenant(&tenant).await? {
+            return Err(CustomError::TenantNotFound);
+        }
+        tracing::info!("Updated tenant {}", tenant.tenant_id);
+        Ok(tenant)
+    }
+
+    /// Deletes a tenant.
+    ///
+    /// The tenant can no longer be selected and its tokens are no longer accepted. Its
+    /// datastore is kept, so recreating a tenant with the same ID restores its data.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant_id` - The ID of the tenant.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CustomError` if:
+    /// - The tenant doesn't exist.
+    /// - Removing the tenant fails.
+    pub async fn delete(&self, tenant_id: &str) -> Result<(), CustomError> {
+        if !self.root.delete_tenant(tenant_id).await? {
+            return Err(CustomError::TenantNotFound);
+        }
+        remove_keyring(tenant_id);
+        tracing::info!("Deleted tenant {}", tenant_id);
+        Ok(())
+    }
+
+    /// Checks that no other tenant uses one of the hosts of a tenant.
+    async fn check_hosts(&self, tenant: &Tenant) -> Result<(), CustomError> {
+        for host in &tenant.hosts {
+            if let Some(other) = self.root.get_tenant_by_host(host).await? {
+                if other.tenant_id != tenant.tenant_id {
+                    return Err(CustomError::InvalidTenant(format!(
+                        "Host {} is already used by tenant {}",
+                        host, other.tenant_id
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a tenant is well-formed.
+///
+/// # Arguments
+///
+/// * `tenant` - The tenant to check.
+///
+/// # Returns
+///
+/// A `Result` indicating whether the tenant is valid.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The ID isn't 1 to 32 lowercase letters, digits and `-`, or is the default tenant's ID.
+/// - A host is empty, has a port or contains uppercase letters, whitespace or `/`.
+/// - The email verification policy is neither `allow` nor `block`.
+pub fn validate_tenant(tenant: &Tenant) -> Result<(), CustomError> {
+    let id = &tenant.tenant_id;
+    if id.is_empty()
+        || id.len() > 32
+        || id.starts_with('-')
+        || id == DEFAULT_TENANT
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(CustomError::InvalidTenant(format!(
+            "Invalid tenant ID: {}",
+            id
+        )));
+    }
+    if let Some(host) = tenant.hosts.iter().find(|host| {
+        host.is_empty()
+            || host
+                .chars()
+                .any(|c| c.is_ascii_uppercase() || c.is_whitespace() || c == '/' || c == ':')
+    }) {
+        return Err(CustomError::InvalidTenant(format!(
+            "Invalid host: {:?}",
+            host
+        )));
+    }
+    if let Some(policy) = &tenant.settings.email_verification_policy {
+        if EmailVerificationPolicy::parse(policy).is_none() {
+            return Err(CustomError::InvalidTenant(format!(
+                "Invalid email verification policy: {}",
+                policy
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Splits the tenant prefix off a request path.
+///
+/// # Arguments
+///
+/// * `path` - The request path, e.g. `/realms/acme/login`.
+///
+/// # Returns
+///
+/// The tenant ID and the remaining path, e.g. `("acme", "/login")`, or `None` if the path has
+/// no tenant prefix.
+pub fn split_tenant_path(path: &str) -> Option<(&str, String)> {
+    let rest = path.strip_prefix(TENANT_PATH_PREFIX)?;
+    let (tenant_id, rest) = match rest.find('/') {
+        Some(index) => (&rest[..index], rest[index..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    if tenant_id.is_empty() {
+        return None;
+    }
+    Some((tenant_id, rest))
+}
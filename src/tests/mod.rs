@@ -3,4 +3,5 @@
 //! This module exposes integration tests for the IAM project.
 
 /// The test module
+#[allow(clippy::module_inception)]
 pub mod tests;
@@ -2,7 +2,18 @@
 //!
 //! This module contains integration tests for the IAM project.
 
+use crate::database::Database;
+use crate::errors::custom_errors::CustomError;
+use crate::mailer::{EmailMessage, Mailer, StdoutMailer};
+use crate::middleware::{AuthenticationMiddlewareFactory, RequirePermission};
+use crate::server::AppState;
+use crate::tenants::Tenants;
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Condition;
+use actix_web::test::{self, TestRequest};
+use actix_web::{web, App, HttpResponse};
 use std::env;
+use std::sync::{Arc, Mutex};
 
 const ENCRYPTION_KEY_ENV: &str = "ENCRYPTION_KEY";
 const ENCRYPTION_KEY_ENV_VAR: &str = "12345678901234567890123456789012";
@@ -20,11 +31,108 @@ fn setup() {
     }
 }
 
+/// Opens a fresh database in a unique temporary directory.
+async fn setup_database() -> Database {
+    setup();
+    let path = env::temp_dir().join(format!("iam-test-{}", uuid::Uuid::new_v4()));
+    Database::connect(path.to_string_lossy().into_owned(), "test", "test")
+        .await
+        .unwrap()
+}
+
+/// Registers a user and returns their ID.
+async fn register_test_user(db: &Database, email: &str) -> String {
+    db.register(
+        "First".to_string(),
+        "Last".to_string(),
+        "user".to_string(),
+        "password123".to_string(),
+        email.to_string(),
+    )
+    .await
+    .unwrap();
+    db.authenticate_user(email.to_string(), "password123".to_string())
+        .await
+        .unwrap()
+        .id
+        .to_string()
+}
+
+/// Builds a GET request with a bearer token.
+fn bearer_request(uri: &str, token: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(uri)
+        .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+}
+
+/// Sends a request through the authentication middleware to a route that answers `200 OK`.
+///
+/// The route requires `permission` if one is given. Requests rejected by a middleware return
+/// the status of the error.
+async fn call_authenticated(
+    db: &Database,
+    req: TestRequest,
+    permission: Option<&str>,
+) -> StatusCode {
+    async fn test_route() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(AppState {
+                db: db.clone(),
+                mailer: Arc::new(StdoutMailer),
+                tenants: Tenants::new(db.clone()),
+            }))
+            .wrap(AuthenticationMiddlewareFactory::new())
+            .service(
+                web::resource("/{path:.*}")
+                    .wrap(Condition::new(
+                        permission.is_some(),
+                        RequirePermission::new(permission.unwrap_or_default()),
+                    ))
+                    .route(web::route().to(test_route)),
+            ),
+    )
+    .await;
+
+    match test::try_call_service(&app, req.to_request()).await {
+        Ok(res) => res.status(),
+        Err(error) => error.as_response_error().status_code(),
+    }
+}
+
+/// A mailer that keeps sent emails in memory.
+#[derive(Default)]
+struct MemoryMailer {
+    messages: Mutex<Vec<EmailMessage>>,
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, message: &EmailMessage) -> Result<(), CustomError> {
+        self.messages.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
+
+impl MemoryMailer {
+    /// Returns the token from the most recent email.
+    fn last_token(&self) -> String {
+        let messages = self.messages.lock().unwrap();
+        let body = &messages.last().unwrap().body;
+        body.lines()
+            .find(|line| line.len() == 43)
+            .unwrap()
+            .to_string()
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::encryption::{decrypt_with_nonce, encrypt_with_random_nonce, generate_key};
     use crate::hashing::{hash_random_salt, verify_password};
-    use std::env;
 
     #[test]
     fn test_hashing_correct() {
@@ -101,10 +209,23 @@ mod tests {
 
         #[actix_web::test]
         async fn test_authentication_middleware_valid_token() {
-            crate::tests::tests::setup();
+            let db = crate::tests::tests::setup_database().await;
             let user_id = "test_user";
             let token = generate_jwt(user_id.to_string()).unwrap();
 
+            let req = crate::tests::tests::bearer_request("/test", &token);
+            assert_eq!(
+                crate::tests::tests::call_authenticated(&db, req, None).await,
+                StatusCode::OK
+            );
+        }
+
+        #[actix_web::test]
+        async fn test_authentication_middleware_without_state() {
+            crate::tests::tests::setup();
+            let token = generate_jwt("test_user".to_string()).unwrap();
+
+            // Without the database, revocations can't be checked, so no token is accepted.
             let app = test::init_service(
                 App::new()
                     .wrap(AuthenticationMiddlewareFactory::new())
@@ -117,8 +238,11 @@ mod tests {
                 .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
                 .to_request();
 
-            let resp = test::call_service(&app, req).await;
-            assert_eq!(resp.status(), StatusCode::OK);
+            let status = match test::try_call_service(&app, req).await {
+                Ok(res) => res.status(),
+                Err(error) => error.as_response_error().status_code(),
+            };
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
         }
 
         #[actix_web::test]
@@ -162,4 +286,3092 @@ mod tests {
             };
         }
     }
+
+    mod test_refresh_tokens {
+        use crate::errors::custom_errors::CustomError;
+        use crate::jwt::validate_jwt;
+        use crate::tokens::{issue_token_pair, rotate_refresh_token};
+
+        #[actix_web::test]
+        async fn test_refresh_token_rotation() {
+            let db = crate::tests::tests::setup_database().await;
+            let tokens = issue_token_pair(&db, "test_user", &[]).await.unwrap();
+            assert_eq!(validate_jwt(&tokens.access_token).unwrap().sub, "test_user");
+
+            let rotated = rotate_refresh_token(&db, &tokens.refresh_token)
+                .await
+                .unwrap();
+            assert_ne!(rotated.refresh_token, tokens.refresh_token);
+            assert_eq!(
+                validate_jwt(&rotated.access_token).unwrap().sub,
+                "test_user"
+            );
+        }
+
+        #[actix_web::test]
+        async fn test_refresh_token_reuse_revokes_family() {
+            let db = crate::tests::tests::setup_database().await;
+            let tokens = issue_token_pair(&db, "test_user", &[]).await.unwrap();
+            let rotated = rotate_refresh_token(&db, &tokens.refresh_token)
+                .await
+                .unwrap();
+
+            let replayed = rotate_refresh_token(&db, &tokens.refresh_token).await;
+            assert!(matches!(replayed, Err(CustomError::RefreshTokenReuse)));
+
+            let after_reuse = rotate_refresh_token(&db, &rotated.refresh_token).await;
+            assert!(matches!(after_reuse, Err(CustomError::InvalidRefreshToken)));
+        }
+
+        #[actix_web::test]
+        async fn test_unknown_refresh_token() {
+            let db = crate::tests::tests::setup_database().await;
+            let result = rotate_refresh_token(&db, "unknown").await;
+            assert!(matches!(result, Err(CustomError::InvalidRefreshToken)));
+        }
+    }
+
+    mod test_revocation {
+        use crate::database::Database;
+        use crate::jwt::validate_jwt;
+        use crate::tokens::issue_token_pair;
+        use actix_web::http::StatusCode;
+
+        async fn call_with_token(db: &Database, token: &str) -> StatusCode {
+            let req = crate::tests::tests::bearer_request("/test", token);
+            crate::tests::tests::call_authenticated(db, req, None).await
+        }
+
+        #[actix_web::test]
+        async fn test_revoked_token_is_rejected() {
+            let db = crate::tests::tests::setup_database().await;
+            let tokens = issue_token_pair(&db, "test_user", &[]).await.unwrap();
+            assert_eq!(
+                call_with_token(&db, &tokens.access_token).await,
+                StatusCode::OK
+            );
+
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            db.revoke_access_token(&claims.jti, claims.exp as i64)
+                .await
+                .unwrap();
+            assert!(db.is_access_token_revoked(&claims.jti).await.unwrap());
+            assert_eq!(
+                call_with_token(&db, &tokens.access_token).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        #[actix_web::test]
+        async fn test_token_generation_logs_out_everywhere() {
+            let db = crate::tests::tests::setup_database().await;
+            db.register(
+                "First".to_string(),
+                "Last".to_string(),
+                "user".to_string(),
+                "password123".to_string(),
+                "user@example.com".to_string(),
+            )
+            .await
+            .unwrap();
+            let user = db
+                .authenticate_user("user@example.com".to_string(), "password123".to_string())
+                .await
+                .unwrap();
+            let user_id = user.id.to_string();
+
+            let old_tokens = issue_token_pair(&db, &user_id, &[]).await.unwrap();
+            db.increment_token_generation(&user_id).await.unwrap();
+            assert_eq!(db.get_token_generation(&user_id).await.unwrap(), 1);
+            assert_eq!(
+                call_with_token(&db, &old_tokens.access_token).await,
+                StatusCode::UNAUTHORIZED
+            );
+
+            let new_tokens = issue_token_pair(&db, &user_id, &[]).await.unwrap();
+            assert_eq!(
+                call_with_token(&db, &new_tokens.access_token).await,
+                StatusCode::OK
+            );
+        }
+    }
+
+    mod test_signing_keys {
+        use crate::jwt::{Claims, SigningKey};
+        use jsonwebtoken::jwk::AlgorithmParameters;
+        use jsonwebtoken::{decode_header, Algorithm};
+
+        fn load_key(algorithm: Algorithm, name: &str) -> SigningKey {
+            let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/keys");
+            let private_pem = std::fs::read(format!("{}/{}_private.pem", directory, name)).unwrap();
+            let public_pem = std::fs::read(format!("{}/{}_public.pem", directory, name)).unwrap();
+            SigningKey::from_pem(name, algorithm, &private_pem, &public_pem).unwrap()
+        }
+
+        fn assert_round_trip(key: &SigningKey) {
+            crate::tests::tests::setup();
+            let token = key.sign(&Claims::new("test_user".to_string(), 0)).unwrap();
+            let header = decode_header(&token).unwrap();
+            assert_eq!(header.alg, key.algorithm);
+            assert_eq!(header.kid.as_deref(), Some(key.kid.as_str()));
+            assert_eq!(key.verify(&token).unwrap().sub, "test_user");
+        }
+
+        #[test]
+        fn test_rs256_signing_key() {
+            let key = load_key(Algorithm::RS256, "rsa");
+            assert_round_trip(&key);
+            let jwk = key.public_jwk().unwrap();
+            assert_eq!(jwk.common.key_id.as_deref(), Some("rsa"));
+            assert!(matches!(jwk.algorithm, AlgorithmParameters::RSA(_)));
+        }
+
+        #[test]
+        fn test_es256_signing_key() {
+            let key = load_key(Algorithm::ES256, "ec");
+            assert_round_trip(&key);
+            let jwk = key.public_jwk().unwrap();
+            assert!(matches!(
+                jwk.algorithm,
+                AlgorithmParameters::EllipticCurve(_)
+            ));
+        }
+
+        #[test]
+        fn test_eddsa_signing_key() {
+            let key = load_key(Algorithm::EdDSA, "ed25519");
+            assert_round_trip(&key);
+            let jwk = key.public_jwk().unwrap();
+            assert!(matches!(
+                jwk.algorithm,
+                AlgorithmParameters::OctetKeyPair(_)
+            ));
+        }
+
+        #[test]
+        fn test_jwk_verifies_token() {
+            crate::tests::tests::setup();
+            let key = load_key(Algorithm::ES256, "ec");
+            let token = key.sign(&Claims::new("test_user".to_string(), 0)).unwrap();
+            let decoding_key =
+                jsonwebtoken::DecodingKey::from_jwk(key.public_jwk().unwrap()).unwrap();
+            let validation = jsonwebtoken::Validation::new(Algorithm::ES256);
+            let data = jsonwebtoken::decode::<Claims>(&token, &decoding_key, &validation).unwrap();
+            assert_eq!(data.claims.sub, "test_user");
+        }
+
+        #[test]
+        fn test_token_from_other_key_is_rejected() {
+            crate::tests::tests::setup();
+            let signer = load_key(Algorithm::EdDSA, "ed25519");
+            let verifier = SigningKey::from_secret("default", b"secret");
+            let token = signer
+                .sign(&Claims::new("test_user".to_string(), 0))
+                .unwrap();
+            assert!(verifier.verify(&token).is_err());
+        }
+
+        #[test]
+        fn test_secret_key_is_not_published() {
+            let key = SigningKey::from_secret("default", b"secret");
+            assert!(key.public_jwk().is_none());
+        }
+    }
+
+    mod test_keyring {
+        use crate::errors::custom_errors::CustomError;
+        use crate::jwt::Claims;
+        use crate::keyring::{rotate_signing_key, set_key_state, KeyState, Keyring};
+        use jsonwebtoken::{decode_header, Algorithm};
+
+        #[actix_web::test]
+        async fn test_rotation_keeps_old_tokens_valid() {
+            let db = crate::tests::tests::setup_database().await;
+            let keyring = Keyring::load(&db).await.unwrap();
+            let old_token = keyring
+                .sign(&Claims::new("test_user".to_string(), 0))
+                .unwrap();
+
+            let kid = rotate_signing_key(&db, Algorithm::ES256).await.unwrap();
+            let keyring = Keyring::load(&db).await.unwrap();
+            assert_eq!(keyring.active_key().unwrap().kid, kid);
+            assert_eq!(keyring.verify(&old_token).unwrap().sub, "test_user");
+
+            let new_token = keyring
+                .sign(&Claims::new("test_user".to_string(), 0))
+                .unwrap();
+            assert_eq!(decode_header(&new_token).unwrap().kid, Some(kid));
+            assert_eq!(keyring.verify(&new_token).unwrap().sub, "test_user");
+            assert_eq!(keyring.public_jwks().keys.len(), 1);
+        }
+
+        #[actix_web::test]
+        async fn test_retired_key_is_rejected() {
+            let db = crate::tests::tests::setup_database().await;
+            let keyring = Keyring::load(&db).await.unwrap();
+            let old_kid = keyring.active_key().unwrap().kid.clone();
+            let old_token = keyring
+                .sign(&Claims::new("test_user".to_string(), 0))
+                .unwrap();
+
+            rotate_signing_key(&db, Algorithm::EdDSA).await.unwrap();
+            set_key_state(&db, &old_kid, KeyState::Retired)
+                .await
+                .unwrap();
+            let keyring = Keyring::load(&db).await.unwrap();
+            assert!(keyring.verify(&old_token).is_err());
+        }
+
+        #[actix_web::test]
+        async fn test_active_key_cannot_be_retired() {
+            let db = crate::tests::tests::setup_database().await;
+            let keyring = Keyring::load(&db).await.unwrap();
+            let kid = keyring.active_key().unwrap().kid.clone();
+            let result = set_key_state(&db, &kid, KeyState::Retired).await;
+            assert!(matches!(result, Err(CustomError::KeyringError(_))));
+        }
+    }
+
+    mod test_claims {
+        use crate::jwt::{Claims, SigningKey};
+
+        fn claims_for(issuer: &str, audience: &str) -> Claims {
+            crate::tests::tests::setup();
+            let mut claims = Claims::new("test_user".to_string(), 0);
+            claims.iss = Some(issuer.to_string());
+            claims.aud = Some(audience.to_string());
+            claims
+        }
+
+        #[test]
+        fn test_issuer_and_audience_are_enforced() {
+            let key = SigningKey::from_secret("default", b"secret");
+            let token = key.sign(&claims_for("iam", "app-a")).unwrap();
+
+            assert!(key.verify_with(&token, Some("iam"), Some("app-a")).is_ok());
+            assert!(key.verify_with(&token, Some("iam"), Some("app-b")).is_err());
+            assert!(key
+                .verify_with(&token, Some("other"), Some("app-a"))
+                .is_err());
+        }
+
+        #[test]
+        fn test_missing_audience_is_rejected() {
+            crate::tests::tests::setup();
+            let key = SigningKey::from_secret("default", b"secret");
+            let mut claims = Claims::new("test_user".to_string(), 0);
+            claims.aud = None;
+            let token = key.sign(&claims).unwrap();
+
+            assert!(key.verify_with(&token, None, None).is_ok());
+            assert!(key.verify_with(&token, None, Some("app-a")).is_err());
+        }
+
+        #[test]
+        fn test_custom_claims_round_trip() {
+            let key = SigningKey::from_secret("default", b"secret");
+            let mut claims = claims_for("iam", "app-a");
+            claims.username = Some("user".to_string());
+            claims.roles = vec!["admin".to_string()];
+            claims.tenant = Some("tenant-a".to_string());
+            claims.amr = vec!["pwd".to_string()];
+            claims
+                .extra
+                .insert("department".to_string(), serde_json::json!("sales"));
+            let token = key.sign(&claims).unwrap();
+
+            let verified = key.verify_with(&token, Some("iam"), Some("app-a")).unwrap();
+            assert_eq!(verified.username.as_deref(), Some("user"));
+            assert_eq!(verified.roles, vec!["admin".to_string()]);
+            assert_eq!(verified.tenant.as_deref(), Some("tenant-a"));
+            assert_eq!(verified.amr, vec!["pwd".to_string()]);
+            assert_eq!(verified.extra["department"], serde_json::json!("sales"));
+        }
+    }
+
+    mod test_totp {
+        use crate::errors::custom_errors::CustomError;
+        use crate::jwt::validate_jwt;
+        use crate::mfa::{
+            begin_totp_enrollment, challenge_second_factor, complete_mfa_challenge,
+            confirm_totp_enrollment, create_mfa_challenge, pass_mfa_challenge,
+            regenerate_recovery_codes,
+        };
+        use crate::totp::{generate_code, verify_code};
+        use chrono::Utc;
+
+        // The SHA-1 secret from the RFC 6238 test vectors, base32-encoded.
+        const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+        #[test]
+        fn test_rfc_6238_vector() {
+            assert_eq!(generate_code(RFC_SECRET, 59).unwrap(), "287082");
+            assert_eq!(generate_code(RFC_SECRET, 1111111109).unwrap(), "081804");
+        }
+
+        #[test]
+        fn test_verify_code_allows_skew_and_rejects_replay() {
+            assert_eq!(
+                verify_code(RFC_SECRET, "287082", 59, None).unwrap(),
+                Some(1)
+            );
+            assert_eq!(
+                verify_code(RFC_SECRET, "287082", 89, None).unwrap(),
+                Some(1)
+            );
+            assert_eq!(verify_code(RFC_SECRET, "287082", 150, None).unwrap(), None);
+            assert_eq!(
+                verify_code(RFC_SECRET, "287082", 59, Some(1)).unwrap(),
+                None
+            );
+            assert_eq!(verify_code(RFC_SECRET, "000000", 59, None).unwrap(), None);
+        }
+
+        #[actix_web::test]
+        async fn test_totp_login_flow() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "totp@example.com").await;
+
+            let enrollment = begin_totp_enrollment(&db, &user_id).await.unwrap();
+            assert!(enrollment.otpauth_uri.starts_with("otpauth://totp/"));
+            let user = db.get_user_by_id(&user_id).await.unwrap().unwrap();
+            assert!(!user.totp_enabled);
+            assert_ne!(
+                user.totp_secret.as_deref(),
+                Some(enrollment.secret.as_str())
+            );
+
+            let now = Utc::now().timestamp() as u64;
+            let code = generate_code(&enrollment.secret, now).unwrap();
+            let recovery_codes = confirm_totp_enrollment(&db, &user_id, &code).await.unwrap();
+            assert!(
+                db.get_user_by_id(&user_id)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .totp_enabled
+            );
+
+            // The code used for enrollment can't be replayed to complete a login.
+            let challenge = create_mfa_challenge(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            let replayed = complete_mfa_challenge(&db, &challenge.mfa_token, &code).await;
+            assert!(matches!(replayed, Err(CustomError::InvalidMfaCode)));
+
+            let next_code = generate_code(&enrollment.secret, now + 30).unwrap();
+            let tokens = complete_mfa_challenge(&db, &challenge.mfa_token, &next_code)
+                .await
+                .unwrap();
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.sub, user_id);
+            assert!(claims.amr.contains(&"otp".to_string()));
+
+            let reused = complete_mfa_challenge(&db, &challenge.mfa_token, &next_code).await;
+            assert!(matches!(reused, Err(CustomError::InvalidMfaChallenge)));
+
+            // Logins that don't end in a token pair, such as OAuth logins, use the same challenge.
+            let user = db.get_user_by_id(&user_id).await.unwrap().unwrap();
+            let (methods, challenge) = challenge_second_factor(&db, &user, &["pwd".to_string()])
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(methods, vec!["totp".to_string()]);
+            let wrong = pass_mfa_challenge(&db, &challenge.mfa_token, "000000").await;
+            assert!(matches!(wrong, Err(CustomError::InvalidMfaCode)));
+            let completed = pass_mfa_challenge(&db, &challenge.mfa_token, &recovery_codes[0])
+                .await
+                .unwrap();
+            assert_eq!(completed.user_id, user_id);
+            assert_eq!(
+                completed.auth_methods,
+                vec!["pwd".to_string(), "mfa".to_string()]
+            );
+
+            let again = begin_totp_enrollment(&db, &user_id).await;
+            assert!(matches!(again, Err(CustomError::MfaAlreadyEnabled)));
+        }
+
+        #[actix_web::test]
+        async fn test_recovery_codes() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id =
+                crate::tests::tests::register_test_user(&db, "recovery@example.com").await;
+            let enrollment = begin_totp_enrollment(&db, &user_id).await.unwrap();
+            let code = generate_code(&enrollment.secret, Utc::now().timestamp() as u64).unwrap();
+            let recovery_codes = confirm_totp_enrollment(&db, &user_id, &code).await.unwrap();
+            assert_eq!(recovery_codes.len(), 10);
+            assert_eq!(db.count_unused_recovery_codes(&user_id).await.unwrap(), 10);
+
+            // Recovery codes are accepted regardless of case and separators.
+            let challenge = create_mfa_challenge(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            let entered = recovery_codes[0].to_uppercase().replace('-', " ");
+            let tokens = complete_mfa_challenge(&db, &challenge.mfa_token, &entered)
+                .await
+                .unwrap();
+            assert_eq!(validate_jwt(&tokens.access_token).unwrap().sub, user_id);
+            assert_eq!(db.count_unused_recovery_codes(&user_id).await.unwrap(), 9);
+
+            let challenge = create_mfa_challenge(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            let reused =
+                complete_mfa_challenge(&db, &challenge.mfa_token, &recovery_codes[0]).await;
+            assert!(matches!(reused, Err(CustomError::InvalidMfaCode)));
+
+            let regenerated = regenerate_recovery_codes(&db, &user_id, &recovery_codes[1])
+                .await
+                .unwrap();
+            assert_eq!(db.count_unused_recovery_codes(&user_id).await.unwrap(), 10);
+            let old = complete_mfa_challenge(&db, &challenge.mfa_token, &recovery_codes[2]).await;
+            assert!(matches!(old, Err(CustomError::InvalidMfaCode)));
+            assert!(
+                complete_mfa_challenge(&db, &challenge.mfa_token, &regenerated[0])
+                    .await
+                    .is_ok()
+            );
+        }
+    }
+
+    mod test_webauthn {
+        use crate::errors::custom_errors::CustomError;
+        use crate::jwt::validate_jwt;
+        use crate::mfa::{
+            complete_mfa_challenge_with_passkey, create_mfa_challenge, mfa_methods,
+            start_mfa_passkey_assertion,
+        };
+        use crate::webauthn::{
+            finish_authentication, finish_registration, start_authentication, start_registration,
+            AssertionCredential, AssertionResponse, AttestationResponse, RegistrationCredential,
+        };
+        use argon2::password_hash::rand_core::{OsRng, RngCore};
+        use base64::{engine::general_purpose, Engine};
+        use ciborium::Value;
+        use p256::ecdsa::{signature::Signer, DerSignature, SigningKey};
+        use sha2::{Digest, Sha256};
+
+        /// A software authenticator holding a single ES256 credential.
+        struct SoftwareAuthenticator {
+            credential_id: Vec<u8>,
+            key: SigningKey,
+            sign_count: u32,
+        }
+
+        impl SoftwareAuthenticator {
+            fn new() -> Self {
+                let mut credential_id = vec![0u8; 16];
+                OsRng.fill_bytes(&mut credential_id);
+                SoftwareAuthenticator {
+                    credential_id,
+                    key: SigningKey::random(&mut OsRng),
+                    sign_count: 0,
+                }
+            }
+
+            fn client_data(ceremony_type: &str, options: &serde_json::Value) -> String {
+                let client_data = serde_json::json!({
+                    "type": ceremony_type,
+                    "challenge": options["challenge"],
+                    "origin": "http://localhost:8080",
+                });
+                general_purpose::URL_SAFE_NO_PAD.encode(client_data.to_string())
+            }
+
+            fn authenticator_data(&mut self, user_verified: bool, attested: bool) -> Vec<u8> {
+                self.sign_count += 1;
+                let mut flags = 0x01;
+                if user_verified {
+                    flags |= 0x04;
+                }
+                if attested {
+                    flags |= 0x40;
+                }
+
+                let mut data = Sha256::digest(b"localhost").to_vec();
+                data.push(flags);
+                data.extend_from_slice(&self.sign_count.to_be_bytes());
+                if attested {
+                    data.extend_from_slice(&[0u8; 16]);
+                    data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+                    data.extend_from_slice(&self.credential_id);
+                    let point = self.key.verifying_key().to_encoded_point(false);
+                    let cose_key = Value::Map(vec![
+                        (Value::from(1), Value::from(2)),
+                        (Value::from(3), Value::from(-7)),
+                        (Value::from(-1), Value::from(1)),
+                        (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+                        (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+                    ]);
+                    ciborium::into_writer(&cose_key, &mut data).unwrap();
+                }
+                data
+            }
+
+            fn register(&mut self, options: &serde_json::Value) -> RegistrationCredential {
+                let attestation_object = Value::Map(vec![
+                    (Value::from("fmt"), Value::from("none")),
+                    (Value::from("attStmt"), Value::Map(vec![])),
+                    (
+                        Value::from("authData"),
+                        Value::Bytes(self.authenticator_data(true, true)),
+                    ),
+                ]);
+                let mut attestation_bytes = Vec::new();
+                ciborium::into_writer(&attestation_object, &mut attestation_bytes).unwrap();
+
+                RegistrationCredential {
+                    id: general_purpose::URL_SAFE_NO_PAD.encode(&self.credential_id),
+                    credential_type: "public-key".to_string(),
+                    response: AttestationResponse {
+                        client_data_json: Self::client_data("webauthn.create", options),
+                        attestation_object: general_purpose::URL_SAFE_NO_PAD
+                            .encode(attestation_bytes),
+                    },
+                }
+            }
+
+            fn assert(
+                &mut self,
+                options: &serde_json::Value,
+                user_verified: bool,
+            ) -> AssertionCredential {
+                let client_data_json = Self::client_data("webauthn.get", options);
+                let authenticator_data = self.authenticator_data(user_verified, false);
+
+                let mut signed_data = authenticator_data.clone();
+                signed_data.extend_from_slice(&Sha256::digest(
+                    general_purpose::URL_SAFE_NO_PAD
+                        .decode(&client_data_json)
+                        .unwrap(),
+                ));
+                let signature: DerSignature = self.key.sign(&signed_data);
+
+                AssertionCredential {
+                    id: general_purpose::URL_SAFE_NO_PAD.encode(&self.credential_id),
+                    credential_type: "public-key".to_string(),
+                    response: AssertionResponse {
+                        client_data_json,
+                        authenticator_data: general_purpose::URL_SAFE_NO_PAD
+                            .encode(authenticator_data),
+                        signature: general_purpose::URL_SAFE_NO_PAD.encode(signature.as_bytes()),
+                        user_handle: None,
+                    },
+                }
+            }
+        }
+
+        #[actix_web::test]
+        async fn test_passkey_registration_and_login() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "passkey@example.com").await;
+            let mut authenticator = SoftwareAuthenticator::new();
+
+            let options = start_registration(&db, &user_id).await.unwrap();
+            let credential = authenticator.register(&options);
+            finish_registration(&db, &user_id, &credential, Some("Laptop"))
+                .await
+                .unwrap();
+            // The registration challenge can't be used twice.
+            assert!(finish_registration(&db, &user_id, &credential, None)
+                .await
+                .is_err());
+
+            let options = start_authentication(&db, None, true).await.unwrap();
+            let assertion = authenticator.assert(&options, true);
+            let verified = finish_authentication(&db, &assertion).await.unwrap();
+            assert_eq!(verified.user_id, user_id);
+            assert!(verified.user_verified);
+            assert!(finish_authentication(&db, &assertion).await.is_err());
+
+            // Passwordless login requires user verification.
+            let options = start_authentication(&db, None, true).await.unwrap();
+            let assertion = authenticator.assert(&options, false);
+            assert!(matches!(
+                finish_authentication(&db, &assertion).await,
+                Err(CustomError::WebAuthnError(_))
+            ));
+
+            // A signature counter that goes backwards points to a cloned authenticator.
+            authenticator.sign_count = 0;
+            let options = start_authentication(&db, None, true).await.unwrap();
+            let assertion = authenticator.assert(&options, true);
+            assert!(finish_authentication(&db, &assertion).await.is_err());
+        }
+
+        #[actix_web::test]
+        async fn test_passkey_as_second_factor() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "second@example.com").await;
+            let mut authenticator = SoftwareAuthenticator::new();
+            let options = start_registration(&db, &user_id).await.unwrap();
+            finish_registration(&db, &user_id, &authenticator.register(&options), None)
+                .await
+                .unwrap();
+
+            let user = db.get_user_by_id(&user_id).await.unwrap().unwrap();
+            assert_eq!(
+                mfa_methods(&db, &user).await.unwrap(),
+                vec!["webauthn".to_string()]
+            );
+
+            let challenge = create_mfa_challenge(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            let options = start_mfa_passkey_assertion(&db, &challenge.mfa_token)
+                .await
+                .unwrap();
+            let assertion = authenticator.assert(&options, false);
+            let tokens = complete_mfa_challenge_with_passkey(&db, &challenge.mfa_token, &assertion)
+                .await
+                .unwrap();
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.sub, user_id);
+            assert!(claims.amr.contains(&"hwk".to_string()));
+        }
+    }
+
+    mod test_password_reset {
+        use crate::errors::custom_errors::CustomError;
+        use crate::password_reset::{request_password_reset, reset_password};
+        use crate::tests::tests::MemoryMailer;
+
+        #[actix_web::test]
+        async fn test_unknown_email_sends_nothing() {
+            let db = crate::tests::tests::setup_database().await;
+            let mailer = MemoryMailer::default();
+            request_password_reset(&db, &mailer, "nobody@example.com")
+                .await
+                .unwrap();
+            assert!(mailer.messages.lock().unwrap().is_empty());
+        }
+
+        #[actix_web::test]
+        async fn test_password_reset() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "reset@example.com").await;
+            let mailer = MemoryMailer::default();
+
+            request_password_reset(&db, &mailer, "reset@example.com")
+                .await
+                .unwrap();
+            let first_token = mailer.last_token();
+            request_password_reset(&db, &mailer, "reset@example.com")
+                .await
+                .unwrap();
+            let token = mailer.last_token();
+            assert_eq!(
+                mailer.messages.lock().unwrap()[0].to,
+                "reset@example.com".to_string()
+            );
+
+            // Only the most recent token is valid.
+            let result = reset_password(&db, &first_token, "new-password").await;
+            assert!(matches!(
+                result,
+                Err(CustomError::InvalidPasswordResetToken)
+            ));
+
+            assert_eq!(
+                reset_password(&db, &token, "new-password").await.unwrap(),
+                user_id
+            );
+            assert!(db
+                .authenticate_user("reset@example.com".to_string(), "new-password".to_string())
+                .await
+                .is_ok());
+            assert!(db
+                .authenticate_user("reset@example.com".to_string(), "password123".to_string())
+                .await
+                .is_err());
+            assert_eq!(db.get_token_generation(&user_id).await.unwrap(), 1);
+
+            let reused = reset_password(&db, &token, "another-password").await;
+            assert!(matches!(
+                reused,
+                Err(CustomError::InvalidPasswordResetToken)
+            ));
+        }
+    }
+
+    mod test_email_verification {
+        use crate::email_verification::{
+            check_login_allowed, send_verification_email, verify_email,
+        };
+        use crate::errors::custom_errors::CustomError;
+        use crate::tests::tests::MemoryMailer;
+        use crate::tokens::user_claims;
+
+        #[actix_web::test]
+        async fn test_verify_email() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "verify@example.com").await;
+            let mailer = MemoryMailer::default();
+
+            // New accounts start unverified and may log in under the default policy.
+            let user = db.get_user_by_id(&user_id).await.unwrap().unwrap();
+            assert!(!user.email_verified);
+            assert!(check_login_allowed(&db, &user).is_ok());
+            let claims = user_claims(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            assert_eq!(claims.email_verified, Some(false));
+
+            send_verification_email(&db, &mailer, "verify@example.com")
+                .await
+                .unwrap();
+            let token = mailer.last_token();
+            assert_eq!(verify_email(&db, &token).await.unwrap(), user_id);
+
+            let user = db.get_user_by_id(&user_id).await.unwrap().unwrap();
+            assert!(user.email_verified);
+            let claims = user_claims(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            assert_eq!(claims.email_verified, Some(true));
+
+            let reused = verify_email(&db, &token).await;
+            assert!(matches!(
+                reused,
+                Err(CustomError::InvalidEmailVerificationToken)
+            ));
+
+            // Verified accounts don't get another email.
+            send_verification_email(&db, &mailer, "verify@example.com")
+                .await
+                .unwrap();
+            assert_eq!(mailer.messages.lock().unwrap().len(), 1);
+        }
+    }
+
+    mod test_rbac {
+        use crate::database::{Database, Role};
+        use crate::rbac::{self, effective_permissions, has_permission, permission_matches};
+        use crate::tokens::{issue_token_pair, user_claims};
+        use actix_web::http::StatusCode;
+
+        async fn call_with_token(db: &Database, token: &str) -> StatusCode {
+            let req = crate::tests::tests::bearer_request("/test", token);
+            crate::tests::tests::call_authenticated(db, req, Some(rbac::KEYS_MANAGE)).await
+        }
+
+        fn role(name: &str, permissions: &[&str]) -> Role {
+            Role {
+                name: name.to_string(),
+                description: String::new(),
+                permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            }
+        }
+
+        #[test]
+        fn test_permission_matches() {
+            assert!(permission_matches("keys:manage", "keys:manage"));
+            assert!(permission_matches("keys:*", "keys:manage"));
+            assert!(permission_matches("*", "roles:manage"));
+            assert!(!permission_matches("keys:read", "keys:manage"));
+            assert!(!permission_matches("keys:*", "roles:manage"));
+            assert!(!permission_matches("keys*", "keys:manage"));
+        }
+
+        #[test]
+        fn test_validate_role() {
+            assert!(rbac::validate_role(&role("key-admin", &["keys:*"])).is_ok());
+            assert!(rbac::validate_role(&role("key admin", &["keys:*"])).is_err());
+            assert!(rbac::validate_role(&role("key-admin", &["keys: manage"])).is_err());
+        }
+
+        #[actix_web::test]
+        async fn test_role_assignment() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "rbac@example.com").await;
+            assert!(!has_permission(&db, &user_id, rbac::KEYS_READ)
+                .await
+                .unwrap());
+
+            db.upsert_role(&role("key-admin", &["keys:*"]))
+                .await
+                .unwrap();
+            db.upsert_role(&role("auditor", &["roles:read"]))
+                .await
+                .unwrap();
+            rbac::assign_role(&db, &user_id, "key-admin").await.unwrap();
+            rbac::assign_role(&db, &user_id, "auditor").await.unwrap();
+            assert!(rbac::assign_role(&db, &user_id, "unknown").await.is_err());
+
+            assert!(has_permission(&db, &user_id, rbac::KEYS_MANAGE)
+                .await
+                .unwrap());
+            assert!(!has_permission(&db, &user_id, rbac::ROLES_MANAGE)
+                .await
+                .unwrap());
+            let claims = user_claims(&db, &user_id, &[]).await.unwrap();
+            assert_eq!(
+                claims.roles,
+                vec!["auditor".to_string(), "key-admin".to_string()]
+            );
+
+            // Changing a role changes the permissions of its holders.
+            db.upsert_role(&role("key-admin", &["keys:read"]))
+                .await
+                .unwrap();
+            assert!(!has_permission(&db, &user_id, rbac::KEYS_MANAGE)
+                .await
+                .unwrap());
+
+            assert!(db.unassign_role(&user_id, "auditor").await.unwrap());
+            assert!(!db.unassign_role(&user_id, "auditor").await.unwrap());
+            assert!(db.delete_role("key-admin").await.unwrap());
+            assert!(db.get_user_roles(&user_id).await.unwrap().is_empty());
+            assert!(effective_permissions(&db, &user_id)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+
+        #[actix_web::test]
+        async fn test_route_requires_permission() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "guard@example.com").await;
+            let tokens = issue_token_pair(&db, &user_id, &[]).await.unwrap();
+            assert_eq!(
+                call_with_token(&db, &tokens.access_token).await,
+                StatusCode::FORBIDDEN
+            );
+
+            db.upsert_role(&role("key-admin", &["keys:manage"]))
+                .await
+                .unwrap();
+            rbac::assign_role(&db, &user_id, "key-admin").await.unwrap();
+            assert_eq!(
+                call_with_token(&db, &tokens.access_token).await,
+                StatusCode::OK
+            );
+        }
+    }
+
+    mod test_groups {
+        use crate::database::Role;
+        use crate::errors::custom_errors::CustomError;
+        use crate::groups::{self, user_groups};
+        use crate::rbac::{self, explain_permissions, has_permission, GrantSource};
+        use crate::tokens::user_claims;
+
+        #[actix_web::test]
+        async fn test_nested_groups_inherit_roles() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "groups@example.com").await;
+            db.upsert_role(&Role {
+                name: "key-admin".to_string(),
+                description: String::new(),
+                permissions: vec!["keys:manage".to_string()],
+            })
+            .await
+            .unwrap();
+
+            let engineering = groups::create_group(&db, "engineering", "").await.unwrap();
+            let backend = groups::create_group(&db, "backend", "").await.unwrap();
+            let engineering_id = engineering.id.to_string();
+            let backend_id = backend.id.to_string();
+            assert!(groups::create_group(&db, "backend", "").await.is_err());
+
+            groups::add_member(&db, &backend_id, &user_id)
+                .await
+                .unwrap();
+            groups::add_member(&db, &engineering_id, &backend_id)
+                .await
+                .unwrap();
+            groups::assign_role(&db, &engineering_id, "key-admin")
+                .await
+                .unwrap();
+
+            let memberships = user_groups(&db, &user_id).await.unwrap();
+            assert_eq!(memberships.len(), 2);
+            assert!(has_permission(&db, &user_id, rbac::KEYS_MANAGE)
+                .await
+                .unwrap());
+            let claims = user_claims(&db, &user_id, &[]).await.unwrap();
+            assert_eq!(claims.roles, vec!["key-admin".to_string()]);
+
+            // The explanation names the groups the permission was inherited through.
+            groups::rename_group(&db, &engineering_id, "platform")
+                .await
+                .unwrap();
+            let grants = explain_permissions(&db, &user_id).await.unwrap();
+            assert_eq!(grants.len(), 1);
+            assert_eq!(grants[0].permission, "keys:manage");
+            assert_eq!(
+                grants[0].source,
+                GrantSource::Group {
+                    role: "key-admin".to_string(),
+                    path: vec!["backend".to_string(), "platform".to_string()],
+                }
+            );
+
+            // Removing the nested group removes the inherited permission.
+            assert!(db
+                .remove_group_member(&engineering_id, &backend_id)
+                .await
+                .unwrap());
+            assert!(!has_permission(&db, &user_id, rbac::KEYS_MANAGE)
+                .await
+                .unwrap());
+        }
+
+        #[actix_web::test]
+        async fn test_group_cycles_are_rejected() {
+            let db = crate::tests::tests::setup_database().await;
+            let a = groups::create_group(&db, "a", "")
+                .await
+                .unwrap()
+                .id
+                .to_string();
+            let b = groups::create_group(&db, "b", "")
+                .await
+                .unwrap()
+                .id
+                .to_string();
+            let c = groups::create_group(&db, "c", "")
+                .await
+                .unwrap()
+                .id
+                .to_string();
+
+            groups::add_member(&db, &a, &b).await.unwrap();
+            groups::add_member(&db, &b, &c).await.unwrap();
+            assert!(matches!(
+                groups::add_member(&db, &c, &a).await,
+                Err(CustomError::InvalidGroup(_))
+            ));
+            assert!(matches!(
+                groups::add_member(&db, &a, &a).await,
+                Err(CustomError::InvalidGroup(_))
+            ));
+            // A diamond is not a cycle.
+            groups::add_member(&db, &a, &c).await.unwrap();
+
+            assert!(db.delete_group(&b).await.unwrap());
+            assert!(matches!(
+                groups::add_member(&db, &b, &c).await,
+                Err(CustomError::GroupNotFound)
+            ));
+        }
+    }
+
+    mod test_policy {
+        use crate::policy::{self, evaluate, AuthorizationRequest, Policy, PolicyDocument};
+        use chrono::{TimeZone, Utc};
+        use serde_json::{json, Value};
+
+        fn document() -> PolicyDocument {
+            serde_json::from_value(json!({
+                "description": "Documents",
+                "rules": [
+                    {
+                        "id": "other-tenant",
+                        "effect": "deny",
+                        "resources": ["document"],
+                        "condition": {"ne": [{"attr": "resource.tenant"}, {"attr": "subject.tenant"}]}
+                    },
+                    {
+                        "id": "owner",
+                        "effect": "allow",
+                        "actions": ["document:*"],
+                        "resources": ["document"],
+                        "condition": {"eq": [{"attr": "resource.owner"}, {"attr": "subject.id"}]}
+                    },
+                    {
+                        "id": "editors-in-business-hours",
+                        "effect": "allow",
+                        "actions": ["document:edit"],
+                        "resources": ["document"],
+                        "condition": {"all": [
+                            {"contains": [{"attr": "subject.roles"}, "editor"]},
+                            {"gte": [{"attr": "environment.hour"}, 9]},
+                            {"lt": [{"attr": "environment.hour"}, 17]},
+                            {"not": {"in": [{"attr": "environment.weekday"}, ["sat", "sun"]]}}
+                        ]}
+                    }
+                ]
+            }))
+            .unwrap()
+        }
+
+        fn request(subject: Value, action: &str, hour: u32) -> AuthorizationRequest {
+            // 2024-01-01 was a Monday.
+            let now = Utc.with_ymd_and_hms(2024, 1, 1, hour, 0, 0).unwrap();
+            AuthorizationRequest {
+                subject,
+                action: action.to_string(),
+                resource: json!({"type": "document", "owner": "users:alice", "tenant": "acme"}),
+                environment: Value::Object(policy::environment_attributes(now)),
+            }
+        }
+
+        #[test]
+        fn test_evaluate() {
+            let policies = vec![Policy {
+                name: "documents".to_string(),
+                version: 1,
+                document: document(),
+                created_by: "users:admin".to_string(),
+                created_at: 0,
+            }];
+            let alice = json!({"id": "users:alice", "tenant": "acme", "roles": []});
+            let bob = json!({"id": "users:bob", "tenant": "acme", "roles": ["editor"]});
+            let mallory = json!({"id": "users:alice", "tenant": "evil", "roles": ["editor"]});
+
+            let decision = evaluate(&policies, &request(alice.clone(), "document:delete", 20));
+            assert!(decision.allowed);
+            assert_eq!(decision.rule.as_deref(), Some("owner"));
+            assert_eq!(decision.version, Some(1));
+
+            assert!(evaluate(&policies, &request(bob.clone(), "document:edit", 10)).allowed);
+            assert!(!evaluate(&policies, &request(bob.clone(), "document:edit", 18)).allowed);
+            assert!(!evaluate(&policies, &request(bob, "document:delete", 10)).allowed);
+
+            // Deny overrides the matching allow rules.
+            let decision = evaluate(&policies, &request(mallory, "document:edit", 10));
+            assert!(!decision.allowed);
+            assert_eq!(decision.rule.as_deref(), Some("other-tenant"));
+
+            // Nothing matches, so access is denied by default.
+            let decision = evaluate(&[], &request(alice, "document:read", 10));
+            assert!(!decision.allowed);
+            assert_eq!(decision.rule, None);
+        }
+
+        #[actix_web::test]
+        async fn test_policy_versions() {
+            let db = crate::tests::tests::setup_database().await;
+            let mut document = document();
+            assert!(
+                policy::save_policy(&db, "bad name", &document, "users:admin")
+                    .await
+                    .is_err()
+            );
+
+            let first = policy::save_policy(&db, "documents", &document, "users:admin")
+                .await
+                .unwrap();
+            assert_eq!(first.version, 1);
+            document.rules.truncate(1);
+            let second = policy::save_policy(&db, "documents", &document, "users:admin")
+                .await
+                .unwrap();
+            assert_eq!(second.version, 2);
+
+            let active = policy::active_policies(&db).await.unwrap();
+            assert_eq!(active.len(), 1);
+            assert_eq!(active[0].document.rules.len(), 1);
+
+            let restored = policy::rollback_policy(&db, "documents", 1, "users:admin")
+                .await
+                .unwrap();
+            assert_eq!(restored.version, 3);
+            assert_eq!(restored.document.rules.len(), 3);
+            let versions = policy::policy_versions(&db, "documents").await.unwrap();
+            assert_eq!(
+                versions
+                    .iter()
+                    .map(|policy| policy.version)
+                    .collect::<Vec<_>>(),
+                vec![3, 2, 1]
+            );
+            assert!(policy::rollback_policy(&db, "documents", 7, "users:admin")
+                .await
+                .is_err());
+
+            let decision = policy::authorize(
+                &db,
+                &request(
+                    json!({"id": "users:alice", "tenant": "acme"}),
+                    "document:read",
+                    8,
+                ),
+            )
+            .await
+            .unwrap();
+            assert!(decision.allowed);
+            assert_eq!(decision.version, Some(3));
+        }
+
+        #[test]
+        fn test_client_ip() {
+            let request = |peer: &str| {
+                actix_web::test::TestRequest::default()
+                    .peer_addr(peer.parse().unwrap())
+                    .insert_header(("X-Forwarded-For", "203.0.113.7"))
+                    .to_http_request()
+            };
+
+            // Forwarding headers are only honored from trusted proxies.
+            std::env::set_var("TRUSTED_PROXIES", "10.0.0.1, 10.0.0.2");
+            assert_eq!(
+                policy::client_ip(&request("10.0.0.2:4321")).as_deref(),
+                Some("203.0.113.7")
+            );
+            assert_eq!(
+                policy::client_ip(&request("198.51.100.1:4321")).as_deref(),
+                Some("198.51.100.1")
+            );
+        }
+    }
+
+    mod test_simulation {
+        use crate::database::Role;
+        use crate::groups;
+        use crate::rbac::{effective_permissions, has_permission};
+        use crate::simulation::{simulate, Simulation};
+        use serde_json::json;
+
+        #[actix_web::test]
+        async fn test_simulate_role_and_policy_changes() {
+            let db = crate::tests::tests::setup_database().await;
+            let alice = crate::tests::tests::register_test_user(&db, "alice@example.com").await;
+            let bob = crate::tests::tests::register_test_user(&db, "bob@example.com").await;
+            db.upsert_role(&Role {
+                name: "reader".to_string(),
+                description: String::new(),
+                permissions: vec!["docs:read".to_string()],
+            })
+            .await
+            .unwrap();
+            let readers = groups::create_group(&db, "readers", "")
+                .await
+                .unwrap()
+                .id
+                .to_string();
+            groups::add_member(&db, &readers, &alice).await.unwrap();
+            groups::assign_role(&db, &readers, "reader").await.unwrap();
+
+            // Widening the role only affects the members of the group holding it.
+            let simulation: Simulation = serde_json::from_value(json!({
+                "change": {"upsert_role": {"name": "reader", "permissions": ["docs:read", "docs:write"]}}
+            }))
+            .unwrap();
+            let report = simulate(&db, &simulation).await.unwrap();
+            assert_eq!(report.users_evaluated, 2);
+            assert_eq!(report.affected.len(), 1);
+            assert_eq!(report.affected[0].user_id, alice);
+            assert_eq!(report.affected[0].gained, vec!["docs:write".to_string()]);
+            assert!(report.affected[0].lost.is_empty());
+            assert!(!has_permission(&db, &alice, "docs:write").await.unwrap());
+
+            let simulation: Simulation = serde_json::from_value(json!({
+                "change": {"assign_role": {"member_id": bob, "role": "reader"}},
+                "user_ids": [bob]
+            }))
+            .unwrap();
+            let report = simulate(&db, &simulation).await.unwrap();
+            assert_eq!(report.users_evaluated, 1);
+            assert_eq!(report.affected[0].gained, vec!["docs:read".to_string()]);
+            assert!(effective_permissions(&db, &bob).await.unwrap().is_empty());
+
+            // A policy change is reported through the checks it flips.
+            let simulation: Simulation = serde_json::from_value(json!({
+                "change": {"save_policy": {"name": "docs", "document": {"rules": [{
+                    "id": "readers",
+                    "effect": "allow",
+                    "actions": ["docs:read"],
+                    "condition": {"contains": [{"attr": "subject.roles"}, "reader"]}
+                }]}}},
+                "checks": [{"action": "docs:read", "resource": {"type": "doc"}}],
+                "sample": 5
+            }))
+            .unwrap();
+            let report = simulate(&db, &simulation).await.unwrap();
+            assert_eq!(report.users_evaluated, 2);
+            assert_eq!(report.affected.len(), 1);
+            let decision = &report.affected[0].decisions[0];
+            assert!(!decision.before.allowed);
+            assert!(decision.after.allowed);
+            assert_eq!(decision.after.version, Some(1));
+            assert!(crate::policy::active_policies(&db)
+                .await
+                .unwrap()
+                .is_empty());
+
+            let simulation: Simulation = serde_json::from_value(json!({
+                "change": {"delete_role": {"name": "unknown"}}
+            }))
+            .unwrap();
+            assert!(simulate(&db, &simulation).await.is_err());
+        }
+    }
+
+    mod test_tenants {
+        use crate::database::{Tenant, TenantSettings};
+        use crate::errors::custom_errors::CustomError;
+        use crate::mailer::StdoutMailer;
+        use crate::middleware::{AuthenticationMiddlewareFactory, TenantMiddlewareFactory};
+        use crate::rbac::{self, has_permission};
+        use crate::server::AppState;
+        use crate::tenants::{split_tenant_path, Tenants};
+        use crate::tokens::issue_token_pair;
+        use actix_web::http::header;
+        use actix_web::{http::StatusCode, web, App, HttpResponse};
+        use std::sync::Arc;
+
+        async fn whoami(data: web::Data<AppState>) -> HttpResponse {
+            HttpResponse::Ok().body(data.db.tenant().to_string())
+        }
+
+        fn tenant(tenant_id: &str, hosts: &[&str]) -> Tenant {
+            Tenant {
+                tenant_id: tenant_id.to_string(),
+                name: String::new(),
+                hosts: hosts.iter().map(|host| host.to_string()).collect(),
+                settings: TenantSettings::default(),
+                created_at: 0,
+            }
+        }
+
+        #[test]
+        fn test_split_tenant_path() {
+            assert_eq!(
+                split_tenant_path("/realms/acme/login"),
+                Some(("acme", "/login".to_string()))
+            );
+            assert_eq!(
+                split_tenant_path("/realms/acme"),
+                Some(("acme", "/".to_string()))
+            );
+            assert_eq!(split_tenant_path("/realms/"), None);
+            assert_eq!(split_tenant_path("/login"), None);
+        }
+
+        #[actix_web::test]
+        async fn test_tenants_are_isolated() {
+            let root = crate::tests::tests::setup_database().await;
+            let tenants = Tenants::new(root.clone());
+            assert!(matches!(
+                tenants.create(tenant("Acme", &[])).await,
+                Err(CustomError::InvalidTenant(_))
+            ));
+            tenants
+                .create(tenant("acme", &["acme.example.com"]))
+                .await
+                .unwrap();
+            assert!(matches!(
+                tenants
+                    .create(tenant("globex", &["acme.example.com"]))
+                    .await,
+                Err(CustomError::InvalidTenant(_))
+            ));
+
+            // The same email address can be registered in both tenants.
+            let acme = tenants.database("acme").await.unwrap();
+            let acme_user = crate::tests::tests::register_test_user(&acme, "t@example.com").await;
+            assert!(root
+                .get_user_by_email("t@example.com")
+                .await
+                .unwrap()
+                .is_none());
+            let root_user = crate::tests::tests::register_test_user(&root, "t@example.com").await;
+
+            let app = actix_web::test::init_service(
+                App::new()
+                    .app_data(web::Data::new(AppState {
+                        db: root.clone(),
+                        mailer: Arc::new(StdoutMailer),
+                        tenants: tenants.clone(),
+                    }))
+                    .wrap(AuthenticationMiddlewareFactory::new())
+                    .wrap(TenantMiddlewareFactory::new())
+                    .route("/whoami", web::get().to(whoami)),
+            )
+            .await;
+            let call = |uri: &str, host: &str, token: &str| {
+                actix_web::test::TestRequest::get()
+                    .uri(uri)
+                    .insert_header((header::HOST, host.to_string()))
+                    .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                    .to_request()
+            };
+
+            let acme_tokens = issue_token_pair(&acme, &acme_user, &[]).await.unwrap();
+            let root_tokens = issue_token_pair(&root, &root_user, &[]).await.unwrap();
+
+            let req = call(
+                "/realms/acme/whoami",
+                "localhost",
+                &acme_tokens.access_token,
+            );
+            let res = actix_web::test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(actix_web::test::read_body(res).await, "acme");
+
+            let req = call(
+                "/whoami",
+                "acme.example.com:8080",
+                &acme_tokens.access_token,
+            );
+            let res = actix_web::test::call_service(&app, req).await;
+            assert_eq!(actix_web::test::read_body(res).await, "acme");
+
+            // Tokens are only accepted by the tenant that issued them.
+            for (uri, token) in [
+                ("/whoami", &acme_tokens.access_token),
+                ("/realms/acme/whoami", &root_tokens.access_token),
+            ] {
+                let req = call(uri, "localhost", token);
+                let status = match actix_web::test::try_call_service(&app, req).await {
+                    Ok(res) => res.status(),
+                    Err(error) => error.as_response_error().status_code(),
+                };
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+            }
+
+            let req = call(
+                "/realms/globex/whoami",
+                "localhost",
+                &root_tokens.access_token,
+            );
+            let status = match actix_web::test::try_call_service(&app, req).await {
+                Ok(res) => res.status(),
+                Err(error) => error.as_response_error().status_code(),
+            };
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            // Superusers of a tenant come from its settings.
+            assert!(!has_permission(&acme, &acme_user, rbac::ROLES_MANAGE)
+                .await
+                .unwrap());
+            let mut updated = tenant("acme", &["acme.example.com"]);
+            updated.settings.admin_user_ids = vec![acme_user.clone()];
+            tenants.update(updated).await.unwrap();
+            let acme = tenants.database("acme").await.unwrap();
+            assert!(has_permission(&acme, &acme_user, rbac::ROLES_MANAGE)
+                .await
+                .unwrap());
+
+            tenants.delete("acme").await.unwrap();
+            assert!(matches!(
+                tenants.database("acme").await,
+                Err(CustomError::TenantNotFound)
+            ));
+        }
+    }
+
+    mod test_oauth {
+        use crate::errors::custom_errors::CustomError;
+        use crate::jwt::validate_jwt;
+        use crate::oauth::{
+            code_challenge, exchange_token, issue_authorization_code, register_client,
+            rotate_client_secret, validate_authorization_request, validate_redirect_uri,
+            verify_code_challenge, AuthorizationError, AuthorizationParams, ClientCredentials,
+            ClientRegistration, ClientType, TokenRequest,
+        };
+        use crate::tokens::{issue_token_pair, rotate_refresh_token};
+        use actix_web::http::header;
+        use actix_web::http::StatusCode;
+
+        const VERIFIER: &str = "dBjftJeZ4CVP-mJ0kzyDMA7QZ-E-qhkbFzqk-3NPQ-verifier";
+        const ISSUER: &str = "https://id.example.com";
+
+        fn params(client_id: &str) -> AuthorizationParams {
+            AuthorizationParams {
+                response_type: Some("code".to_string()),
+                client_id: Some(client_id.to_string()),
+                redirect_uri: None,
+                scope: Some("profile profile email".to_string()),
+                state: Some("a b".to_string()),
+                code_challenge: Some(code_challenge(VERIFIER)),
+                code_challenge_method: Some("S256".to_string()),
+                nonce: None,
+            }
+        }
+
+        fn code_from(url: &str) -> String {
+            let query = url.split_once('?').unwrap().1;
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("code="))
+                .unwrap()
+                .to_string()
+        }
+
+        #[test]
+        fn test_redirect_uris_and_pkce() {
+            for uri in [
+                "https://app.example.com/callback?x=1",
+                "http://localhost:8080/callback",
+                "http://127.0.0.1/callback",
+            ] {
+                assert!(validate_redirect_uri(uri, ClientType::Confidential).is_ok());
+            }
+            assert!(validate_redirect_uri("com.example.app:/callback", ClientType::Public).is_ok());
+            for uri in [
+                "http://app.example.com/callback",
+                "https://app.example.com/callback#fragment",
+                "https://user@app.example.com/callback",
+                "/callback",
+                "com.example.app:/callback",
+            ] {
+                assert!(validate_redirect_uri(uri, ClientType::Confidential).is_err());
+            }
+
+            let challenge = code_challenge(VERIFIER);
+            assert!(verify_code_challenge(VERIFIER, &challenge));
+            assert!(!verify_code_challenge(
+                &format!("{}x", VERIFIER),
+                &challenge
+            ));
+            assert!(!verify_code_challenge("short", &code_challenge("short")));
+        }
+
+        #[actix_web::test]
+        async fn test_authorization_code_flow() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            let client = register_client(
+                &db,
+                ClientRegistration {
+                    name: "Web app".to_string(),
+                    client_type: ClientType::Confidential,
+                    redirect_uris: vec!["https://app.example.com/callback".to_string()],
+                    grant_types: None,
+                    scopes: vec!["profile".to_string(), "email".to_string()],
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let client_id = client.client.client_id.clone();
+            let secret = client.client_secret.clone().unwrap();
+
+            // The user is never redirected to an unregistered URI.
+            let mut request = params(&client_id);
+            request.redirect_uri = Some("https://evil.example.com/callback".to_string());
+            assert!(matches!(
+                validate_authorization_request(&db, &request).await,
+                Err(AuthorizationError::InvalidClient(_))
+            ));
+            let mut request = params(&client_id);
+            request.scope = Some("profile admin".to_string());
+            match validate_authorization_request(&db, &request).await {
+                Err(AuthorizationError::Redirect(url)) => {
+                    assert!(url.starts_with("https://app.example.com/callback?error=invalid_scope"))
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+            let mut request = params(&client_id);
+            request.code_challenge = None;
+            match validate_authorization_request(&db, &request).await {
+                Err(AuthorizationError::Redirect(url)) => {
+                    assert!(
+                        url.starts_with("https://app.example.com/callback?error=invalid_request")
+                    );
+                    assert!(url.ends_with("&state=a%20b"));
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+
+            let authorization = validate_authorization_request(&db, &params(&client_id))
+                .await
+                .unwrap();
+            assert_eq!(authorization.scope.as_deref(), Some("profile email"));
+            let token_request = |code: &str, verifier: &str| TokenRequest {
+                grant_type: "authorization_code".to_string(),
+                code: Some(code.to_string()),
+                code_verifier: Some(verifier.to_string()),
+                client_id: Some(client_id.clone()),
+                client_secret: Some(secret.clone()),
+                ..TokenRequest::default()
+            };
+
+            // Neither a wrong verifier nor another client can burn the code.
+            let url = issue_authorization_code(&db, &authorization, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            let code = code_from(&url);
+            let wrong = exchange_token(
+                &db,
+                &token_request(&code, &format!("{}x", VERIFIER)),
+                None,
+                ISSUER,
+            )
+            .await;
+            assert!(matches!(
+                wrong,
+                Err(CustomError::OAuthError("invalid_grant", _))
+            ));
+            let other = register_client(
+                &db,
+                ClientRegistration {
+                    name: "Other app".to_string(),
+                    client_type: ClientType::Confidential,
+                    redirect_uris: vec!["https://app.example.com/callback".to_string()],
+                    grant_types: None,
+                    scopes: vec!["profile".to_string(), "email".to_string()],
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let mut request = token_request(&code, VERIFIER);
+            request.client_id = Some(other.client.client_id.clone());
+            request.client_secret = other.client_secret.clone();
+            assert!(matches!(
+                exchange_token(&db, &request, None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_grant", _))
+            ));
+            let basic = ClientCredentials {
+                client_id: client_id.clone(),
+                client_secret: Some(secret.clone()),
+            };
+            request.client_secret = None;
+            assert!(matches!(
+                exchange_token(&db, &request, Some(basic), ISSUER).await,
+                Err(CustomError::OAuthError("invalid_request", _))
+            ));
+
+            let basic = ClientCredentials {
+                client_id: client_id.clone(),
+                client_secret: Some("wrong".to_string()),
+            };
+            let mut request = token_request(&code, VERIFIER);
+            request.client_secret = None;
+            let unauthenticated = exchange_token(&db, &request, Some(basic), ISSUER).await;
+            assert!(matches!(
+                unauthenticated,
+                Err(CustomError::OAuthError("invalid_client", _))
+            ));
+
+            let tokens = exchange_token(&db, &token_request(&code, VERIFIER), None, ISSUER)
+                .await
+                .unwrap();
+            assert_eq!(tokens.scope.as_deref(), Some("profile email"));
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.sub, user_id);
+            assert_eq!(claims.client_id.as_deref(), Some(client_id.as_str()));
+
+            // Refresh tokens of a client can only be used by that client.
+            assert!(matches!(
+                rotate_refresh_token(&db, tokens.refresh_token.as_deref().unwrap()).await,
+                Err(CustomError::InvalidRefreshToken)
+            ));
+            let refresh = TokenRequest {
+                grant_type: "refresh_token".to_string(),
+                refresh_token: tokens.refresh_token.clone(),
+                client_id: Some(client_id.clone()),
+                client_secret: Some(secret.clone()),
+                ..TokenRequest::default()
+            };
+            let refreshed = exchange_token(&db, &refresh, None, ISSUER).await.unwrap();
+            assert_eq!(refreshed.scope.as_deref(), Some("profile email"));
+
+            // Replaying the code revokes the tokens issued for it.
+            let replayed = exchange_token(&db, &token_request(&code, VERIFIER), None, ISSUER).await;
+            assert!(matches!(
+                replayed,
+                Err(CustomError::OAuthError("invalid_grant", _))
+            ));
+            let mut refresh = refresh;
+            refresh.refresh_token = refreshed.refresh_token;
+            assert!(matches!(
+                exchange_token(&db, &refresh, None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_grant", _))
+            ));
+
+            // A redirect URI sent with the authorization request has to be sent again.
+            let mut request = params(&client_id);
+            request.redirect_uri = Some("https://app.example.com/callback".to_string());
+            let authorization = validate_authorization_request(&db, &request).await.unwrap();
+            for redirect_uri in [None, Some("https://app.example.com/callback")] {
+                let url =
+                    issue_authorization_code(&db, &authorization, &user_id, &["pwd".to_string()])
+                        .await
+                        .unwrap();
+                let mut request = token_request(&code_from(&url), VERIFIER);
+                request.redirect_uri = redirect_uri.map(str::to_string);
+                let result = exchange_token(&db, &request, None, ISSUER).await;
+                match redirect_uri {
+                    None => assert!(matches!(
+                        result,
+                        Err(CustomError::OAuthError("invalid_grant", _))
+                    )),
+                    Some(_) => assert!(result.is_ok()),
+                }
+            }
+        }
+
+        #[actix_web::test]
+        async fn test_client_tokens_on_first_party_routes() {
+            use crate::database::Role;
+            use crate::rbac;
+            use crate::tests::tests::call_authenticated;
+
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            db.upsert_role(&Role {
+                name: "key-admin".to_string(),
+                description: String::new(),
+                permissions: vec!["keys:*".to_string()],
+            })
+            .await
+            .unwrap();
+            rbac::assign_role(&db, &user_id, "key-admin").await.unwrap();
+            let client = register_client(
+                &db,
+                ClientRegistration {
+                    name: "Web app".to_string(),
+                    client_type: ClientType::Confidential,
+                    redirect_uris: vec!["https://app.example.com/callback".to_string()],
+                    grant_types: None,
+                    scopes: vec!["openid".to_string()],
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let mut request = params(&client.client.client_id);
+            request.scope = Some("openid".to_string());
+            let authorization = validate_authorization_request(&db, &request).await.unwrap();
+            let url = issue_authorization_code(&db, &authorization, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            let client_token = exchange_token(
+                &db,
+                &TokenRequest {
+                    grant_type: "authorization_code".to_string(),
+                    code: Some(code_from(&url)),
+                    code_verifier: Some(VERIFIER.to_string()),
+                    client_id: Some(client.client.client_id.clone()),
+                    client_secret: client.client_secret.clone(),
+                    ..TokenRequest::default()
+                },
+                None,
+                ISSUER,
+            )
+            .await
+            .unwrap()
+            .access_token;
+            let session_token = issue_token_pair(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap()
+                .access_token;
+
+            let call = |method: &str, uri: &str, token: &str| {
+                let req = match method {
+                    "POST" => actix_web::test::TestRequest::post(),
+                    _ => actix_web::test::TestRequest::get(),
+                };
+                req.uri(uri)
+                    .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            };
+
+            // Clients only get the user's claims, never the user's account.
+            for (method, uri, permission, expected) in [
+                ("POST", "/change_password", None, StatusCode::FORBIDDEN),
+                (
+                    "GET",
+                    "/admin/keys",
+                    Some(rbac::KEYS_READ),
+                    StatusCode::FORBIDDEN,
+                ),
+                ("GET", "/userinfo", None, StatusCode::OK),
+            ] {
+                let status =
+                    call_authenticated(&db, call(method, uri, &client_token), permission).await;
+                assert_eq!(status, expected, "{}", uri);
+                let status =
+                    call_authenticated(&db, call(method, uri, &session_token), permission).await;
+                assert_eq!(status, StatusCode::OK, "{}", uri);
+            }
+        }
+
+        #[actix_web::test]
+        async fn test_client_credentials_grant() {
+            let db = crate::tests::tests::setup_database().await;
+            let registration = |client_type| ClientRegistration {
+                name: "Billing job".to_string(),
+                client_type,
+                redirect_uris: Vec::new(),
+                grant_types: Some(vec!["client_credentials".to_string()]),
+                scopes: vec!["invoices:read".to_string(), "invoices:write".to_string()],
+                logo_uri: None,
+                access_token_lifetime: None,
+                refresh_token_lifetime: None,
+                first_party: false,
+            };
+            assert!(matches!(
+                register_client(&db, registration(ClientType::Public)).await,
+                Err(CustomError::InvalidClient(_))
+            ));
+            let client = register_client(&db, registration(ClientType::Confidential))
+                .await
+                .unwrap();
+            let client_id = client.client.client_id.clone();
+            let request = |secret: &str, scope: Option<&str>| TokenRequest {
+                grant_type: "client_credentials".to_string(),
+                scope: scope.map(str::to_string),
+                client_id: Some(client_id.clone()),
+                client_secret: Some(secret.to_string()),
+                ..TokenRequest::default()
+            };
+
+            let secret = client.client_secret.unwrap();
+            let tokens = exchange_token(&db, &request(&secret, None), None, ISSUER)
+                .await
+                .unwrap();
+            assert!(tokens.refresh_token.is_none());
+            assert_eq!(
+                tokens.scope.as_deref(),
+                Some("invoices:read invoices:write")
+            );
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.sub, client_id);
+            assert!(claims.roles.is_empty());
+            let tokens =
+                exchange_token(&db, &request(&secret, Some("invoices:read")), None, ISSUER)
+                    .await
+                    .unwrap();
+            assert_eq!(tokens.scope.as_deref(), Some("invoices:read"));
+            assert!(matches!(
+                exchange_token(&db, &request(&secret, Some("users:read")), None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_scope", _))
+            ));
+
+            // The client may only use the grants it was registered for.
+            let mut code_request = request(&secret, None);
+            code_request.grant_type = "authorization_code".to_string();
+            assert!(matches!(
+                exchange_token(&db, &code_request, None, ISSUER).await,
+                Err(CustomError::OAuthError("unauthorized_client", _))
+            ));
+
+            // A rotated secret replaces the old one immediately.
+            let rotated = rotate_client_secret(&db, &client_id).await.unwrap();
+            assert!(matches!(
+                exchange_token(&db, &request(&secret, None), None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_client", _))
+            ));
+            assert!(exchange_token(&db, &request(&rotated, None), None, ISSUER)
+                .await
+                .is_ok());
+
+            assert!(db
+                .set_oauth_client_disabled(&client_id, true)
+                .await
+                .unwrap());
+            assert!(matches!(
+                exchange_token(&db, &request(&rotated, None), None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_client", _))
+            ));
+        }
+    }
+
+    mod test_device_authorization {
+        use crate::device_authorization::{
+            decide_device_authorization, find_device_authorization, normalize_user_code,
+            start_device_authorization, DeviceAuthorizationRequest,
+        };
+        use crate::errors::custom_errors::CustomError;
+        use crate::jwt::validate_jwt;
+        use crate::oauth::{
+            exchange_token, register_client, ClientRegistration, ClientType, TokenRequest,
+            DEVICE_CODE_GRANT,
+        };
+
+        const ISSUER: &str = "https://id.example.com";
+
+        #[actix_web::test]
+        async fn test_device_code_flow() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            let client = register_client(
+                &db,
+                ClientRegistration {
+                    name: "CLI".to_string(),
+                    client_type: ClientType::Public,
+                    redirect_uris: Vec::new(),
+                    grant_types: Some(vec![DEVICE_CODE_GRANT.to_string()]),
+                    scopes: vec!["profile".to_string()],
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let client_id = client.client.client_id;
+            let request = DeviceAuthorizationRequest {
+                client_id: Some(client_id.clone()),
+                scope: Some("profile".to_string()),
+                ..DeviceAuthorizationRequest::default()
+            };
+            let poll = |device_code: &str| TokenRequest {
+                grant_type: DEVICE_CODE_GRANT.to_string(),
+                device_code: Some(device_code.to_string()),
+                client_id: Some(client_id.clone()),
+                ..TokenRequest::default()
+            };
+
+            let mut unknown = request.clone();
+            unknown.scope = Some("profile email".to_string());
+            assert!(matches!(
+                start_device_authorization(&db, &unknown, None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_scope", _))
+            ));
+            let device = start_device_authorization(&db, &request, None, ISSUER)
+                .await
+                .unwrap();
+            assert_eq!(device.user_code.len(), 9);
+            assert_eq!(
+                device.verification_uri,
+                "https://id.example.com/oauth/device"
+            );
+            assert!(device
+                .verification_uri_complete
+                .ends_with(&format!("?user_code={}", device.user_code)));
+
+            // The device has to wait for the user and must not poll too often.
+            assert!(matches!(
+                exchange_token(&db, &poll(&device.device_code), None, ISSUER).await,
+                Err(CustomError::OAuthError("authorization_pending", _))
+            ));
+            assert!(matches!(
+                exchange_token(&db, &poll(&device.device_code), None, ISSUER).await,
+                Err(CustomError::OAuthError("slow_down", _))
+            ));
+
+            // Users may type the code in lowercase and without the separator.
+            let typed = device.user_code.replace('-', " ").to_lowercase();
+            assert_eq!(
+                normalize_user_code(&typed),
+                device.user_code.replace('-', "")
+            );
+            let (authorization, found) = find_device_authorization(&db, &typed).await.unwrap();
+            assert_eq!(found.client_id, client_id);
+            assert_eq!(authorization.scope.as_deref(), Some("profile"));
+            decide_device_authorization(&db, &typed, &user_id, &["pwd".to_string()], true)
+                .await
+                .unwrap();
+            assert!(matches!(
+                find_device_authorization(&db, &typed).await,
+                Err(CustomError::InvalidUserCode)
+            ));
+
+            // Another client presenting the device code neither gets tokens nor uses them up.
+            let other = register_client(
+                &db,
+                ClientRegistration {
+                    name: "Other CLI".to_string(),
+                    client_type: ClientType::Public,
+                    redirect_uris: Vec::new(),
+                    grant_types: Some(vec![DEVICE_CODE_GRANT.to_string()]),
+                    scopes: Vec::new(),
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let mut stolen = poll(&device.device_code);
+            stolen.client_id = Some(other.client.client_id);
+            assert!(matches!(
+                exchange_token(&db, &stolen, None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_grant", _))
+            ));
+
+            let tokens = exchange_token(&db, &poll(&device.device_code), None, ISSUER)
+                .await
+                .unwrap();
+            assert_eq!(tokens.scope.as_deref(), Some("profile"));
+            assert!(tokens.refresh_token.is_some());
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.sub, user_id);
+            assert_eq!(claims.amr, vec!["pwd".to_string()]);
+            assert!(matches!(
+                exchange_token(&db, &poll(&device.device_code), None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_grant", _))
+            ));
+
+            let denied = start_device_authorization(&db, &request, None, ISSUER)
+                .await
+                .unwrap();
+            decide_device_authorization(&db, &denied.user_code, &user_id, &[], false)
+                .await
+                .unwrap();
+            assert!(matches!(
+                exchange_token(&db, &poll(&denied.device_code), None, ISSUER).await,
+                Err(CustomError::OAuthError("access_denied", _))
+            ));
+        }
+    }
+
+    mod test_introspection {
+        use crate::errors::custom_errors::CustomError;
+        use crate::introspection::{introspect_token, revoke_token, IntrospectionRequest};
+        use crate::oauth::{
+            exchange_token, register_client, ClientRegistration, ClientType, RegisteredClient,
+            TokenRequest,
+        };
+        use crate::tokens::{issue_client_token_pair, ClientGrant};
+
+        async fn service_client(db: &crate::database::Database) -> RegisteredClient {
+            register_client(
+                db,
+                ClientRegistration {
+                    name: "Gateway".to_string(),
+                    client_type: ClientType::Confidential,
+                    redirect_uris: Vec::new(),
+                    grant_types: Some(vec!["client_credentials".to_string()]),
+                    scopes: vec!["orders:read".to_string()],
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap()
+        }
+
+        fn request(token: &str) -> IntrospectionRequest {
+            IntrospectionRequest {
+                token: token.to_string(),
+                ..IntrospectionRequest::default()
+            }
+        }
+
+        #[actix_web::test]
+        async fn test_introspect_and_revoke() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            let gateway = service_client(&db).await;
+            let token_request = TokenRequest {
+                grant_type: "client_credentials".to_string(),
+                client_id: Some(gateway.client.client_id.clone()),
+                client_secret: gateway.client_secret.clone(),
+                ..TokenRequest::default()
+            };
+            let access_token = exchange_token(&db, &token_request, None, "https://id.example.com")
+                .await
+                .unwrap()
+                .access_token;
+
+            let response = introspect_token(&db, &gateway.client, &request(&access_token))
+                .await
+                .unwrap();
+            assert!(response.active);
+            assert_eq!(
+                response.sub.as_deref(),
+                Some(gateway.client.client_id.as_str())
+            );
+            assert_eq!(response.scope.as_deref(), Some("orders:read"));
+            assert_eq!(response.token_type.as_deref(), Some("access_token"));
+            assert!(
+                !introspect_token(&db, &gateway.client, &request("garbage"))
+                    .await
+                    .unwrap()
+                    .active
+            );
+
+            // Refresh tokens can only be revoked by the client they were issued to.
+            let grant = ClientGrant::new(&gateway.client, Some("profile".to_string()), None);
+            let tokens = issue_client_token_pair(&db, &user_id, "family", &[], &grant)
+                .await
+                .unwrap();
+            let response = introspect_token(&db, &gateway.client, &request(&tokens.refresh_token))
+                .await
+                .unwrap();
+            assert!(response.active);
+            assert_eq!(response.sub.as_deref(), Some(user_id.as_str()));
+            assert_eq!(response.username.as_deref(), Some("user"));
+            assert_eq!(response.token_type.as_deref(), Some("refresh_token"));
+            let other = service_client(&db).await;
+            assert!(matches!(
+                revoke_token(&db, &other.client, &request(&tokens.refresh_token)).await,
+                Err(CustomError::OAuthError("unauthorized_client", _))
+            ));
+            revoke_token(&db, &gateway.client, &request(&tokens.refresh_token))
+                .await
+                .unwrap();
+            assert!(
+                !introspect_token(&db, &gateway.client, &request(&tokens.refresh_token))
+                    .await
+                    .unwrap()
+                    .active
+            );
+
+            revoke_token(&db, &gateway.client, &request(&access_token))
+                .await
+                .unwrap();
+            assert!(
+                !introspect_token(&db, &gateway.client, &request(&access_token))
+                    .await
+                    .unwrap()
+                    .active
+            );
+            revoke_token(&db, &gateway.client, &request("garbage"))
+                .await
+                .unwrap();
+        }
+    }
+
+    mod test_token_exchange {
+        use crate::dpop::Confirmation;
+        use crate::errors::custom_errors::CustomError;
+        use crate::introspection::{introspect_token, revoke_token, IntrospectionRequest};
+        use crate::jwt::{encode_jwt, validate_jwt};
+        use crate::oauth::{
+            exchange_token, register_client, ClientRegistration, ClientType, TokenRequest,
+            TOKEN_EXCHANGE_GRANT,
+        };
+        use crate::policy::{save_policy, PolicyDocument};
+        use crate::token_exchange::ACCESS_TOKEN_TYPE;
+        use crate::tokens::issue_token_pair;
+        use serde_json::json;
+
+        const ISSUER: &str = "https://id.example.com";
+
+        #[actix_web::test]
+        async fn test_delegation_and_downscoping() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            let client = register_client(
+                &db,
+                ClientRegistration {
+                    name: "Orders".to_string(),
+                    client_type: ClientType::Confidential,
+                    redirect_uris: Vec::new(),
+                    grant_types: Some(vec![TOKEN_EXCHANGE_GRANT.to_string()]),
+                    scopes: Vec::new(),
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let client_id = client.client.client_id.clone();
+            let exchange = |subject_token: &str, audience: &str, scope: &str| TokenRequest {
+                grant_type: TOKEN_EXCHANGE_GRANT.to_string(),
+                subject_token: Some(subject_token.to_string()),
+                subject_token_type: Some(ACCESS_TOKEN_TYPE.to_string()),
+                audience: Some(audience.to_string()),
+                scope: Some(scope.to_string()),
+                client_id: Some(client_id.clone()),
+                client_secret: client.client_secret.clone(),
+                ..TokenRequest::default()
+            };
+            let user_token = issue_token_pair(&db, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap()
+                .access_token;
+
+            // Exchanges are denied unless a policy allows them.
+            let request = exchange(&user_token, "billing", "invoices:read invoices:write");
+            assert!(matches!(
+                exchange_token(&db, &request, None, ISSUER).await,
+                Err(CustomError::OAuthError("invalid_target", _))
+            ));
+            let document: PolicyDocument = serde_json::from_value(json!({
+                "rules": [{
+                    "id": "orders-to-billing",
+                    "effect": "allow",
+                    "actions": ["token:exchange"],
+                    "resources": ["audience"],
+                    "condition": {"all": [
+                        {"eq": [{"attr": "subject.id"}, client_id]},
+                        {"eq": [{"attr": "resource.id"}, "billing"]}
+                    ]}
+                }]
+            }))
+            .unwrap();
+            save_policy(&db, "token-exchange", &document, "admin")
+                .await
+                .unwrap();
+
+            let response = exchange_token(&db, &request, None, ISSUER).await.unwrap();
+            assert_eq!(
+                response.issued_token_type.as_deref(),
+                Some(ACCESS_TOKEN_TYPE)
+            );
+            assert!(response.refresh_token.is_none());
+            let claims = validate_jwt(&response.access_token).unwrap();
+            assert_eq!(claims.sub, user_id);
+            assert_eq!(claims.aud.as_deref(), Some("billing"));
+            assert_eq!(
+                claims.scope.as_deref(),
+                Some("invoices:read invoices:write")
+            );
+            assert_eq!(claims.act.as_ref().unwrap().sub, client_id);
+            assert!(claims.roles.is_empty());
+
+            // Exchanged tokens can only be narrowed further.
+            let narrowed = &response.access_token;
+            assert!(matches!(
+                exchange_token(
+                    &db,
+                    &exchange(narrowed, "billing", "invoices:delete"),
+                    None,
+                    ISSUER
+                )
+                .await,
+                Err(CustomError::OAuthError("invalid_scope", _))
+            ));
+            assert!(matches!(
+                exchange_token(
+                    &db,
+                    &exchange(narrowed, "payroll", "invoices:read"),
+                    None,
+                    ISSUER
+                )
+                .await,
+                Err(CustomError::OAuthError("invalid_target", _))
+            ));
+            let response = exchange_token(
+                &db,
+                &exchange(narrowed, "billing", "invoices:read"),
+                None,
+                ISSUER,
+            )
+            .await
+            .unwrap();
+            let claims = validate_jwt(&response.access_token).unwrap();
+            assert_eq!(claims.scope.as_deref(), Some("invoices:read"));
+            let actor = claims.act.unwrap();
+            assert_eq!(actor.act.unwrap().sub, client_id);
+
+            // Policies see whom the subject token was issued to.
+            let document: PolicyDocument = serde_json::from_value(json!({
+                "rules": [{
+                    "id": "own-tokens-to-reports",
+                    "effect": "allow",
+                    "actions": ["token:exchange"],
+                    "resources": ["audience"],
+                    "condition": {"all": [
+                        {"eq": [{"attr": "resource.subject_client"}, client_id]},
+                        {"eq": [{"attr": "resource.subject_aud"}, "billing"]},
+                        {"eq": [{"attr": "resource.id"}, "reports"]}
+                    ]}
+                }]
+            }))
+            .unwrap();
+            save_policy(&db, "own-tokens", &document, "admin")
+                .await
+                .unwrap();
+            assert!(matches!(
+                exchange_token(
+                    &db,
+                    &exchange(&user_token, "reports", "invoices:read"),
+                    None,
+                    ISSUER
+                )
+                .await,
+                Err(CustomError::OAuthError("invalid_target", _))
+            ));
+            assert!(exchange_token(
+                &db,
+                &exchange(narrowed, "reports", "invoices:read"),
+                None,
+                ISSUER
+            )
+            .await
+            .is_ok());
+
+            // Bound tokens can only be exchanged with a proof of their key.
+            let mut bound = validate_jwt(&user_token).unwrap();
+            bound.cnf = Some(Confirmation {
+                jkt: "key".to_string(),
+            });
+            let bound = encode_jwt(&bound).unwrap();
+            let mut request = exchange(&bound, "billing", "invoices:read");
+            for jkt in [None, Some("other")] {
+                request.dpop_jkt = jkt.map(str::to_string);
+                assert!(matches!(
+                    exchange_token(&db, &request, None, ISSUER).await,
+                    Err(CustomError::OAuthError("invalid_grant", _))
+                ));
+            }
+            request.dpop_jkt = Some("key".to_string());
+            let response = exchange_token(&db, &request, None, ISSUER).await.unwrap();
+            let claims = validate_jwt(&response.access_token).unwrap();
+            assert_eq!(claims.cnf.map(|cnf| cnf.jkt).as_deref(), Some("key"));
+
+            // Exchanged tokens can be introspected and revoked although their audience differs
+            // from the configured one.
+            let exchanged = exchange_token(
+                &db,
+                &exchange(&user_token, "billing", "invoices:read"),
+                None,
+                ISSUER,
+            )
+            .await
+            .unwrap()
+            .access_token;
+            let introspection = |token: &str| IntrospectionRequest {
+                token: token.to_string(),
+                ..IntrospectionRequest::default()
+            };
+            std::env::set_var("JWT_AUDIENCE", "iam");
+            let active = introspect_token(&db, &client.client, &introspection(&exchanged)).await;
+            let revoked = revoke_token(&db, &client.client, &introspection(&exchanged)).await;
+            let inactive = introspect_token(&db, &client.client, &introspection(&exchanged)).await;
+            std::env::remove_var("JWT_AUDIENCE");
+            let active = active.unwrap();
+            assert!(active.active);
+            assert_eq!(active.aud.as_deref(), Some("billing"));
+            revoked.unwrap();
+            assert!(!inactive.unwrap().active);
+        }
+    }
+
+    mod test_dpop {
+        use crate::database::Database;
+        use crate::dpop::{access_token_hash, jwk_thumbprint, verify_proof, DPOP};
+        use crate::errors::custom_errors::CustomError;
+        use crate::introspection::{introspect_token, IntrospectionRequest};
+        use crate::jwt::{validate_jwt, SigningKey};
+        use crate::keyring::generate_key_material;
+        use crate::oauth::{
+            exchange_token, register_client, ClientRegistration, ClientType, TokenRequest,
+        };
+        use crate::tokens::{issue_client_token_pair, rotate_client_refresh_token, ClientGrant};
+        use actix_web::http::{header, StatusCode};
+        use actix_web::test::TestRequest;
+        use chrono::Utc;
+        use jsonwebtoken::jwk::Jwk;
+        use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+        use serde_json::json;
+        use uuid::Uuid;
+
+        const ISSUER: &str = "https://id.example.com";
+        const TOKEN_URL: &str = "https://id.example.com/oauth/token";
+        const RESOURCE_URL: &str = "http://localhost:8080/userinfo";
+
+        struct ProofKey {
+            jwk: Jwk,
+            encoding_key: EncodingKey,
+        }
+
+        impl ProofKey {
+            fn generate() -> Self {
+                let material = generate_key_material(Algorithm::ES256).unwrap();
+                let key = SigningKey::from_pem(
+                    &material.kid,
+                    Algorithm::ES256,
+                    &material.private_key,
+                    &material.public_key,
+                )
+                .unwrap();
+                ProofKey {
+                    jwk: key.public_jwk().unwrap().clone(),
+                    encoding_key: EncodingKey::from_ec_pem(&material.private_key).unwrap(),
+                }
+            }
+
+            fn proof(&self, method: &str, url: &str, access_token: Option<&str>) -> String {
+                let mut header = Header::new(Algorithm::ES256);
+                header.typ = Some("dpop+jwt".to_string());
+                header.jwk = Some(self.jwk.clone());
+                let claims = json!({
+                    "jti": Uuid::new_v4().to_string(),
+                    "htm": method,
+                    "htu": url,
+                    "iat": Utc::now().timestamp(),
+                    "ath": access_token.map(access_token_hash),
+                });
+                encode(&header, &claims, &self.encoding_key).unwrap()
+            }
+        }
+
+        async fn call_with_token(
+            db: &Database,
+            scheme: &str,
+            token: &str,
+            proof: Option<String>,
+        ) -> StatusCode {
+            let mut req = TestRequest::get()
+                .uri("/userinfo")
+                .insert_header((header::AUTHORIZATION, format!("{} {}", scheme, token)));
+            if let Some(proof) = proof {
+                req = req.insert_header((DPOP, proof));
+            }
+            crate::tests::tests::call_authenticated(db, req, None).await
+        }
+
+        #[actix_web::test]
+        async fn test_sender_constrained_tokens() {
+            let db = crate::tests::tests::setup_database().await;
+            let key = ProofKey::generate();
+            let jkt = jwk_thumbprint(&key.jwk).unwrap();
+
+            // Proofs are bound to the request and can only be used once.
+            let proof = key.proof("POST", TOKEN_URL, None);
+            assert!(matches!(
+                verify_proof(&db, &proof, "GET", TOKEN_URL, None).await,
+                Err(CustomError::InvalidDpopProof(_))
+            ));
+            assert!(matches!(
+                verify_proof(&db, &proof, "POST", ISSUER, None).await,
+                Err(CustomError::InvalidDpopProof(_))
+            ));
+            assert_eq!(
+                verify_proof(&db, &proof, "POST", TOKEN_URL, None)
+                    .await
+                    .unwrap(),
+                jkt
+            );
+            assert!(matches!(
+                verify_proof(&db, &proof, "POST", TOKEN_URL, None).await,
+                Err(CustomError::InvalidDpopProof(_))
+            ));
+
+            let client = register_client(
+                &db,
+                ClientRegistration {
+                    name: "Billing job".to_string(),
+                    client_type: ClientType::Confidential,
+                    redirect_uris: Vec::new(),
+                    grant_types: Some(vec!["client_credentials".to_string()]),
+                    scopes: Vec::new(),
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let request = TokenRequest {
+                grant_type: "client_credentials".to_string(),
+                client_id: Some(client.client.client_id.clone()),
+                client_secret: client.client_secret.clone(),
+                dpop_jkt: Some(jkt.clone()),
+                ..TokenRequest::default()
+            };
+            let tokens = exchange_token(&db, &request, None, ISSUER).await.unwrap();
+            assert_eq!(tokens.token_type, "DPoP");
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.cnf.map(|cnf| cnf.jkt), Some(jkt.clone()));
+            let introspected = introspect_token(
+                &db,
+                &client.client,
+                &IntrospectionRequest {
+                    token: tokens.access_token.clone(),
+                    ..IntrospectionRequest::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(introspected.token_type.as_deref(), Some("DPoP"));
+            assert_eq!(introspected.cnf.map(|cnf| cnf.jkt), Some(jkt.clone()));
+
+            // Bound tokens need the DPoP scheme and a proof of the same key for the same token.
+            let token = tokens.access_token.as_str();
+            let proof = |key: &ProofKey, token| Some(key.proof("GET", RESOURCE_URL, Some(token)));
+            assert_eq!(
+                call_with_token(&db, "DPoP", token, proof(&key, token)).await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                call_with_token(&db, "Bearer", token, proof(&key, token)).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                call_with_token(&db, "DPoP", token, None).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                call_with_token(&db, "DPoP", token, proof(&ProofKey::generate(), token)).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                call_with_token(&db, "DPoP", token, proof(&key, "another token")).await,
+                StatusCode::UNAUTHORIZED
+            );
+
+            // Bound refresh tokens can only be used with the key they are bound to.
+            let grant = ClientGrant::new(&client.client, None, Some(jkt.clone()));
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            let pair = issue_client_token_pair(&db, &user_id, "family", &[], &grant)
+                .await
+                .unwrap();
+            assert!(matches!(
+                rotate_client_refresh_token(&db, &pair.refresh_token, Some(&client.client), None)
+                    .await,
+                Err(CustomError::InvalidRefreshToken)
+            ));
+            let rotated = rotate_client_refresh_token(
+                &db,
+                &pair.refresh_token,
+                Some(&client.client),
+                Some(&jkt),
+            )
+            .await
+            .unwrap();
+            assert_eq!(rotated.token_type, "DPoP");
+        }
+    }
+
+    mod test_client_registration {
+        use crate::client_registration::{
+            issue_registration_token, register_dynamic_client, ClientMetadata,
+        };
+        use crate::errors::custom_errors::CustomError;
+        use crate::jwt::validate_jwt;
+        use crate::oauth::{
+            exchange_token, register_client, update_client, ClientRegistration, ClientType,
+            TokenRequest,
+        };
+
+        const ISSUER: &str = "https://id.example.com";
+
+        #[actix_web::test]
+        async fn test_dynamic_registration() {
+            let db = crate::tests::tests::setup_database().await;
+            let metadata = |redirect_uri: &str| ClientMetadata {
+                redirect_uris: vec![redirect_uri.to_string()],
+                token_endpoint_auth_method: Some("none".to_string()),
+                client_name: Some("Mobile app".to_string()),
+                logo_uri: Some("https://app.example.com/logo.png".to_string()),
+                ..ClientMetadata::default()
+            };
+
+            assert!(matches!(
+                register_dynamic_client(&db, None, metadata("com.example.app:/callback")).await,
+                Err(CustomError::OAuthError("invalid_token", _))
+            ));
+            let token = issue_registration_token(&db, "admin", 1).await.unwrap();
+
+            // Invalid metadata doesn't use up the token.
+            assert!(matches!(
+                register_dynamic_client(
+                    &db,
+                    Some(&token.token),
+                    metadata("http://app.example.com")
+                )
+                .await,
+                Err(CustomError::OAuthError("invalid_redirect_uri", _))
+            ));
+            let mut unsupported = metadata("com.example.app:/callback");
+            unsupported.grant_types = Some(vec!["password".to_string()]);
+            assert!(matches!(
+                register_dynamic_client(&db, Some(&token.token), unsupported).await,
+                Err(CustomError::OAuthError("invalid_client_metadata", _))
+            ));
+            let client = register_dynamic_client(
+                &db,
+                Some(&token.token),
+                metadata("com.example.app:/callback"),
+            )
+            .await
+            .unwrap();
+            assert!(client.client_secret.is_none());
+            assert_eq!(client.token_endpoint_auth_method, "none");
+            let stored = db
+                .get_oauth_client(&client.client_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.client_type, "public");
+            assert_eq!(stored.logo_uri, client.logo_uri);
+            assert!(matches!(
+                register_dynamic_client(&db, Some(&token.token), metadata("com.example.app:/cb"))
+                    .await,
+                Err(CustomError::OAuthError("invalid_token", _))
+            ));
+        }
+
+        #[actix_web::test]
+        async fn test_client_update_and_token_lifetimes() {
+            let db = crate::tests::tests::setup_database().await;
+            let registration = |client_type, access_token_lifetime| ClientRegistration {
+                name: "Billing job".to_string(),
+                client_type,
+                redirect_uris: Vec::new(),
+                grant_types: Some(vec!["client_credentials".to_string()]),
+                scopes: Vec::new(),
+                logo_uri: None,
+                access_token_lifetime,
+                refresh_token_lifetime: None,
+                first_party: false,
+            };
+            let client = register_client(&db, registration(ClientType::Confidential, None))
+                .await
+                .unwrap();
+            let client_id = client.client.client_id.clone();
+
+            assert!(matches!(
+                update_client(&db, &client_id, registration(ClientType::Public, None)).await,
+                Err(CustomError::InvalidClient(_))
+            ));
+            assert!(matches!(
+                update_client(
+                    &db,
+                    &client_id,
+                    registration(ClientType::Confidential, Some(0))
+                )
+                .await,
+                Err(CustomError::InvalidClient(_))
+            ));
+            assert!(matches!(
+                update_client(&db, "unknown", registration(ClientType::Confidential, None)).await,
+                Err(CustomError::ClientNotFound)
+            ));
+            let updated = update_client(
+                &db,
+                &client_id,
+                registration(ClientType::Confidential, Some(60)),
+            )
+            .await
+            .unwrap();
+            assert_eq!(updated.secret_hash, client.client.secret_hash);
+
+            let request = TokenRequest {
+                grant_type: "client_credentials".to_string(),
+                client_id: Some(client_id),
+                client_secret: client.client_secret,
+                ..TokenRequest::default()
+            };
+            let tokens = exchange_token(&db, &request, None, ISSUER).await.unwrap();
+            assert_eq!(tokens.expires_in, 60);
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.exp - claims.iat, 60);
+        }
+    }
+
+    mod test_consent {
+        use crate::consent::{
+            decide_consent, find_consent_request, has_consent, render_consent_page,
+            request_consent, revoke_consent,
+        };
+        use crate::errors::custom_errors::CustomError;
+        use crate::hashing::hash_token;
+        use crate::oauth::{
+            code_challenge, exchange_token, register_client, validate_authorization_request,
+            AuthorizationParams, ClientRegistration, ClientType, TokenRequest,
+        };
+
+        const VERIFIER: &str = "dBjftJeZ4CVP-mJ0kzyDMA7QZ-E-qhkbFzqk-3NPQ-verifier";
+        const ISSUER: &str = "https://id.example.com";
+
+        #[actix_web::test]
+        async fn test_consent_flow() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            let registration = |name: &str, first_party| ClientRegistration {
+                name: name.to_string(),
+                client_type: ClientType::Public,
+                redirect_uris: vec!["com.example.app:/callback".to_string()],
+                grant_types: None,
+                scopes: ["openid", "profile", "email"]
+                    .iter()
+                    .map(|scope| scope.to_string())
+                    .collect(),
+                logo_uri: None,
+                access_token_lifetime: None,
+                refresh_token_lifetime: None,
+                first_party,
+            };
+            let client = register_client(&db, registration("<Partner> app", false))
+                .await
+                .unwrap()
+                .client;
+            let own = register_client(&db, registration("Own app", true))
+                .await
+                .unwrap()
+                .client;
+            let params = |scope: &str| AuthorizationParams {
+                response_type: Some("code".to_string()),
+                client_id: Some(client.client_id.clone()),
+                scope: Some(scope.to_string()),
+                state: Some("xyz".to_string()),
+                code_challenge: Some(code_challenge(VERIFIER)),
+                code_challenge_method: Some("S256".to_string()),
+                ..AuthorizationParams::default()
+            };
+            let authorization = validate_authorization_request(&db, &params("openid email"))
+                .await
+                .unwrap();
+            let pwd = ["pwd".to_string()];
+
+            assert!(has_consent(&db, &own, &user_id, Some("openid email"))
+                .await
+                .unwrap());
+            assert!(!has_consent(&db, &client, &user_id, Some("openid email"))
+                .await
+                .unwrap());
+
+            // Denying sends the error to the client and uses up the ticket.
+            let ticket = request_consent(&db, &authorization, &user_id, &pwd)
+                .await
+                .unwrap();
+            let (request, _) = find_consent_request(&db, &ticket).await.unwrap();
+            let page = render_consent_page(&client, request.scope.as_deref(), &ticket);
+            assert!(page.contains("&lt;Partner&gt; app"));
+            assert!(page.contains("Your email address"));
+            let url = decide_consent(&db, &ticket, false).await.unwrap();
+            assert_eq!(
+                url,
+                "com.example.app:/callback?error=access_denied&error_description=The%20user%20denied%20the%20request&state=xyz"
+            );
+            assert!(matches!(
+                decide_consent(&db, &ticket, true).await,
+                Err(CustomError::InvalidConsentRequest)
+            ));
+
+            let ticket = request_consent(&db, &authorization, &user_id, &pwd)
+                .await
+                .unwrap();
+            let url = decide_consent(&db, &ticket, true).await.unwrap();
+            let code = url
+                .split_once("code=")
+                .unwrap()
+                .1
+                .split('&')
+                .next()
+                .unwrap();
+            assert!(has_consent(&db, &client, &user_id, Some("email"))
+                .await
+                .unwrap());
+            assert!(!has_consent(&db, &client, &user_id, Some("email profile"))
+                .await
+                .unwrap());
+
+            let request = TokenRequest {
+                grant_type: "authorization_code".to_string(),
+                code: Some(code.to_string()),
+                code_verifier: Some(VERIFIER.to_string()),
+                client_id: Some(client.client_id.clone()),
+                ..TokenRequest::default()
+            };
+            let tokens = exchange_token(&db, &request, None, ISSUER).await.unwrap();
+            let refresh_token = tokens.refresh_token.unwrap();
+
+            // Revoking the consent revokes the client's refresh tokens.
+            assert!(revoke_consent(&db, &user_id, &client.client_id)
+                .await
+                .unwrap());
+            assert!(!revoke_consent(&db, &user_id, &client.client_id)
+                .await
+                .unwrap());
+            let stored = db
+                .find_refresh_token(&hash_token(&refresh_token))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(stored.revoked);
+            assert!(!has_consent(&db, &client, &user_id, Some("email"))
+                .await
+                .unwrap());
+        }
+    }
+
+    mod test_federation {
+        use crate::database::ClaimMapping;
+        use crate::errors::custom_errors::CustomError;
+        use crate::federation::{
+            complete_login, create_provider, start_login, update_provider, ProviderRegistration,
+        };
+        use crate::jwt::{validate_jwt, SigningKey};
+        use crate::keyring::generate_key_material;
+        use crate::mfa::{
+            begin_totp_enrollment, complete_first_factor, complete_mfa_challenge,
+            confirm_totp_enrollment, FirstFactorOutcome,
+        };
+        use crate::oauth::code_challenge;
+        use crate::totp::generate_code;
+        use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+        use base64::{engine::general_purpose, Engine as base64Engine};
+        use chrono::Utc;
+        use jsonwebtoken::jwk::JwkSet;
+        use jsonwebtoken::Algorithm;
+        use serde_json::{json, Value};
+        use std::collections::HashMap;
+        use std::net::TcpListener;
+        use std::sync::Mutex;
+
+        const CLIENT_ID: &str = "iam";
+        const CLIENT_SECRET: &str = "provider-secret";
+        const REDIRECT_URI: &str = "https://id.example.com/federation/corp/callback";
+
+        /// A local OpenID Connect provider that issues ID tokens for the claims registered
+        /// with each authorization code.
+        struct MockProvider {
+            issuer: String,
+            key: SigningKey,
+            codes: Mutex<HashMap<String, (String, Value)>>,
+        }
+
+        async fn discovery(provider: web::Data<MockProvider>) -> HttpResponse {
+            HttpResponse::Ok().json(json!({
+                "issuer": provider.issuer,
+                "authorization_endpoint": format!("{}/authorize", provider.issuer),
+                "token_endpoint": format!("{}/token", provider.issuer),
+                "jwks_uri": format!("{}/jwks", provider.issuer),
+            }))
+        }
+
+        async fn jwks(provider: web::Data<MockProvider>) -> HttpResponse {
+            HttpResponse::Ok().json(JwkSet {
+                keys: vec![provider.key.public_jwk().unwrap().clone()],
+            })
+        }
+
+        async fn token(
+            http_req: HttpRequest,
+            form: web::Form<HashMap<String, String>>,
+            provider: web::Data<MockProvider>,
+        ) -> HttpResponse {
+            let credentials = general_purpose::STANDARD
+                .encode(format!("{}:{}", CLIENT_ID, CLIENT_SECRET).as_bytes());
+            let authorization = http_req.headers().get("Authorization").unwrap();
+            if authorization.to_str().unwrap() != format!("Basic {}", credentials) {
+                return HttpResponse::Unauthorized().json(json!({"error": "invalid_client"}));
+            }
+            let entry = provider.codes.lock().unwrap().remove(&form["code"]);
+            match entry {
+                Some((challenge, claims))
+                    if code_challenge(&form["code_verifier"]) == challenge
+                        && form["redirect_uri"] == REDIRECT_URI =>
+                {
+                    HttpResponse::Ok().json(json!({
+                        "access_token": "upstream",
+                        "token_type": "Bearer",
+                        "id_token": provider.key.sign(&claims).unwrap(),
+                    }))
+                }
+                _ => HttpResponse::BadRequest().json(json!({"error": "invalid_grant"})),
+            }
+        }
+
+        fn start_mock_provider() -> web::Data<MockProvider> {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let material = generate_key_material(Algorithm::ES256).unwrap();
+            let provider = web::Data::new(MockProvider {
+                issuer: format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port()),
+                key: material.signing_key().unwrap(),
+                codes: Mutex::new(HashMap::new()),
+            });
+            let data = provider.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(data.clone())
+                    .route(
+                        "/.well-known/openid-configuration",
+                        web::get().to(discovery),
+                    )
+                    .route("/jwks", web::get().to(jwks))
+                    .route("/token", web::post().to(token))
+            })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run();
+            actix_web::rt::spawn(server);
+            provider
+        }
+
+        /// Logs the user in at the mock provider and returns the code and state of the
+        /// callback.
+        fn authorize(provider: &MockProvider, url: &str, mut claims: Value) -> (String, String) {
+            let param = |name: &str| {
+                url.split_once('?')
+                    .unwrap()
+                    .1
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix(&format!("{}=", name)))
+                    .unwrap()
+                    .to_string()
+            };
+            let now = Utc::now().timestamp();
+            claims["iss"] = json!(provider.issuer);
+            claims["aud"] = json!(CLIENT_ID);
+            claims["iat"] = json!(now);
+            claims["exp"] = json!(now + 300);
+            if claims.get("nonce").is_none() {
+                claims["nonce"] = json!(param("nonce"));
+            }
+            let code = uuid::Uuid::new_v4().to_string();
+            provider
+                .codes
+                .lock()
+                .unwrap()
+                .insert(code.clone(), (param("code_challenge"), claims));
+            (code, param("state"))
+        }
+
+        fn registration(issuer: &str) -> ProviderRegistration {
+            ProviderRegistration {
+                name: "Corp".to_string(),
+                issuer: issuer.to_string(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: Some(CLIENT_SECRET.to_string()),
+                scopes: None,
+                claim_mapping: ClaimMapping {
+                    username: "upn".to_string(),
+                    ..ClaimMapping::default()
+                },
+                provision_users: None,
+                disabled: false,
+            }
+        }
+
+        #[actix_web::test]
+        async fn test_federated_login() {
+            let db = crate::tests::tests::setup_database().await;
+            let provider = start_mock_provider();
+            assert!(matches!(
+                create_provider(&db, "corp", registration("http://idp.example.com")).await,
+                Err(CustomError::InvalidIdentityProvider(_))
+            ));
+            create_provider(&db, "corp", registration(&provider.issuer))
+                .await
+                .unwrap();
+            let alice = json!({
+                "sub": "alice-1",
+                "email": "Alice@Corp.example",
+                "email_verified": true,
+                "given_name": "Alice",
+                "upn": "alice",
+            });
+
+            // The first login provisions a user.
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            assert!(url.starts_with(&format!("{}/authorize?", provider.issuer)));
+            assert!(url.contains("&scope=openid%20email%20profile&"));
+            let (code, state) = authorize(&provider, &url, alice.clone());
+            let user = complete_login(&db, "corp", &code, &state).await.unwrap();
+            assert_eq!(user.email, "alice@corp.example");
+            assert_eq!(user.username, "alice");
+            assert!(user.email_verified);
+            assert!(matches!(
+                complete_login(&db, "corp", &code, &state).await,
+                Err(CustomError::FederationError(_))
+            ));
+
+            // Later logins find the user by the link, even if the address changed.
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let mut renamed = alice.clone();
+            renamed["email"] = json!("alice.smith@corp.example");
+            let (code, state) = authorize(&provider, &url, renamed);
+            let again = complete_login(&db, "corp", &code, &state).await.unwrap();
+            assert_eq!(again.id, user.id);
+
+            // An ID token for another login is rejected.
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let mut replayed = alice.clone();
+            replayed["nonce"] = json!("other");
+            let (code, state) = authorize(&provider, &url, replayed);
+            assert!(matches!(
+                complete_login(&db, "corp", &code, &state).await,
+                Err(CustomError::FederationError(_))
+            ));
+        }
+
+        #[actix_web::test]
+        async fn test_federated_account_linking() {
+            let db = crate::tests::tests::setup_database().await;
+            let provider = start_mock_provider();
+            create_provider(&db, "corp", registration(&provider.issuer))
+                .await
+                .unwrap();
+            let user_id = crate::tests::tests::register_test_user(&db, "bob@corp.example").await;
+            let bob = |email_verified: bool| json!({"sub": "bob-1", "email": "bob@corp.example", "email_verified": email_verified});
+
+            // Addresses are only linked if both the provider and the local user verified them.
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let (code, state) = authorize(&provider, &url, bob(true));
+            assert!(matches!(
+                complete_login(&db, "corp", &code, &state).await,
+                Err(CustomError::FederationError(_))
+            ));
+            db.mark_email_verified(&user_id).await.unwrap();
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let (code, state) = authorize(&provider, &url, bob(false));
+            assert!(matches!(
+                complete_login(&db, "corp", &code, &state).await,
+                Err(CustomError::FederationError(_))
+            ));
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let (code, state) = authorize(&provider, &url, bob(true));
+            let linked = complete_login(&db, "corp", &code, &state).await.unwrap();
+            assert_eq!(linked.id.to_string(), user_id);
+
+            // Without provisioning, unknown users are rejected. The client secret is kept.
+            let mut settings = registration(&provider.issuer);
+            settings.client_secret = None;
+            settings.provision_users = Some(false);
+            update_provider(&db, "corp", settings).await.unwrap();
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let (code, state) = authorize(
+                &provider,
+                &url,
+                json!({"sub": "carol-1", "email": "carol@corp.example", "email_verified": true}),
+            );
+            assert!(matches!(
+                complete_login(&db, "corp", &code, &state).await,
+                Err(CustomError::FederationError(_))
+            ));
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let (code, state) = authorize(&provider, &url, bob(true));
+            let again = complete_login(&db, "corp", &code, &state).await.unwrap();
+            assert_eq!(again.id.to_string(), user_id);
+        }
+
+        #[actix_web::test]
+        async fn test_federated_login_requires_local_mfa() {
+            let db = crate::tests::tests::setup_database().await;
+            let provider = start_mock_provider();
+            create_provider(&db, "corp", registration(&provider.issuer))
+                .await
+                .unwrap();
+            let user_id = crate::tests::tests::register_test_user(&db, "dave@corp.example").await;
+            db.mark_email_verified(&user_id).await.unwrap();
+            let enrollment = begin_totp_enrollment(&db, &user_id).await.unwrap();
+            let now = Utc::now().timestamp() as u64;
+            let code = generate_code(&enrollment.secret, now).unwrap();
+            confirm_totp_enrollment(&db, &user_id, &code).await.unwrap();
+
+            // Logging in through the provider still asks for the local second factor.
+            let url = start_login(&db, "corp", REDIRECT_URI).await.unwrap();
+            let (code, state) = authorize(
+                &provider,
+                &url,
+                json!({"sub": "dave-1", "email": "dave@corp.example", "email_verified": true}),
+            );
+            let user = complete_login(&db, "corp", &code, &state).await.unwrap();
+            assert_eq!(user.id.to_string(), user_id);
+            let challenge = match complete_first_factor(&db, &user, &["fed".to_string()])
+                .await
+                .unwrap()
+            {
+                FirstFactorOutcome::MfaRequired { methods, challenge } => {
+                    assert_eq!(methods, vec!["totp".to_string()]);
+                    challenge
+                }
+                FirstFactorOutcome::Tokens(_) => panic!("second factor skipped"),
+            };
+            let next_code = generate_code(&enrollment.secret, now + 30).unwrap();
+            let tokens = complete_mfa_challenge(&db, &challenge.mfa_token, &next_code)
+                .await
+                .unwrap();
+            let claims = validate_jwt(&tokens.access_token).unwrap();
+            assert_eq!(claims.sub, user_id);
+            assert!(claims.amr.contains(&"fed".to_string()));
+            assert!(claims.amr.contains(&"otp".to_string()));
+            assert!(!claims.amr.contains(&"pwd".to_string()));
+        }
+    }
+
+    mod test_oidc {
+        use crate::jwt::validate_jwt;
+        use crate::oauth::{
+            code_challenge, exchange_token, issue_authorization_code, register_client,
+            validate_authorization_request, AuthorizationParams, ClientRegistration, ClientType,
+            TokenRequest,
+        };
+        use crate::oidc::{discovery_document, issuer, scope_claims};
+        use base64::{engine::general_purpose, Engine as base64Engine};
+
+        const VERIFIER: &str = "oidc-test-verifier-with-enough-characters-to-be-valid";
+
+        #[actix_web::test]
+        async fn test_id_token_and_claim_release() {
+            let db = crate::tests::tests::setup_database().await;
+            let user_id = crate::tests::tests::register_test_user(&db, "t@example.com").await;
+            let client = register_client(
+                &db,
+                ClientRegistration {
+                    name: "Mobile app".to_string(),
+                    client_type: ClientType::Public,
+                    redirect_uris: vec!["com.example.app:/callback".to_string()],
+                    grant_types: None,
+                    scopes: vec!["openid".to_string(), "email".to_string()],
+                    logo_uri: None,
+                    access_token_lifetime: None,
+                    refresh_token_lifetime: None,
+                    first_party: false,
+                },
+            )
+            .await
+            .unwrap();
+            let client_id = client.client.client_id;
+
+            let params = AuthorizationParams {
+                response_type: Some("code".to_string()),
+                client_id: Some(client_id.clone()),
+                scope: Some("openid email".to_string()),
+                code_challenge: Some(code_challenge(VERIFIER)),
+                code_challenge_method: Some("S256".to_string()),
+                nonce: Some("n-0S6_WzA2Mj".to_string()),
+                ..AuthorizationParams::default()
+            };
+            let authorization = validate_authorization_request(&db, &params).await.unwrap();
+            let url = issue_authorization_code(&db, &authorization, &user_id, &["pwd".to_string()])
+                .await
+                .unwrap();
+            let code = url.split_once("code=").unwrap().1.to_string();
+
+            let request = TokenRequest {
+                grant_type: "authorization_code".to_string(),
+                code: Some(code),
+                code_verifier: Some(VERIFIER.to_string()),
+                client_id: Some(client_id.clone()),
+                ..TokenRequest::default()
+            };
+            let tokens = exchange_token(&db, &request, None, "https://id.example.com")
+                .await
+                .unwrap();
+            let id_token = tokens.id_token.unwrap();
+
+            // ID tokens can't be used as access tokens.
+            assert!(validate_jwt(&id_token).is_err());
+
+            let payload = id_token.split('.').nth(1).unwrap();
+            let payload = general_purpose::URL_SAFE_NO_PAD.decode(payload).unwrap();
+            let claims: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+            assert_eq!(claims["iss"], "https://id.example.com");
+            assert_eq!(claims["sub"], user_id.as_str());
+            assert_eq!(claims["aud"], client_id.as_str());
+            assert_eq!(claims["nonce"], "n-0S6_WzA2Mj");
+            assert_eq!(claims["email"], "t@example.com");
+            assert_eq!(claims["email_verified"], false);
+            assert!(claims.get("name").is_none());
+
+            let user = db.get_user_by_id(&user_id).await.unwrap().unwrap();
+            let profile = scope_claims(&user, Some("openid profile")).unwrap();
+            assert_eq!(profile["name"], "First Last");
+            assert_eq!(profile["preferred_username"], "user");
+            assert!(profile.get("email").is_none());
+            assert!(scope_claims(&user, Some("openid")).unwrap().is_empty());
+        }
+
+        #[actix_web::test]
+        async fn test_discovery_document() {
+            let db = crate::tests::tests::setup_database().await;
+            crate::keyring::reload_keyring(&db).await.unwrap();
+            assert_eq!(
+                issuer("https://id.example.com/", "acme"),
+                "https://id.example.com/realms/acme"
+            );
+            let issuer = issuer("https://id.example.com", "default");
+            let document = discovery_document(&issuer, "default").unwrap();
+            assert_eq!(document["issuer"], "https://id.example.com");
+            assert_eq!(
+                document["token_endpoint"],
+                "https://id.example.com/oauth/token"
+            );
+            assert_eq!(document["code_challenge_methods_supported"][0], "S256");
+        }
+    }
 }
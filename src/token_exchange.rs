@@ -0,0 +1,195 @@
+//! src/token_exchange.rs
+//!
+//! This module implements the OAuth 2.0 token exchange grant (RFC 8693) for delegation. A
+//! service that calls another service on behalf of a user swaps the user's access token for a
+//! narrower one: its audience is the called service, its scopes are a subset of the original
+//! ones, and its `act` claim names the calling client.
+//!
+//! Which clients may exchange tokens for which audiences is controlled by the policy engine.
+//! An exchange is the action `token:exchange` by the subject `{"type": "client", "id": ...}` on
+//! the resource `{"type": "audience", "id": ...}`, and is denied unless a policy allows it.
+//!
+//! Besides its `id`, the resource describes the subject token: `user` is its subject, `scopes`
+//! are the requested scopes, `subject_client` is the client the token was issued to and
+//! `subject_aud` is its audience. Both are `null` for tokens from the user's own sessions, so a
+//! policy can e.g. only let clients exchange tokens that were issued to themselves.
+
+use crate::database::{Database, OAuthClient};
+use crate::dpop::Confirmation;
+use crate::errors::custom_errors::CustomError;
+use crate::jwt::{access_token_lifetime, encode_jwt, validate_tenant_jwt, Actor};
+use crate::oauth::{normalize_scope, oauth_error, TokenRequest, TokenResponse};
+use crate::policy::{self, environment_attributes, AuthorizationRequest};
+use crate::tokens::{is_access_token_revoked, token_type};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+/// The token type of access tokens.
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+/// The token type of JWTs, accepted for subject tokens since access tokens are JWTs.
+pub const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// The policy action of a token exchange.
+pub const EXCHANGE_ACTION: &str = "token:exchange";
+/// The policy resource type of the audience of a token exchange.
+pub const AUDIENCE_RESOURCE: &str = "audience";
+
+/// Exchanges an access token for a narrower one issued to another audience.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `client` - The authenticated client that acts on behalf of the user.
+/// * `request` - The token request.
+///
+/// # Returns
+///
+/// A `Result` containing the new access token. No refresh token is issued.
+///
+/// # Errors
+///
+/// Returns a `CustomError` with the OAuth error:
+/// - `invalid_request` if a parameter is missing or a token type isn't supported.
+/// - `invalid_grant` if the subject token is invalid, expired or revoked, or is bound to a key
+///   the client didn't prove possession of.
+/// - `invalid_scope` if a scope is requested that the subject token doesn't have.
+/// - `invalid_target` if no policy allows the client to exchange tokens for the audience.
+///
+/// Returns other `CustomError`s if loading the policies or signing the token fails.
+pub async fn exchange_subject_token(
+    db: &Database,
+    client: &OAuthClient,
+    request: &TokenRequest,
+) -> Result<TokenResponse, CustomError> {
+    let subject_token = request
+        .subject_token
+        .as_deref()
+        .ok_or_else(|| oauth_error("invalid_request", "subject_token is required"))?;
+    if !matches!(
+        request.subject_token_type.as_deref(),
+        Some(ACCESS_TOKEN_TYPE | JWT_TOKEN_TYPE)
+    ) {
+        return Err(oauth_error(
+            "invalid_request",
+            "subject_token_type must be an access token or a JWT",
+        ));
+    }
+    if request
+        .requested_token_type
+        .as_deref()
+        .is_some_and(|token_type| token_type != ACCESS_TOKEN_TYPE)
+    {
+        return Err(oauth_error(
+            "invalid_request",
+            "Only access tokens can be requested",
+        ));
+    }
+    let audience = request
+        .audience
+        .as_deref()
+        .filter(|audience| !audience.is_empty())
+        .ok_or_else(|| oauth_error("invalid_request", "audience is required"))?;
+
+    let invalid_grant = || oauth_error("invalid_grant", "The subject token is invalid");
+    let subject = validate_tenant_jwt(db.tenant(), subject_token).map_err(|_| invalid_grant())?;
+    if is_access_token_revoked(db, &subject).await? {
+        return Err(invalid_grant());
+    }
+    // Bound tokens can only be exchanged by the holder of the key, and stay bound to it
+    if let Some(cnf) = &subject.cnf {
+        if request.dpop_jkt.as_deref() != Some(cnf.jkt.as_str()) {
+            tracing::warn!(
+                "Client {} tried to exchange a bound token without its key",
+                client.client_id
+            );
+            return Err(invalid_grant());
+        }
+    }
+
+    // Unscoped tokens stand for all of the user's access, so any scope narrows them
+    let scope = match (normalize_scope(request.scope.as_deref())?, &subject.scope) {
+        (Some(requested), Some(granted)) => {
+            if requested
+                .split(' ')
+                .any(|scope| !granted.split(' ').any(|granted| granted == scope))
+            {
+                return Err(oauth_error(
+                    "invalid_scope",
+                    "The scope exceeds the scope of the subject token",
+                ));
+            }
+            Some(requested)
+        }
+        (requested, granted) => requested.or_else(|| granted.clone()),
+    };
+
+    let scopes: Vec<&str> = scope
+        .as_deref()
+        .map_or(Vec::new(), |scope| scope.split(' ').collect());
+    let decision = policy::authorize(
+        db,
+        &AuthorizationRequest {
+            subject: json!({"type": "client", "id": client.client_id, "name": client.name}),
+            action: EXCHANGE_ACTION.to_string(),
+            resource: json!({
+                "type": AUDIENCE_RESOURCE,
+                "id": audience,
+                "user": subject.sub,
+                "scopes": scopes,
+                "subject_client": subject.client_id,
+                "subject_aud": subject.aud,
+            }),
+            environment: Value::Object(environment_attributes(Utc::now())),
+        },
+    )
+    .await?;
+    if !decision.allowed {
+        tracing::warn!(
+            "Client {} may not exchange tokens for audience {}",
+            client.client_id,
+            audience
+        );
+        return Err(oauth_error(
+            "invalid_target",
+            "The client may not exchange tokens for this audience",
+        ));
+    }
+
+    // The new token keeps the user and their token generation, but never outlives the original
+    let now = Utc::now().timestamp();
+    let mut claims = subject.clone();
+    claims.jti = Uuid::new_v4().to_string();
+    claims.iat = now as usize;
+    let lifetime = client
+        .access_token_lifetime
+        .unwrap_or_else(access_token_lifetime);
+    claims.exp = subject.exp.min((now + lifetime) as usize);
+    claims.aud = Some(audience.to_string());
+    claims.scope = scope.clone();
+    claims.roles = Vec::new();
+    claims.client_id = Some(client.client_id.clone());
+    claims.act = Some(Actor {
+        sub: client.client_id.clone(),
+        act: subject.act.map(Box::new),
+    });
+    claims.cnf = request.dpop_jkt.clone().map(|jkt| Confirmation { jkt });
+    let access_token = encode_jwt(&claims)?;
+    tracing::info!(
+        "Client {} exchanged a token of {} for audience {}",
+        client.client_id,
+        subject.sub,
+        audience
+    );
+
+    Ok(TokenResponse {
+        access_token,
+        token_type: token_type(request.dpop_jkt.as_deref()),
+        expires_in: claims.exp as i64 - now,
+        refresh_token: None,
+        scope,
+        id_token: None,
+        issued_token_type: Some(ACCESS_TOKEN_TYPE.to_string()),
+    })
+}
@@ -0,0 +1,435 @@
+//! src/tokens.rs
+//!
+//! This module issues access and refresh token pairs and rotates refresh tokens.
+
+use crate::database::{Database, OAuthClient};
+use crate::dpop::{Confirmation, DPOP};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::jwt::{access_token_lifetime, encode_jwt, Claims};
+use crate::rbac::effective_roles;
+
+use base64::{engine::general_purpose, Engine as base64Engine};
+use chrono::Utc;
+use rand::{rng, RngCore};
+use serde::Serialize;
+use std::env;
+use uuid::Uuid;
+
+const REFRESH_TOKEN_LIFETIME_ENV: &str = "REFRESH_TOKEN_LIFETIME_SECONDS";
+const DEFAULT_REFRESH_TOKEN_LIFETIME_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// Represents an access token together with its refresh token.
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    /// The short-lived JWT access token.
+    pub access_token: String,
+    /// The opaque refresh token.
+    pub refresh_token: String,
+    /// The type of the access token.
+    pub token_type: String,
+    /// The lifetime of the access token in seconds.
+    pub expires_in: i64,
+}
+
+/// Describes the OAuth client a token pair is issued to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientGrant {
+    /// The ID of the client.
+    pub client_id: String,
+    /// The space-separated scopes granted to the client, if any.
+    pub scope: Option<String>,
+    /// The JWK thumbprint of the DPoP key the tokens are bound to, if any.
+    pub jkt: Option<String>,
+    /// The lifetime of the access token in seconds.
+    pub access_token_lifetime: i64,
+    /// The lifetime of the refresh token in seconds.
+    pub refresh_token_lifetime: i64,
+}
+
+impl ClientGrant {
+    /// Creates a grant for a client, with the client's token lifetimes or the server defaults.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client the tokens are issued to.
+    /// * `scope` - The space-separated scopes granted to the client, if any.
+    /// * `jkt` - The thumbprint of the DPoP key the tokens are bound to, if any.
+    pub fn new(client: &OAuthClient, scope: Option<String>, jkt: Option<String>) -> Self {
+        ClientGrant {
+            client_id: client.client_id.clone(),
+            scope,
+            jkt,
+            access_token_lifetime: client
+                .access_token_lifetime
+                .unwrap_or_else(access_token_lifetime),
+            refresh_token_lifetime: client
+                .refresh_token_lifetime
+                .unwrap_or_else(refresh_token_lifetime),
+        }
+    }
+}
+
+/// Returns the token type of an access token: `DPoP` if it is bound to a key, else `Bearer`.
+///
+/// # Arguments
+///
+/// * `jkt` - The thumbprint of the key the token is bound to, if any.
+pub fn token_type(jkt: Option<&str>) -> String {
+    match jkt {
+        Some(_) => DPOP.to_string(),
+        None => "Bearer".to_string(),
+    }
+}
+
+/// Returns the lifetime of refresh tokens in seconds.
+///
+/// The lifetime is read from the `REFRESH_TOKEN_LIFETIME_SECONDS` environment variable and
+/// defaults to 30 days if it is missing or invalid.
+pub fn refresh_token_lifetime() -> i64 {
+    env::var(REFRESH_TOKEN_LIFETIME_ENV)
+        .ok()
+        .and_then(|lifetime| lifetime.parse::<i64>().ok())
+        .filter(|lifetime| *lifetime > 0)
+        .unwrap_or(DEFAULT_REFRESH_TOKEN_LIFETIME_SECONDS)
+}
+
+/// Generates a new opaque token with 256 bits of randomness.
+///
+/// # Returns
+///
+/// The URL-safe base64-encoded token.
+pub fn generate_opaque_token() -> String {
+    let mut bytes = [0u8; 32];
+    rng().fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Issues a new token pair for the given user, starting a new refresh token family.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `auth_methods` - The methods the user authenticated with, e.g. `pwd` (RFC 8176).
+///
+/// # Returns
+///
+/// A `Result` containing the new token pair.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Generating the JWT fails.
+/// - Storing the refresh token fails.
+pub async fn issue_token_pair(
+    db: &Database,
+    user_id: &str,
+    auth_methods: &[String],
+) -> Result<TokenPair, CustomError> {
+    let family_id = Uuid::new_v4().to_string();
+    issue_token_pair_in_family(db, user_id, &family_id, auth_methods, None).await
+}
+
+/// Issues a new token pair for a user to an OAuth client.
+///
+/// The access token carries the client ID and the granted scopes, and the refresh token can
+/// only be exchanged by the same client.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `family_id` - The ID of the new refresh token family.
+/// * `auth_methods` - The methods the user authenticated with.
+/// * `grant` - The client and scopes the tokens are issued for.
+///
+/// # Returns
+///
+/// A `Result` containing the new token pair.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Generating the JWT fails.
+/// - Storing the refresh token fails.
+pub async fn issue_client_token_pair(
+    db: &Database,
+    user_id: &str,
+    family_id: &str,
+    auth_methods: &[String],
+    grant: &ClientGrant,
+) -> Result<TokenPair, CustomError> {
+    issue_token_pair_in_family(db, user_id, family_id, auth_methods, Some(grant)).await
+}
+
+/// Issues an access token to an OAuth client acting on its own behalf.
+///
+/// The subject of the token is the client itself. It carries no roles, only the granted scopes,
+/// and comes without a refresh token.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `grant` - The client and scopes the token is issued for.
+///
+/// # Returns
+///
+/// A `Result` containing the signed access token.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Generating the JWT fails.
+pub fn issue_service_token(db: &Database, grant: &ClientGrant) -> Result<String, CustomError> {
+    let mut claims = Claims::new(grant.client_id.clone(), 0);
+    claims.tenant = Some(db.tenant().to_string());
+    claims.client_id = Some(grant.client_id.clone());
+    claims.scope = grant.scope.clone();
+    claims.cnf = grant.jkt.clone().map(|jkt| Confirmation { jkt });
+    claims.exp = claims.iat + grant.access_token_lifetime as usize;
+    Ok(encode_jwt(&claims)?)
+}
+
+/// Builds the access token claims for a user.
+///
+/// Besides the standard claims, the username, the roles, the email verification state and the
+/// authentication methods are embedded so that downstream services don't need to look them up.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `auth_methods` - The methods the user authenticated with.
+///
+/// # Returns
+///
+/// A `Result` containing the claims.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the user fails.
+pub async fn user_claims(
+    db: &Database,
+    user_id: &str,
+    auth_methods: &[String],
+) -> Result<Claims, CustomError> {
+    let generation = db.get_token_generation(user_id).await?;
+    let mut claims = Claims::new(user_id.to_string(), generation);
+    claims.amr = auth_methods.to_vec();
+    claims.tenant = Some(db.tenant().to_string());
+    if let Some(user) = db.get_user_by_id(user_id).await? {
+        claims.username = Some(user.username);
+        claims.email_verified = Some(user.email_verified);
+        claims.roles = effective_roles(db, user_id).await?;
+    }
+    Ok(claims)
+}
+
+/// Checks whether a validly signed access token has been revoked.
+///
+/// A token is revoked if it is in the revocation store, if it was issued before the user
+/// logged out everywhere, or if the OAuth client it was issued to is disabled or deleted.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `claims` - The claims of the access token.
+///
+/// # Returns
+///
+/// A `Result` containing `true` if the token must be rejected.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Looking up the revocation store, the user or the client fails.
+pub async fn is_access_token_revoked(db: &Database, claims: &Claims) -> Result<bool, CustomError> {
+    if db.is_access_token_revoked(&claims.jti).await? {
+        tracing::warn!("Revoked token presented for user: {}", claims.sub);
+        return Ok(true);
+    }
+
+    if claims.gen < db.get_token_generation(&claims.sub).await? {
+        tracing::warn!(
+            "Outdated token generation presented for user: {}",
+            claims.sub
+        );
+        return Ok(true);
+    }
+
+    // Tokens of OAuth clients stop working as soon as the client is disabled or deleted
+    if let Some(client_id) = &claims.client_id {
+        let client = db.get_oauth_client(client_id).await?;
+        if client.is_none_or(|client| client.disabled) {
+            tracing::warn!(
+                "Token of disabled or deleted client presented: {}",
+                client_id
+            );
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Exchanges a refresh token for a new token pair.
+///
+/// The presented refresh token is consumed and a new one from the same family is issued. If a
+/// token that was already consumed is presented again, the whole family is revoked, since either
+/// the legitimate client or an attacker is holding a stolen copy. Tokens issued to an OAuth
+/// client are rejected; they have to be refreshed with [`rotate_client_refresh_token`].
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `refresh_token` - The refresh token presented by the client.
+///
+/// # Returns
+///
+/// A `Result` containing the new token pair.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The refresh token is unknown, expired or revoked.
+/// - The refresh token was already used.
+/// - Generating or storing the new tokens fails.
+pub async fn rotate_refresh_token(
+    db: &Database,
+    refresh_token: &str,
+) -> Result<TokenPair, CustomError> {
+    rotate_client_refresh_token(db, refresh_token, None, None).await
+}
+
+/// Exchanges a refresh token issued to an OAuth client for a new token pair.
+///
+/// Works like [`rotate_refresh_token`], but only accepts tokens that were issued to the given
+/// client. The new tokens keep the scopes of the old ones.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `refresh_token` - The refresh token presented by the client.
+/// * `client` - The authenticated client, or `None` outside of OAuth.
+/// * `jkt` - The thumbprint of the DPoP key the client proved possession of, if any. The new
+///   tokens are bound to it.
+///
+/// # Returns
+///
+/// A `Result` containing the new token pair.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The refresh token is unknown, expired, revoked or was issued to another client.
+/// - The refresh token is bound to a DPoP key the client didn't prove possession of.
+/// - The refresh token was already used.
+/// - Generating or storing the new tokens fails.
+pub async fn rotate_client_refresh_token(
+    db: &Database,
+    refresh_token: &str,
+    client: Option<&OAuthClient>,
+    jkt: Option<&str>,
+) -> Result<TokenPair, CustomError> {
+    let token_hash = hash_token(refresh_token);
+    let stored = match db.find_refresh_token(&token_hash).await? {
+        Some(stored) => stored,
+        None => {
+            tracing::warn!("Unknown refresh token presented");
+            return Err(CustomError::InvalidRefreshToken);
+        }
+    };
+
+    if stored.client_id.as_deref() != client.map(|client| client.client_id.as_str()) {
+        tracing::warn!(
+            "Refresh token of user {} presented by the wrong client",
+            stored.user_id
+        );
+        return Err(CustomError::InvalidRefreshToken);
+    }
+
+    if stored.jkt.is_some() && stored.jkt.as_deref() != jkt {
+        tracing::warn!(
+            "Refresh token of user {} presented without its DPoP key",
+            stored.user_id
+        );
+        return Err(CustomError::InvalidRefreshToken);
+    }
+
+    if stored.revoked {
+        tracing::warn!(
+            "Revoked refresh token presented for user: {}",
+            stored.user_id
+        );
+        return Err(CustomError::InvalidRefreshToken);
+    }
+
+    if stored.expires_at <= Utc::now().timestamp() {
+        tracing::warn!(
+            "Expired refresh token presented for user: {}",
+            stored.user_id
+        );
+        return Err(CustomError::InvalidRefreshToken);
+    }
+
+    if stored.used || !db.consume_refresh_token(&token_hash).await? {
+        tracing::warn!(
+            "Refresh token reuse detected for user {}, revoking family {}",
+            stored.user_id,
+            stored.family_id
+        );
+        db.revoke_refresh_token_family(&stored.family_id).await?;
+        return Err(CustomError::RefreshTokenReuse);
+    }
+
+    let grant =
+        client.map(|client| ClientGrant::new(client, stored.scope, jkt.map(str::to_string)));
+    issue_token_pair_in_family(
+        db,
+        &stored.user_id,
+        &stored.family_id,
+        &stored.auth_methods,
+        grant.as_ref(),
+    )
+    .await
+}
+
+/// Issues a token pair whose refresh token belongs to the given family.
+async fn issue_token_pair_in_family(
+    db: &Database,
+    user_id: &str,
+    family_id: &str,
+    auth_methods: &[String],
+    grant: Option<&ClientGrant>,
+) -> Result<TokenPair, CustomError> {
+    let mut claims = user_claims(db, user_id, auth_methods).await?;
+    if let Some(grant) = grant {
+        claims.client_id = Some(grant.client_id.clone());
+        claims.scope = grant.scope.clone();
+        claims.cnf = grant.jkt.clone().map(|jkt| Confirmation { jkt });
+        claims.exp = claims.iat + grant.access_token_lifetime as usize;
+    }
+    let access_token = encode_jwt(&claims)?;
+
+    let refresh_token = generate_opaque_token();
+    let expires_at = Utc::now().timestamp()
+        + grant.map_or_else(refresh_token_lifetime, |grant| grant.refresh_token_lifetime);
+    db.store_refresh_token(
+        user_id,
+        family_id,
+        &hash_token(&refresh_token),
+        expires_at,
+        auth_methods,
+        grant,
+    )
+    .await?;
+
+    Ok(TokenPair {
+        access_token,
+        refresh_token,
+        token_type: token_type(grant.and_then(|grant| grant.jkt.as_deref())),
+        expires_in: grant.map_or_else(access_token_lifetime, |grant| grant.access_token_lifetime),
+    })
+}
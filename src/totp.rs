@@ -0,0 +1,143 @@
+//! src/totp.rs
+//!
+//! This module implements time-based one-time passwords (RFC 6238) for two-factor authentication.
+
+use crate::errors::custom_errors::CustomError;
+
+use rand::{rng, RngCore};
+use std::env;
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const TOTP_ISSUER_ENV: &str = "TOTP_ISSUER";
+const DEFAULT_TOTP_ISSUER: &str = "IAM";
+
+/// The number of digits in a code.
+const DIGITS: usize = 6;
+/// The length of a time step in seconds.
+const STEP_SECONDS: u64 = 30;
+/// The number of time steps a code may be early or late to allow for clock drift.
+const SKEW_STEPS: i64 = 1;
+/// The length of a generated secret in bytes, as recommended by RFC 4226.
+const SECRET_LENGTH: usize = 20;
+
+/// Returns the issuer shown in authenticator apps.
+///
+/// The issuer is read from the `TOTP_ISSUER` environment variable and defaults to `IAM`.
+pub fn configured_issuer() -> String {
+    env::var(TOTP_ISSUER_ENV)
+        .ok()
+        .filter(|issuer| !issuer.is_empty())
+        .unwrap_or_else(|| DEFAULT_TOTP_ISSUER.to_string())
+}
+
+/// Generates a new random TOTP secret.
+///
+/// # Returns
+///
+/// The base32-encoded secret.
+pub fn generate_secret() -> String {
+    let mut bytes = vec![0u8; SECRET_LENGTH];
+    rng().fill_bytes(&mut bytes);
+    Secret::Raw(bytes).to_encoded().to_string()
+}
+
+/// Builds the `otpauth://` URI used to provision an authenticator app, usually shown as a QR code.
+///
+/// # Arguments
+///
+/// * `secret` - The base32-encoded secret.
+/// * `account_name` - The name of the account, e.g. the user's email address.
+///
+/// # Returns
+///
+/// A `Result` containing the provisioning URI.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The secret is invalid.
+/// - The issuer or account name contains a colon.
+pub fn provisioning_uri(secret: &str, account_name: &str) -> Result<String, CustomError> {
+    Ok(totp(secret, Some(configured_issuer()), account_name)?.get_url())
+}
+
+/// Generates the code for the given time.
+///
+/// # Arguments
+///
+/// * `secret` - The base32-encoded secret.
+/// * `timestamp` - The unix timestamp in seconds.
+///
+/// # Returns
+///
+/// A `Result` containing the code.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The secret is invalid.
+pub fn generate_code(secret: &str, timestamp: u64) -> Result<String, CustomError> {
+    Ok(totp(secret, None, "")?.generate(timestamp))
+}
+
+/// Verifies a code against the given secret.
+///
+/// Codes from one step before or after the current one are accepted to allow for clock drift.
+/// To prevent a code from being replayed, steps up to and including `last_step` are rejected.
+///
+/// # Arguments
+///
+/// * `secret` - The base32-encoded secret.
+/// * `code` - The code entered by the user.
+/// * `timestamp` - The current unix timestamp in seconds.
+/// * `last_step` - The time step of the last code that was accepted, if any.
+///
+/// # Returns
+///
+/// A `Result` containing the time step of the matching code, or `None` if the code is invalid.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The secret is invalid.
+pub fn verify_code(
+    secret: &str,
+    code: &str,
+    timestamp: u64,
+    last_step: Option<i64>,
+) -> Result<Option<i64>, CustomError> {
+    let totp = totp(secret, None, "")?;
+    let code = code.trim();
+    let current_step = (timestamp / STEP_SECONDS) as i64;
+
+    let mut matched = None;
+    for step in (current_step - SKEW_STEPS)..=(current_step + SKEW_STEPS) {
+        if step < 0 || last_step.is_some_and(|last_step| step <= last_step) {
+            continue;
+        }
+        let expected = totp.generate(step as u64 * STEP_SECONDS);
+        // Compare every candidate in constant time so that timing doesn't reveal which step matched.
+        if bool::from(expected.as_bytes().ct_eq(code.as_bytes())) && matched.is_none() {
+            matched = Some(step);
+        }
+    }
+    Ok(matched)
+}
+
+/// Builds a TOTP instance for the given secret.
+fn totp(secret: &str, issuer: Option<String>, account_name: &str) -> Result<TOTP, CustomError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|error| CustomError::InvalidTotpSecret(error.to_string()))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP_SECONDS,
+        secret,
+        issuer,
+        account_name.to_string(),
+    )
+    .map_err(|error| CustomError::InvalidTotpSecret(error.to_string()))
+}
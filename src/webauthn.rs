@@ -0,0 +1,705 @@
+//! src/webauthn.rs
+//!
+//! This module implements the WebAuthn registration and authentication ceremonies for passkeys.
+//!
+//! Attestation statements are not verified: the server asks for `none` attestation and only
+//! needs the credential's public key. ES256, EdDSA and RS256 credentials are supported.
+
+use crate::database::{Database, WebAuthnChallenge, WebAuthnCredential};
+use crate::errors::custom_errors::CustomError;
+use crate::hashing::hash_token;
+use crate::tokens::generate_opaque_token;
+
+use base64::{engine::general_purpose, Engine as base64Engine};
+use chrono::Utc;
+use ciborium::Value as CborValue;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::env;
+
+const RP_ID_ENV: &str = "WEBAUTHN_RP_ID";
+const DEFAULT_RP_ID: &str = "localhost";
+const RP_NAME_ENV: &str = "WEBAUTHN_RP_NAME";
+const DEFAULT_RP_NAME: &str = "IAM";
+const ORIGINS_ENV: &str = "WEBAUTHN_ORIGINS";
+const DEFAULT_ORIGIN: &str = "http://localhost:8080";
+
+/// The lifetime of a ceremony in seconds.
+const CHALLENGE_LIFETIME_SECONDS: i64 = 5 * 60;
+
+/// COSE algorithm identifier for ECDSA with P-256 and SHA-256.
+pub const COSE_ALG_ES256: i64 = -7;
+/// COSE algorithm identifier for EdDSA.
+pub const COSE_ALG_EDDSA: i64 = -8;
+/// COSE algorithm identifier for RSASSA-PKCS1-v1_5 with SHA-256.
+pub const COSE_ALG_RS256: i64 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+const CEREMONY_REGISTRATION: &str = "registration";
+const CEREMONY_AUTHENTICATION: &str = "authentication";
+
+/// Represents the credential returned by `navigator.credentials.create()`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RegistrationCredential {
+    /// The base64url-encoded credential ID.
+    pub id: String,
+    /// The credential type, always `public-key`.
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    /// The authenticator's response.
+    pub response: AttestationResponse,
+}
+
+/// Represents the authenticator's response to a registration ceremony.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    /// The base64url-encoded client data JSON.
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    /// The base64url-encoded CBOR attestation object.
+    pub attestation_object: String,
+}
+
+/// Represents the credential returned by `navigator.credentials.get()`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AssertionCredential {
+    /// The base64url-encoded credential ID.
+    pub id: String,
+    /// The credential type, always `public-key`.
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    /// The authenticator's response.
+    pub response: AssertionResponse,
+}
+
+/// Represents the authenticator's response to an authentication ceremony.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    /// The base64url-encoded client data JSON.
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    /// The base64url-encoded authenticator data.
+    pub authenticator_data: String,
+    /// The base64url-encoded signature.
+    pub signature: String,
+    /// The base64url-encoded user handle, returned for discoverable credentials.
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+/// Represents a successfully verified assertion.
+#[derive(Debug, Clone)]
+pub struct VerifiedAssertion {
+    /// The ID of the user the credential belongs to.
+    pub user_id: String,
+    /// The base64url-encoded credential ID.
+    pub credential_id: String,
+    /// Whether the authenticator verified the user, e.g. with a PIN or biometrics.
+    pub user_verified: bool,
+}
+
+/// Represents the client data collected by the browser.
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony_type: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Represents parsed authenticator data.
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    attested_credential: Option<AttestedCredential>,
+}
+
+/// Represents the attested credential data included in a registration.
+struct AttestedCredential {
+    credential_id: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+/// Represents a credential public key decoded from its COSE encoding.
+enum CoseKey {
+    Es256 { x: Vec<u8>, y: Vec<u8> },
+    EdDsa { x: Vec<u8> },
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+/// Returns the relying party ID, i.e. the domain passkeys are scoped to.
+///
+/// The ID is read from the `WEBAUTHN_RP_ID` environment variable and defaults to `localhost`.
+pub fn relying_party_id() -> String {
+    env::var(RP_ID_ENV)
+        .ok()
+        .filter(|rp_id| !rp_id.is_empty())
+        .unwrap_or_else(|| DEFAULT_RP_ID.to_string())
+}
+
+/// Returns the relying party name shown by authenticators.
+///
+/// The name is read from the `WEBAUTHN_RP_NAME` environment variable and defaults to `IAM`.
+pub fn relying_party_name() -> String {
+    env::var(RP_NAME_ENV)
+        .ok()
+        .filter(|rp_name| !rp_name.is_empty())
+        .unwrap_or_else(|| DEFAULT_RP_NAME.to_string())
+}
+
+/// Returns the origins WebAuthn responses are accepted from.
+///
+/// The origins are read from the comma-separated `WEBAUTHN_ORIGINS` environment variable and
+/// default to `http://localhost:8080`.
+pub fn allowed_origins() -> Vec<String> {
+    let origins: Vec<String> = env::var(ORIGINS_ENV)
+        .unwrap_or_default()
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    if origins.is_empty() {
+        vec![DEFAULT_ORIGIN.to_string()]
+    } else {
+        origins
+    }
+}
+
+/// Returns the WebAuthn user handle of a user.
+///
+/// The handle is a hash of the user ID, so authenticators never store the ID itself.
+///
+/// # Arguments
+///
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// The user handle.
+pub fn user_handle(user_id: &str) -> Vec<u8> {
+    Sha256::digest(user_id.as_bytes()).to_vec()
+}
+
+/// Starts registering a new passkey for a user.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the options to pass to `navigator.credentials.create()`.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The user doesn't exist.
+/// - Storing the challenge fails.
+pub async fn start_registration(
+    db: &Database,
+    user_id: &str,
+) -> Result<serde_json::Value, CustomError> {
+    let user = db
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or(CustomError::UserNotFound)?;
+    let challenge = create_challenge(db, CEREMONY_REGISTRATION, Some(user_id), false).await?;
+
+    // Keep authenticators from registering a second credential for the same account.
+    let exclude_credentials: Vec<serde_json::Value> = db
+        .get_webauthn_credentials(user_id)
+        .await?
+        .into_iter()
+        .map(|credential| json!({"type": "public-key", "id": credential.credential_id}))
+        .collect();
+
+    Ok(json!({
+        "challenge": challenge,
+        "rp": {"id": relying_party_id(), "name": relying_party_name()},
+        "user": {
+            "id": encode(&user_handle(user_id)),
+            "name": user.email,
+            "displayName": user.username,
+        },
+        "pubKeyCredParams": [
+            {"type": "public-key", "alg": COSE_ALG_ES256},
+            {"type": "public-key", "alg": COSE_ALG_EDDSA},
+            {"type": "public-key", "alg": COSE_ALG_RS256},
+        ],
+        "timeout": CHALLENGE_LIFETIME_SECONDS * 1000,
+        "attestation": "none",
+        "authenticatorSelection": {
+            "residentKey": "preferred",
+            "userVerification": "preferred",
+        },
+        "excludeCredentials": exclude_credentials,
+    }))
+}
+
+/// Finishes registering a passkey and stores the credential.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user.
+/// * `credential` - The credential returned by the browser.
+/// * `name` - An optional name for the credential.
+///
+/// # Returns
+///
+/// A `Result` containing the stored credential.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The challenge is unknown, expired or belongs to another user.
+/// - The response fails verification.
+/// - The credential is already registered.
+/// - Storing the credential fails.
+pub async fn finish_registration(
+    db: &Database,
+    user_id: &str,
+    credential: &RegistrationCredential,
+    name: Option<&str>,
+) -> Result<WebAuthnCredential, CustomError> {
+    if credential.credential_type != "public-key" {
+        return Err(webauthn_error("Unsupported credential type"));
+    }
+
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    let challenge = consume_challenge(db, &client_data_json, CEREMONY_REGISTRATION).await?;
+    if challenge.user_id.as_deref() != Some(user_id) {
+        return Err(webauthn_error("Challenge was issued to another user"));
+    }
+
+    let auth_data_bytes =
+        parse_attestation_object(&decode(&credential.response.attestation_object)?)?;
+    let auth_data = parse_authenticator_data(&auth_data_bytes)?;
+    verify_authenticator_data(&auth_data, challenge.user_verification_required)?;
+
+    let attested = auth_data
+        .attested_credential
+        .ok_or_else(|| webauthn_error("Missing attested credential data"))?;
+    let credential_id = encode(&attested.credential_id);
+    if decode(&credential.id)? != attested.credential_id {
+        return Err(webauthn_error("Credential ID mismatch"));
+    }
+    let algorithm = parse_cose_key(&attested.public_key)?.algorithm();
+
+    if db.find_webauthn_credential(&credential_id).await?.is_some() {
+        return Err(webauthn_error("Credential is already registered"));
+    }
+
+    let stored = WebAuthnCredential {
+        credential_id,
+        user_id: user_id.to_string(),
+        public_key: encode(&attested.public_key),
+        algorithm,
+        sign_count: auth_data.sign_count as i64,
+        name: name
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .unwrap_or("Passkey")
+            .to_string(),
+        created_at: Utc::now().timestamp(),
+        last_used_at: None,
+    };
+    db.store_webauthn_credential(&stored).await?;
+
+    tracing::info!("Registered passkey for user: {}", user_id);
+    Ok(stored)
+}
+
+/// Starts an authentication ceremony.
+///
+/// If a user is given, the ceremony is bound to them and only their credentials are allowed.
+/// Otherwise the browser offers any discoverable credential for this relying party.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `user_id` - The ID of the user to authenticate, if known.
+/// * `user_verification_required` - Whether the authenticator has to verify the user.
+///
+/// # Returns
+///
+/// A `Result` containing the options to pass to `navigator.credentials.get()`.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - Storing the challenge fails.
+pub async fn start_authentication(
+    db: &Database,
+    user_id: Option<&str>,
+    user_verification_required: bool,
+) -> Result<serde_json::Value, CustomError> {
+    let challenge = create_challenge(
+        db,
+        CEREMONY_AUTHENTICATION,
+        user_id,
+        user_verification_required,
+    )
+    .await?;
+
+    let allow_credentials: Vec<serde_json::Value> = match user_id {
+        Some(user_id) => db
+            .get_webauthn_credentials(user_id)
+            .await?
+            .into_iter()
+            .map(|credential| json!({"type": "public-key", "id": credential.credential_id}))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(json!({
+        "challenge": challenge,
+        "rpId": relying_party_id(),
+        "timeout": CHALLENGE_LIFETIME_SECONDS * 1000,
+        "allowCredentials": allow_credentials,
+        "userVerification": if user_verification_required { "required" } else { "preferred" },
+    }))
+}
+
+/// Finishes an authentication ceremony.
+///
+/// # Arguments
+///
+/// * `db` - The database connection.
+/// * `credential` - The credential returned by the browser.
+///
+/// # Returns
+///
+/// A `Result` containing the verified assertion.
+///
+/// # Errors
+///
+/// Returns a `CustomError` if:
+/// - The challenge is unknown or expired.
+/// - The credential is unknown or belongs to another user than the ceremony.
+/// - The response fails verification.
+/// - The signature counter indicates a cloned authenticator.
+pub async fn finish_authentication(
+    db: &Database,
+    credential: &AssertionCredential,
+) -> Result<VerifiedAssertion, CustomError> {
+    if credential.credential_type != "public-key" {
+        return Err(webauthn_error("Unsupported credential type"));
+    }
+
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    let challenge = consume_challenge(db, &client_data_json, CEREMONY_AUTHENTICATION).await?;
+
+    let credential_id = encode(&decode(&credential.id)?);
+    let stored = db
+        .find_webauthn_credential(&credential_id)
+        .await?
+        .ok_or_else(|| webauthn_error("Unknown credential"))?;
+    if let Some(user_id) = &challenge.user_id {
+        if *user_id != stored.user_id {
+            return Err(webauthn_error("Credential belongs to another user"));
+        }
+    }
+    if let Some(handle) = &credential.response.user_handle {
+        if decode(handle)? != user_handle(&stored.user_id) {
+            return Err(webauthn_error("User handle mismatch"));
+        }
+    }
+
+    let auth_data_bytes = decode(&credential.response.authenticator_data)?;
+    let auth_data = parse_authenticator_data(&auth_data_bytes)?;
+    verify_authenticator_data(&auth_data, challenge.user_verification_required)?;
+
+    // The signature covers the authenticator data followed by the hash of the client data.
+    let mut signed_data = auth_data_bytes.clone();
+    signed_data.extend_from_slice(&Sha256::digest(&client_data_json));
+    let public_key = parse_cose_key(&decode(&stored.public_key)?)?;
+    public_key.verify(&signed_data, &decode(&credential.response.signature)?)?;
+
+    // Authenticators that keep a counter must increase it on every use. A counter that didn't
+    // move forward means that the credential was cloned.
+    let sign_count = auth_data.sign_count as i64;
+    if (sign_count != 0 || stored.sign_count != 0) && sign_count <= stored.sign_count {
+        tracing::warn!(
+            "Signature counter of passkey {} went backwards for user {}",
+            stored.credential_id,
+            stored.user_id
+        );
+        return Err(webauthn_error("Signature counter did not increase"));
+    }
+    db.update_webauthn_credential_usage(&stored.credential_id, sign_count)
+        .await?;
+
+    Ok(VerifiedAssertion {
+        user_id: stored.user_id,
+        credential_id: stored.credential_id,
+        user_verified: auth_data.flags & FLAG_USER_VERIFIED != 0,
+    })
+}
+
+/// Creates and stores a new challenge.
+async fn create_challenge(
+    db: &Database,
+    ceremony: &str,
+    user_id: Option<&str>,
+    user_verification_required: bool,
+) -> Result<String, CustomError> {
+    let challenge = generate_opaque_token();
+    db.store_webauthn_challenge(&WebAuthnChallenge {
+        challenge_hash: hash_token(&challenge),
+        ceremony: ceremony.to_string(),
+        user_id: user_id.map(str::to_string),
+        user_verification_required,
+        expires_at: Utc::now().timestamp() + CHALLENGE_LIFETIME_SECONDS,
+    })
+    .await?;
+    Ok(challenge)
+}
+
+/// Checks the client data and consumes the challenge it refers to.
+async fn consume_challenge(
+    db: &Database,
+    client_data_json: &[u8],
+    ceremony: &str,
+) -> Result<WebAuthnChallenge, CustomError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| webauthn_error("Invalid client data"))?;
+
+    let expected_type = match ceremony {
+        CEREMONY_REGISTRATION => "webauthn.create",
+        _ => "webauthn.get",
+    };
+    if client_data.ceremony_type != expected_type {
+        return Err(webauthn_error("Unexpected client data type"));
+    }
+    if !allowed_origins().contains(&client_data.origin) {
+        tracing::warn!(
+            "WebAuthn response from unexpected origin: {}",
+            client_data.origin
+        );
+        return Err(webauthn_error("Unexpected origin"));
+    }
+
+    let challenge = db
+        .consume_webauthn_challenge(&hash_token(&client_data.challenge))
+        .await?
+        .ok_or_else(|| webauthn_error("Unknown challenge"))?;
+    if challenge.ceremony != ceremony || challenge.expires_at <= Utc::now().timestamp() {
+        return Err(webauthn_error("Unknown challenge"));
+    }
+    Ok(challenge)
+}
+
+/// Checks the relying party and the user presence and verification flags.
+fn verify_authenticator_data(
+    auth_data: &AuthenticatorData,
+    user_verification_required: bool,
+) -> Result<(), CustomError> {
+    if auth_data.rp_id_hash != Sha256::digest(relying_party_id().as_bytes()).as_slice() {
+        return Err(webauthn_error("Relying party mismatch"));
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(webauthn_error("User presence is required"));
+    }
+    if user_verification_required && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(webauthn_error("User verification is required"));
+    }
+    Ok(())
+}
+
+/// Extracts the authenticator data from a CBOR attestation object.
+fn parse_attestation_object(bytes: &[u8]) -> Result<Vec<u8>, CustomError> {
+    let object: CborValue =
+        ciborium::from_reader(bytes).map_err(|_| webauthn_error("Invalid attestation object"))?;
+    let entries = object
+        .into_map()
+        .map_err(|_| webauthn_error("Invalid attestation object"))?;
+    entries
+        .into_iter()
+        .find(|(key, _)| key.as_text() == Some("authData"))
+        .and_then(|(_, value)| value.into_bytes().ok())
+        .ok_or_else(|| webauthn_error("Missing authenticator data"))
+}
+
+/// Parses authenticator data as defined in the WebAuthn specification.
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, CustomError> {
+    if data.len() < 37 {
+        return Err(webauthn_error("Authenticator data is too short"));
+    }
+    let rp_id_hash = data[..32].to_vec();
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        // The attested credential data starts with a 16 byte AAGUID and the credential ID length.
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(webauthn_error("Attested credential data is too short"));
+        }
+        let id_length = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        if rest.len() < 18 + id_length {
+            return Err(webauthn_error("Attested credential data is too short"));
+        }
+        let credential_id = rest[18..18 + id_length].to_vec();
+
+        // The public key is followed by optional extensions, so decode it to find its end.
+        let mut key_bytes = &rest[18 + id_length..];
+        let key: CborValue = ciborium::from_reader(&mut key_bytes)
+            .map_err(|_| webauthn_error("Invalid credential public key"))?;
+        let mut public_key = Vec::new();
+        ciborium::into_writer(&key, &mut public_key)
+            .map_err(|_| webauthn_error("Invalid credential public key"))?;
+
+        Some(AttestedCredential {
+            credential_id,
+            public_key,
+        })
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        rp_id_hash,
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+/// Decodes a COSE public key.
+fn parse_cose_key(bytes: &[u8]) -> Result<CoseKey, CustomError> {
+    let key: CborValue = ciborium::from_reader(bytes)
+        .map_err(|_| webauthn_error("Invalid credential public key"))?;
+    let entries = key
+        .into_map()
+        .map_err(|_| webauthn_error("Invalid credential public key"))?;
+
+    let integer = |label: i64| -> Option<i128> {
+        entries.iter().find_map(
+            |(key, value)| match (key.as_integer(), value.as_integer()) {
+                (Some(key), Some(value)) if i128::from(key) == label as i128 => {
+                    Some(i128::from(value))
+                }
+                _ => None,
+            },
+        )
+    };
+    let bytes = |label: i64| -> Result<Vec<u8>, CustomError> {
+        entries
+            .iter()
+            .find_map(|(key, value)| match (key.as_integer(), value.as_bytes()) {
+                (Some(key), Some(value)) if i128::from(key) == label as i128 => Some(value.clone()),
+                _ => None,
+            })
+            .ok_or_else(|| webauthn_error("Incomplete credential public key"))
+    };
+
+    match integer(3).map(|algorithm| algorithm as i64) {
+        Some(COSE_ALG_ES256) => Ok(CoseKey::Es256 {
+            x: bytes(-2)?,
+            y: bytes(-3)?,
+        }),
+        Some(COSE_ALG_EDDSA) => Ok(CoseKey::EdDsa { x: bytes(-2)? }),
+        Some(COSE_ALG_RS256) => Ok(CoseKey::Rs256 {
+            n: bytes(-1)?,
+            e: bytes(-2)?,
+        }),
+        _ => Err(webauthn_error("Unsupported credential algorithm")),
+    }
+}
+
+impl CoseKey {
+    /// Returns the COSE algorithm identifier of the key.
+    fn algorithm(&self) -> i64 {
+        match self {
+            CoseKey::Es256 { .. } => COSE_ALG_ES256,
+            CoseKey::EdDsa { .. } => COSE_ALG_EDDSA,
+            CoseKey::Rs256 { .. } => COSE_ALG_RS256,
+        }
+    }
+
+    /// Verifies a signature made with the key.
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), CustomError> {
+        let valid = match self {
+            CoseKey::Es256 { x, y } => {
+                use p256::ecdsa::signature::Verifier;
+                use p256::ecdsa::{Signature, VerifyingKey};
+                use p256::EncodedPoint;
+
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(webauthn_error("Invalid credential public key"));
+                }
+                let point = EncodedPoint::from_affine_coordinates(
+                    x.as_slice().into(),
+                    y.as_slice().into(),
+                    false,
+                );
+                let key = VerifyingKey::from_encoded_point(&point)
+                    .map_err(|_| webauthn_error("Invalid credential public key"))?;
+                // WebAuthn ECDSA signatures are DER-encoded.
+                match Signature::from_der(signature) {
+                    Ok(signature) => key.verify(message, &signature).is_ok(),
+                    Err(_) => false,
+                }
+            }
+            CoseKey::EdDsa { x } => {
+                use ed25519_dalek::{Signature, VerifyingKey};
+
+                let key_bytes: [u8; 32] = x
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| webauthn_error("Invalid credential public key"))?;
+                let key = VerifyingKey::from_bytes(&key_bytes)
+                    .map_err(|_| webauthn_error("Invalid credential public key"))?;
+                match Signature::from_slice(signature) {
+                    Ok(signature) => key.verify_strict(message, &signature).is_ok(),
+                    Err(_) => false,
+                }
+            }
+            CoseKey::Rs256 { n, e } => {
+                use rsa::pkcs1v15::{Signature, VerifyingKey};
+                use rsa::signature::Verifier;
+                use rsa::{BigUint, RsaPublicKey};
+
+                let key = RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e))
+                    .map_err(|_| webauthn_error("Invalid credential public key"))?;
+                let key = VerifyingKey::<Sha256>::new(key);
+                match Signature::try_from(signature) {
+                    Ok(signature) => key.verify(message, &signature).is_ok(),
+                    Err(_) => false,
+                }
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(webauthn_error("Invalid signature"))
+        }
+    }
+}
+
+/// Encodes bytes as unpadded base64url, the encoding WebAuthn uses for binary values in JSON.
+fn encode(bytes: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Decodes base64url, with or without padding.
+fn decode(value: &str) -> Result<Vec<u8>, CustomError> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| webauthn_error("Invalid base64url value"))
+}
+
+/// Builds a WebAuthn verification error.
+fn webauthn_error(message: &str) -> CustomError {
+    CustomError::WebAuthnError(message.to_string())
+}